@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE doc_group_embeddings
+    DROP COLUMN IF EXISTS first_index,
+    DROP COLUMN IF EXISTS last_index;
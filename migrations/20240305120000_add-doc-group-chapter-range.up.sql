@@ -0,0 +1,14 @@
+-- Add up migration script here
+ALTER TABLE doc_group_embeddings
+    ADD COLUMN first_index INTEGER,
+    ADD COLUMN last_index INTEGER;
+
+-- Existing groups are assumed to cover full, consecutive chapter ranges until they are rebuilt
+UPDATE doc_group_embeddings
+SET
+    first_index = index * doc_group_size,
+    last_index = index * doc_group_size + doc_group_size - 1;
+
+ALTER TABLE doc_group_embeddings
+    ALTER COLUMN first_index SET NOT NULL,
+    ALTER COLUMN last_index SET NOT NULL;
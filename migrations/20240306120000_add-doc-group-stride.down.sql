@@ -0,0 +1,10 @@
+-- Add down migration script here
+DELETE FROM doc_group_embeddings WHERE doc_group_stride <> doc_group_size;
+
+ALTER TABLE doc_group_embeddings DROP CONSTRAINT IF EXISTS unique_story_id_group_size_stride_index;
+
+ALTER TABLE doc_group_embeddings
+    ADD CONSTRAINT unique_story_id_group_size_index
+    UNIQUE (story_id, doc_group_size, index);
+
+ALTER TABLE doc_group_embeddings DROP COLUMN IF EXISTS doc_group_stride;
@@ -0,0 +1,13 @@
+-- Add up migration script here
+ALTER TABLE doc_group_embeddings ADD COLUMN doc_group_stride INTEGER;
+
+-- Groups built so far never overlap, so their stride is their size
+UPDATE doc_group_embeddings SET doc_group_stride = doc_group_size;
+
+ALTER TABLE doc_group_embeddings ALTER COLUMN doc_group_stride SET NOT NULL;
+
+ALTER TABLE doc_group_embeddings DROP CONSTRAINT unique_story_id_group_size_index;
+
+ALTER TABLE doc_group_embeddings
+    ADD CONSTRAINT unique_story_id_group_size_stride_index
+    UNIQUE (story_id, doc_group_size, doc_group_stride, index);
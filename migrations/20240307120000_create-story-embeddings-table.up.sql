@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TABLE story_embeddings (
+    id UUID NOT NULL UNIQUE PRIMARY KEY,
+    story_id BIGINT NOT NULL UNIQUE,
+    chapter_count INTEGER NOT NULL,
+    recency_half_life REAL,
+    qdrant_point_id UUID NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TRIGGER update_updated_at
+BEFORE UPDATE ON story_embeddings
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at();
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS update_updated_at ON named_chapter_ranges;
+
+DROP TABLE IF EXISTS named_chapter_ranges;
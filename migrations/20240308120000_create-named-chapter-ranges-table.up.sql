@@ -0,0 +1,21 @@
+-- Add up migration script here
+CREATE TABLE named_chapter_ranges (
+    id UUID NOT NULL UNIQUE PRIMARY KEY,
+    story_id BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    first_index INTEGER NOT NULL,
+    last_index INTEGER NOT NULL,
+    qdrant_point_id UUID NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT unique_story_id_name UNIQUE (story_id, name),
+    CONSTRAINT valid_chapter_range CHECK (first_index >= 0 AND first_index <= last_index)
+);
+
+CREATE INDEX named_chapter_ranges_story_id_bounds
+    ON named_chapter_ranges (story_id, first_index, last_index);
+
+CREATE TRIGGER update_updated_at
+BEFORE UPDATE ON named_chapter_ranges
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at();
@@ -0,0 +1,17 @@
+-- Add up migration script here
+CREATE TABLE doc_group_sizes (
+    doc_group_size INTEGER NOT NULL,
+    doc_group_stride INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (doc_group_size, doc_group_stride)
+);
+
+-- Register every size that already has groups
+INSERT INTO doc_group_sizes (doc_group_size, doc_group_stride)
+SELECT DISTINCT doc_group_size, doc_group_stride FROM doc_group_embeddings;
+
+CREATE TRIGGER update_updated_at
+BEFORE UPDATE ON doc_group_sizes
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at();
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE doc_group_sizes DROP COLUMN last_rebuilt_at;
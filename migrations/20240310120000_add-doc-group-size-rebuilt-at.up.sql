@@ -0,0 +1,12 @@
+-- Add up migration script here
+ALTER TABLE doc_group_sizes ADD COLUMN last_rebuilt_at TIMESTAMP;
+
+UPDATE doc_group_sizes
+SET last_rebuilt_at = rebuilt.last_rebuilt_at
+FROM (
+    SELECT doc_group_size, doc_group_stride, MAX(updated_at) AS last_rebuilt_at
+    FROM doc_group_embeddings
+    GROUP BY doc_group_size, doc_group_stride
+) AS rebuilt
+WHERE doc_group_sizes.doc_group_size = rebuilt.doc_group_size
+    AND doc_group_sizes.doc_group_stride = rebuilt.doc_group_stride;
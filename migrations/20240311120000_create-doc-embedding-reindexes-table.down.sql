@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS update_updated_at ON doc_embedding_reindexes;
+
+DROP TABLE IF EXISTS doc_embedding_reindexes;
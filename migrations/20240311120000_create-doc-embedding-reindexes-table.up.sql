@@ -0,0 +1,23 @@
+-- Add up migration script here
+CREATE TABLE doc_embedding_reindexes (
+    version INTEGER PRIMARY KEY,
+    collection_name TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'running',
+    total_count BIGINT NOT NULL DEFAULT 0,
+    indexed_count BIGINT NOT NULL DEFAULT 0,
+    error TEXT,
+    previous_collection TEXT,
+    switched_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Only one rebuild runs at a time
+CREATE UNIQUE INDEX doc_embedding_reindexes_one_running
+ON doc_embedding_reindexes ((status))
+WHERE status = 'running';
+
+CREATE TRIGGER update_updated_at
+BEFORE UPDATE ON doc_embedding_reindexes
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at();
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS update_updated_at ON vector_outbox;
+
+DROP TABLE IF EXISTS vector_outbox;
@@ -0,0 +1,25 @@
+-- Add up migration script here
+CREATE TABLE vector_outbox (
+    id BIGSERIAL PRIMARY KEY,
+    collection_name TEXT NOT NULL,
+    operation TEXT NOT NULL,
+    point_id UUID NOT NULL,
+    vector REAL[],
+    payload JSONB,
+    available_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    gave_up_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Mutations of the same point are applied in the order they were recorded
+CREATE INDEX vector_outbox_point
+ON vector_outbox (collection_name, point_id, id)
+WHERE gave_up_at IS NULL;
+
+CREATE TRIGGER update_updated_at
+BEFORE UPDATE ON vector_outbox
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at();
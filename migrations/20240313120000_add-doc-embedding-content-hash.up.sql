@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE doc_embeddings ADD COLUMN content_hash TEXT;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS update_updated_at ON chunk_embeddings;
+
+DROP TABLE IF EXISTS chunk_embeddings;
@@ -0,0 +1,18 @@
+-- Add up migration script here
+CREATE TABLE chunk_embeddings (
+    chunk_hash TEXT NOT NULL,
+    model TEXT NOT NULL,
+    embedding REAL[] NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (chunk_hash, model)
+);
+
+-- Expired entries are found by age
+CREATE INDEX chunk_embeddings_created_at
+ON chunk_embeddings (created_at);
+
+CREATE TRIGGER update_updated_at
+BEFORE UPDATE ON chunk_embeddings
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at();
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS update_updated_at ON story_embedding_sums;
+
+DROP TABLE IF EXISTS story_embedding_sums;
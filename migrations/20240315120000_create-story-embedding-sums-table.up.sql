@@ -0,0 +1,14 @@
+-- Add up migration script here
+CREATE TABLE story_embedding_sums (
+    story_id BIGINT NOT NULL PRIMARY KEY REFERENCES story_embeddings(story_id) ON DELETE CASCADE,
+    weighted_sum DOUBLE PRECISION[] NOT NULL,
+    total_weight DOUBLE PRECISION NOT NULL,
+    latest_index INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TRIGGER update_updated_at
+BEFORE UPDATE ON story_embedding_sums
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at();
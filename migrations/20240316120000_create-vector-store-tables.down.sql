@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS vector_aliases;
+
+DROP TABLE IF EXISTS vector_points;
+
+DROP TABLE IF EXISTS vector_collections;
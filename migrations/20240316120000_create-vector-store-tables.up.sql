@@ -0,0 +1,27 @@
+-- Add up migration script here
+-- Only databases with pgvector available get the tables, so deployments using Qdrant do not
+-- need the extension installed
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM pg_available_extensions WHERE name = 'vector') THEN
+        CREATE EXTENSION IF NOT EXISTS vector;
+
+        CREATE TABLE IF NOT EXISTS vector_collections (
+            name TEXT PRIMARY KEY,
+            size INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS vector_points (
+            collection_name TEXT NOT NULL REFERENCES vector_collections(name) ON DELETE CASCADE,
+            id UUID NOT NULL,
+            embedding vector NOT NULL,
+            payload JSONB NOT NULL DEFAULT '{}',
+            PRIMARY KEY (collection_name, id)
+        );
+
+        CREATE TABLE IF NOT EXISTS vector_aliases (
+            alias TEXT PRIMARY KEY,
+            collection_name TEXT NOT NULL REFERENCES vector_collections(name) ON DELETE CASCADE
+        );
+    END IF;
+END $$;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS update_updated_at ON reconcile_jobs;
+
+DROP TABLE IF EXISTS reconcile_jobs;
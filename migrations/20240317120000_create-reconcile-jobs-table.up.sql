@@ -0,0 +1,19 @@
+-- Add up migration script here
+CREATE TABLE reconcile_jobs (
+    id UUID PRIMARY KEY,
+    status TEXT NOT NULL DEFAULT 'running',
+    report JSONB,
+    error TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Only one repair runs at a time
+CREATE UNIQUE INDEX reconcile_jobs_one_running
+ON reconcile_jobs ((status))
+WHERE status = 'running';
+
+CREATE TRIGGER update_updated_at
+BEFORE UPDATE ON reconcile_jobs
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at();
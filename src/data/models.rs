@@ -1,5 +1,6 @@
+use crate::errors::ServiceError;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::RangeInclusive};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct DocEmbedding {
@@ -8,32 +9,12 @@ pub struct DocEmbedding {
     pub story_id: i64,
     pub index: i32,
     pub qdrant_point_id: uuid::Uuid,
+    pub content_hash: Option<String>,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
 }
 
-impl DocEmbedding {
-    pub fn from_details(
-        id: Option<uuid::Uuid>,
-        doc_html: String,
-        story_id: i64,
-        index: i32,
-        qdrant_point_id: Option<uuid::Uuid>,
-        created_at: Option<chrono::NaiveDateTime>,
-        updated_at: Option<chrono::NaiveDateTime>,
-    ) -> Self {
-        Self {
-            id: id.unwrap_or(uuid::Uuid::new_v4()),
-            doc_html,
-            story_id,
-            index,
-            qdrant_point_id: qdrant_point_id.unwrap_or(uuid::Uuid::new_v4()),
-            created_at: created_at.unwrap_or(chrono::Utc::now().naive_utc()),
-            updated_at: updated_at.unwrap_or(chrono::Utc::now().naive_utc()),
-        }
-    }
-}
-
+#[derive(Debug, Clone)]
 pub struct DocEmbeddingQdrantPayload {
     pub story_id: i64,
     pub index: i32,
@@ -60,59 +41,202 @@ impl From<DocEmbeddingQdrantPayload> for HashMap<String, qdrant_client::prelude:
 impl From<HashMap<String, qdrant_client::prelude::Value>> for DocEmbeddingQdrantPayload {
     fn from(value: HashMap<String, qdrant_client::prelude::Value>) -> Self {
         Self {
-            story_id: value
-                .get("story_id")
-                .unwrap_or(&qdrant_client::qdrant::Value { kind: Option::None })
-                .to_string()
-                .parse::<i64>()
-                .unwrap_or(0),
-            index: value
-                .get("index")
-                .unwrap_or(&qdrant_client::qdrant::Value { kind: Option::None })
-                .to_string()
-                .parse::<i32>()
-                .unwrap_or(0),
+            story_id: payload_integer(value.get("story_id")).unwrap_or(0),
+            index: payload_integer(value.get("index")).unwrap_or(0) as i32,
         }
     }
 }
 
+/// Payload integers are stored either as strings or as integers depending on the collection
+pub fn payload_integer(value: Option<&qdrant_client::prelude::Value>) -> Option<i64> {
+    match value?.kind.clone()? {
+        qdrant_client::qdrant::value::Kind::IntegerValue(num) => Some(num),
+        qdrant_client::qdrant::value::Kind::StringValue(s) => s.parse::<i64>().ok(),
+        qdrant_client::qdrant::value::Kind::DoubleValue(num) => Some(num as i64),
+        _ => None,
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct DocGroupEmbedding {
     pub id: uuid::Uuid,
     pub story_id: i64,
     pub doc_group_size: i32,
+    pub doc_group_stride: i32,
     pub index: i32,
+    pub first_index: i32,
+    pub last_index: i32,
     pub qdrant_point_id: uuid::Uuid,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
 }
 
 impl DocGroupEmbedding {
-    pub fn from_details(
-        id: Option<uuid::Uuid>,
-        story_id: i64,
-        doc_group_size: i32,
-        index: i32,
-        qdrant_point_id: Option<uuid::Uuid>,
-        created_at: Option<chrono::NaiveDateTime>,
-        updated_at: Option<chrono::NaiveDateTime>,
-    ) -> Self {
-        Self {
-            id: id.unwrap_or(uuid::Uuid::new_v4()),
-            story_id,
+    pub fn chapter_range(&self) -> ChapterRange {
+        ChapterRange {
+            first_index: self.first_index,
+            last_index: self.last_index,
+        }
+    }
+}
+
+/// Size and stride of a doc group collection. Groups are windows over the chapter indices:
+/// group `g` covers the chapters `g * stride..g * stride + size`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DocGroupSpec {
+    pub doc_group_size: i32,
+    pub doc_group_stride: i32,
+}
+
+impl DocGroupSpec {
+    /// Without a stride, groups do not overlap
+    pub fn new(doc_group_size: i32, doc_group_stride: Option<i32>) -> Result<Self, ServiceError> {
+        let doc_group_stride = doc_group_stride.unwrap_or(doc_group_size);
+
+        if doc_group_size < 1 || doc_group_stride < 1 || doc_group_stride > doc_group_size {
+            return Err(ServiceError::InvalidDocGroupSize);
+        }
+
+        Ok(Self {
             doc_group_size,
-            index,
-            qdrant_point_id: qdrant_point_id.unwrap_or(uuid::Uuid::new_v4()),
-            created_at: created_at.unwrap_or(chrono::Utc::now().naive_utc()),
-            updated_at: updated_at.unwrap_or(chrono::Utc::now().naive_utc()),
+            doc_group_stride,
+        })
+    }
+
+    /// `None` targets the single chapter embeddings
+    pub fn from_optional(
+        doc_group_size: Option<i32>,
+        doc_group_stride: Option<i32>,
+    ) -> Result<Option<Self>, ServiceError> {
+        match (doc_group_size, doc_group_stride) {
+            (Some(doc_group_size), doc_group_stride) => {
+                Self::new(doc_group_size, doc_group_stride).map(Some)
+            }
+            (None, Some(_)) => Err(ServiceError::InvalidDocGroupSize),
+            (None, None) => Ok(None),
         }
     }
+
+    pub fn collection_name(&self) -> String {
+        if self.doc_group_stride == self.doc_group_size {
+            format!("doc_group_{}", self.doc_group_size)
+        } else {
+            format!(
+                "doc_group_{}_stride_{}",
+                self.doc_group_size, self.doc_group_stride
+            )
+        }
+    }
+
+    /// The first chapter index of a group and the chapter index right after its end
+    pub fn chapter_bounds(&self, group_index: i32) -> (i32, i32) {
+        let first_index = group_index * self.doc_group_stride;
+        (first_index, first_index + self.doc_group_size)
+    }
+
+    pub fn group_indices_containing(&self, chapter_index: i32) -> RangeInclusive<i32> {
+        let lowest_first_index = (chapter_index - self.doc_group_size + 1).max(0);
+        let first_group = (lowest_first_index + self.doc_group_stride - 1) / self.doc_group_stride;
+        first_group..=chapter_index.div_euclid(self.doc_group_stride)
+    }
+}
+
+/// A registered doc group size and stride
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DocGroupSize {
+    pub doc_group_size: i32,
+    pub doc_group_stride: i32,
+    pub last_rebuilt_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl DocGroupSize {
+    pub fn doc_group(&self) -> DocGroupSpec {
+        DocGroupSpec {
+            doc_group_size: self.doc_group_size,
+            doc_group_stride: self.doc_group_stride,
+        }
+    }
+}
+
+/// A rebuild of `doc_embeddings` into a versioned collection. The status moves from running to
+/// completed, then to live once the alias points at it; a live version is retired by the next
+/// switch or rolled back.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DocEmbeddingReindex {
+    pub version: i32,
+    pub collection_name: String,
+    pub status: String,
+    pub total_count: i64,
+    pub indexed_count: i64,
+    pub error: Option<String>,
+    pub previous_collection: Option<String>,
+    pub switched_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl DocEmbeddingReindex {
+    pub fn progress(&self) -> f64 {
+        if self.total_count == 0 {
+            return 1.0;
+        }
+        (self.indexed_count as f64 / self.total_count as f64).min(1.0)
+    }
+}
+
+/// A repair of Postgres and the vector store run in the background. The status moves from
+/// running to completed or failed; `report` is the reconcile report once it completed.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ReconcileJob {
+    pub id: uuid::Uuid,
+    pub status: String,
+    pub report: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct ChapterRange {
+    pub first_index: i32,
+    pub last_index: i32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum IndexDocumentGroupRequest {
+    Stories {
+        doc_group_size: i32,
+        doc_group_stride: Option<i32>,
+        story_ids: Vec<i64>,
+    },
+    Story {
+        story_id: i64,
+        doc_group_size: i32,
+        doc_group_stride: Option<i32>,
+    },
+    All {
+        doc_group_size: i32,
+        doc_group_stride: Option<i32>,
+    },
+}
+
+/// A chapter of a story, or one of its doc groups when the request names a group size
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct SimilarityTarget {
+    pub story_id: i64,
+    pub index: i32,
 }
 
 pub struct DocGroupEmbeddingQdrantPayload {
     pub story_id: i64,
     pub doc_group_size: i32,
+    pub doc_group_stride: i32,
     pub index: i32,
+    pub first_index: i32,
+    pub last_index: i32,
 }
 
 impl From<DocGroupEmbedding> for DocGroupEmbeddingQdrantPayload {
@@ -120,7 +244,10 @@ impl From<DocGroupEmbedding> for DocGroupEmbeddingQdrantPayload {
         Self {
             story_id: doc_group_embedding.story_id,
             doc_group_size: doc_group_embedding.doc_group_size,
+            doc_group_stride: doc_group_embedding.doc_group_stride,
             index: doc_group_embedding.index,
+            first_index: doc_group_embedding.first_index,
+            last_index: doc_group_embedding.last_index,
         }
     }
 }
@@ -134,10 +261,166 @@ impl From<DocGroupEmbeddingQdrantPayload> for HashMap<String, qdrant_client::pre
             "story_id".to_string(),
             doc_group_embedding.story_id.to_string().into(),
         );
+        map.insert(
+            "doc_group_stride".to_string(),
+            (doc_group_embedding.doc_group_stride as i64).into(),
+        );
         map.insert(
             "index".to_string(),
             (doc_group_embedding.index as i64).into(),
         );
+        map.insert(
+            "first_index".to_string(),
+            (doc_group_embedding.first_index as i64).into(),
+        );
+        map.insert(
+            "last_index".to_string(),
+            (doc_group_embedding.last_index as i64).into(),
+        );
+        map
+    }
+}
+
+/// Whether a search or recommendation runs over chapters (or their doc groups), over named
+/// chapter ranges or over whole stories
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingLevel {
+    #[default]
+    Chapter,
+    Range,
+    Story,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StoryEmbedding {
+    pub id: uuid::Uuid,
+    pub story_id: i64,
+    pub chapter_count: i32,
+    pub recency_half_life: Option<f32>,
+    pub qdrant_point_id: uuid::Uuid,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl StoryEmbedding {
+    pub fn from_details(
+        id: Option<uuid::Uuid>,
+        story_id: i64,
+        chapter_count: i32,
+        recency_half_life: Option<f32>,
+        qdrant_point_id: Option<uuid::Uuid>,
+        created_at: Option<chrono::NaiveDateTime>,
+        updated_at: Option<chrono::NaiveDateTime>,
+    ) -> Self {
+        Self {
+            id: id.unwrap_or(uuid::Uuid::new_v4()),
+            story_id,
+            chapter_count,
+            recency_half_life,
+            qdrant_point_id: qdrant_point_id.unwrap_or(uuid::Uuid::new_v4()),
+            created_at: created_at.unwrap_or(chrono::Utc::now().naive_utc()),
+            updated_at: updated_at.unwrap_or(chrono::Utc::now().naive_utc()),
+        }
+    }
+}
+
+pub struct StoryEmbeddingQdrantPayload {
+    pub story_id: i64,
+    pub chapter_count: i32,
+}
+
+impl From<StoryEmbedding> for StoryEmbeddingQdrantPayload {
+    fn from(story_embedding: StoryEmbedding) -> Self {
+        Self {
+            story_id: story_embedding.story_id,
+            chapter_count: story_embedding.chapter_count,
+        }
+    }
+}
+
+impl From<StoryEmbeddingQdrantPayload> for HashMap<String, qdrant_client::prelude::Value> {
+    fn from(
+        story_embedding: StoryEmbeddingQdrantPayload,
+    ) -> HashMap<String, qdrant_client::prelude::Value> {
+        let mut map = HashMap::new();
+        map.insert(
+            "story_id".to_string(),
+            story_embedding.story_id.to_string().into(),
+        );
+        map.insert(
+            "chapter_count".to_string(),
+            (story_embedding.chapter_count as i64).into(),
+        );
+        map
+    }
+}
+
+/// A range of chapters the author gave a name to, such as a volume or an arc
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NamedChapterRange {
+    pub id: uuid::Uuid,
+    pub story_id: i64,
+    pub name: String,
+    pub first_index: i32,
+    pub last_index: i32,
+    pub qdrant_point_id: uuid::Uuid,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl NamedChapterRange {
+    /// A range needs a name and at least one chapter
+    pub fn validate(&self) -> Result<(), ServiceError> {
+        if self.name.trim().is_empty() || self.first_index < 0 || self.first_index > self.last_index
+        {
+            return Err(ServiceError::InvalidChapterRange);
+        }
+
+        Ok(())
+    }
+
+    pub fn chapter_range(&self) -> ChapterRange {
+        ChapterRange {
+            first_index: self.first_index,
+            last_index: self.last_index,
+        }
+    }
+}
+
+pub struct NamedChapterRangeQdrantPayload {
+    pub story_id: i64,
+    pub first_index: i32,
+    pub last_index: i32,
+}
+
+impl From<NamedChapterRange> for NamedChapterRangeQdrantPayload {
+    fn from(named_chapter_range: NamedChapterRange) -> Self {
+        Self {
+            story_id: named_chapter_range.story_id,
+            first_index: named_chapter_range.first_index,
+            last_index: named_chapter_range.last_index,
+        }
+    }
+}
+
+impl From<NamedChapterRangeQdrantPayload> for HashMap<String, qdrant_client::prelude::Value> {
+    fn from(
+        named_chapter_range: NamedChapterRangeQdrantPayload,
+    ) -> HashMap<String, qdrant_client::prelude::Value> {
+        let mut map = HashMap::new();
+        map.insert(
+            "story_id".to_string(),
+            named_chapter_range.story_id.to_string().into(),
+        );
+        map.insert(
+            "first_index".to_string(),
+            (named_chapter_range.first_index as i64).into(),
+        );
+        map.insert(
+            "last_index".to_string(),
+            (named_chapter_range.last_index as i64).into(),
+        );
         map
     }
 }
@@ -43,6 +43,43 @@ pub enum ServiceError {
     DeleteTmpFileError(io::Error),
     DeleteDocEmbeddingError(sqlx::Error),
     InvalidUtf8Error(Utf8Error),
+    GetPointsQdrantError(anyhow::Error),
+    InvalidDocGroupSize,
+    PgTransactionError(sqlx::Error),
+    UpsertStoryEmbeddingPgError(sqlx::Error),
+    UpsertStoryEmbeddingQdrantError(anyhow::Error),
+    SelectStoryEmbeddingsPgError(sqlx::Error),
+    InvalidEmbeddingLevel,
+    InvalidChapterRange,
+    UpsertNamedChapterRangePgError(sqlx::Error),
+    UpsertNamedChapterRangeQdrantError(anyhow::Error),
+    SelectNamedChapterRangesPgError(sqlx::Error),
+    DeleteNamedChapterRangeError(sqlx::Error),
+    DeleteNamedChapterRangeQdrantError(anyhow::Error),
+    RegisterDocGroupSizePgError(sqlx::Error),
+    CreateCollectionQdrantError(anyhow::Error),
+    CountPointsQdrantError(anyhow::Error),
+    StartReindexPgError(sqlx::Error),
+    SelectReindexPgError(sqlx::Error),
+    UpdateReindexPgError(sqlx::Error),
+    ReindexNotFound,
+    ReindexAlreadyRunning,
+    ReindexNotSwitchable,
+    NothingToRollBack,
+    SwitchAliasQdrantError(anyhow::Error),
+    QdrantCollectionConfigError(anyhow::Error),
+    ReconcilePgError(sqlx::Error),
+    EnqueueVectorMutationPgError(sqlx::Error),
+    RelayVectorMutationPgError(sqlx::Error),
+    ChunkEmbeddingCachePgError(sqlx::Error),
+    QueryEmbeddingCacheError(anyhow::Error),
+    EmbeddingProviderConfigError(String),
+    /// Seconds until the embedding provider is worth calling again
+    EmbeddingProviderUnavailable(u64),
+    RecordNotFound,
+    ReconcileJobNotFound,
+    ReconcileAlreadyRunning,
+    DocGroupNotCreated,
 }
 
 impl ResponseError for ServiceError {
@@ -162,7 +199,8 @@ impl ResponseError for ServiceError {
             }
             ServiceError::MatchingRecordNotFound => {
                 HttpResponse::InternalServerError().json(ErrorResponse {
-                    message: "Matching record not found.".to_string(),
+                    message: "Matching record not found for the requested story_id, index and doc_group_size."
+                        .to_string(),
                     error_code: "0019".to_string(),
                 })
             }
@@ -237,6 +275,203 @@ impl ResponseError for ServiceError {
                     error_code: "0030".to_string(),
                 })
             }
+            ServiceError::GetPointsQdrantError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error getting points from Qdrant: {:?}", e),
+                    error_code: "0031".to_string(),
+                })
+            }
+            ServiceError::InvalidDocGroupSize => HttpResponse::BadRequest().json(ErrorResponse {
+                message: "doc_group_size must be at least 1 and doc_group_stride must be between 1 and doc_group_size.".to_string(),
+                error_code: "0032".to_string(),
+            }),
+            ServiceError::PgTransactionError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error running Postgres transaction: {:?}", e),
+                    error_code: "0033".to_string(),
+                })
+            }
+            ServiceError::UpsertStoryEmbeddingPgError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error upserting StoryEmbedding to Postgres: {:?}", e),
+                    error_code: "0034".to_string(),
+                })
+            }
+            ServiceError::UpsertStoryEmbeddingQdrantError(e) => HttpResponse::InternalServerError()
+                .json(ErrorResponse {
+                    message: format!("Error upserting StoryEmbedding to Qdrant: {:?}", e),
+                    error_code: "0035".to_string(),
+                }),
+            ServiceError::SelectStoryEmbeddingsPgError(e) => HttpResponse::InternalServerError()
+                .json(ErrorResponse {
+                    message: format!("Error selecting StoryEmbeddings from Postgres: {:?}", e),
+                    error_code: "0036".to_string(),
+                }),
+            ServiceError::InvalidEmbeddingLevel => HttpResponse::BadRequest().json(ErrorResponse {
+                message: "Only the chapter level takes doc_group_size or doc_group_stride, chapter level recommendations require doc_group_size, and ranges are recommended through /api/recommend/range.".to_string(),
+                error_code: "0037".to_string(),
+            }),
+            ServiceError::InvalidChapterRange => HttpResponse::BadRequest().json(ErrorResponse {
+                message: "A chapter range needs a name and 0 <= first_index <= last_index."
+                    .to_string(),
+                error_code: "0038".to_string(),
+            }),
+            ServiceError::UpsertNamedChapterRangePgError(e) => HttpResponse::InternalServerError()
+                .json(ErrorResponse {
+                    message: format!("Error upserting NamedChapterRange to Postgres: {:?}", e),
+                    error_code: "0039".to_string(),
+                }),
+            ServiceError::UpsertNamedChapterRangeQdrantError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error upserting NamedChapterRange to Qdrant: {:?}", e),
+                    error_code: "0040".to_string(),
+                })
+            }
+            ServiceError::SelectNamedChapterRangesPgError(e) => HttpResponse::InternalServerError()
+                .json(ErrorResponse {
+                    message: format!("Error selecting NamedChapterRanges from Postgres: {:?}", e),
+                    error_code: "0041".to_string(),
+                }),
+            ServiceError::DeleteNamedChapterRangeError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error Deleting NamedChapterRange: {:?}", e),
+                    error_code: "0042".to_string(),
+                })
+            }
+            ServiceError::DeleteNamedChapterRangeQdrantError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error deleting NamedChapterRange from Qdrant: {:?}", e),
+                    error_code: "0043".to_string(),
+                })
+            }
+            ServiceError::RegisterDocGroupSizePgError(e) => HttpResponse::InternalServerError()
+                .json(ErrorResponse {
+                    message: format!("Error registering DocGroup size in Postgres: {:?}", e),
+                    error_code: "0044".to_string(),
+                }),
+            ServiceError::CreateCollectionQdrantError(e) => HttpResponse::InternalServerError()
+                .json(ErrorResponse {
+                    message: format!("Error creating collection in Qdrant: {:?}", e),
+                    error_code: "0045".to_string(),
+                }),
+            ServiceError::CountPointsQdrantError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error counting points in Qdrant: {:?}", e),
+                    error_code: "0046".to_string(),
+                })
+            }
+            ServiceError::StartReindexPgError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error starting reindex in Postgres: {:?}", e),
+                    error_code: "0047".to_string(),
+                })
+            }
+            ServiceError::SelectReindexPgError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error selecting reindexes from Postgres: {:?}", e),
+                    error_code: "0048".to_string(),
+                })
+            }
+            ServiceError::UpdateReindexPgError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error updating reindex in Postgres: {:?}", e),
+                    error_code: "0049".to_string(),
+                })
+            }
+            ServiceError::ReindexNotFound => HttpResponse::NotFound().json(ErrorResponse {
+                message: "No reindex with that version exists.".to_string(),
+                error_code: "0050".to_string(),
+            }),
+            ServiceError::ReindexAlreadyRunning => HttpResponse::Conflict().json(ErrorResponse {
+                message: "A reindex is already running.".to_string(),
+                error_code: "0051".to_string(),
+            }),
+            ServiceError::ReindexNotSwitchable => HttpResponse::BadRequest().json(ErrorResponse {
+                message: "Only a completed reindex that is not live yet can be switched to."
+                    .to_string(),
+                error_code: "0052".to_string(),
+            }),
+            ServiceError::NothingToRollBack => HttpResponse::BadRequest().json(ErrorResponse {
+                message: "The live collection has no previous collection to roll back to."
+                    .to_string(),
+                error_code: "0053".to_string(),
+            }),
+            ServiceError::SwitchAliasQdrantError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error switching collection alias in Qdrant: {:?}", e),
+                    error_code: "0054".to_string(),
+                })
+            }
+            ServiceError::QdrantCollectionConfigError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Invalid Qdrant collection config: {:?}", e),
+                    error_code: "0055".to_string(),
+                })
+            }
+            ServiceError::ReconcilePgError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error comparing Postgres with the vector store: {:?}", e),
+                    error_code: "0056".to_string(),
+                })
+            }
+            ServiceError::EnqueueVectorMutationPgError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error recording vector store writes: {:?}", e),
+                    error_code: "0057".to_string(),
+                })
+            }
+            ServiceError::RelayVectorMutationPgError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error relaying vector store writes: {:?}", e),
+                    error_code: "0058".to_string(),
+                })
+            }
+            ServiceError::ChunkEmbeddingCachePgError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error reading or writing cached chunk embeddings: {:?}", e),
+                    error_code: "0059".to_string(),
+                })
+            }
+            ServiceError::QueryEmbeddingCacheError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Error in the query embedding cache: {:?}", e),
+                    error_code: "0060".to_string(),
+                })
+            }
+            ServiceError::EmbeddingProviderConfigError(e) => {
+                HttpResponse::InternalServerError().json(ErrorResponse {
+                    message: format!("Invalid embedding provider config: {}", e),
+                    error_code: "0061".to_string(),
+                })
+            }
+            ServiceError::EmbeddingProviderUnavailable(retry_after) => {
+                HttpResponse::ServiceUnavailable()
+                    .insert_header(("Retry-After", retry_after.to_string()))
+                    .json(ErrorResponse {
+                        message: "The embedding provider is unavailable, try again later."
+                            .to_string(),
+                        error_code: "0062".to_string(),
+                    })
+            }
+            ServiceError::RecordNotFound => HttpResponse::NotFound().json(ErrorResponse {
+                message: "Record not found.".to_string(),
+                error_code: "0063".to_string(),
+            }),
+            ServiceError::ReconcileJobNotFound => HttpResponse::NotFound().json(ErrorResponse {
+                message: "No reconcile job with that id exists.".to_string(),
+                error_code: "0064".to_string(),
+            }),
+            ServiceError::ReconcileAlreadyRunning => {
+                HttpResponse::Conflict().json(ErrorResponse {
+                    message: "A reconcile job is already running.".to_string(),
+                    error_code: "0065".to_string(),
+                })
+            }
+            ServiceError::DocGroupNotCreated => HttpResponse::BadRequest().json(ErrorResponse {
+                message: "Create the document group size with POST /api/document_group first."
+                    .to_string(),
+                error_code: "0066".to_string(),
+            }),
         }
     }
 }
@@ -0,0 +1,195 @@
+use super::auth_handler::AuthRequired;
+use crate::operators::vector_store_operator::VectorStore;
+use crate::{
+    data::models::{ChapterRange, NamedChapterRange},
+    errors::ServiceError,
+    operators::{
+        named_chapter_range_operator::{
+            delete_named_chapter_range_pg_query, get_named_chapter_range_pg_query,
+            get_named_chapter_ranges_by_qdrant_ids_pg_query,
+            get_story_named_chapter_ranges_pg_query, index_named_chapter_range,
+            upsert_named_chapter_range_pg_query,
+        },
+        qdrant_operator::{recommend_similar_points_qdrant_query, NAMED_CHAPTER_RANGES_COLLECTION},
+        vector_outbox_operator::{
+            enqueue_vector_mutations_pg_query, VectorMutation, VectorOutboxRelay,
+        },
+    },
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::time::Duration;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UpsertChapterRangeRequest {
+    pub name: String,
+    pub first_index: i32,
+    pub last_index: i32,
+}
+
+pub async fn upsert_chapter_range(
+    story_id: web::Path<i64>,
+    upsert_chapter_range_request: web::Json<UpsertChapterRangeRequest>,
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    relay: web::Data<VectorOutboxRelay>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let upsert_chapter_range_request = upsert_chapter_range_request.into_inner();
+    let now = chrono::Utc::now().naive_utc();
+    let named_chapter_range = NamedChapterRange {
+        id: uuid::Uuid::new_v4(),
+        story_id: story_id.into_inner(),
+        name: upsert_chapter_range_request.name,
+        first_index: upsert_chapter_range_request.first_index,
+        last_index: upsert_chapter_range_request.last_index,
+        qdrant_point_id: uuid::Uuid::new_v4(),
+        created_at: now,
+        updated_at: now,
+    };
+    named_chapter_range.validate()?;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+    let named_chapter_range =
+        upsert_named_chapter_range_pg_query(named_chapter_range, &mut transaction).await?;
+    index_named_chapter_range(
+        named_chapter_range.clone(),
+        &mut transaction,
+        vector_store.get_ref(),
+    )
+    .await?;
+    transaction
+        .commit()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+    relay.flush().await;
+
+    Ok(HttpResponse::Ok().json(named_chapter_range))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GetChapterRangesResponse {
+    pub ranges: Vec<NamedChapterRange>,
+}
+
+pub async fn get_chapter_ranges(
+    story_id: web::Path<i64>,
+    pool: web::Data<Pool<Postgres>>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let ranges =
+        get_story_named_chapter_ranges_pg_query(story_id.into_inner(), pool.get_ref().clone())
+            .await?;
+
+    Ok(HttpResponse::Ok().json(GetChapterRangesResponse { ranges }))
+}
+
+pub async fn delete_chapter_range(
+    path: web::Path<(i64, String)>,
+    pool: web::Data<Pool<Postgres>>,
+    relay: web::Data<VectorOutboxRelay>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let (story_id, name) = path.into_inner();
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+    let named_chapter_range = delete_named_chapter_range_pg_query(story_id, name, &mut transaction)
+        .await?
+        .ok_or(ServiceError::RecordNotFound)?;
+    enqueue_vector_mutations_pg_query(
+        vec![VectorMutation::Delete {
+            collection_name: NAMED_CHAPTER_RANGES_COLLECTION.to_owned(),
+            point_id: named_chapter_range.qdrant_point_id,
+        }],
+        Duration::ZERO,
+        &mut transaction,
+    )
+    .await?;
+    transaction
+        .commit()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+    relay.flush().await;
+
+    Ok(HttpResponse::NoContent().into())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecommendChapterRangeRequest {
+    pub story_id: i64,
+    pub name: String,
+    pub limit: Option<u64>,
+    pub page: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChapterRangeRecommendation {
+    pub story_id: i64,
+    pub name: String,
+    pub chapter_range: ChapterRange,
+    pub score: f32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecommendChapterRangeResponse {
+    pub recommendations: Vec<ChapterRangeRecommendation>,
+}
+
+/// Recommends named ranges of other stories that are closest to the given range
+pub async fn recommend_chapter_range(
+    recommend_chapter_range_request: web::Json<RecommendChapterRangeRequest>,
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let recommend_chapter_range_request = recommend_chapter_range_request.into_inner();
+
+    let seed = get_named_chapter_range_pg_query(
+        recommend_chapter_range_request.story_id,
+        recommend_chapter_range_request.name,
+        pool.get_ref().clone(),
+    )
+    .await?
+    .ok_or(ServiceError::RecordNotFound)?;
+
+    let points = recommend_similar_points_qdrant_query(
+        seed.qdrant_point_id,
+        seed.story_id,
+        NAMED_CHAPTER_RANGES_COLLECTION.to_owned(),
+        recommend_chapter_range_request.limit,
+        recommend_chapter_range_request.page,
+        vector_store.get_ref(),
+    )
+    .await?;
+
+    let named_chapter_ranges = get_named_chapter_ranges_by_qdrant_ids_pg_query(
+        points.iter().map(|point| point.point_id).collect(),
+        pool.get_ref().clone(),
+    )
+    .await?;
+
+    // keep the order of the recommendation scores
+    let recommendations = points
+        .iter()
+        .filter_map(|point| {
+            named_chapter_ranges
+                .iter()
+                .find(|range| range.qdrant_point_id == point.point_id)
+                .map(|range| ChapterRangeRecommendation {
+                    story_id: range.story_id,
+                    name: range.name.clone(),
+                    chapter_range: range.chapter_range(),
+                    score: point.score,
+                })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(RecommendChapterRangeResponse { recommendations }))
+}
@@ -1,57 +1,114 @@
 use super::auth_handler::AuthRequired;
+use crate::operators::vector_store_operator::VectorStore;
 use crate::{
+    data::models::{ChapterRange, DocGroupSpec, EmbeddingLevel, IndexDocumentGroupRequest},
     errors::ServiceError,
     operators::{
-        doc_embedding_operator::create_doc_group_embedding,
-        doc_group_embedding_operator::get_doc_group_qdrant_ids_pg_query,
+        doc_embedding_operator::{
+            create_doc_group_embedding, get_doc_embedding_qdrant_id_pg_query,
+        },
+        doc_group_embedding_operator::{
+            explain_recommendations, get_doc_group_qdrant_ids_by_story_index_pg_query,
+            get_doc_group_qdrant_ids_pg_query, get_doc_group_sizes_pg_query,
+            get_doc_groups_by_qdrant_ids_pg_query, get_story_doc_groups_pg_query,
+            register_doc_group_pg_query, RecommendationExplanation,
+        },
         qdrant_operator::{
-            create_doc_group_collection_qdrant_query, recommend_group_doc_embeddings_qdrant_query,
+            count_points_qdrant_query, create_doc_group_collection_qdrant_query,
+            doc_collection_name, get_points_with_vectors_qdrant_query,
+            recommend_group_doc_embeddings_qdrant_query, recommend_similar_points_qdrant_query,
+            recommend_story_embeddings_qdrant_query,
         },
+        story_embedding_operator::get_story_embedding_qdrant_ids_pg_query,
+        vector_outbox_operator::VectorOutboxRelay,
     },
 };
 use actix_web::{web, HttpResponse};
+use futures::future::try_join_all;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use sqlx::{Pool, Postgres};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GroupDocumentRequest {
     pub doc_group_size: i32,
+    pub doc_group_stride: Option<i32>,
 }
 
 pub async fn create_document_group(
     group_document_request: web::Json<GroupDocumentRequest>,
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
     _: AuthRequired,
 ) -> Result<HttpResponse, ServiceError> {
-    create_doc_group_collection_qdrant_query(group_document_request.doc_group_size)
+    let doc_group = DocGroupSpec::new(
+        group_document_request.doc_group_size,
+        group_document_request.doc_group_stride,
+    )?;
+
+    create_doc_group_collection_qdrant_query(doc_group, vector_store.get_ref()).await?;
+    register_doc_group_pg_query(doc_group, pool.get_ref().clone())
         .await
         .map(|_| HttpResponse::NoContent().into())
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(untagged)]
-pub enum IndexDocumentGroupRequest {
-    Stories {
-        doc_group_size: i32,
-        story_ids: Vec<i64>,
-    },
-    Story {
-        story_id: i64,
-        doc_group_size: i32,
-    },
-    All {
-        doc_group_size: i32,
-    },
-}
-
 pub async fn index_document_group(
     req: web::Json<IndexDocumentGroupRequest>,
     pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    relay: web::Data<VectorOutboxRelay>,
     _: AuthRequired,
 ) -> Result<HttpResponse, ServiceError> {
-    create_doc_group_embedding(req.into_inner(), pool.get_ref().clone())
-        .await
-        .map(|_| HttpResponse::NoContent().into())
+    create_doc_group_embedding(
+        req.into_inner(),
+        pool.get_ref().clone(),
+        vector_store.get_ref(),
+    )
+    .await?;
+    relay.flush().await;
+
+    Ok(HttpResponse::NoContent().into())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DocumentGroupSizeInfo {
+    pub doc_group_size: i32,
+    pub doc_group_stride: i32,
+    pub point_count: u64,
+    pub last_rebuilt_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ListDocumentGroupsResponse {
+    pub doc_groups: Vec<DocumentGroupSizeInfo>,
+}
+
+pub async fn list_document_groups(
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let doc_group_sizes = get_doc_group_sizes_pg_query(pool.get_ref().clone()).await?;
+
+    let point_counts = try_join_all(doc_group_sizes.iter().map(|doc_group_size| {
+        count_points_qdrant_query(
+            doc_group_size.doc_group().collection_name(),
+            vector_store.get_ref(),
+        )
+    }))
+    .await?;
+
+    let doc_groups = doc_group_sizes
+        .into_iter()
+        .zip(point_counts)
+        .map(|(doc_group_size, point_count)| DocumentGroupSizeInfo {
+            doc_group_size: doc_group_size.doc_group_size,
+            doc_group_stride: doc_group_size.doc_group_stride,
+            point_count,
+            last_rebuilt_at: doc_group_size.last_rebuilt_at,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(ListDocumentGroupsResponse { doc_groups }))
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -60,27 +117,114 @@ pub struct GetDocumentGroupRequest {
     pub story_id: i64,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GetDocumentGroupQuery {
+    pub doc_group_stride: Option<i32>,
+}
+
+/// `indices`, `chapter_ranges` and `embeddings` are aligned and ordered by group index
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GetDocumentGroupResponse {
+    pub indices: Vec<i32>,
+    pub chapter_ranges: Vec<ChapterRange>,
     pub embeddings: Vec<Vec<f32>>,
 }
 
+pub async fn get_document_group(
+    get_document_group_request: web::Path<GetDocumentGroupRequest>,
+    query: web::Query<GetDocumentGroupQuery>,
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let doc_group = DocGroupSpec::new(
+        get_document_group_request.doc_group_size,
+        query.doc_group_stride,
+    )?;
+
+    let doc_groups = get_story_doc_groups_pg_query(
+        get_document_group_request.story_id,
+        doc_group,
+        pool.get_ref().clone(),
+    )
+    .await?;
+
+    if doc_groups.is_empty() {
+        return Err(ServiceError::RecordNotFound);
+    }
+
+    let points = get_points_with_vectors_qdrant_query(
+        doc_group.collection_name(),
+        doc_groups
+            .iter()
+            .map(|doc_group| doc_group.qdrant_point_id)
+            .collect(),
+        vector_store.get_ref(),
+    )
+    .await?;
+
+    let mut response = GetDocumentGroupResponse {
+        indices: vec![],
+        chapter_ranges: vec![],
+        embeddings: vec![],
+    };
+    for doc_group in doc_groups.iter() {
+        if let Some(point) = points
+            .iter()
+            .find(|point| point.point_id == doc_group.qdrant_point_id)
+        {
+            response.indices.push(doc_group.index);
+            response.chapter_ranges.push(doc_group.chapter_range());
+            response.embeddings.push(point.vector.clone());
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RecommendDocumentRequest {
-    pub doc_group_size: i32,
+    pub doc_group_size: Option<i32>,
+    pub doc_group_stride: Option<i32>,
+    #[serde(default)]
+    pub level: EmbeddingLevel,
     pub story_ids: Vec<i64>,
     pub limit: Option<u64>,
     pub page: Option<u64>,
+    pub explain: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecommendDocumentResponse {
+    pub recommended_story_ids: Vec<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explanations: Option<Vec<RecommendationExplanation>>,
 }
 
 pub async fn recommend_document_group(
     recommend_document_request: web::Json<RecommendDocumentRequest>,
     pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
     _: AuthRequired,
 ) -> Result<HttpResponse, ServiceError> {
+    let doc_group = match (
+        recommend_document_request.level,
+        DocGroupSpec::from_optional(
+            recommend_document_request.doc_group_size,
+            recommend_document_request.doc_group_stride,
+        )?,
+    ) {
+        (EmbeddingLevel::Chapter, Some(doc_group)) => doc_group,
+        (EmbeddingLevel::Story, None) => {
+            return recommend_stories(recommend_document_request.into_inner(), pool, vector_store)
+                .await
+        }
+        _ => return Err(ServiceError::InvalidEmbeddingLevel),
+    };
+
     let positive_qdrant_ids = get_doc_group_qdrant_ids_pg_query(
         recommend_document_request.story_ids.clone(),
-        recommend_document_request.doc_group_size,
+        doc_group,
         pool.get_ref().clone(),
     )
     .await?
@@ -88,15 +232,180 @@ pub async fn recommend_document_group(
     .map(|doc_group_qdrant_id| doc_group_qdrant_id.qdrant_point_id)
     .collect::<Vec<uuid::Uuid>>();
 
-    let recommended_story_ids = recommend_group_doc_embeddings_qdrant_query(
-        positive_qdrant_ids,
-        recommend_document_request.doc_group_size,
+    let recommended_points = recommend_group_doc_embeddings_qdrant_query(
+        positive_qdrant_ids.clone(),
+        doc_group,
         recommend_document_request.limit,
         recommend_document_request.page,
+        vector_store.get_ref(),
     )
     .await?;
 
-    Ok(HttpResponse::Ok().json(json!({
-        "recommended_story_ids": recommended_story_ids,
-    })))
+    let recommended_story_ids = recommended_points
+        .iter()
+        .map(|point| point.payload.story_id)
+        .collect::<Vec<i64>>();
+
+    let explanations = if recommend_document_request.explain.unwrap_or(false) {
+        let recommended_qdrant_ids = recommended_points
+            .iter()
+            .map(|point| point.point_id)
+            .collect::<Vec<uuid::Uuid>>();
+
+        let doc_groups = get_doc_groups_by_qdrant_ids_pg_query(
+            positive_qdrant_ids
+                .iter()
+                .chain(recommended_qdrant_ids.iter())
+                .cloned()
+                .collect(),
+            doc_group,
+            pool.get_ref().clone(),
+        )
+        .await?;
+
+        // Fetch the seed and recommended vectors in a single call and split them afterwards
+        let (seed_points, recommended_points): (Vec<_>, Vec<_>) =
+            get_points_with_vectors_qdrant_query(
+                doc_collection_name(Some(doc_group)),
+                positive_qdrant_ids
+                    .iter()
+                    .chain(recommended_qdrant_ids.iter())
+                    .cloned()
+                    .collect(),
+                vector_store.get_ref(),
+            )
+            .await?
+            .into_iter()
+            .partition(|point| positive_qdrant_ids.contains(&point.point_id));
+
+        let recommended_points = recommended_qdrant_ids
+            .iter()
+            .filter_map(|id| {
+                recommended_points
+                    .iter()
+                    .find(|point| point.point_id == *id)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        Some(explain_recommendations(
+            &seed_points,
+            &recommended_points,
+            &doc_groups,
+        ))
+    } else {
+        None
+    };
+
+    Ok(HttpResponse::Ok().json(RecommendDocumentResponse {
+        recommended_story_ids,
+        explanations,
+    }))
+}
+
+/// Story level recommendations compare whole-story centroids, so they come without
+/// explanations
+async fn recommend_stories(
+    recommend_document_request: RecommendDocumentRequest,
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+) -> Result<HttpResponse, ServiceError> {
+    let positive_qdrant_ids = get_story_embedding_qdrant_ids_pg_query(
+        recommend_document_request.story_ids,
+        pool.get_ref().clone(),
+    )
+    .await?
+    .into_iter()
+    .map(|story_qdrant_id| story_qdrant_id.qdrant_point_id)
+    .collect::<Vec<uuid::Uuid>>();
+
+    let recommended_story_ids = recommend_story_embeddings_qdrant_query(
+        positive_qdrant_ids,
+        recommend_document_request.limit,
+        recommend_document_request.page,
+        vector_store.get_ref(),
+    )
+    .await?
+    .into_iter()
+    .map(|point| point.payload.story_id)
+    .collect::<Vec<i64>>();
+
+    Ok(HttpResponse::Ok().json(RecommendDocumentResponse {
+        recommended_story_ids,
+        explanations: None,
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecommendChapterRequest {
+    pub story_id: i64,
+    pub index: i32,
+    pub doc_group_size: Option<i32>,
+    pub doc_group_stride: Option<i32>,
+    pub limit: Option<u64>,
+    pub page: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChapterRecommendation {
+    pub story_id: i64,
+    pub index: i32,
+    pub score: f32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecommendChapterResponse {
+    pub recommendations: Vec<ChapterRecommendation>,
+}
+
+pub async fn recommend_chapter(
+    recommend_chapter_request: web::Json<RecommendChapterRequest>,
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let doc_group = DocGroupSpec::from_optional(
+        recommend_chapter_request.doc_group_size,
+        recommend_chapter_request.doc_group_stride,
+    )?;
+
+    let seed_qdrant_id = match doc_group {
+        Some(doc_group) => get_doc_group_qdrant_ids_by_story_index_pg_query(
+            vec![recommend_chapter_request.story_id],
+            vec![recommend_chapter_request.index],
+            doc_group,
+            pool.get_ref().clone(),
+        )
+        .await?
+        .first()
+        .map(|doc_group| doc_group.qdrant_point_id),
+        None => {
+            get_doc_embedding_qdrant_id_pg_query(
+                recommend_chapter_request.story_id,
+                recommend_chapter_request.index,
+                pool.get_ref().clone(),
+            )
+            .await?
+        }
+    }
+    .ok_or(ServiceError::RecordNotFound)?;
+
+    let recommendations = recommend_similar_points_qdrant_query(
+        seed_qdrant_id,
+        recommend_chapter_request.story_id,
+        doc_collection_name(doc_group),
+        recommend_chapter_request.limit,
+        recommend_chapter_request.page,
+        vector_store.get_ref(),
+    )
+    .await?
+    .into_iter()
+    .map(|point| ChapterRecommendation {
+        story_id: point.payload.story_id,
+        index: point.payload.index,
+        score: point.score,
+    })
+    .collect();
+
+    Ok(HttpResponse::Ok().json(RecommendChapterResponse { recommendations }))
 }
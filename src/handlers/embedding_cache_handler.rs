@@ -0,0 +1,37 @@
+use super::auth_handler::AuthRequired;
+use crate::{
+    errors::ServiceError,
+    operators::{
+        chunk_embedding_cache_operator::{chunk_embedding_cache_stats, ChunkEmbeddingCacheStats},
+        embedding_operator::embedding_model_id,
+        query_embedding_cache_operator::{QueryEmbeddingCache, QueryEmbeddingCacheStats},
+    },
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmbeddingCacheStatsResponse {
+    pub chunks: ChunkEmbeddingCacheStats,
+    pub queries: QueryEmbeddingCacheStats,
+}
+
+/// Both caches count the hits and misses of the instance that served the request
+pub async fn get_embedding_cache_stats(
+    query_cache: web::Data<QueryEmbeddingCache>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    Ok(HttpResponse::Ok().json(EmbeddingCacheStatsResponse {
+        chunks: chunk_embedding_cache_stats(&embedding_model_id()),
+        queries: query_cache.stats(),
+    }))
+}
+
+pub async fn flush_query_embedding_cache(
+    query_cache: web::Data<QueryEmbeddingCache>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    query_cache.flush().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
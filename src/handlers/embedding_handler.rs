@@ -1,37 +1,73 @@
-use super::{auth_handler::AuthRequired, doc_group_handler::IndexDocumentGroupRequest};
+use super::auth_handler::AuthRequired;
+use crate::operators::vector_store_operator::{normalize, VectorStore};
 use crate::{
     data::models::DocEmbedding,
     errors::ServiceError,
     operators::{
         doc_embedding_operator::{
-            create_doc_group_embedding, delete_doc_embedding_pg_query,
+            get_unchanged_doc_embedding_qdrant_id_pg_query,
+            lock_unchanged_doc_embedding_qdrant_id_pg_query, recompute_doc_groups_for_chapter,
             upsert_doc_embedding_pg_query,
         },
-        doc_group_embedding_operator::get_unique_doc_group_sizes,
-        embedding_operator, parse_operator,
-        qdrant_operator::delete_reinsert_doc_embedding_qdrant_query,
+        embedding_operator,
+        group_maintenance_operator::GroupMaintenanceQueue,
+        named_chapter_range_operator::recompute_named_chapter_ranges_for_chapter,
+        parse_operator,
+        qdrant_operator::{
+            doc_collection_name, doc_embedding_vector_mutations,
+            get_points_with_vectors_qdrant_query,
+        },
+        reindex_operator::get_doc_embedding_write_collections_pg_query,
+        story_embedding_operator::update_story_embedding_for_chapter,
+        vector_outbox_operator::{enqueue_vector_mutations_pg_query, VectorOutboxRelay},
     },
 };
-use actix_rt::Arbiter;
 use actix_web::{web, HttpResponse};
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
+use std::time::Duration;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct IndexDocumentRequest {
     pub doc_html: String,
     pub story_id: i64,
     pub index: i32,
+    /// Embeds the chapter again even if its text did not change
+    #[serde(default)]
+    pub force: bool,
 }
 
+/// `changed` is false when the chapter already had the same text and its stored embedding was
+/// returned without embedding it again. The embedding is normalized either way.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct IndexDocumentResponse {
-    embedding: Vec<f32>,
+    pub embedding: Vec<f32>,
+    pub changed: bool,
+}
+
+/// The stored vector of an unchanged chapter. A missing point gives `None`, so the chapter is
+/// embedded again, which restores it.
+async fn get_stored_embedding(
+    qdrant_point_id: uuid::Uuid,
+    vector_store: &dyn VectorStore,
+) -> Result<Option<Vec<f32>>, ServiceError> {
+    Ok(get_points_with_vectors_qdrant_query(
+        doc_collection_name(None),
+        vec![qdrant_point_id],
+        vector_store,
+    )
+    .await?
+    .into_iter()
+    .next()
+    .map(|point| point.vector))
 }
 
 pub async fn embed_document(
     document: web::Json<IndexDocumentRequest>,
     pool: web::Data<Pool<Postgres>>,
+    group_maintenance: web::Data<GroupMaintenanceQueue>,
+    relay: web::Data<VectorOutboxRelay>,
+    vector_store: web::Data<dyn VectorStore>,
     _: AuthRequired,
 ) -> Result<HttpResponse, ServiceError> {
     let doc_html = match std::str::from_utf8(document.doc_html.as_bytes()) {
@@ -47,53 +83,161 @@ pub async fn embed_document(
         return Err(ServiceError::EmptyDocumentError);
     }
 
-    let embedding = embedding_operator::get_average_embedding(doc_chunks).await?;
+    let content_hash = parse_operator::content_hash(&doc_chunks);
+
+    // checked again under the row lock below; this only saves embedding an unchanged chapter
+    if !document.force {
+        if let Some(qdrant_point_id) = get_unchanged_doc_embedding_qdrant_id_pg_query(
+            document.story_id,
+            document.index,
+            &content_hash,
+            pool_inner.clone(),
+        )
+        .await?
+        {
+            if let Some(embedding) =
+                get_stored_embedding(qdrant_point_id, vector_store.get_ref()).await?
+            {
+                return Ok(HttpResponse::Ok().json(IndexDocumentResponse {
+                    embedding,
+                    changed: false,
+                }));
+            }
+        }
+    }
 
-    let doc_embedding_to_upsert = DocEmbedding::from_details(
-        None,
-        doc_html.clone(),
-        document.story_id,
-        document.index,
-        None,
-        None,
-        None,
-    );
+    let embedding =
+        embedding_operator::get_average_embedding(doc_chunks, pool_inner.clone()).await?;
+
+    let now = chrono::Utc::now().naive_utc();
+    let doc_embedding_to_upsert = DocEmbedding {
+        id: uuid::Uuid::new_v4(),
+        doc_html: doc_html.clone(),
+        story_id: document.story_id,
+        index: document.index,
+        qdrant_point_id: uuid::Uuid::new_v4(),
+        content_hash: Some(content_hash.clone()),
+        created_at: now,
+        updated_at: now,
+    };
 
-    let qdrant_point_id_to_delete =
-        upsert_doc_embedding_pg_query(doc_embedding_to_upsert.clone(), pool_inner.clone()).await?;
+    let write_collections =
+        get_doc_embedding_write_collections_pg_query(pool_inner.clone()).await?;
+
+    let mut transaction = pool_inner
+        .begin()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+
+    // the same text may have been written while this request was embedding it
+    if !document.force {
+        if let Some(qdrant_point_id) = lock_unchanged_doc_embedding_qdrant_id_pg_query(
+            document.story_id,
+            document.index,
+            &content_hash,
+            &mut transaction,
+        )
+        .await?
+        {
+            if let Some(embedding) =
+                get_stored_embedding(qdrant_point_id, vector_store.get_ref()).await?
+            {
+                let _ = transaction.rollback().await;
+                return Ok(HttpResponse::Ok().json(IndexDocumentResponse {
+                    embedding,
+                    changed: false,
+                }));
+            }
+        }
+    }
 
-    if qdrant_point_id_to_delete.is_some() {
-        let unique_group_sizes =
-            get_unique_doc_group_sizes(vec![document.story_id], pool_inner.clone()).await?;
+    let qdrant_point_id_to_delete =
+        upsert_doc_embedding_pg_query(doc_embedding_to_upsert.clone(), &mut transaction).await?;
+
+    // The new point gets a fresh id, so the previous point stays valid until the relay replaces
+    // it. The groups, ranges and story embedding recomputed below read the new point from the
+    // outbox.
+    let enqueue_result = enqueue_vector_mutations_pg_query(
+        doc_embedding_vector_mutations(
+            doc_embedding_to_upsert,
+            embedding.clone(),
+            qdrant_point_id_to_delete,
+            &write_collections,
+        ),
+        Duration::ZERO,
+        &mut transaction,
+    )
+    .await
+    .map(|_| ());
+
+    let group_result = match (enqueue_result, qdrant_point_id_to_delete) {
+        (Ok(()), Some(_)) => {
+            recompute_doc_groups_for_chapter(
+                document.story_id,
+                document.index,
+                &mut transaction,
+                vector_store.get_ref(),
+            )
+            .await
+        }
+        (Ok(()), None) => Ok(()),
+        (Err(e), _) => Err(e),
+    };
 
-        let pool_inner1 = pool.get_ref().clone();
+    let range_result = match group_result {
+        Ok(()) => {
+            recompute_named_chapter_ranges_for_chapter(
+                document.story_id,
+                document.index,
+                &mut transaction,
+                vector_store.get_ref(),
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
 
-        Arbiter::new().spawn(async move {
-            for group_size in unique_group_sizes {
-                let doc_group_to_index = IndexDocumentGroupRequest::Story {
-                    story_id: document.story_id,
-                    doc_group_size: group_size,
-                };
+    let story_result = match range_result {
+        Ok(()) => {
+            update_story_embedding_for_chapter(
+                document.story_id,
+                document.index,
+                qdrant_point_id_to_delete,
+                embedding.clone(),
+                &mut transaction,
+                vector_store.get_ref(),
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
 
-                let _ = create_doc_group_embedding(doc_group_to_index, pool_inner1.clone()).await;
-            }
-        });
+    match story_result {
+        Ok(()) => transaction
+            .commit()
+            .await
+            .map_err(ServiceError::PgTransactionError)?,
+        Err(e) => {
+            let _ = transaction.rollback().await;
+            return Err(e);
+        }
     }
+    // every vector write of the chapter is applied by the relay
+    let flushed = relay.flush().await;
 
-    let qdrant_result = delete_reinsert_doc_embedding_qdrant_query(
-        qdrant_point_id_to_delete,
-        doc_embedding_to_upsert.clone(),
-        embedding.clone(),
-    )
-    .await;
+    // New chapters are grouped in the background once the story stops receiving chapters
+    if qdrant_point_id_to_delete.is_none() {
+        group_maintenance.schedule(document.story_id);
+    }
 
-    if let Err(e) = qdrant_result {
-        if qdrant_point_id_to_delete.is_some() {
-            delete_doc_embedding_pg_query(doc_embedding_to_upsert.clone(), pool_inner.clone())
-                .await?;
-        }
-        return Err(e);
+    let response = IndexDocumentResponse {
+        embedding: normalize(embedding),
+        changed: true,
+    };
+    // the write is committed, but not searchable until the relay gets to it
+    if !flushed {
+        return Ok(HttpResponse::Accepted().json(response));
     }
 
-    Ok(HttpResponse::Ok().json(IndexDocumentResponse { embedding }))
+    Ok(HttpResponse::Ok().json(response))
 }
@@ -0,0 +1,9 @@
+use super::auth_handler::AuthRequired;
+use crate::{errors::ServiceError, operators::openai_key_pool_operator::openai_key_pool};
+use actix_web::HttpResponse;
+use std::time::Instant;
+
+/// Usage and health of each OpenAI key since the process started
+pub async fn get_openai_key_usage(_: AuthRequired) -> Result<HttpResponse, ServiceError> {
+    Ok(HttpResponse::Ok().json(openai_key_pool().usage(Instant::now())))
+}
@@ -1,8 +1,14 @@
 use actix_web::{Responder, HttpResponse};
 pub mod auth_handler;
+pub mod chapter_range_handler;
 pub mod doc_group_handler;
+pub mod embedding_cache_handler;
 pub mod embedding_handler;
+pub mod embedding_provider_handler;
+pub mod reconcile_handler;
+pub mod reindex_handler;
 pub mod search_handler;
+pub mod story_handler;
 
 pub async fn healthcheck() -> impl Responder {
     HttpResponse::Ok().body("OK")
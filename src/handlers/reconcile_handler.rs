@@ -0,0 +1,45 @@
+use super::auth_handler::AuthRequired;
+use crate::operators::vector_store_operator::VectorStore;
+use crate::{
+    errors::ServiceError,
+    operators::reconcile_operator::{get_reconcile_job_pg_query, reconcile, start_reconcile_job},
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+pub async fn get_reconcile_report(
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let report = reconcile(pool.get_ref().clone(), vector_store.get_ref()).await?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Starts the repair in the background; its job carries the report once it completed
+pub async fn fix_reconcile_report(
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let job = start_reconcile_job(pool.get_ref().clone(), vector_store.into_inner()).await?;
+
+    Ok(HttpResponse::Accepted().json(job))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReconcileJobRequest {
+    pub id: uuid::Uuid,
+}
+
+pub async fn get_reconcile_job(
+    request: web::Path<ReconcileJobRequest>,
+    pool: web::Data<Pool<Postgres>>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let job = get_reconcile_job_pg_query(request.id, pool.get_ref().clone()).await?;
+
+    Ok(HttpResponse::Ok().json(job))
+}
@@ -0,0 +1,100 @@
+use super::auth_handler::AuthRequired;
+use crate::operators::vector_store_operator::VectorStore;
+use crate::{
+    data::models::DocEmbeddingReindex,
+    errors::ServiceError,
+    operators::reindex_operator::{
+        get_reindex_pg_query, get_reindexes_pg_query, rollback_doc_embeddings_alias,
+        start_doc_embedding_reindex, switch_doc_embeddings_alias, DOC_EMBEDDINGS_ALIAS,
+    },
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+pub async fn start_reindex(
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let reindex =
+        start_doc_embedding_reindex(pool.get_ref().clone(), vector_store.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(reindex))
+}
+
+/// `live_collection` is the collection `doc_embeddings` currently points at
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ListReindexesResponse {
+    pub live_collection: Option<String>,
+    pub reindexes: Vec<DocEmbeddingReindex>,
+}
+
+pub async fn list_reindexes(
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let live_collection = vector_store
+        .alias_target(DOC_EMBEDDINGS_ALIAS)
+        .await
+        .map_err(ServiceError::SwitchAliasQdrantError)?;
+    let reindexes = get_reindexes_pg_query(pool.get_ref().clone()).await?;
+
+    Ok(HttpResponse::Ok().json(ListReindexesResponse {
+        live_collection,
+        reindexes,
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReindexVersionRequest {
+    pub version: i32,
+}
+
+/// `progress` is the share of chapters written to the new collection, from 0 to 1
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReindexProgressResponse {
+    pub reindex: DocEmbeddingReindex,
+    pub progress: f64,
+}
+
+pub async fn get_reindex_progress(
+    request: web::Path<ReindexVersionRequest>,
+    pool: web::Data<Pool<Postgres>>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let reindex = get_reindex_pg_query(request.version, pool.get_ref().clone()).await?;
+
+    Ok(HttpResponse::Ok().json(ReindexProgressResponse {
+        progress: reindex.progress(),
+        reindex,
+    }))
+}
+
+pub async fn switch_reindex(
+    request: web::Path<ReindexVersionRequest>,
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let reindex = switch_doc_embeddings_alias(
+        request.version,
+        pool.get_ref().clone(),
+        vector_store.get_ref(),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(reindex))
+}
+
+pub async fn rollback_reindex(
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let reindex =
+        rollback_doc_embeddings_alias(pool.get_ref().clone(), vector_store.get_ref()).await?;
+
+    Ok(HttpResponse::Ok().json(reindex))
+}
@@ -1,16 +1,26 @@
 use super::auth_handler::AuthRequired;
+use crate::operators::vector_store_operator::VectorStore;
 use crate::{
+    data::models::{DocGroupSpec, EmbeddingLevel, SimilarityTarget},
     errors::ServiceError,
-    operators::{embedding_operator, qdrant_operator, search_operator},
+    operators::{
+        doc_group_embedding_operator::get_registered_doc_groups_pg_query,
+        embedding_operator, qdrant_operator,
+        query_embedding_cache_operator::QueryEmbeddingCache,
+        search_operator::{self, MultiResolutionMatch},
+    },
 };
 use actix_web::{web, HttpResponse};
+use futures::future::join_all;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use sqlx::{Pool, Postgres};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SemanticSearchRequest {
     pub doc_group_size: Option<i32>,
+    pub doc_group_stride: Option<i32>,
+    #[serde(default)]
+    pub level: EmbeddingLevel,
     pub page: u64,
     pub query: String,
 }
@@ -18,6 +28,8 @@ pub struct SemanticSearchRequest {
 pub async fn semantic_search(
     group_document_request: web::Json<SemanticSearchRequest>,
     pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    query_cache: web::Data<QueryEmbeddingCache>,
 ) -> Result<HttpResponse, ServiceError> {
     /*
        Step 1: Create an embedding for query from microservice
@@ -26,50 +38,244 @@ pub async fn semantic_search(
        Step 4: Join with postgres to get the full document
        Step 5: Return the results
     */
-    let embedding =
-        embedding_operator::create_embedding(group_document_request.query.clone()).await?;
+    let doc_group = DocGroupSpec::from_optional(
+        group_document_request.doc_group_size,
+        group_document_request.doc_group_stride,
+    )?;
+
+    let collection_name = match (group_document_request.level, doc_group) {
+        (EmbeddingLevel::Chapter, doc_group) => qdrant_operator::doc_collection_name(doc_group),
+        (EmbeddingLevel::Range, None) => {
+            qdrant_operator::NAMED_CHAPTER_RANGES_COLLECTION.to_owned()
+        }
+        (EmbeddingLevel::Story, None) => qdrant_operator::STORY_EMBEDDINGS_COLLECTION.to_owned(),
+        (_, Some(_)) => return Err(ServiceError::InvalidEmbeddingLevel),
+    };
+
+    let embedding = query_cache
+        .create_embedding(group_document_request.query.clone())
+        .await?;
     let point_ids = qdrant_operator::search_qdrant_query(
         embedding,
         group_document_request.page,
-        group_document_request.doc_group_size,
+        10,
+        collection_name,
+        vector_store.get_ref(),
     )
     .await?;
 
-    let results = search_operator::get_docs_by_point_id(
-        point_ids,
-        group_document_request.doc_group_size,
-        pool.get_ref().clone(),
-    )
-    .await?;
+    let results = match group_document_request.level {
+        EmbeddingLevel::Chapter => {
+            search_operator::get_docs_by_point_id(point_ids, doc_group, pool.get_ref().clone())
+                .await?
+        }
+        EmbeddingLevel::Range => {
+            search_operator::get_named_chapter_ranges_by_point_id(point_ids, pool.get_ref().clone())
+                .await?
+        }
+        EmbeddingLevel::Story => {
+            search_operator::get_stories_by_point_id(point_ids, pool.get_ref().clone()).await?
+        }
+    };
     Ok(HttpResponse::Ok().json(results))
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MultiResolutionSearchRequest {
+    pub query: String,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MultiResolutionSearchResponse {
+    pub results: Vec<MultiResolutionMatch>,
+}
+
+/// Every resolution is asked for this many candidates per requested story, since a story can
+/// fill several ranks of one resolution with its chapters or groups
+const CANDIDATES_PER_STORY: u64 = 5;
+
+/// Caps the requested limit, which is multiplied by `CANDIDATES_PER_STORY` for every resolution
+const MAX_MULTI_RESOLUTION_LIMIT: u64 = 100;
+
+pub async fn multi_resolution_search(
+    multi_resolution_search_request: web::Json<MultiResolutionSearchRequest>,
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    query_cache: web::Data<QueryEmbeddingCache>,
+) -> Result<HttpResponse, ServiceError> {
+    let limit = multi_resolution_search_request
+        .limit
+        .unwrap_or(10)
+        .min(MAX_MULTI_RESOLUTION_LIMIT);
+
+    let mut resolutions: Vec<Option<DocGroupSpec>> = vec![None];
+    resolutions.extend(
+        get_registered_doc_groups_pg_query(pool.get_ref().clone())
+            .await?
+            .into_iter()
+            .map(Some),
+    );
+
+    let embedding = query_cache
+        .create_embedding(multi_resolution_search_request.query.clone())
+        .await?;
+
+    let vector_store = vector_store.get_ref();
+    let searches = join_all(resolutions.into_iter().map(|doc_group| {
+        let embedding = embedding.clone();
+        async move {
+            let points = qdrant_operator::search_qdrant_query(
+                embedding,
+                1,
+                limit * CANDIDATES_PER_STORY,
+                qdrant_operator::doc_collection_name(doc_group),
+                vector_store,
+            )
+            .await;
+            (doc_group, points)
+        }
+    }))
+    .await;
+
+    // a resolution that fails, such as a group size whose collection is gone, is left out of the
+    // fusion instead of failing the whole search
+    let mut rankings = vec![];
+    let mut last_error = None;
+    for (doc_group, points) in searches {
+        match points {
+            Ok(points) => rankings.push((doc_group, points)),
+            Err(err) => {
+                log::info!(
+                    "Skipping resolution {:?} of multi-resolution search: {:?}",
+                    doc_group,
+                    err
+                );
+                last_error = Some(err);
+            }
+        }
+    }
+    if let (true, Some(err)) = (rankings.is_empty(), last_error) {
+        return Err(err);
+    }
+
+    let mut results = search_operator::fuse_resolutions_by_story(rankings);
+    results.truncate(limit as usize);
+
+    Ok(HttpResponse::Ok().json(MultiResolutionSearchResponse { results }))
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SimilarityToSingleVectorRequest {
     pub query: String,
     pub doc_group_size: Option<i32>,
+    pub doc_group_stride: Option<i32>,
     pub index: i64,
     pub story_id: i64,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SimilarityToSingleVectorResponse {
+    pub similarity: f32,
+}
+
 pub async fn similarity_to_single_vector(
     similarity_to_single_vector_request: web::Json<SimilarityToSingleVectorRequest>,
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    query_cache: web::Data<QueryEmbeddingCache>,
     _auth_required: AuthRequired,
 ) -> Result<HttpResponse, ServiceError> {
-    let query_embedding =
-        embedding_operator::create_embedding(similarity_to_single_vector_request.query.clone())
-            .await?;
-
-    let similarity = qdrant_operator::similarity_top_filtered_point(
-        query_embedding,
-        similarity_to_single_vector_request.story_id,
-        similarity_to_single_vector_request.index,
+    // no chapter or group index is beyond i32
+    let target = SimilarityTarget {
+        story_id: similarity_to_single_vector_request.story_id,
+        index: i32::try_from(similarity_to_single_vector_request.index)
+            .map_err(|_| ServiceError::MatchingRecordNotFound)?,
+    };
+
+    let doc_group = DocGroupSpec::from_optional(
         similarity_to_single_vector_request.doc_group_size,
+        similarity_to_single_vector_request.doc_group_stride,
+    )?;
+
+    // Resolve the exact chapter or group before paying for an embedding call
+    let target_vector = search_operator::get_target_vectors_query(
+        &[target],
+        doc_group,
+        pool.get_ref().clone(),
+        vector_store.get_ref(),
+    )
+    .await?
+    .pop()
+    .flatten()
+    .ok_or(ServiceError::MatchingRecordNotFound)?;
+
+    let query_embedding = query_cache
+        .create_embedding(similarity_to_single_vector_request.query.clone())
+        .await?;
+
+    Ok(HttpResponse::Ok().json(SimilarityToSingleVectorResponse {
+        similarity: embedding_operator::cosine_similarity(&query_embedding, &target_vector),
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BatchSimilarityRequest {
+    pub query: String,
+    pub doc_group_size: Option<i32>,
+    pub doc_group_stride: Option<i32>,
+    pub targets: Vec<SimilarityTarget>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BatchSimilarityResult {
+    pub story_id: i64,
+    pub index: i32,
+    pub found: bool,
+    pub similarity: Option<f32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BatchSimilarityResponse {
+    pub results: Vec<BatchSimilarityResult>,
+}
+
+pub async fn batch_similarity(
+    batch_similarity_request: web::Json<BatchSimilarityRequest>,
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    query_cache: web::Data<QueryEmbeddingCache>,
+    _auth_required: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let doc_group = DocGroupSpec::from_optional(
+        batch_similarity_request.doc_group_size,
+        batch_similarity_request.doc_group_stride,
+    )?;
+
+    let query_embedding = query_cache
+        .create_embedding(batch_similarity_request.query.clone())
+        .await?;
+
+    let target_vectors = search_operator::get_target_vectors_query(
+        &batch_similarity_request.targets,
+        doc_group,
+        pool.get_ref().clone(),
+        vector_store.get_ref(),
     )
     .await?;
 
-    match similarity {
-        Some(similarity) => Ok(HttpResponse::Ok().json(json!({ "similarity": similarity }))),
-        None => Err(ServiceError::MatchingRecordNotFound),
-    }
+    let results = batch_similarity_request
+        .targets
+        .iter()
+        .zip(target_vectors)
+        .map(|(target, vector)| BatchSimilarityResult {
+            story_id: target.story_id,
+            index: target.index,
+            found: vector.is_some(),
+            similarity: vector
+                .map(|vector| embedding_operator::cosine_similarity(&query_embedding, &vector)),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(BatchSimilarityResponse { results }))
 }
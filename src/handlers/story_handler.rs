@@ -0,0 +1,121 @@
+use super::auth_handler::AuthRequired;
+use crate::operators::vector_store_operator::VectorStore;
+use crate::{
+    data::models::DocGroupSpec,
+    errors::ServiceError,
+    operators::{
+        doc_embedding_operator::get_story_doc_embedding_qdrant_ids_pg_query,
+        doc_group_embedding_operator::get_story_doc_group_qdrant_ids_pg_query,
+        embedding_operator::{change_points, similarity_matrix},
+        qdrant_operator::{doc_collection_name, get_points_with_vectors_qdrant_query},
+        story_embedding_operator::recompute_story_embedding,
+        vector_outbox_operator::VectorOutboxRelay,
+    },
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SimilarityMatrixQuery {
+    pub doc_group_size: Option<i32>,
+    pub doc_group_stride: Option<i32>,
+    pub change_point_threshold: Option<f32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SimilarityMatrixResponse {
+    pub indices: Vec<i32>,
+    pub matrix: Vec<Vec<f32>>,
+    pub change_points: Option<Vec<i32>>,
+}
+
+pub async fn get_similarity_matrix(
+    story_id: web::Path<i64>,
+    query: web::Query<SimilarityMatrixQuery>,
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let story_id = story_id.into_inner();
+
+    let doc_group = DocGroupSpec::from_optional(query.doc_group_size, query.doc_group_stride)?;
+
+    let qdrant_point_ids = match doc_group {
+        Some(doc_group) => {
+            get_story_doc_group_qdrant_ids_pg_query(story_id, doc_group, pool.get_ref().clone())
+                .await?
+        }
+        None => {
+            get_story_doc_embedding_qdrant_ids_pg_query(story_id, pool.get_ref().clone()).await?
+        }
+    };
+
+    if qdrant_point_ids.is_empty() {
+        return Err(ServiceError::RecordNotFound);
+    }
+
+    let points = get_points_with_vectors_qdrant_query(
+        doc_collection_name(doc_group),
+        qdrant_point_ids
+            .iter()
+            .map(|container| container.qdrant_point_id)
+            .collect(),
+        vector_store.get_ref(),
+    )
+    .await?;
+
+    // Qdrant does not preserve the requested order, so rebuild it from the index order
+    let (indices, embeddings): (Vec<i32>, Vec<Vec<f32>>) = qdrant_point_ids
+        .iter()
+        .filter_map(|container| {
+            points
+                .iter()
+                .find(|point| point.point_id == container.qdrant_point_id)
+                .map(|point| (container.index, point.vector.clone()))
+        })
+        .unzip();
+
+    let matrix = similarity_matrix(&embeddings)?;
+
+    let change_points = query.change_point_threshold.map(|threshold| {
+        change_points(&matrix, threshold)
+            .into_iter()
+            .map(|position| indices[position])
+            .collect()
+    });
+
+    Ok(HttpResponse::Ok().json(SimilarityMatrixResponse {
+        indices,
+        matrix: matrix.rows().into_iter().map(|row| row.to_vec()).collect(),
+        change_points,
+    }))
+}
+
+/// Rebuilds the story embedding from the chapters already indexed, for stories indexed before
+/// story embeddings existed
+pub async fn index_story_embedding(
+    story_id: web::Path<i64>,
+    pool: web::Data<Pool<Postgres>>,
+    vector_store: web::Data<dyn VectorStore>,
+    relay: web::Data<VectorOutboxRelay>,
+    _: AuthRequired,
+) -> Result<HttpResponse, ServiceError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+    recompute_story_embedding(
+        story_id.into_inner(),
+        &mut transaction,
+        vector_store.get_ref(),
+    )
+    .await?;
+    transaction
+        .commit()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+    relay.flush().await;
+
+    Ok(HttpResponse::NoContent().into())
+}
@@ -1,7 +1,22 @@
-use crate::operators::qdrant_operator::get_qdrant_connection;
+use crate::operators::{
+    chunk_embedding_cache_operator::start_chunk_embedding_cache_eviction,
+    embedding_provider_operator::embedding_provider,
+    group_maintenance_operator::{doc_group_rebuild_debounce, GroupMaintenanceQueue},
+    openai_key_pool_operator::openai_key_pool,
+    pgvector_operator::PgVectorStore,
+    qdrant_operator::{
+        embedding_size, qdrant_client_from_env, qdrant_collection_config_from_env,
+        NAMED_CHAPTER_RANGES_COLLECTION, STORY_EMBEDDINGS_COLLECTION,
+    },
+    query_embedding_cache_operator::QueryEmbeddingCache,
+    reconcile_operator::{fail_interrupted_reconcile_jobs_pg_query, reconcile, reconcile_and_fix},
+    reindex_operator::{ensure_doc_embeddings_alias, fail_interrupted_reindexes_pg_query},
+    vector_outbox_operator::VectorOutboxRelay,
+    vector_store_operator::{InMemoryVectorStore, QdrantVectorStore, VectorStore},
+};
 use actix_web::{middleware, web, App, HttpServer};
-use qdrant_client::qdrant::{CreateCollection, Distance, VectorParams, VectorsConfig};
-use sqlx::postgres::PgPoolOptions;
+use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use std::{sync::Arc, time::Duration};
 pub mod data;
 pub mod errors;
 pub mod handlers;
@@ -13,16 +28,9 @@ pub fn check_environment_variables() -> Result<(), String> {
     Ok(())
 }
 
-#[actix_web::main]
-pub async fn main() -> std::io::Result<()> {
-    dotenvy::dotenv().ok();
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-
-    let env_result = check_environment_variables();
-    if let Err(e) = env_result {
-        panic!("{}", e);
-    }
-
+/// Connects to Postgres and the configured vector store, migrating the database and creating
+/// the collections every request relies on
+async fn connect_stores() -> (Pool<Postgres>, Arc<dyn VectorStore>) {
     let database_url = std::env::var("DATABASE_URL")
         .expect("POSTGRES_CONNECTION_URL environment variable not set.");
     let pool = PgPoolOptions::new()
@@ -36,70 +44,264 @@ pub async fn main() -> std::io::Result<()> {
         .await
         .expect("Failed to migrate database.");
 
-    let qdrant_client = get_qdrant_connection().await.unwrap();
-    let embedding_size = std::env::var("EMBEDDING_SIZE").unwrap_or("1536".to_owned());
-    let embedding_size = embedding_size.parse::<u64>().unwrap_or(1536);
-
-    let _ = qdrant_client
-        .create_collection(&CreateCollection {
-            collection_name: "doc_embeddings".to_owned(),
-            vectors_config: Some(VectorsConfig {
-                config: Some(qdrant_client::qdrant::vectors_config::Config::Params(
-                    VectorParams {
-                        size: embedding_size,
-                        distance: Distance::Cosine.into(),
-                        hnsw_config: None,
-                        quantization_config: None,
-                        on_disk: None,
-                    },
-                )),
-            }),
-            ..Default::default()
-        })
-        .await
-        .map_err(|err| {
-            log::info!("Failed to create collection: {:?}", err);
-        });
+    let vector_store: Arc<dyn VectorStore> = match std::env::var("VECTOR_STORE")
+        .unwrap_or("qdrant".to_owned())
+        .as_str()
+    {
+        "qdrant" => {
+            let qdrant_url = std::env::var("QDRANT_URL").unwrap_or_default();
+            let qdrant_client = match qdrant_client_from_env() {
+                Ok(qdrant_client) => qdrant_client,
+                Err(err) => panic!("Failed to configure Qdrant client: {:?}", err),
+            };
+            let collection_config = match qdrant_collection_config_from_env() {
+                Ok(collection_config) => collection_config,
+                Err(err) => panic!("Failed to configure Qdrant collections: {:?}", err),
+            };
+            let qdrant_vector_store = QdrantVectorStore::new(qdrant_client, collection_config);
+            if let Err(err) = qdrant_vector_store.health_check().await {
+                panic!("Failed to connect to Qdrant at {}: {:?}", qdrant_url, err);
+            }
+            match qdrant_vector_store.collection_drift(embedding_size()).await {
+                Ok(drifted) => {
+                    for (collection_name, drift) in drifted {
+                        log::warn!(
+                            "Collection {} does not match the configured settings: {}",
+                            collection_name,
+                            drift.join(", ")
+                        );
+                    }
+                }
+                Err(err) => log::warn!("Failed to check collection settings: {:?}", err),
+            }
+            Arc::new(qdrant_vector_store)
+        }
+        "pgvector" => {
+            let pgvector_store = PgVectorStore::new(pool.clone());
+            if let Err(err) = pgvector_store.check_installed().await {
+                panic!("Failed to set up pgvector: {:?}", err);
+            }
+            Arc::new(pgvector_store)
+        }
+        "memory" => {
+            log::info!("Using the in-memory vector store, vectors are lost on restart");
+            Arc::new(InMemoryVectorStore::default())
+        }
+        vector_store => panic!(
+            "Unknown VECTOR_STORE {}, expected qdrant, pgvector or memory",
+            vector_store
+        ),
+    };
+
+    for collection_name in [STORY_EMBEDDINGS_COLLECTION, NAMED_CHAPTER_RANGES_COLLECTION] {
+        if let Err(err) = vector_store
+            .create_collection(collection_name, embedding_size())
+            .await
+        {
+            panic!("Failed to create collection {}: {:?}", collection_name, err);
+        }
+    }
+    if let Err(err) = ensure_doc_embeddings_alias(vector_store.as_ref()).await {
+        panic!("Failed to set up the doc_embeddings alias: {:?}", err);
+    }
+
+    (pool, vector_store)
+}
+
+#[actix_web::main]
+pub async fn main() -> std::io::Result<()> {
+    dotenvy::dotenv().ok();
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
+    let env_result = check_environment_variables();
+    if let Err(e) = env_result {
+        panic!("{}", e);
+    }
+
+    // reads the provider settings now, so a bad value stops the server before it serves
+    embedding_provider();
+    openai_key_pool();
+
+    let (pool, vector_store) = connect_stores().await;
+
+    if let Err(err) = fail_interrupted_reindexes_pg_query(pool.clone()).await {
+        panic!("Failed to clean up interrupted reindexes: {:?}", err);
+    }
+    if let Err(err) = fail_interrupted_reconcile_jobs_pg_query(pool.clone()).await {
+        panic!("Failed to clean up interrupted reconcile jobs: {:?}", err);
+    }
+    start_chunk_embedding_cache_eviction(pool.clone());
+
+    let vector_store = web::Data::from(vector_store);
+    let group_maintenance = GroupMaintenanceQueue::start(
+        pool.clone(),
+        vector_store.clone().into_inner(),
+        doc_group_rebuild_debounce(),
+    );
+    let relay = VectorOutboxRelay::start(
+        pool.clone(),
+        vector_store.clone().into_inner(),
+        Duration::from_secs(5),
+    );
+    let query_cache = match QueryEmbeddingCache::from_env().await {
+        Ok(query_cache) => web::Data::new(query_cache),
+        Err(err) => panic!("Failed to set up the query embedding cache: {:?}", err),
+    };
 
     log::info!("starting HTTP server at http://localhost:8090");
 
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
+            .app_data(vector_store.clone())
+            .app_data(web::Data::new(group_maintenance.clone()))
+            .app_data(web::Data::new(relay.clone()))
+            .app_data(query_cache.clone())
             .wrap(middleware::Logger::default())
-            .service(
-                web::scope("/api")
-                    .route("/healthcheck", web::get().to(handlers::healthcheck))
-                    .route(
-                        "/check_key",
-                        web::get().to(handlers::auth_handler::check_key),
-                    )
-                    .route(
-                        "/index_document",
-                        web::post().to(handlers::embedding_handler::embed_document),
-                    )
-                    .service(
-                        web::resource("/document_group")
-                            .route(
-                                web::post().to(handlers::doc_group_handler::create_document_group),
-                            )
-                            .route(
-                                web::put().to(handlers::doc_group_handler::index_document_group),
-                            ),
-                    )
-                    .service(web::resource("/recommend").route(
-                        web::post().to(handlers::doc_group_handler::recommend_document_group),
-                    ))
-                    .service(web::resource("/similarity").route(
-                        web::post().to(handlers::search_handler::similarity_to_single_vector),
-                    ))
-                    .route(
-                        "/search",
-                        web::post().to(handlers::search_handler::semantic_search),
-                    ),
-            )
+            .configure(configure_routes)
     })
     .bind(("0.0.0.0", 8090))?
     .run()
     .await
 }
+
+/// Runs the consistency check once and prints the report, failing when inconsistencies were
+/// found and left in place
+#[actix_web::main]
+pub async fn reconcile_command(fix: bool) -> std::io::Result<()> {
+    dotenvy::dotenv().ok();
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
+    if let Err(e) = check_environment_variables() {
+        panic!("{}", e);
+    }
+
+    let (pool, vector_store) = connect_stores().await;
+    let report = if fix {
+        reconcile_and_fix(pool, vector_store.as_ref()).await
+    } else {
+        reconcile(pool, vector_store.as_ref()).await
+    }
+    .map_err(|err| std::io::Error::other(format!("{:?}", err)))?;
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).expect("Failed to serialize the report")
+    );
+
+    if !report.fixed && !report.is_consistent() {
+        return Err(std::io::Error::other(
+            "Postgres and the vector store are out of sync, run with --fix to repair",
+        ));
+    }
+    Ok(())
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api")
+            .route("/healthcheck", web::get().to(handlers::healthcheck))
+            .route(
+                "/check_key",
+                web::get().to(handlers::auth_handler::check_key),
+            )
+            .route(
+                "/index_document",
+                web::post().to(handlers::embedding_handler::embed_document),
+            )
+            .route(
+                "/embedding_cache",
+                web::get().to(handlers::embedding_cache_handler::get_embedding_cache_stats),
+            )
+            .route(
+                "/embedding_cache/queries",
+                web::delete().to(handlers::embedding_cache_handler::flush_query_embedding_cache),
+            )
+            .route(
+                "/embedding_provider/keys",
+                web::get().to(handlers::embedding_provider_handler::get_openai_key_usage),
+            )
+            .service(
+                web::resource("/document_group")
+                    .route(web::get().to(handlers::doc_group_handler::list_document_groups))
+                    .route(web::post().to(handlers::doc_group_handler::create_document_group))
+                    .route(web::put().to(handlers::doc_group_handler::index_document_group)),
+            )
+            .route(
+                "/document_group/{doc_group_size}/{story_id}",
+                web::get().to(handlers::doc_group_handler::get_document_group),
+            )
+            .service(
+                web::resource("/recommend")
+                    .route(web::post().to(handlers::doc_group_handler::recommend_document_group)),
+            )
+            .service(
+                web::resource("/recommend/chapter")
+                    .route(web::post().to(handlers::doc_group_handler::recommend_chapter)),
+            )
+            .service(
+                web::resource("/recommend/range").route(
+                    web::post().to(handlers::chapter_range_handler::recommend_chapter_range),
+                ),
+            )
+            .service(
+                web::resource("/similarity")
+                    .route(web::post().to(handlers::search_handler::similarity_to_single_vector)),
+            )
+            .service(
+                web::resource("/similarity/batch")
+                    .route(web::post().to(handlers::search_handler::batch_similarity)),
+            )
+            .service(
+                web::resource("/story/{story_id}/ranges")
+                    .route(web::get().to(handlers::chapter_range_handler::get_chapter_ranges))
+                    .route(web::put().to(handlers::chapter_range_handler::upsert_chapter_range)),
+            )
+            .route(
+                "/story/{story_id}/ranges/{name}",
+                web::delete().to(handlers::chapter_range_handler::delete_chapter_range),
+            )
+            .route(
+                "/story/{story_id}/embedding",
+                web::put().to(handlers::story_handler::index_story_embedding),
+            )
+            .route(
+                "/story/{story_id}/similarity_matrix",
+                web::get().to(handlers::story_handler::get_similarity_matrix),
+            )
+            .service(
+                web::resource("/reindex")
+                    .route(web::get().to(handlers::reindex_handler::list_reindexes))
+                    .route(web::post().to(handlers::reindex_handler::start_reindex)),
+            )
+            .route(
+                "/reindex/rollback",
+                web::post().to(handlers::reindex_handler::rollback_reindex),
+            )
+            .route(
+                "/reindex/{version}",
+                web::get().to(handlers::reindex_handler::get_reindex_progress),
+            )
+            .route(
+                "/reindex/{version}/switch",
+                web::post().to(handlers::reindex_handler::switch_reindex),
+            )
+            .service(
+                web::resource("/reconcile")
+                    .route(web::get().to(handlers::reconcile_handler::get_reconcile_report))
+                    .route(web::post().to(handlers::reconcile_handler::fix_reconcile_report)),
+            )
+            .route(
+                "/reconcile/{id}",
+                web::get().to(handlers::reconcile_handler::get_reconcile_job),
+            )
+            .route(
+                "/search",
+                web::post().to(handlers::search_handler::semantic_search),
+            )
+            .route(
+                "/search/multi_resolution",
+                web::post().to(handlers::search_handler::multi_resolution_search),
+            ),
+    );
+}
@@ -1,3 +1,9 @@
 fn main() -> std::io::Result<()> {
-    royal_road_embeddings::main()
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("reconcile") => {
+            royal_road_embeddings::reconcile_command(args.iter().any(|arg| arg == "--fix"))
+        }
+        _ => royal_road_embeddings::main(),
+    }
 }
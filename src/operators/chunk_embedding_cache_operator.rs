@@ -0,0 +1,158 @@
+use super::parse_operator::content_hash;
+use crate::errors::ServiceError;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// How often expired entries are deleted
+const CHUNK_EMBEDDING_CACHE_EVICTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+static CHUNK_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CHUNK_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// How long a chunk embedding is reused before the chunk is embedded again
+pub fn chunk_embedding_cache_ttl() -> Duration {
+    let seconds = std::env::var("CHUNK_EMBEDDING_CACHE_TTL_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .unwrap_or(30 * 24 * 3600);
+    Duration::from_secs(seconds)
+}
+
+/// Hash a chunk is cached under, the same normalized hash chapters are compared by
+pub fn chunk_hash(chunk: &str) -> String {
+    content_hash(&[chunk.to_owned()])
+}
+
+/// Cached embeddings of `chunk_hashes` for `model` that have not expired, by hash
+pub async fn get_cached_chunk_embeddings_pg_query(
+    chunk_hashes: &[String],
+    model: &str,
+    pool: Pool<Postgres>,
+) -> Result<HashMap<String, Vec<f32>>, ServiceError> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT chunk_hash, embedding AS "embedding: Vec<f32>"
+        FROM chunk_embeddings
+        WHERE model = $1
+            AND chunk_hash = ANY($2)
+            AND created_at > CURRENT_TIMESTAMP - make_interval(secs => $3)
+        "#,
+        model,
+        chunk_hashes,
+        chunk_embedding_cache_ttl().as_secs_f64(),
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::ChunkEmbeddingCachePgError)?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.chunk_hash, row.embedding))
+        .collect())
+}
+
+/// Caches embeddings of chunks that missed, as `(chunk_hash, embedding)` pairs with distinct
+/// hashes, in one insert. An expired entry is replaced.
+pub async fn insert_chunk_embeddings_pg_query(
+    chunk_embeddings: Vec<(String, Vec<f32>)>,
+    model: &str,
+    pool: Pool<Postgres>,
+) -> Result<(), ServiceError> {
+    let Some(dimension) = chunk_embeddings
+        .first()
+        .map(|(_, embedding)| embedding.len())
+    else {
+        return Ok(());
+    };
+    if chunk_embeddings
+        .iter()
+        .any(|(_, embedding)| embedding.len() != dimension)
+    {
+        log::info!("Chunk embeddings differ in size and were not cached");
+        return Ok(());
+    }
+
+    let (chunk_hashes, embeddings): (Vec<String>, Vec<Vec<f32>>) =
+        chunk_embeddings.into_iter().unzip();
+    let embeddings = embeddings.concat();
+    // arrays of arrays are flattened by UNNEST, so every embedding is sliced out of one array
+    sqlx::query!(
+        r#"
+        INSERT INTO chunk_embeddings (chunk_hash, model, embedding)
+        SELECT chunks.chunk_hash, $2, ($3::REAL[])[(chunks.position - 1) * $4 + 1 : chunks.position * $4]
+        FROM UNNEST($1::TEXT[]) WITH ORDINALITY AS chunks (chunk_hash, position)
+        ON CONFLICT (chunk_hash, model) DO UPDATE
+        SET embedding = EXCLUDED.embedding, created_at = CURRENT_TIMESTAMP
+        WHERE chunk_embeddings.created_at <= CURRENT_TIMESTAMP - make_interval(secs => $5)
+        "#,
+        chunk_hashes.as_slice(),
+        model,
+        embeddings.as_slice(),
+        dimension as i32,
+        chunk_embedding_cache_ttl().as_secs_f64(),
+    )
+    .execute(&pool)
+    .await
+    .map_err(ServiceError::ChunkEmbeddingCachePgError)?;
+
+    Ok(())
+}
+
+pub async fn delete_expired_chunk_embeddings_pg_query(
+    pool: Pool<Postgres>,
+) -> Result<u64, ServiceError> {
+    let deleted = sqlx::query!(
+        r#"
+        DELETE FROM chunk_embeddings
+        WHERE created_at <= CURRENT_TIMESTAMP - make_interval(secs => $1)
+        "#,
+        chunk_embedding_cache_ttl().as_secs_f64(),
+    )
+    .execute(&pool)
+    .await
+    .map_err(ServiceError::ChunkEmbeddingCachePgError)?;
+
+    Ok(deleted.rows_affected())
+}
+
+/// Spawns the worker that deletes expired entries, so the table only holds chunks embedded
+/// within the TTL
+pub fn start_chunk_embedding_cache_eviction(pool: Pool<Postgres>) {
+    actix_rt::spawn(async move {
+        loop {
+            match delete_expired_chunk_embeddings_pg_query(pool.clone()).await {
+                Ok(0) => {}
+                Ok(deleted) => log::info!("Evicted {} expired chunk embeddings", deleted),
+                Err(err) => log::info!("Failed to evict expired chunk embeddings: {:?}", err),
+            }
+            actix_rt::time::sleep(CHUNK_EMBEDDING_CACHE_EVICTION_INTERVAL).await;
+        }
+    });
+}
+
+/// Counts chunk lookups of this process. A chunk repeated within a chapter counts once.
+pub fn record_chunk_embedding_cache_lookup(hits: usize, misses: usize) {
+    CHUNK_CACHE_HITS.fetch_add(hits as u64, Ordering::Relaxed);
+    CHUNK_CACHE_MISSES.fetch_add(misses as u64, Ordering::Relaxed);
+}
+
+/// Hits and misses of this instance since it started
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChunkEmbeddingCacheStats {
+    pub model: String,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+pub fn chunk_embedding_cache_stats(model: &str) -> ChunkEmbeddingCacheStats {
+    ChunkEmbeddingCacheStats {
+        model: model.to_owned(),
+        hits: CHUNK_CACHE_HITS.load(Ordering::Relaxed),
+        misses: CHUNK_CACHE_MISSES.load(Ordering::Relaxed),
+    }
+}
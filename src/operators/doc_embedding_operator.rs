@@ -1,16 +1,22 @@
 use super::doc_group_embedding_operator::{
-    get_indexed_doc_group_qdrant_ids_pg_query, upsert_doc_group_embedding_pg_query,
+    delete_stale_doc_groups_pg_query, doc_group_registered_pg_query,
+    get_indexed_doc_group_qdrant_ids_pg_query, get_single_vectors_to_re_average,
+    get_unique_doc_groups, mark_doc_group_rebuilt_pg_query, upsert_doc_group_embedding_pg_query,
 };
-use super::embedding_operator::group_average_embeddings;
-use super::qdrant_operator::get_doc_embeddings_qdrant_query;
-use super::qdrant_operator::insert_doc_group_embedding_qdrant_query;
-use crate::data::models::DocGroupEmbedding;
+use super::embedding_operator::group_chapter_embeddings;
+use super::qdrant_operator::{
+    doc_collection_name, doc_group_embedding_vector_mutations, get_points_with_vectors_qdrant_query,
+};
+use super::vector_outbox_operator::{
+    enqueue_vector_mutations_pg_query, get_pending_vectors_pg_query,
+};
+use super::vector_store_operator::{normalize, VectorStore};
 use crate::{
-    data::models::DocEmbedding, errors::ServiceError,
-    handlers::doc_group_handler::IndexDocumentGroupRequest,
+    data::models::{DocEmbedding, DocGroupSpec, IndexDocumentGroupRequest},
+    errors::ServiceError,
 };
-use qdrant_client::qdrant;
-use sqlx::{Pool, Postgres};
+use sqlx::{Pool, Postgres, Transaction};
+use std::{ops::RangeInclusive, time::Duration};
 
 pub struct QdrantPointIdContainer {
     pub qdrant_point_id: uuid::Uuid,
@@ -18,7 +24,7 @@ pub struct QdrantPointIdContainer {
 
 pub async fn upsert_doc_embedding_pg_query(
     doc_embedding: DocEmbedding,
-    pool: Pool<Postgres>,
+    transaction: &mut Transaction<'_, Postgres>,
 ) -> Result<Option<uuid::Uuid>, ServiceError> {
     // select qdrant_point_id from doc_embeddings where story_id = $1 and index = $2
     let qdrant_point_id: Option<QdrantPointIdContainer> = sqlx::query_as!(
@@ -31,20 +37,21 @@ pub async fn upsert_doc_embedding_pg_query(
         doc_embedding.story_id,
         doc_embedding.index,
     )
-    .fetch_optional(&pool)
+    .fetch_optional(&mut **transaction)
     .await
     .map_err(ServiceError::UpsertDocEmbeddingPgError)?;
 
     sqlx::query!(
         r#"
-        INSERT INTO doc_embeddings (id, doc_html, story_id, index, qdrant_point_id, created_at, updated_at)
-        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        INSERT INTO doc_embeddings (id, doc_html, story_id, index, qdrant_point_id, content_hash, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
         ON CONFLICT (story_id, index) DO UPDATE
         SET
             doc_html = EXCLUDED.doc_html,
             story_id = EXCLUDED.story_id,
             index = EXCLUDED.index,
             qdrant_point_id = EXCLUDED.qdrant_point_id,
+            content_hash = EXCLUDED.content_hash,
             updated_at = EXCLUDED.updated_at
         "#,
         doc_embedding.id,
@@ -52,16 +59,222 @@ pub async fn upsert_doc_embedding_pg_query(
         doc_embedding.story_id,
         doc_embedding.index,
         doc_embedding.qdrant_point_id,
+        doc_embedding.content_hash,
         doc_embedding.created_at,
         doc_embedding.updated_at,
     )
-    .execute(&pool)
+    .execute(&mut **transaction)
     .await
     .map_err(ServiceError::UpsertDocEmbeddingPgError)?;
 
     Ok(qdrant_point_id.map(|qdrant_point_id_container| qdrant_point_id_container.qdrant_point_id))
 }
 
+/// The point of the chapter at `index` if its stored text hashes to `content_hash`, meaning an
+/// upsert of it would embed the same text again
+pub async fn get_unchanged_doc_embedding_qdrant_id_pg_query(
+    story_id: i64,
+    index: i32,
+    content_hash: &str,
+    pool: Pool<Postgres>,
+) -> Result<Option<uuid::Uuid>, ServiceError> {
+    let qdrant_point_id: Option<QdrantPointIdContainer> = sqlx::query_as!(
+        QdrantPointIdContainer,
+        r#"
+        SELECT qdrant_point_id
+        FROM doc_embeddings
+        WHERE story_id = $1 AND index = $2 AND content_hash = $3
+        "#,
+        story_id,
+        index,
+        content_hash,
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ServiceError::SelectDocEmbeddingsQdrantIdsPgError)?;
+
+    Ok(qdrant_point_id.map(|qdrant_point_id_container| qdrant_point_id_container.qdrant_point_id))
+}
+
+/// The same check under a lock on the chapter row, which holds until `transaction` ends so no
+/// other write of the chapter can commit in between
+pub async fn lock_unchanged_doc_embedding_qdrant_id_pg_query(
+    story_id: i64,
+    index: i32,
+    content_hash: &str,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<uuid::Uuid>, ServiceError> {
+    let doc_embedding = sqlx::query!(
+        r#"
+        SELECT qdrant_point_id, content_hash IS NOT DISTINCT FROM $3 AS "unchanged!"
+        FROM doc_embeddings
+        WHERE story_id = $1 AND index = $2
+        FOR UPDATE
+        "#,
+        story_id,
+        index,
+        content_hash,
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map_err(ServiceError::SelectDocEmbeddingsQdrantIdsPgError)?;
+
+    Ok(doc_embedding
+        .filter(|doc_embedding| doc_embedding.unchanged)
+        .map(|doc_embedding| doc_embedding.qdrant_point_id))
+}
+
+pub async fn get_doc_embedding_qdrant_id_pg_query(
+    story_id: i64,
+    index: i32,
+    pool: Pool<Postgres>,
+) -> Result<Option<uuid::Uuid>, ServiceError> {
+    let qdrant_point_id: Option<QdrantPointIdContainer> = sqlx::query_as!(
+        QdrantPointIdContainer,
+        r#"
+        SELECT qdrant_point_id
+        FROM doc_embeddings
+        WHERE story_id = $1 AND index = $2
+        "#,
+        story_id,
+        index,
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ServiceError::SelectDocEmbeddingsQdrantIdsPgError)?;
+
+    Ok(qdrant_point_id.map(|qdrant_point_id_container| qdrant_point_id_container.qdrant_point_id))
+}
+
+pub struct StoryIndexQdrantPointIdContainer {
+    pub story_id: i64,
+    pub index: i32,
+    pub qdrant_point_id: uuid::Uuid,
+}
+
+pub async fn get_doc_embedding_qdrant_ids_by_story_index_pg_query(
+    story_ids: Vec<i64>,
+    indices: Vec<i32>,
+    pool: Pool<Postgres>,
+) -> Result<Vec<StoryIndexQdrantPointIdContainer>, ServiceError> {
+    let qdrant_point_ids = sqlx::query_as!(
+        StoryIndexQdrantPointIdContainer,
+        r#"
+        SELECT story_id, index, qdrant_point_id
+        FROM doc_embeddings
+        WHERE (story_id, index) IN (SELECT * FROM UNNEST($1::BIGINT[], $2::INTEGER[]))
+        "#,
+        story_ids.as_slice(),
+        indices.as_slice(),
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectDocEmbeddingsQdrantIdsPgError)?;
+
+    Ok(qdrant_point_ids)
+}
+
+pub async fn get_story_doc_embedding_qdrant_ids_pg_query(
+    story_id: i64,
+    pool: Pool<Postgres>,
+) -> Result<Vec<StoryIndexQdrantPointIdContainer>, ServiceError> {
+    let qdrant_point_ids = sqlx::query_as!(
+        StoryIndexQdrantPointIdContainer,
+        r#"
+        SELECT story_id, index, qdrant_point_id
+        FROM doc_embeddings
+        WHERE story_id = $1
+        ORDER BY index
+        "#,
+        story_id,
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectDocEmbeddingsQdrantIdsPgError)?;
+
+    Ok(qdrant_point_ids)
+}
+
+/// Returns the `(index, vector)` of every indexed chapter of the story within `indices`, ordered
+/// by index. Chapters are read through `transaction` so an uncommitted chapter upsert is included.
+pub async fn get_chapter_vectors_in_range(
+    story_id: i64,
+    indices: RangeInclusive<i32>,
+    transaction: &mut Transaction<'_, Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<Vec<(i32, Vec<f32>)>, ServiceError> {
+    let chapters = sqlx::query!(
+        r#"
+        SELECT index, qdrant_point_id
+        FROM doc_embeddings
+        WHERE story_id = $1 AND index >= $2 AND index <= $3
+        ORDER BY index
+        "#,
+        story_id,
+        indices.start(),
+        indices.end(),
+    )
+    .fetch_all(&mut **transaction)
+    .await
+    .map_err(ServiceError::SelectDocEmbeddingsQdrantIdsPgError)?;
+
+    let points = get_chapter_points_with_vectors(
+        chapters
+            .iter()
+            .map(|chapter| chapter.qdrant_point_id)
+            .collect(),
+        transaction,
+        vector_store,
+    )
+    .await?;
+
+    Ok(chapters
+        .iter()
+        .filter_map(|chapter| {
+            points
+                .iter()
+                .find(|(point_id, _)| *point_id == chapter.qdrant_point_id)
+                .map(|(_, vector)| (chapter.index, vector.clone()))
+        })
+        .collect())
+}
+
+/// Returns the `(point id, vector)` of the chapter points among `point_ids`. A chapter write
+/// only reaches the vector store through the relay, so a point whose upsert is still waiting in
+/// the outbox, such as one recorded earlier in `transaction`, is read from there.
+pub async fn get_chapter_points_with_vectors(
+    point_ids: Vec<uuid::Uuid>,
+    transaction: &mut Transaction<'_, Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<Vec<(uuid::Uuid, Vec<f32>)>, ServiceError> {
+    // the vector store normalizes what it stores, the outbox holds the vectors as written
+    let mut points: Vec<(uuid::Uuid, Vec<f32>)> =
+        get_pending_vectors_pg_query(&point_ids, transaction)
+            .await?
+            .into_iter()
+            .map(|(point_id, vector)| (point_id, normalize(vector)))
+            .collect();
+
+    let stored_point_ids = point_ids
+        .into_iter()
+        .filter(|point_id| points.iter().all(|(pending_id, _)| pending_id != point_id))
+        .collect::<Vec<uuid::Uuid>>();
+    if !stored_point_ids.is_empty() {
+        points.extend(
+            get_points_with_vectors_qdrant_query(
+                doc_collection_name(None),
+                stored_point_ids,
+                vector_store,
+            )
+            .await?
+            .into_iter()
+            .map(|point| (point.point_id, point.vector)),
+        );
+    }
+
+    Ok(points)
+}
+
 pub async fn delete_doc_embedding_pg_query(
     doc_embedding: DocEmbedding,
     pool: Pool<Postgres>,
@@ -86,80 +299,160 @@ pub async fn delete_doc_embedding_pg_query(
 pub async fn create_doc_group_embedding(
     groups: IndexDocumentGroupRequest,
     pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
 ) -> Result<(), ServiceError> {
-    let qdrant_points = match groups.clone() {
-        IndexDocumentGroupRequest::Story { story_id, .. } => sqlx::query_as!(
-            QdrantPointIdContainer,
-            r#"
-	        SELECT qdrant_point_id
-            FROM doc_embeddings
-            WHERE story_id = $1
-            "#,
-            story_id
-        )
-        .fetch_all(&pool)
-        .await
-        .map_err(ServiceError::GetDocEmbeddingsPgError)?,
-        _ => unimplemented!("Not yet implemented for multiple stories at once"),
-    };
-
-    // fetch all embeddings
-    let embeddings =
-        get_doc_embeddings_qdrant_query(qdrant_points.iter().map(|x| x.qdrant_point_id).collect())
-            .await?;
-
     match groups {
         IndexDocumentGroupRequest::Story {
             story_id,
             doc_group_size,
+            doc_group_stride,
         } => {
-            let group_average = group_average_embeddings(embeddings, doc_group_size)?;
+            let doc_group = DocGroupSpec::new(doc_group_size, doc_group_stride)?;
+            // without its collection the relay could never write the groups
+            if !doc_group_registered_pg_query(doc_group, pool.clone()).await? {
+                return Err(ServiceError::DocGroupNotCreated);
+            }
 
-            let indices = (0..group_average.len() as i32).collect::<Vec<i32>>();
+            // chapters are ordered by index so that groups are made of consecutive chapters
+            let chapters =
+                get_story_doc_embedding_qdrant_ids_pg_query(story_id, pool.clone()).await?;
+
+            let points = get_points_with_vectors_qdrant_query(
+                doc_collection_name(None),
+                chapters
+                    .iter()
+                    .map(|chapter| chapter.qdrant_point_id)
+                    .collect(),
+                vector_store,
+            )
+            .await?;
+
+            let chapter_embeddings = chapters
+                .iter()
+                .filter_map(|chapter| {
+                    points
+                        .iter()
+                        .find(|point| point.point_id == chapter.qdrant_point_id)
+                        .map(|point| (chapter.index, point.vector.clone()))
+                })
+                .collect::<Vec<(i32, Vec<f32>)>>();
+
+            if chapter_embeddings.len() < chapters.len() {
+                log::info!(
+                    "{} chapters of story {} are missing from Qdrant and were not grouped",
+                    chapters.len() - chapter_embeddings.len(),
+                    story_id
+                );
+            }
+
+            let chapter_groups = group_chapter_embeddings(chapter_embeddings, doc_group)?;
+
+            let indices = chapter_groups
+                .iter()
+                .map(|group| group.index)
+                .collect::<Vec<i32>>();
+            let mut transaction = pool
+                .begin()
+                .await
+                .map_err(ServiceError::PgTransactionError)?;
             let existing_doc_groups = get_indexed_doc_group_qdrant_ids_pg_query(
                 vec![story_id],
-                doc_group_size,
-                indices,
-                pool.clone(),
+                doc_group,
+                indices.clone(),
+                &mut transaction,
             )
             .await?;
 
-            // upsert doc group metadata
-            // upsert doc group embedding
-            let qdrant_points_added = insert_doc_group_embedding_qdrant_query(
+            let (doc_groups, mutations) = doc_group_embedding_vector_mutations(
                 existing_doc_groups,
-                group_average,
+                chapter_groups,
                 story_id,
-                doc_group_size,
+                doc_group,
+            );
+
+            // the points are written by the relay once the metadata commits
+            upsert_doc_group_embedding_pg_query(doc_groups.into_iter(), &mut transaction).await?;
+            enqueue_vector_mutations_pg_query(mutations, Duration::ZERO, &mut transaction).await?;
+            // groups past the end of the story, left by chapters that are no longer grouped
+            delete_stale_doc_groups_pg_query(
+                story_id,
+                doc_group,
+                0..=i32::MAX,
+                &indices,
+                &mut transaction,
             )
             .await?;
-
-            let doc_groups = qdrant_points_added.into_iter().flat_map(|point_struct| {
-                let qdrant_point_id: uuid::Uuid = match point_struct.id?.point_id_options? {
-                    qdrant::point_id::PointIdOptions::Uuid(id) => Some(id.parse().unwrap())?,
-                    qdrant::point_id::PointIdOptions::Num(_) => {
-                        unreachable!("This should not happen")
-                    }
-                };
-                let index = match point_struct.payload.get("index")?.kind.clone()? {
-                    qdrant::value::Kind::IntegerValue(num) => num as usize,
-                    _ => unreachable!("This should not happen"),
-                };
-                Some(DocGroupEmbedding::from_details(
-                    None,
-                    story_id,
-                    doc_group_size,
-                    index as i32,
-                    Some(qdrant_point_id),
-                    None,
-                    None,
-                ))
-            });
-
-            upsert_doc_group_embedding_pg_query(doc_groups, pool).await?;
+            mark_doc_group_rebuilt_pg_query(doc_group, &mut transaction).await?;
+            transaction
+                .commit()
+                .await
+                .map_err(ServiceError::PgTransactionError)?;
 
             Ok(())
         }
-        _ => unimplemented!("Not yet implemented for multiple stories at once"),
+        _ => Err(ServiceError::NotImplemented),
+    }
+}
+
+/// Recomputes, for every group size of the story, only the groups that contain the chapter at
+/// `index`. Everything is read through `transaction`, so an uncommitted chapter upsert is
+/// included and no other connection is needed while the transaction is open.
+pub async fn recompute_doc_groups_for_chapter(
+    story_id: i64,
+    index: i32,
+    transaction: &mut Transaction<'_, Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<(), ServiceError> {
+    let doc_groups = get_unique_doc_groups(vec![story_id], transaction).await?;
+
+    for doc_group in doc_groups {
+        let group_indices = doc_group.group_indices_containing(index);
+
+        let chapter_embeddings = get_single_vectors_to_re_average(
+            story_id,
+            doc_group,
+            group_indices.clone(),
+            transaction,
+            vector_store,
+        )
+        .await?;
+
+        // neighbouring chapters also belong to groups that do not contain `index`
+        let chapter_groups = group_chapter_embeddings(chapter_embeddings, doc_group)?
+            .into_iter()
+            .filter(|chapter_group| group_indices.contains(&chapter_group.index))
+            .collect::<Vec<_>>();
+        let kept_indices = chapter_groups
+            .iter()
+            .map(|chapter_group| chapter_group.index)
+            .collect::<Vec<i32>>();
+
+        let existing_doc_groups = get_indexed_doc_group_qdrant_ids_pg_query(
+            vec![story_id],
+            doc_group,
+            group_indices.clone().collect(),
+            transaction,
+        )
+        .await?;
+
+        let (doc_groups, mutations) = doc_group_embedding_vector_mutations(
+            existing_doc_groups,
+            chapter_groups,
+            story_id,
+            doc_group,
+        );
+
+        upsert_doc_group_embedding_pg_query(doc_groups.into_iter(), transaction).await?;
+        enqueue_vector_mutations_pg_query(mutations, Duration::ZERO, transaction).await?;
+        delete_stale_doc_groups_pg_query(
+            story_id,
+            doc_group,
+            group_indices,
+            &kept_indices,
+            transaction,
+        )
+        .await?;
     }
+
+    Ok(())
 }
@@ -1,33 +1,37 @@
-use super::qdrant_operator::get_doc_embeddings_qdrant_query;
-use crate::{data::models::DocGroupEmbedding, errors::ServiceError};
-use sqlx::{Pool, Postgres};
+use super::doc_embedding_operator::{
+    get_chapter_vectors_in_range, StoryIndexQdrantPointIdContainer,
+};
+use super::embedding_operator::cosine_similarity;
+use super::qdrant_operator::QdrantVectorPoint;
+use super::vector_outbox_operator::{enqueue_vector_mutations_pg_query, VectorMutation};
+use super::vector_store_operator::VectorStore;
+use crate::{
+    data::models::{ChapterRange, DocGroupEmbedding, DocGroupSize, DocGroupSpec},
+    errors::ServiceError,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, Transaction};
+use std::{ops::RangeInclusive, time::Duration};
 
+/// Returns the `(index, vector)` of every chapter inside the groups `group_indices`, ordered by
+/// index
 pub async fn get_single_vectors_to_re_average(
     story_id: i64,
-    doc_group_size: i32,
-    group_index: i32,
-    pool: Pool<Postgres>,
-) -> Result<Vec<Vec<f32>>, ServiceError> {
-    let qdrant_point_uuids = sqlx::query!(
-        r#"
-        SELECT qdrant_point_id
-        FROM doc_embeddings
-        WHERE story_id = $1 AND index >= $2 AND index < $3
-        "#,
+    doc_group: DocGroupSpec,
+    group_indices: RangeInclusive<i32>,
+    transaction: &mut Transaction<'_, Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<Vec<(i32, Vec<f32>)>, ServiceError> {
+    let (first_index, _) = doc_group.chapter_bounds(*group_indices.start());
+    let (_, end_index) = doc_group.chapter_bounds(*group_indices.end());
+
+    get_chapter_vectors_in_range(
         story_id,
-        group_index * doc_group_size,
-        group_index * doc_group_size + doc_group_size - 1,
+        first_index..=end_index - 1,
+        transaction,
+        vector_store,
     )
-    .fetch_all(&pool)
     .await
-    .map_err(ServiceError::SelectDocEmbeddingsQdrantIdsPgError)?
-    .into_iter()
-    .map(|embedding_container| embedding_container.qdrant_point_id)
-    .collect::<Vec<uuid::Uuid>>();
-
-    let qdrant_vectors = get_doc_embeddings_qdrant_query(qdrant_point_uuids).await?;
-
-    Ok(qdrant_vectors)
 }
 
 #[derive(Clone)]
@@ -39,7 +43,7 @@ pub struct DocGroupQdrantPointIdContainer {
 
 pub async fn get_doc_group_qdrant_ids_pg_query(
     story_ids: Vec<i64>,
-    doc_group_size: i32,
+    doc_group: DocGroupSpec,
     pool: Pool<Postgres>,
 ) -> Result<Vec<DocGroupQdrantPointIdContainer>, ServiceError> {
     let doc_group_qdrant_point_ids = sqlx::query_as!(
@@ -47,10 +51,11 @@ pub async fn get_doc_group_qdrant_ids_pg_query(
         r#"
         SELECT qdrant_point_id, doc_group_size, index
         FROM doc_group_embeddings
-        WHERE story_id = ANY($1) AND doc_group_size = $2
+        WHERE story_id = ANY($1) AND doc_group_size = $2 AND doc_group_stride = $3
         "#,
         story_ids.as_slice(),
-        doc_group_size as i64,
+        doc_group.doc_group_size as i64,
+        doc_group.doc_group_stride,
     )
     .fetch_all(&pool)
     .await
@@ -61,74 +66,394 @@ pub async fn get_doc_group_qdrant_ids_pg_query(
 
 pub async fn get_indexed_doc_group_qdrant_ids_pg_query(
     story_ids: Vec<i64>,
-    doc_group_size: i32,
+    doc_group: DocGroupSpec,
     indices: Vec<i32>,
-    pool: Pool<Postgres>,
+    transaction: &mut Transaction<'_, Postgres>,
 ) -> Result<Vec<DocGroupQdrantPointIdContainer>, ServiceError> {
     let doc_group_qdrant_point_ids = sqlx::query_as!(
         DocGroupQdrantPointIdContainer,
         r#"
         SELECT qdrant_point_id, doc_group_size, index
         FROM doc_group_embeddings
-        WHERE story_id = ANY($1) AND doc_group_size = $2 AND index = ANY($3)
+        WHERE story_id = ANY($1) AND doc_group_size = $2 AND doc_group_stride = $4
+            AND index = ANY($3)
         "#,
         story_ids.as_slice(),
-        doc_group_size as i64,
+        doc_group.doc_group_size as i64,
         indices.as_slice(),
+        doc_group.doc_group_stride,
     )
-    .fetch_all(&pool)
+    .fetch_all(&mut **transaction)
     .await
     .map_err(ServiceError::SelectDocGroupQdrantIdsPgError)?;
 
     Ok(doc_group_qdrant_point_ids)
 }
 
-pub async fn get_unique_doc_group_sizes(
+pub async fn get_doc_group_qdrant_ids_by_story_index_pg_query(
     story_ids: Vec<i64>,
+    indices: Vec<i32>,
+    doc_group: DocGroupSpec,
+    pool: Pool<Postgres>,
+) -> Result<Vec<StoryIndexQdrantPointIdContainer>, ServiceError> {
+    let doc_group_qdrant_point_ids = sqlx::query_as!(
+        StoryIndexQdrantPointIdContainer,
+        r#"
+        SELECT story_id, index, qdrant_point_id
+        FROM doc_group_embeddings
+        WHERE doc_group_size = $3 AND doc_group_stride = $4
+            AND (story_id, index) IN (SELECT * FROM UNNEST($1::BIGINT[], $2::INTEGER[]))
+        "#,
+        story_ids.as_slice(),
+        indices.as_slice(),
+        doc_group.doc_group_size,
+        doc_group.doc_group_stride,
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectDocGroupQdrantIdsPgError)?;
+
+    Ok(doc_group_qdrant_point_ids)
+}
+
+pub async fn get_story_doc_group_qdrant_ids_pg_query(
+    story_id: i64,
+    doc_group: DocGroupSpec,
     pool: Pool<Postgres>,
-) -> Result<Vec<i32>, ServiceError> {
-    let unique_doc_group_sizes = sqlx::query!(
+) -> Result<Vec<StoryIndexQdrantPointIdContainer>, ServiceError> {
+    let doc_group_qdrant_point_ids = sqlx::query_as!(
+        StoryIndexQdrantPointIdContainer,
         r#"
-        SELECT DISTINCT doc_group_size
+        SELECT story_id, index, qdrant_point_id
+        FROM doc_group_embeddings
+        WHERE story_id = $1 AND doc_group_size = $2 AND doc_group_stride = $3
+        ORDER BY index
+        "#,
+        story_id,
+        doc_group.doc_group_size,
+        doc_group.doc_group_stride,
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectDocGroupQdrantIdsPgError)?;
+
+    Ok(doc_group_qdrant_point_ids)
+}
+
+pub async fn get_story_doc_groups_pg_query(
+    story_id: i64,
+    doc_group: DocGroupSpec,
+    pool: Pool<Postgres>,
+) -> Result<Vec<DocGroupEmbedding>, ServiceError> {
+    let doc_groups = sqlx::query_as!(
+        DocGroupEmbedding,
+        r#"
+        SELECT *
+        FROM doc_group_embeddings
+        WHERE story_id = $1 AND doc_group_size = $2 AND doc_group_stride = $3
+        ORDER BY index
+        "#,
+        story_id,
+        doc_group.doc_group_size,
+        doc_group.doc_group_stride,
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectDocGroupQdrantIdsPgError)?;
+
+    Ok(doc_groups)
+}
+
+pub async fn get_doc_groups_by_qdrant_ids_pg_query(
+    qdrant_point_ids: Vec<uuid::Uuid>,
+    doc_group: DocGroupSpec,
+    pool: Pool<Postgres>,
+) -> Result<Vec<DocGroupEmbedding>, ServiceError> {
+    let doc_groups = sqlx::query_as!(
+        DocGroupEmbedding,
+        r#"
+        SELECT *
+        FROM doc_group_embeddings
+        WHERE qdrant_point_id = ANY($1) AND doc_group_size = $2 AND doc_group_stride = $3
+        "#,
+        qdrant_point_ids.as_slice(),
+        doc_group.doc_group_size,
+        doc_group.doc_group_stride,
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectDocGroupQdrantIdsPgError)?;
+
+    Ok(doc_groups)
+}
+
+pub async fn get_unique_doc_groups(
+    story_ids: Vec<i64>,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<DocGroupSpec>, ServiceError> {
+    let unique_doc_groups = sqlx::query_as!(
+        DocGroupSpec,
+        r#"
+        SELECT DISTINCT doc_group_size, doc_group_stride
         FROM doc_group_embeddings
         WHERE story_id = ANY($1)
         "#,
         story_ids.as_slice(),
     )
+    .fetch_all(&mut **transaction)
+    .await
+    .map_err(ServiceError::SelectUniqueDocGroupSizesPgError)?;
+
+    Ok(unique_doc_groups)
+}
+
+pub async fn register_doc_group_pg_query(
+    doc_group: DocGroupSpec,
+    pool: Pool<Postgres>,
+) -> Result<(), ServiceError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO doc_group_sizes (doc_group_size, doc_group_stride)
+        VALUES ($1, $2)
+        ON CONFLICT DO NOTHING
+        "#,
+        doc_group.doc_group_size,
+        doc_group.doc_group_stride,
+    )
+    .execute(&pool)
+    .await
+    .map_err(ServiceError::RegisterDocGroupSizePgError)?;
+
+    Ok(())
+}
+
+/// Whether the size was created, so its collection exists to write groups to
+pub async fn doc_group_registered_pg_query(
+    doc_group: DocGroupSpec,
+    pool: Pool<Postgres>,
+) -> Result<bool, ServiceError> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM doc_group_sizes
+            WHERE doc_group_size = $1 AND doc_group_stride = $2
+        ) AS "registered!"
+        "#,
+        doc_group.doc_group_size,
+        doc_group.doc_group_stride,
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(ServiceError::SelectUniqueDocGroupSizesPgError)
+}
+
+/// Registers the size if needed and records that groups of this size were just rebuilt
+pub async fn mark_doc_group_rebuilt_pg_query(
+    doc_group: DocGroupSpec,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), ServiceError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO doc_group_sizes (doc_group_size, doc_group_stride, last_rebuilt_at)
+        VALUES ($1, $2, CURRENT_TIMESTAMP)
+        ON CONFLICT (doc_group_size, doc_group_stride) DO UPDATE
+        SET last_rebuilt_at = EXCLUDED.last_rebuilt_at
+        "#,
+        doc_group.doc_group_size,
+        doc_group.doc_group_stride,
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(ServiceError::RegisterDocGroupSizePgError)?;
+
+    Ok(())
+}
+
+pub async fn get_doc_group_sizes_pg_query(
+    pool: Pool<Postgres>,
+) -> Result<Vec<DocGroupSize>, ServiceError> {
+    let doc_group_sizes = sqlx::query_as!(
+        DocGroupSize,
+        r#"
+        SELECT doc_group_size, doc_group_stride, last_rebuilt_at, created_at, updated_at
+        FROM doc_group_sizes
+        ORDER BY doc_group_size, doc_group_stride
+        "#,
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectUniqueDocGroupSizesPgError)?;
+
+    Ok(doc_group_sizes)
+}
+
+pub async fn get_registered_doc_groups_pg_query(
+    pool: Pool<Postgres>,
+) -> Result<Vec<DocGroupSpec>, ServiceError> {
+    let doc_groups = sqlx::query_as!(
+        DocGroupSpec,
+        r#"
+        SELECT doc_group_size, doc_group_stride
+        FROM doc_group_sizes
+        ORDER BY doc_group_size, doc_group_stride
+        "#,
+    )
     .fetch_all(&pool)
     .await
-    .map_err(ServiceError::SelectUniqueDocGroupSizesPgError)?
-    .into_iter()
-    .map(|doc_group_size_container| doc_group_size_container.doc_group_size)
-    .collect::<Vec<i32>>();
+    .map_err(ServiceError::SelectUniqueDocGroupSizesPgError)?;
 
-    Ok(unique_doc_group_sizes)
+    Ok(doc_groups)
 }
 
 pub async fn upsert_doc_group_embedding_pg_query(
     doc_groups: impl Iterator<Item = DocGroupEmbedding>,
-    pool: Pool<Postgres>,
+    transaction: &mut Transaction<'_, Postgres>,
 ) -> Result<(), ServiceError> {
     // TODO make this into a bulk query
     for g in doc_groups {
         sqlx::query!(
             r#"
-            INSERT INTO doc_group_embeddings (id, story_id, doc_group_size, index, qdrant_point_id, created_at, updated_at)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
-            ON CONFLICT (story_id, doc_group_size, index) DO UPDATE
+            INSERT INTO doc_group_embeddings (id, story_id, doc_group_size, doc_group_stride, index, first_index, last_index, qdrant_point_id, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            ON CONFLICT (story_id, doc_group_size, doc_group_stride, index) DO UPDATE
             SET
+                first_index = EXCLUDED.first_index,
+                last_index = EXCLUDED.last_index,
                 qdrant_point_id = EXCLUDED.qdrant_point_id,
                 updated_at = EXCLUDED.updated_at
             "#,
             g.id,
             g.story_id,
             g.doc_group_size,
+            g.doc_group_stride,
             g.index as i32,
+            g.first_index,
+            g.last_index,
             g.qdrant_point_id,
             g.created_at,
             g.updated_at,
-        ).execute(&pool).await.map_err(ServiceError::InsertDocGroupEmbeddingPgError)?;
+        ).execute(&mut **transaction).await.map_err(ServiceError::InsertDocGroupEmbeddingPgError)?;
     }
 
     Ok(())
 }
+
+/// Deletes the groups of the story with an index in `group_indices` that are not in
+/// `kept_indices`, and queues the removal of their points
+pub async fn delete_stale_doc_groups_pg_query(
+    story_id: i64,
+    doc_group: DocGroupSpec,
+    group_indices: RangeInclusive<i32>,
+    kept_indices: &[i32],
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), ServiceError> {
+    let stale_qdrant_point_ids = sqlx::query_scalar!(
+        r#"
+        DELETE FROM doc_group_embeddings
+        WHERE story_id = $1 AND doc_group_size = $2 AND doc_group_stride = $3
+            AND index BETWEEN $4 AND $5 AND NOT (index = ANY($6))
+        RETURNING qdrant_point_id
+        "#,
+        story_id,
+        doc_group.doc_group_size,
+        doc_group.doc_group_stride,
+        *group_indices.start(),
+        *group_indices.end(),
+        kept_indices,
+    )
+    .fetch_all(&mut **transaction)
+    .await
+    .map_err(ServiceError::InsertDocGroupEmbeddingPgError)?;
+
+    enqueue_vector_mutations_pg_query(
+        stale_qdrant_point_ids
+            .into_iter()
+            .map(|point_id| VectorMutation::Delete {
+                collection_name: doc_group.collection_name(),
+                point_id,
+            })
+            .collect(),
+        Duration::ZERO,
+        transaction,
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecommendationExplanation {
+    pub story_id: i64,
+    pub group_index: i32,
+    pub chapter_range: ChapterRange,
+    pub seed_story_id: i64,
+    pub seed_group_index: i32,
+    pub seed_chapter_range: ChapterRange,
+    pub similarity: f32,
+}
+
+/// For every recommended story, finds the pair of recommended group and seed group with the
+/// highest cosine similarity. Stories are returned in the order they were first recommended.
+pub fn explain_recommendations(
+    seed_points: &[QdrantVectorPoint],
+    recommended_points: &[QdrantVectorPoint],
+    doc_groups: &[DocGroupEmbedding],
+) -> Vec<RecommendationExplanation> {
+    let chapter_range = |point: &QdrantVectorPoint| {
+        doc_groups
+            .iter()
+            .find(|doc_group| doc_group.qdrant_point_id == point.point_id)
+            .map(|doc_group| doc_group.chapter_range())
+    };
+
+    let mut explanations: Vec<RecommendationExplanation> = vec![];
+
+    for recommended_point in recommended_points {
+        let closest_seed = seed_points
+            .iter()
+            .map(|seed_point| {
+                (
+                    seed_point,
+                    cosine_similarity(&recommended_point.vector, &seed_point.vector),
+                )
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        let (seed_point, similarity) = match closest_seed {
+            Some(closest_seed) => closest_seed,
+            None => continue,
+        };
+
+        let (chapter_range, seed_chapter_range) =
+            match (chapter_range(recommended_point), chapter_range(seed_point)) {
+                (Some(chapter_range), Some(seed_chapter_range)) => {
+                    (chapter_range, seed_chapter_range)
+                }
+                _ => continue,
+            };
+
+        let explanation = RecommendationExplanation {
+            story_id: recommended_point.payload.story_id,
+            group_index: recommended_point.payload.index,
+            chapter_range,
+            seed_story_id: seed_point.payload.story_id,
+            seed_group_index: seed_point.payload.index,
+            seed_chapter_range,
+            similarity,
+        };
+
+        match explanations
+            .iter_mut()
+            .find(|existing| existing.story_id == explanation.story_id)
+        {
+            Some(existing) if existing.similarity < explanation.similarity => {
+                *existing = explanation
+            }
+            Some(_) => {}
+            None => explanations.push(explanation),
+        }
+    }
+
+    explanations
+}
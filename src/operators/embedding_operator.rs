@@ -1,12 +1,31 @@
-use crate::errors::ServiceError;
+use super::chunk_embedding_cache_operator::{
+    chunk_hash, get_cached_chunk_embeddings_pg_query, insert_chunk_embeddings_pg_query,
+    record_chunk_embedding_cache_lookup,
+};
 #[cfg(not(feature = "embedding_server"))]
-use async_openai::config::OpenAIConfig;
+use super::embedding_provider_operator::is_transient_status;
+use super::embedding_provider_operator::{
+    check_provider_response, embedding_provider, ProviderCallError,
+};
 #[cfg(not(feature = "embedding_server"))]
-use async_openai::types::CreateEmbeddingRequest;
-use ndarray::Array2;
+use super::openai_key_pool_operator::openai_key_pool;
+use crate::{data::models::DocGroupSpec, errors::ServiceError};
+#[cfg(not(feature = "embedding_server"))]
+use async_openai::config::{Config, OpenAIConfig};
+#[cfg(not(feature = "embedding_server"))]
+use async_openai::error::{ApiError, OpenAIError};
 #[cfg(not(feature = "embedding_server"))]
-use rand::Rng;
+use async_openai::types::{CreateEmbeddingRequest, CreateEmbeddingResponse};
+use async_trait::async_trait;
+#[cfg(feature = "embedding_server")]
+use futures::{StreamExt, TryStreamExt};
+use ndarray::Array2;
 use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, OnceLock},
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CustomServerData {
@@ -19,68 +38,205 @@ pub struct CustomServerResponse {
 }
 
 pub async fn create_embedding(message: String) -> Result<Vec<f32>, ServiceError> {
-    get_average_embedding(vec![message]).await
+    average_embeddings(embed_chunks(vec![message]).await?)
+}
+
+/// Produces embeddings in place of the configured provider, such as a deterministic embedder
+/// in tests
+#[async_trait]
+pub trait ChunkEmbedder: Send + Sync {
+    fn model_id(&self) -> String;
+
+    /// One embedding per chunk, in order
+    async fn embed_chunks(&self, chunks: Vec<String>) -> Result<Vec<Vec<f32>>, ServiceError>;
+}
+
+static CHUNK_EMBEDDER: OnceLock<Arc<dyn ChunkEmbedder>> = OnceLock::new();
+
+/// Sends every embedding of the process to `embedder` instead of the provider. Only the first
+/// embedder set is used.
+pub fn set_chunk_embedder(embedder: Arc<dyn ChunkEmbedder>) {
+    let _ = CHUNK_EMBEDDER.set(embedder);
+}
+
+/// One embedding per chunk, in order
+pub async fn embed_chunks(chunks: Vec<String>) -> Result<Vec<Vec<f32>>, ServiceError> {
+    match CHUNK_EMBEDDER.get() {
+        Some(embedder) => embedder.embed_chunks(chunks).await,
+        None => provider_embed_chunks(chunks).await,
+    }
+}
+
+/// Identifies the model the embeddings come from, so cached embeddings of another model are
+/// never mixed in
+pub fn embedding_model_id() -> String {
+    match CHUNK_EMBEDDER.get() {
+        Some(embedder) => embedder.model_id(),
+        None => provider_model_id(),
+    }
 }
 
 #[cfg(not(feature = "embedding_server"))]
-pub async fn get_openai_client() -> async_openai::Client<OpenAIConfig> {
-    let openai_api_keys = std::env::var("OPENAI_API_KEYS").expect("OPENAI_API_KEYS should be set");
-    let openai_api_keys: Vec<&str> = openai_api_keys.split(",").collect();
+fn provider_model_id() -> String {
+    OPENAI_EMBEDDING_MODEL.to_owned()
+}
 
-    let mut rng = rand::thread_rng();
-    let random_index = rng.gen_range(0..openai_api_keys.len());
-    let openai_api_key = openai_api_keys[random_index];
+#[cfg(feature = "embedding_server")]
+fn provider_model_id() -> String {
+    std::env::var("EMBEDDING_MODEL_ID").unwrap_or("BAAI/bge-large-en".to_owned())
+}
 
-    let config = OpenAIConfig::new().with_api_key(openai_api_key);
+#[cfg(not(feature = "embedding_server"))]
+const OPENAI_EMBEDDING_MODEL: &str = "text-embedding-ada-002";
 
-    async_openai::Client::with_config(config)
+#[cfg(not(feature = "embedding_server"))]
+#[derive(Debug, Deserialize)]
+struct OpenAIErrorResponse {
+    error: ApiError,
 }
 
 #[cfg(not(feature = "embedding_server"))]
-pub async fn get_average_embedding(chunks: Vec<String>) -> Result<Vec<f32>, ServiceError> {
-    let client = get_openai_client().await;
-
-    let embeddings = client
-        .embeddings()
-        .create(CreateEmbeddingRequest {
-            input: chunks.into(),
-            model: "text-embedding-ada-002".to_string(),
-            user: None,
-            encoding_format: None,
-            dimensions: None,
+async fn provider_embed_chunks(chunks: Vec<String>) -> Result<Vec<Vec<f32>>, ServiceError> {
+    let request = CreateEmbeddingRequest {
+        input: chunks.into(),
+        model: OPENAI_EMBEDDING_MODEL.to_string(),
+        user: None,
+        encoding_format: None,
+        dimensions: None,
+    };
+    let request = &request;
+
+    let mut data = embedding_provider()
+        .call(|client| async move {
+            let response = openai_key_pool()
+                .send_with_key(|api_key| {
+                    let config = OpenAIConfig::new().with_api_key(api_key);
+                    client
+                        .post(config.url("/embeddings"))
+                        .headers(config.headers())
+                        .json(request)
+                        .send()
+                })
+                .await?;
+
+            // OpenAI explains rejected requests in the body
+            if response.status().is_client_error() && !is_transient_status(response.status()) {
+                let error = match response.json::<OpenAIErrorResponse>().await {
+                    Ok(error) => OpenAIError::ApiError(error.error),
+                    Err(err) => OpenAIError::Reqwest(err),
+                };
+                log::info!("Error calling OpenAI: {:?}", error);
+                return Err(ProviderCallError::Permanent(
+                    ServiceError::CreateEmbeddingServerError(error),
+                ));
+            }
+
+            check_provider_response(response)?
+                .json::<CreateEmbeddingResponse>()
+                .await
+                .map_err(ProviderCallError::from_body_error)
         })
-        .await
-        .map_err(|e| {
-            log::info!("Error calling OpenAI: {:?}", e);
-            ServiceError::CreateEmbeddingServerError(e)
-        })?
-        .data
-        .into_iter()
-        .map(|d| d.embedding)
-        .collect::<Vec<Vec<f32>>>();
+        .await?
+        .data;
+    data.sort_by_key(|embedding| embedding.index);
+
+    Ok(data.into_iter().map(|d| d.embedding).collect())
+}
 
-    average_embeddings(embeddings)
+/// Chunks sent to the embedding server at the same time
+#[cfg(feature = "embedding_server")]
+fn embedding_server_concurrency() -> usize {
+    std::env::var("EMBEDDING_SERVER_CONCURRENCY")
+        .ok()
+        .and_then(|concurrency| concurrency.parse::<usize>().ok())
+        .filter(|concurrency| *concurrency > 0)
+        .unwrap_or(4)
 }
 
+/// The server only returns the embedding of the first input, so each chunk is sent on its own,
+/// a few at a time
 #[cfg(feature = "embedding_server")]
-pub async fn get_average_embedding(document_chunks: Vec<String>) -> Result<Vec<f32>, ServiceError> {
+async fn provider_embed_chunks(chunks: Vec<String>) -> Result<Vec<Vec<f32>>, ServiceError> {
     let embedding_server_call =
         std::env::var("EMBEDDING_SERVER_CALL").expect("EMBEDDING_SERVER_CALL must be set");
+    let embedding_server_call = &embedding_server_call;
+
+    futures::stream::iter(chunks)
+        .map(|chunk| async move {
+            let chunk = &chunk;
+            let resp = embedding_provider()
+                .call(|client| async move {
+                    let response = client
+                        .post(embedding_server_call)
+                        .json(&CustomServerData {
+                            input: vec![chunk.clone()],
+                        })
+                        .send()
+                        .await
+                        .map_err(ProviderCallError::from_request_error)?;
 
-    let client = reqwest::Client::new();
-    let resp = client
-        .post(embedding_server_call)
-        .json(&CustomServerData {
-            input: document_chunks,
+                    check_provider_response(response)?
+                        .json::<CustomServerResponse>()
+                        .await
+                        .map_err(ProviderCallError::from_body_error)
+                })
+                .await?;
+            Ok(resp.embeddings)
         })
-        .send()
+        // buffered keeps the embeddings in the order of the chunks
+        .buffered(embedding_server_concurrency())
+        .try_collect()
         .await
-        .map_err(ServiceError::EmbeddingServerCallError)?
-        .json::<CustomServerResponse>()
-        .await
-        .map_err(ServiceError::EmbeddingServerParseError)?;
+}
 
-    Ok(resp.embeddings)
+/// Averages the embeddings of the chunks of a chapter. Chunks embedded before are read from
+/// the chunk cache, so only new or edited chunks are sent to the provider.
+pub async fn get_average_embedding(
+    chunks: Vec<String>,
+    pool: Pool<Postgres>,
+) -> Result<Vec<f32>, ServiceError> {
+    let model = embedding_model_id();
+    let chunk_hashes: Vec<String> = chunks.iter().map(|chunk| chunk_hash(chunk)).collect();
+
+    // the cache only saves calls, so the provider is asked for everything when it is unavailable
+    let mut embeddings_by_hash =
+        get_cached_chunk_embeddings_pg_query(&chunk_hashes, &model, pool.clone())
+            .await
+            .unwrap_or_else(|err| {
+                log::info!("Failed to read cached chunk embeddings: {:?}", err);
+                HashMap::new()
+            });
+
+    let mut missing_hashes = vec![];
+    let mut missing_chunks = vec![];
+    for (chunk_hash, chunk) in chunk_hashes.iter().zip(chunks) {
+        if !embeddings_by_hash.contains_key(chunk_hash) && !missing_hashes.contains(chunk_hash) {
+            missing_hashes.push(chunk_hash.clone());
+            missing_chunks.push(chunk);
+        }
+    }
+
+    record_chunk_embedding_cache_lookup(embeddings_by_hash.len(), missing_chunks.len());
+
+    if !missing_chunks.is_empty() {
+        let missing_embeddings: Vec<(String, Vec<f32>)> = missing_hashes
+            .into_iter()
+            .zip(embed_chunks(missing_chunks).await?)
+            .collect();
+        if let Err(err) =
+            insert_chunk_embeddings_pg_query(missing_embeddings.clone(), &model, pool).await
+        {
+            log::info!("Failed to cache chunk embeddings: {:?}", err);
+        }
+        embeddings_by_hash.extend(missing_embeddings);
+    }
+
+    average_embeddings(
+        chunk_hashes
+            .iter()
+            .map(|chunk_hash| embeddings_by_hash[chunk_hash].clone())
+            .collect(),
+    )
 }
 
 pub fn average_embeddings(embeddings: Vec<Vec<f32>>) -> Result<Vec<f32>, ServiceError> {
@@ -109,8 +265,49 @@ pub fn group_average_embeddings_better(
     Ok(arr.sum_axis(ndarray::Axis(1)) / arr.len_of(ndarray::Axis(1)) as f32)
 }
 
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
+
+/// Pairwise cosine similarity of every embedding against every other embedding
+pub fn similarity_matrix(embeddings: &[Vec<f32>]) -> Result<Array2<f32>, ServiceError> {
+    if embeddings.is_empty() {
+        return Ok(Array2::zeros((0, 0)));
+    }
+
+    let shape = (embeddings.len(), embeddings[0].len());
+    let flat: Vec<f32> = embeddings.iter().flatten().cloned().collect();
+    let mut arr: Array2<f32> =
+        Array2::from_shape_vec(shape, flat).map_err(ServiceError::VectorToArrayError)?;
+
+    for mut row in arr.rows_mut() {
+        let norm = row.dot(&row).sqrt();
+        if norm > 0.0 {
+            row /= norm;
+        }
+    }
+
+    Ok(arr.dot(&arr.t()))
+}
+
+/// Positions where the similarity between consecutive rows drops below the threshold. A
+/// position `i` means row `i` diverges sharply from row `i - 1`.
+pub fn change_points(similarity_matrix: &Array2<f32>, threshold: f32) -> Vec<usize> {
+    (1..similarity_matrix.nrows())
+        .filter(|&i| similarity_matrix[[i - 1, i]] < threshold)
+        .collect()
+}
+
 pub fn ceil_div(a: usize, b: usize) -> usize {
-    (a + b - 1) / b
+    a.div_ceil(b)
 }
 
 pub fn group_average_embeddings(
@@ -124,6 +321,166 @@ pub fn group_average_embeddings(
         .collect::<Result<Vec<Vec<f32>>, ServiceError>>()
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChapterGroupEmbedding {
+    pub index: i32,
+    pub first_index: i32,
+    pub last_index: i32,
+    pub embedding: Vec<f32>,
+}
+
+/// Averages chapters into groups by chapter index. Group `g` always covers the chapter indices
+/// given by `DocGroupSpec::chapter_bounds`, so a missing chapter leaves a gap inside its own
+/// groups instead of shifting every later chapter into the wrong group. Groups without any
+/// indexed chapter are skipped, and `first_index`/`last_index` are the chapters actually present.
+pub fn group_chapter_embeddings(
+    mut chapters: Vec<(i32, Vec<f32>)>,
+    doc_group: DocGroupSpec,
+) -> Result<Vec<ChapterGroupEmbedding>, ServiceError> {
+    chapters.sort_by_key(|(index, _)| *index);
+
+    let mut groups: BTreeMap<i32, Vec<&(i32, Vec<f32>)>> = BTreeMap::new();
+    for chapter in chapters.iter() {
+        for group_index in doc_group.group_indices_containing(chapter.0) {
+            groups.entry(group_index).or_default().push(chapter);
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(group_index, group)| {
+            Ok(ChapterGroupEmbedding {
+                index: group_index,
+                first_index: group[0].0,
+                last_index: group[group.len() - 1].0,
+                embedding: average_embeddings(
+                    group
+                        .iter()
+                        .map(|(_, embedding)| embedding.clone())
+                        .collect(),
+                )?,
+            })
+        })
+        .collect()
+}
+
+/// Number of chapters after which a chapter counts half as much in its story embedding, read
+/// from `STORY_EMBEDDING_HALF_LIFE`. Unset means every chapter counts the same.
+pub fn story_embedding_half_life() -> Option<f32> {
+    std::env::var("STORY_EMBEDDING_HALF_LIFE")
+        .ok()
+        .and_then(|half_life| half_life.parse::<f32>().ok())
+        .filter(|half_life| *half_life > 0.0)
+}
+
+/// Centroid of every chapter of a story. With a half-life, a chapter's weight halves every
+/// `half_life` chapters it lies before the latest one.
+pub fn story_centroid_embedding(
+    chapters: &[(i32, Vec<f32>)],
+    half_life: Option<f32>,
+) -> Result<Vec<f32>, ServiceError> {
+    let latest_index = chapters
+        .iter()
+        .map(|(index, _)| *index)
+        .max()
+        .ok_or(ServiceError::EmbeddingAveragingError)?;
+
+    let mut centroid = vec![0.0; chapters[0].1.len()];
+    let mut total_weight = 0.0;
+    for (index, embedding) in chapters {
+        if embedding.len() != centroid.len() {
+            return Err(ServiceError::EmbeddingAveragingError);
+        }
+
+        let weight = match half_life {
+            Some(half_life) => 0.5_f32.powf((latest_index - index) as f32 / half_life),
+            None => 1.0,
+        };
+        for (sum, value) in centroid.iter_mut().zip(embedding) {
+            *sum += weight * value;
+        }
+        total_weight += weight;
+    }
+
+    Ok(centroid.into_iter().map(|sum| sum / total_weight).collect())
+}
+
+/// Running form of [`story_centroid_embedding`]. Weights are kept relative to the latest chapter,
+/// so writing one chapter updates the sum without reading the story's other chapters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoryCentroidSum {
+    pub weighted_sum: Vec<f64>,
+    pub total_weight: f64,
+    pub latest_index: i32,
+}
+
+impl StoryCentroidSum {
+    pub fn from_chapters(chapters: &[(i32, Vec<f32>)], half_life: Option<f32>) -> Self {
+        let mut sum = Self {
+            weighted_sum: vec![],
+            total_weight: 0.0,
+            latest_index: chapters.iter().map(|(index, _)| *index).max().unwrap_or(0),
+        };
+        for (index, embedding) in chapters {
+            sum.add(*index, embedding, half_life);
+        }
+        sum
+    }
+
+    fn weight(&self, index: i32, half_life: Option<f32>) -> f64 {
+        match half_life {
+            Some(half_life) => 0.5_f64.powf((self.latest_index - index) as f64 / half_life as f64),
+            None => 1.0,
+        }
+    }
+
+    /// Adds a chapter, moving every weight back when it is past the latest chapter
+    pub fn add(&mut self, index: i32, embedding: &[f32], half_life: Option<f32>) {
+        if index > self.latest_index {
+            let decay = match half_life {
+                Some(half_life) => {
+                    0.5_f64.powf((index - self.latest_index) as f64 / half_life as f64)
+                }
+                None => 1.0,
+            };
+            self.weighted_sum.iter_mut().for_each(|sum| *sum *= decay);
+            self.total_weight *= decay;
+            self.latest_index = index;
+        }
+        if self.weighted_sum.is_empty() {
+            self.weighted_sum = vec![0.0; embedding.len()];
+        }
+
+        let weight = self.weight(index, half_life);
+        for (sum, value) in self.weighted_sum.iter_mut().zip(embedding) {
+            *sum += weight * *value as f64;
+        }
+        self.total_weight += weight;
+    }
+
+    /// Removes a chapter that was added before, such as the previous version of a rewritten
+    /// chapter
+    pub fn remove(&mut self, index: i32, embedding: &[f32], half_life: Option<f32>) {
+        let weight = self.weight(index, half_life);
+        for (sum, value) in self.weighted_sum.iter_mut().zip(embedding) {
+            *sum -= weight * *value as f64;
+        }
+        self.total_weight -= weight;
+    }
+
+    pub fn centroid(&self) -> Result<Vec<f32>, ServiceError> {
+        if self.weighted_sum.is_empty() || self.total_weight <= 0.0 {
+            return Err(ServiceError::EmbeddingAveragingError);
+        }
+
+        Ok(self
+            .weighted_sum
+            .iter()
+            .map(|sum| (sum / self.total_weight) as f32)
+            .collect())
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -154,4 +511,167 @@ mod test {
 
         assert!(result == vec![vec![2.0, 2.5, 1.0], vec![2.0, 2.5, 1.0]]);
     }
+
+    #[test]
+    pub fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 1.0], &[-1.0, -1.0]) + 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]) == 0.0);
+    }
+
+    #[test]
+    pub fn test_similarity_matrix_change_points() {
+        let embeddings = vec![
+            vec![1.0, 0.0],
+            vec![2.0, 0.1],
+            vec![0.0, 1.0],
+            vec![0.1, 3.0],
+        ];
+
+        let matrix = similarity_matrix(&embeddings).unwrap();
+        assert_eq!(matrix.dim(), (4, 4));
+        for i in 0..4 {
+            assert!((matrix[[i, i]] - 1.0).abs() < 1e-6);
+        }
+        assert!((matrix[[0, 2]] - matrix[[2, 0]]).abs() < 1e-6);
+
+        assert_eq!(change_points(&matrix, 0.5), vec![2]);
+        assert!(change_points(&matrix, -1.0).is_empty());
+    }
+
+    #[test]
+    pub fn test_group_chapter_embeddings_orders_by_index() {
+        let chapters = vec![
+            (3, vec![4.0, 0.0]),
+            (0, vec![1.0, 0.0]),
+            (2, vec![3.0, 0.0]),
+            (1, vec![2.0, 0.0]),
+        ];
+        let result =
+            group_chapter_embeddings(chapters, DocGroupSpec::new(2, None).unwrap()).unwrap();
+
+        assert_eq!(
+            result,
+            vec![
+                ChapterGroupEmbedding {
+                    index: 0,
+                    first_index: 0,
+                    last_index: 1,
+                    embedding: vec![1.5, 0.0],
+                },
+                ChapterGroupEmbedding {
+                    index: 1,
+                    first_index: 2,
+                    last_index: 3,
+                    embedding: vec![3.5, 0.0],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_group_chapter_embeddings_with_gaps() {
+        // Chapter 1 and chapters 3 to 5 are missing
+        let chapters = vec![
+            (0, vec![1.0, 0.0]),
+            (2, vec![3.0, 0.0]),
+            (6, vec![7.0, 0.0]),
+            (7, vec![8.0, 0.0]),
+        ];
+        let result =
+            group_chapter_embeddings(chapters, DocGroupSpec::new(3, None).unwrap()).unwrap();
+
+        let ranges = result
+            .iter()
+            .map(|group| (group.index, group.first_index, group.last_index))
+            .collect::<Vec<(i32, i32, i32)>>();
+        assert_eq!(ranges, vec![(0, 0, 2), (2, 6, 7)]);
+        assert_eq!(result[0].embedding, vec![2.0, 0.0]);
+        assert_eq!(result[1].embedding, vec![7.5, 0.0]);
+    }
+
+    #[test]
+    pub fn test_group_chapter_embeddings_sliding_window() {
+        let chapters = (0..5).map(|i| (i, vec![i as f32, 1.0])).collect();
+        let result =
+            group_chapter_embeddings(chapters, DocGroupSpec::new(3, Some(1)).unwrap()).unwrap();
+
+        // Trailing windows keep only the chapters that exist
+        let ranges = result
+            .iter()
+            .map(|group| (group.index, group.first_index, group.last_index))
+            .collect::<Vec<(i32, i32, i32)>>();
+        assert_eq!(
+            ranges,
+            vec![(0, 0, 2), (1, 1, 3), (2, 2, 4), (3, 3, 4), (4, 4, 4)]
+        );
+        assert_eq!(result[1].embedding, vec![2.0, 1.0]);
+        assert_eq!(result[3].embedding, vec![3.5, 1.0]);
+    }
+
+    #[test]
+    pub fn test_group_indices_containing() {
+        let doc_group = DocGroupSpec::new(5, Some(2)).unwrap();
+        assert_eq!(doc_group.group_indices_containing(0), 0..=0);
+        assert_eq!(doc_group.group_indices_containing(4), 0..=2);
+        assert_eq!(doc_group.group_indices_containing(7), 2..=3);
+        assert_eq!(doc_group.chapter_bounds(2), (4, 9));
+
+        let doc_group = DocGroupSpec::new(3, None).unwrap();
+        assert_eq!(doc_group.group_indices_containing(4), 1..=1);
+        assert!(DocGroupSpec::new(3, Some(4)).is_err());
+        assert!(DocGroupSpec::from_optional(None, Some(1)).is_err());
+    }
+
+    #[test]
+    pub fn test_story_centroid_embedding() {
+        let chapters = vec![
+            (0, vec![0.0, 2.0]),
+            (1, vec![2.0, 2.0]),
+            (3, vec![4.0, 2.0]),
+        ];
+
+        let centroid = story_centroid_embedding(&chapters, None).unwrap();
+        assert_eq!(centroid, vec![2.0, 2.0]);
+
+        // Chapter 1 is two chapters before chapter 3 and counts half as much
+        let weighted = story_centroid_embedding(&chapters, Some(2.0)).unwrap();
+        let chapter_0_weight = 0.5_f32.powf(1.5);
+        let expected = (chapter_0_weight * 0.0 + 0.5 * 2.0 + 4.0) / (chapter_0_weight + 0.5 + 1.0);
+        assert!((weighted[0] - expected).abs() < 1e-6);
+        assert!(weighted[0] > centroid[0]);
+        assert!((weighted[1] - 2.0).abs() < 1e-6);
+
+        assert!(story_centroid_embedding(&[], None).is_err());
+    }
+
+    #[test]
+    pub fn test_story_centroid_sum_matches_full_recompute() {
+        let chapters = vec![
+            (0, vec![0.0, 2.0]),
+            (1, vec![2.0, 2.0]),
+            (3, vec![4.0, 2.0]),
+        ];
+
+        for half_life in [None, Some(2.0)] {
+            // chapters arrive out of order and chapter 1 is rewritten
+            let mut sum = StoryCentroidSum::from_chapters(&[(1, vec![9.0, 9.0])], half_life);
+            sum.add(3, &[4.0, 2.0], half_life);
+            sum.add(0, &[0.0, 2.0], half_life);
+            sum.remove(1, &[9.0, 9.0], half_life);
+            sum.add(1, &[2.0, 2.0], half_life);
+
+            let expected = story_centroid_embedding(&chapters, half_life).unwrap();
+            let centroid = sum.centroid().unwrap();
+            for (value, expected) in centroid.iter().zip(expected) {
+                assert!((value - expected).abs() < 1e-5);
+            }
+            assert_eq!(sum.latest_index, 3);
+        }
+
+        assert!(StoryCentroidSum::from_chapters(&[], None)
+            .centroid()
+            .is_err());
+    }
 }
@@ -0,0 +1,414 @@
+use crate::errors::ServiceError;
+use actix_rt::time::sleep;
+use reqwest::{header::HeaderMap, Response, StatusCode};
+use std::{
+    future::Future,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone)]
+pub struct EmbeddingProviderConfig {
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub failure_threshold: u32,
+    pub open_duration: Duration,
+}
+
+impl Default for EmbeddingProviderConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(5),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(8),
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+fn provider_var(name: &str, default: u64) -> Result<u64, ServiceError> {
+    match std::env::var(name) {
+        Ok(value) => value.parse::<u64>().map_err(|_| {
+            ServiceError::EmbeddingProviderConfigError(format!("{} must be a whole number", name))
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
+/// A timeout of 0 would fail every call before it is sent
+fn provider_timeout_var(name: &str, default: Duration) -> Result<Duration, ServiceError> {
+    match provider_var(name, default.as_secs())? {
+        0 => Err(ServiceError::EmbeddingProviderConfigError(format!(
+            "{} must be greater than 0",
+            name
+        ))),
+        seconds => Ok(Duration::from_secs(seconds)),
+    }
+}
+
+impl EmbeddingProviderConfig {
+    pub fn from_env() -> Result<Self, ServiceError> {
+        let default = Self::default();
+
+        Ok(Self {
+            timeout: provider_timeout_var("EMBEDDING_TIMEOUT_SECONDS", default.timeout)?,
+            connect_timeout: provider_timeout_var(
+                "EMBEDDING_CONNECT_TIMEOUT_SECONDS",
+                default.connect_timeout,
+            )?,
+            max_retries: provider_var("EMBEDDING_MAX_RETRIES", default.max_retries as u64)? as u32,
+            initial_backoff: Duration::from_millis(provider_var(
+                "EMBEDDING_RETRY_BACKOFF_MILLIS",
+                default.initial_backoff.as_millis() as u64,
+            )?),
+            max_backoff: Duration::from_secs(provider_var(
+                "EMBEDDING_RETRY_MAX_BACKOFF_SECONDS",
+                default.max_backoff.as_secs(),
+            )?),
+            failure_threshold: provider_var(
+                "EMBEDDING_CIRCUIT_BREAKER_THRESHOLD",
+                default.failure_threshold as u64,
+            )?
+            .max(1) as u32,
+            open_duration: Duration::from_secs(provider_var(
+                "EMBEDDING_CIRCUIT_BREAKER_SECONDS",
+                default.open_duration.as_secs(),
+            )?),
+        })
+    }
+
+    /// Delay before retry `attempt`, counting from 0, doubling up to `max_backoff`
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Debug, Default)]
+struct CircuitState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    /// Set while the one call let through after the breaker was open is under way. A probe
+    /// whose caller went away without an outcome is replaced once this has passed.
+    probe_until: Option<Instant>,
+}
+
+/// Opens after `failure_threshold` failed calls in a row and rejects calls until
+/// `open_duration` has passed. A single call is then let through as a probe while the others
+/// are still rejected; its success closes the breaker and its failure opens it again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Mutex<CircuitState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            failure_threshold,
+            open_duration,
+            state: Mutex::new(CircuitState::default()),
+        }
+    }
+
+    /// Lets a call through, or returns how long the caller should wait when the breaker is
+    /// open or another call is probing it
+    pub fn admit(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().expect("Circuit breaker lock poisoned");
+        match state.open_until {
+            None => None,
+            Some(open_until) if open_until > now => Some(open_until - now),
+            Some(_) => match state.probe_until.filter(|probe_until| *probe_until > now) {
+                Some(probe_until) => Some(probe_until - now),
+                None => {
+                    state.probe_until = Some(now + self.open_duration);
+                    None
+                }
+            },
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().expect("Circuit breaker lock poisoned") = CircuitState::default();
+    }
+
+    pub fn record_failure(&self, now: Instant) {
+        let mut state = self.state.lock().expect("Circuit breaker lock poisoned");
+        state.consecutive_failures += 1;
+        state.probe_until = None;
+        if state.consecutive_failures >= self.failure_threshold {
+            state.open_until = Some(now + self.open_duration);
+        }
+    }
+
+    /// Lets the next call probe again after a call that neither succeeded nor failed transiently
+    pub fn release_probe(&self) {
+        self.state
+            .lock()
+            .expect("Circuit breaker lock poisoned")
+            .probe_until = None;
+    }
+}
+
+/// Why a call to the provider failed. Only transient failures are retried and count toward
+/// opening the circuit breaker.
+pub enum ProviderCallError {
+    Transient {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    Permanent(ServiceError),
+}
+
+impl ProviderCallError {
+    /// Timeouts and failed connections are transient, anything else about the request is not
+    pub fn from_request_error(err: reqwest::Error) -> Self {
+        if err.is_timeout() || err.is_connect() {
+            ProviderCallError::Transient {
+                message: format!("{:?}", err),
+                retry_after: None,
+            }
+        } else {
+            ProviderCallError::Permanent(ServiceError::EmbeddingServerCallError(err))
+        }
+    }
+
+    /// Reading the body can time out or lose the connection like the request, while a body that
+    /// arrived but does not parse will not parse on a retry either
+    pub fn from_body_error(err: reqwest::Error) -> Self {
+        if err.is_timeout() || err.is_body() {
+            ProviderCallError::Transient {
+                message: format!("{:?}", err),
+                retry_after: None,
+            }
+        } else {
+            ProviderCallError::Permanent(ServiceError::EmbeddingServerParseError(err))
+        }
+    }
+}
+
+/// Seconds in a `Retry-After` header. The HTTP date form is not used by embedding providers.
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+pub fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Passes successful responses through and sorts failed ones into transient and permanent
+pub fn check_provider_response(response: Response) -> Result<Response, ProviderCallError> {
+    let status = response.status();
+    if is_transient_status(status) {
+        return Err(ProviderCallError::Transient {
+            message: format!("Embedding provider responded with {}", status),
+            retry_after: parse_retry_after(response.headers()),
+        });
+    }
+
+    response
+        .error_for_status()
+        .map_err(|err| ProviderCallError::Permanent(ServiceError::EmbeddingServerCallError(err)))
+}
+
+fn unavailable_for(delay: Duration) -> ServiceError {
+    ServiceError::EmbeddingProviderUnavailable(delay.as_secs_f64().ceil().max(1.0) as u64)
+}
+
+/// The client and circuit breaker every call to the embedding provider goes through
+pub struct EmbeddingProvider {
+    client: reqwest::Client,
+    config: EmbeddingProviderConfig,
+    circuit_breaker: CircuitBreaker,
+}
+
+impl EmbeddingProvider {
+    pub fn new(config: EmbeddingProviderConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .build()
+            .expect("Failed to build the embedding provider client");
+
+        Self {
+            client,
+            circuit_breaker: CircuitBreaker::new(config.failure_threshold, config.open_duration),
+            config,
+        }
+    }
+
+    /// Sends a request built by `send`, retrying transient failures with exponential backoff.
+    /// Fails fast with `EmbeddingProviderUnavailable` while the circuit breaker is open or
+    /// once the retries run out.
+    pub async fn call<T, F, Fut>(&self, mut send: F) -> Result<T, ServiceError>
+    where
+        F: FnMut(reqwest::Client) -> Fut,
+        Fut: Future<Output = Result<T, ProviderCallError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            if let Some(wait) = self.circuit_breaker.admit(Instant::now()) {
+                return Err(unavailable_for(wait));
+            }
+
+            let (message, retry_after) = match send(self.client.clone()).await {
+                Ok(result) => {
+                    self.circuit_breaker.record_success();
+                    return Ok(result);
+                }
+                Err(ProviderCallError::Permanent(err)) => {
+                    self.circuit_breaker.release_probe();
+                    return Err(err);
+                }
+                Err(ProviderCallError::Transient {
+                    message,
+                    retry_after,
+                }) => (message, retry_after),
+            };
+            self.circuit_breaker.record_failure(Instant::now());
+
+            let delay = retry_after
+                .unwrap_or_default()
+                .max(self.config.backoff(attempt));
+            log::info!(
+                "Embedding provider call failed on attempt {}: {}",
+                attempt + 1,
+                message
+            );
+            // a provider asking to wait longer than we back off is treated as down for that long
+            if attempt >= self.config.max_retries || delay > self.config.max_backoff {
+                return Err(unavailable_for(delay));
+            }
+
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+static EMBEDDING_PROVIDER: OnceLock<EmbeddingProvider> = OnceLock::new();
+
+/// Configured from the environment on first use, which `main` forces so a bad value stops
+/// the server at startup
+pub fn embedding_provider() -> &'static EmbeddingProvider {
+    EMBEDDING_PROVIDER.get_or_init(|| match EmbeddingProviderConfig::from_env() {
+        Ok(config) => EmbeddingProvider::new(config),
+        Err(err) => panic!("Failed to configure the embedding provider: {:?}", err),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::{HeaderValue, RETRY_AFTER};
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let config = EmbeddingProviderConfig::default();
+        assert_eq!(config.backoff(0), Duration::from_millis(500));
+        assert_eq!(config.backoff(2), Duration::from_secs(2));
+        assert_eq!(config.backoff(4), Duration::from_secs(8));
+        assert_eq!(config.backoff(40), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn test_circuit_breaker_opens_and_recovers() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+
+        breaker.record_failure(now);
+        assert_eq!(breaker.admit(now), None);
+        breaker.record_failure(now);
+        assert_eq!(breaker.admit(now), Some(Duration::from_secs(30)));
+
+        // let through once the open period passed, and opened again by the next failure
+        let later = now + Duration::from_secs(31);
+        assert_eq!(breaker.admit(later), None);
+        breaker.record_failure(later);
+        assert_eq!(breaker.admit(later), Some(Duration::from_secs(30)));
+
+        let later = later + Duration::from_secs(31);
+        assert_eq!(breaker.admit(later), None);
+        breaker.record_success();
+        assert_eq!(breaker.admit(later), None);
+        breaker.record_failure(later);
+        assert_eq!(breaker.admit(later), None);
+    }
+
+    #[test]
+    fn test_half_open_circuit_breaker_lets_one_probe_through() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        let now = Instant::now();
+        breaker.record_failure(now);
+
+        let later = now + Duration::from_secs(31);
+        assert_eq!(breaker.admit(later), None);
+        // the others wait on the probe
+        assert_eq!(breaker.admit(later), Some(Duration::from_secs(30)));
+
+        // a probe that ended without a verdict hands over to the next call
+        breaker.release_probe();
+        assert_eq!(breaker.admit(later), None);
+        assert!(breaker.admit(later).is_some());
+
+        // and so does one whose caller never came back
+        let much_later = later + Duration::from_secs(31);
+        assert_eq!(breaker.admit(much_later), None);
+        assert!(breaker.admit(much_later).is_some());
+
+        breaker.record_success();
+        assert_eq!(breaker.admit(much_later), None);
+        assert_eq!(breaker.admit(much_later), None);
+    }
+
+    #[test]
+    fn test_zero_timeout_is_rejected() {
+        std::env::set_var("EMBEDDING_TEST_TIMEOUT_SECONDS", "0");
+        assert!(
+            provider_timeout_var("EMBEDDING_TEST_TIMEOUT_SECONDS", Duration::from_secs(5)).is_err()
+        );
+        std::env::set_var("EMBEDDING_TEST_TIMEOUT_SECONDS", "3");
+        assert_eq!(
+            provider_timeout_var("EMBEDDING_TEST_TIMEOUT_SECONDS", Duration::from_secs(5)).ok(),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            provider_timeout_var("EMBEDDING_UNSET_TIMEOUT_SECONDS", Duration::from_secs(5)).ok(),
+            Some(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn test_retry_after_and_transient_statuses() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("7"));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(7)));
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(parse_retry_after(&headers), None);
+
+        assert!(is_transient_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_transient_status(StatusCode::BAD_GATEWAY));
+        assert!(!is_transient_status(StatusCode::BAD_REQUEST));
+        assert!(!is_transient_status(StatusCode::UNAUTHORIZED));
+    }
+}
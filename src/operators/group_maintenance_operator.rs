@@ -0,0 +1,187 @@
+use super::doc_embedding_operator::create_doc_group_embedding;
+use super::doc_group_embedding_operator::get_registered_doc_groups_pg_query;
+use super::vector_store_operator::VectorStore;
+use crate::data::models::IndexDocumentGroupRequest;
+use actix_rt::time::{timeout, Instant};
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+use sqlx::{Pool, Postgres};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+/// Seconds a story has to go without new chapters before its groups are rebuilt, read from
+/// `DOC_GROUP_REBUILD_DEBOUNCE_SECONDS`
+pub fn doc_group_rebuild_debounce() -> Duration {
+    let seconds = std::env::var("DOC_GROUP_REBUILD_DEBOUNCE_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .unwrap_or(30);
+    Duration::from_secs(seconds)
+}
+
+/// Stories waiting for a rebuild. Every new chapter pushes the story's deadline back, so a burst
+/// of chapters leads to a single rebuild once the burst is over.
+#[derive(Debug, Default)]
+pub struct DebouncedStories {
+    deadlines: HashMap<i64, Instant>,
+}
+
+impl DebouncedStories {
+    pub fn push(&mut self, story_id: i64, deadline: Instant) {
+        self.deadlines.insert(story_id, deadline);
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.values().min().copied()
+    }
+
+    /// Removes and returns the stories whose deadline has passed, in story id order
+    pub fn take_due(&mut self, now: Instant) -> Vec<i64> {
+        let mut due = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(story_id, _)| *story_id)
+            .collect::<Vec<i64>>();
+        due.sort();
+
+        for story_id in due.iter() {
+            self.deadlines.remove(story_id);
+        }
+
+        due
+    }
+}
+
+/// Handle shared with the handlers to schedule group rebuilds
+#[derive(Clone)]
+pub struct GroupMaintenanceQueue {
+    sender: UnboundedSender<i64>,
+}
+
+impl GroupMaintenanceQueue {
+    /// Spawns the worker that rebuilds every registered group size of the scheduled stories
+    pub fn start(
+        pool: Pool<Postgres>,
+        vector_store: Arc<dyn VectorStore>,
+        debounce: Duration,
+    ) -> Self {
+        let (sender, receiver) = unbounded();
+        actix_rt::spawn(run_group_maintenance(
+            receiver,
+            pool,
+            vector_store,
+            debounce,
+        ));
+        Self { sender }
+    }
+
+    pub fn schedule(&self, story_id: i64) {
+        if let Err(err) = self.sender.unbounded_send(story_id) {
+            log::info!(
+                "Failed to schedule doc group rebuild of story {}: {:?}",
+                story_id,
+                err
+            );
+        }
+    }
+}
+
+async fn run_group_maintenance(
+    mut receiver: UnboundedReceiver<i64>,
+    pool: Pool<Postgres>,
+    vector_store: Arc<dyn VectorStore>,
+    debounce: Duration,
+) {
+    let mut pending = DebouncedStories::default();
+
+    loop {
+        let received = match pending.next_deadline() {
+            Some(deadline) => {
+                match timeout(
+                    deadline.saturating_duration_since(Instant::now()),
+                    receiver.next(),
+                )
+                .await
+                {
+                    Ok(story_id) => story_id.map(Some),
+                    Err(_) => Some(None),
+                }
+            }
+            None => receiver.next().await.map(Some),
+        };
+
+        match received {
+            Some(Some(story_id)) => pending.push(story_id, Instant::now() + debounce),
+            Some(None) => {}
+            // every queue handle was dropped, the server is shutting down
+            None => break,
+        }
+
+        for story_id in pending.take_due(Instant::now()) {
+            rebuild_story_doc_groups(story_id, pool.clone(), vector_store.as_ref()).await;
+        }
+    }
+}
+
+async fn rebuild_story_doc_groups(
+    story_id: i64,
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) {
+    let doc_groups = match get_registered_doc_groups_pg_query(pool.clone()).await {
+        Ok(doc_groups) => doc_groups,
+        Err(err) => {
+            log::info!("Failed to load registered doc group sizes: {:?}", err);
+            return;
+        }
+    };
+
+    for doc_group in doc_groups {
+        let _ = create_doc_group_embedding(
+            IndexDocumentGroupRequest::Story {
+                story_id,
+                doc_group_size: doc_group.doc_group_size,
+                doc_group_stride: Some(doc_group.doc_group_stride),
+            },
+            pool.clone(),
+            vector_store,
+        )
+        .await
+        .map_err(|err| {
+            log::info!(
+                "Failed to rebuild doc groups of size {} and stride {} for story {}: {:?}",
+                doc_group.doc_group_size,
+                doc_group.doc_group_stride,
+                story_id,
+                err
+            );
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_debounced_stories() {
+        let start = Instant::now();
+        let mut pending = DebouncedStories::default();
+
+        pending.push(1, start + Duration::from_secs(10));
+        pending.push(2, start + Duration::from_secs(5));
+        // a later chapter of story 1 pushes its rebuild back
+        pending.push(1, start + Duration::from_secs(20));
+
+        assert_eq!(
+            pending.next_deadline(),
+            Some(start + Duration::from_secs(5))
+        );
+        assert!(pending.take_due(start + Duration::from_secs(4)).is_empty());
+        assert_eq!(pending.take_due(start + Duration::from_secs(10)), vec![2]);
+        assert_eq!(pending.take_due(start + Duration::from_secs(20)), vec![1]);
+        assert_eq!(pending.next_deadline(), None);
+    }
+}
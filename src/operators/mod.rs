@@ -1,6 +1,18 @@
+pub mod chunk_embedding_cache_operator;
 pub mod doc_embedding_operator;
 pub mod doc_group_embedding_operator;
 pub mod embedding_operator;
+pub mod embedding_provider_operator;
+pub mod group_maintenance_operator;
+pub mod named_chapter_range_operator;
+pub mod openai_key_pool_operator;
 pub mod parse_operator;
+pub mod pgvector_operator;
 pub mod qdrant_operator;
+pub mod query_embedding_cache_operator;
+pub mod reconcile_operator;
+pub mod reindex_operator;
 pub mod search_operator;
+pub mod story_embedding_operator;
+pub mod vector_outbox_operator;
+pub mod vector_store_operator;
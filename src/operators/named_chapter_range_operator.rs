@@ -0,0 +1,195 @@
+use super::doc_embedding_operator::get_chapter_vectors_in_range;
+use super::embedding_operator::average_embeddings;
+use super::qdrant_operator::{
+    named_chapter_range_vector_mutation, NAMED_CHAPTER_RANGES_COLLECTION,
+};
+use super::vector_outbox_operator::{enqueue_vector_mutations_pg_query, VectorMutation};
+use super::vector_store_operator::VectorStore;
+use crate::{data::models::NamedChapterRange, errors::ServiceError};
+use sqlx::{Pool, Postgres, Transaction};
+use std::time::Duration;
+
+/// Creates the range or moves an existing range with the same name. An existing range keeps its
+/// Qdrant point id, and the stored row is returned.
+pub async fn upsert_named_chapter_range_pg_query(
+    named_chapter_range: NamedChapterRange,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<NamedChapterRange, ServiceError> {
+    let named_chapter_range = sqlx::query_as!(
+        NamedChapterRange,
+        r#"
+        INSERT INTO named_chapter_ranges (id, story_id, name, first_index, last_index, qdrant_point_id, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (story_id, name) DO UPDATE
+        SET
+            first_index = EXCLUDED.first_index,
+            last_index = EXCLUDED.last_index,
+            updated_at = EXCLUDED.updated_at
+        RETURNING *
+        "#,
+        named_chapter_range.id,
+        named_chapter_range.story_id,
+        named_chapter_range.name,
+        named_chapter_range.first_index,
+        named_chapter_range.last_index,
+        named_chapter_range.qdrant_point_id,
+        named_chapter_range.created_at,
+        named_chapter_range.updated_at,
+    )
+    .fetch_one(&mut **transaction)
+    .await
+    .map_err(ServiceError::UpsertNamedChapterRangePgError)?;
+
+    Ok(named_chapter_range)
+}
+
+pub async fn get_story_named_chapter_ranges_pg_query(
+    story_id: i64,
+    pool: Pool<Postgres>,
+) -> Result<Vec<NamedChapterRange>, ServiceError> {
+    let named_chapter_ranges = sqlx::query_as!(
+        NamedChapterRange,
+        r#"
+        SELECT *
+        FROM named_chapter_ranges
+        WHERE story_id = $1
+        ORDER BY first_index, name
+        "#,
+        story_id,
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectNamedChapterRangesPgError)?;
+
+    Ok(named_chapter_ranges)
+}
+
+pub async fn get_named_chapter_range_pg_query(
+    story_id: i64,
+    name: String,
+    pool: Pool<Postgres>,
+) -> Result<Option<NamedChapterRange>, ServiceError> {
+    let named_chapter_range = sqlx::query_as!(
+        NamedChapterRange,
+        r#"
+        SELECT *
+        FROM named_chapter_ranges
+        WHERE story_id = $1 AND name = $2
+        "#,
+        story_id,
+        name,
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ServiceError::SelectNamedChapterRangesPgError)?;
+
+    Ok(named_chapter_range)
+}
+
+pub async fn get_named_chapter_ranges_by_qdrant_ids_pg_query(
+    qdrant_point_ids: Vec<uuid::Uuid>,
+    pool: Pool<Postgres>,
+) -> Result<Vec<NamedChapterRange>, ServiceError> {
+    let named_chapter_ranges = sqlx::query_as!(
+        NamedChapterRange,
+        r#"
+        SELECT *
+        FROM named_chapter_ranges
+        WHERE qdrant_point_id = ANY($1)
+        "#,
+        qdrant_point_ids.as_slice(),
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectNamedChapterRangesPgError)?;
+
+    Ok(named_chapter_ranges)
+}
+
+pub async fn delete_named_chapter_range_pg_query(
+    story_id: i64,
+    name: String,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<NamedChapterRange>, ServiceError> {
+    let named_chapter_range = sqlx::query_as!(
+        NamedChapterRange,
+        r#"
+        DELETE FROM named_chapter_ranges
+        WHERE story_id = $1 AND name = $2
+        RETURNING *
+        "#,
+        story_id,
+        name,
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map_err(ServiceError::DeleteNamedChapterRangeError)?;
+
+    Ok(named_chapter_range)
+}
+
+/// Averages the indexed chapters of the range into its Qdrant point, which the relay writes once
+/// the transaction commits. A range without any indexed chapter has no vector until one of its
+/// chapters is indexed.
+pub async fn index_named_chapter_range(
+    named_chapter_range: NamedChapterRange,
+    transaction: &mut Transaction<'_, Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<(), ServiceError> {
+    let chapter_embeddings = get_chapter_vectors_in_range(
+        named_chapter_range.story_id,
+        named_chapter_range.first_index..=named_chapter_range.last_index,
+        transaction,
+        vector_store,
+    )
+    .await?;
+
+    let mutation = if chapter_embeddings.is_empty() {
+        // the range may have been moved away from the chapters its vector was built from
+        VectorMutation::Delete {
+            collection_name: NAMED_CHAPTER_RANGES_COLLECTION.to_owned(),
+            point_id: named_chapter_range.qdrant_point_id,
+        }
+    } else {
+        let embedding = average_embeddings(
+            chapter_embeddings
+                .into_iter()
+                .map(|(_, embedding)| embedding)
+                .collect(),
+        )?;
+        named_chapter_range_vector_mutation(named_chapter_range, embedding)
+    };
+
+    enqueue_vector_mutations_pg_query(vec![mutation], Duration::ZERO, transaction).await?;
+
+    Ok(())
+}
+
+/// Recomputes every named range of the story that contains the chapter at `index`
+pub async fn recompute_named_chapter_ranges_for_chapter(
+    story_id: i64,
+    index: i32,
+    transaction: &mut Transaction<'_, Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<(), ServiceError> {
+    let named_chapter_ranges = sqlx::query_as!(
+        NamedChapterRange,
+        r#"
+        UPDATE named_chapter_ranges
+        SET updated_at = CURRENT_TIMESTAMP
+        WHERE story_id = $1 AND first_index <= $2 AND last_index >= $2
+        RETURNING *
+        "#,
+        story_id,
+        index,
+    )
+    .fetch_all(&mut **transaction)
+    .await
+    .map_err(ServiceError::UpsertNamedChapterRangePgError)?;
+
+    for named_chapter_range in named_chapter_ranges {
+        index_named_chapter_range(named_chapter_range, transaction, vector_store).await?;
+    }
+
+    Ok(())
+}
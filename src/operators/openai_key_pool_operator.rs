@@ -0,0 +1,510 @@
+use super::embedding_provider_operator::{parse_retry_after, ProviderCallError};
+use crate::errors::ServiceError;
+use reqwest::{header::HeaderMap, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    future::Future,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// Failures in a row, of any kind, after which a key is taken out of rotation
+const KEY_FAILURE_THRESHOLD: u32 = 3;
+
+/// A rejected key is likely revoked or out of credit, so it stays out this many cooldowns
+const REJECTED_KEY_COOLDOWN_FACTOR: u32 = 10;
+
+#[derive(Debug, Default)]
+struct KeyState {
+    key: String,
+    requests: u64,
+    successes: u64,
+    failures: u64,
+    rate_limited: u64,
+    consecutive_failures: u32,
+    cooldown_until: Option<Instant>,
+    remaining_requests: Option<u64>,
+    remaining_tokens: Option<u64>,
+    quota_resets_at: Option<Instant>,
+    last_error: Option<String>,
+}
+
+impl KeyState {
+    fn cooling_down_for(&self, now: Instant) -> Option<Duration> {
+        self.cooldown_until
+            .filter(|cooldown_until| *cooldown_until > now)
+            .map(|cooldown_until| cooldown_until - now)
+    }
+
+    /// Remaining requests and tokens, assumed full once the reported quota has reset
+    fn remaining_quota(&self, now: Instant) -> (u64, u64) {
+        match self.quota_resets_at {
+            Some(quota_resets_at) if quota_resets_at <= now => (u64::MAX, u64::MAX),
+            _ => (
+                self.remaining_requests.unwrap_or(u64::MAX),
+                self.remaining_tokens.unwrap_or(u64::MAX),
+            ),
+        }
+    }
+
+    fn cool_down(&mut self, cooldown: Duration, now: Instant) {
+        self.cooldown_until = Some(now + cooldown);
+    }
+
+    fn record_failure(&mut self, message: String, cooldown: Duration, now: Instant) {
+        self.failures += 1;
+        self.consecutive_failures += 1;
+        self.last_error = Some(message);
+        if self.consecutive_failures >= KEY_FAILURE_THRESHOLD {
+            self.cool_down(cooldown, now);
+        }
+    }
+}
+
+/// Usage of one key, with only its last characters shown
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OpenAIKeyUsage {
+    pub key: String,
+    pub requests: u64,
+    pub successes: u64,
+    pub failures: u64,
+    pub rate_limited: u64,
+    pub remaining_requests: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    pub cooling_down_seconds: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+pub fn mask_key(key: &str) -> String {
+    let visible: String = key
+        .chars()
+        .rev()
+        .take(4)
+        .collect::<Vec<char>>()
+        .into_iter()
+        .rev()
+        .collect();
+    format!("...{}", visible)
+}
+
+/// Durations in OpenAI's rate limit reset headers, such as `20ms`, `1s` or `6m0s`
+pub fn parse_reset_duration(value: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number = rest[..number_len].parse::<f64>().ok()?;
+        rest = &rest[number_len..];
+
+        let (unit_seconds, unit_len) = if rest.starts_with("ms") {
+            (0.001, 2)
+        } else if rest.starts_with('h') {
+            (3600.0, 1)
+        } else if rest.starts_with('m') {
+            (60.0, 1)
+        } else if rest.starts_with('s') {
+            (1.0, 1)
+        } else {
+            return None;
+        };
+        total += Duration::try_from_secs_f64(number * unit_seconds).ok()?;
+        rest = &rest[unit_len..];
+    }
+
+    Some(total)
+}
+
+/// Answers about the key rather than the request, which another key may get through
+pub fn is_key_failure(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::UNAUTHORIZED
+        || status == StatusCode::FORBIDDEN
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse::<u64>().ok()
+}
+
+fn header_duration(headers: &HeaderMap, name: &str) -> Option<Duration> {
+    parse_reset_duration(headers.get(name)?.to_str().ok()?)
+}
+
+/// OpenAI keys in rotation. Each call takes the key with the most remaining quota, and keys
+/// that are rate limited, rejected or failing are left out until their cooldown passes.
+pub struct OpenAIKeyPool {
+    keys: Mutex<Vec<KeyState>>,
+    cooldown: Duration,
+}
+
+impl OpenAIKeyPool {
+    pub fn new(keys: Vec<String>, cooldown: Duration) -> Self {
+        Self {
+            keys: Mutex::new(
+                keys.into_iter()
+                    .map(|key| KeyState {
+                        key,
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            cooldown,
+        }
+    }
+
+    /// Keys from `OPENAI_API_KEYS`, which may be unset when the embedding server is used
+    pub fn from_env() -> Result<Self, ServiceError> {
+        let keys = std::env::var("OPENAI_API_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|key| key.trim().to_owned())
+            .filter(|key| !key.is_empty())
+            .collect();
+        let cooldown = match std::env::var("OPENAI_KEY_COOLDOWN_SECONDS") {
+            Ok(value) => Duration::from_secs(value.parse::<u64>().map_err(|_| {
+                ServiceError::EmbeddingProviderConfigError(
+                    "OPENAI_KEY_COOLDOWN_SECONDS must be a whole number".to_owned(),
+                )
+            })?),
+            Err(_) => Duration::from_secs(60),
+        };
+
+        Ok(Self::new(keys, cooldown))
+    }
+
+    /// Index and value of the key to use next. Fails with `EmbeddingProviderUnavailable` until
+    /// the first cooldown ends when every key is cooling down.
+    pub fn acquire(&self, now: Instant) -> Result<(usize, String), ServiceError> {
+        let mut keys = self.keys.lock().expect("Key pool lock poisoned");
+        if keys.is_empty() {
+            return Err(ServiceError::EmbeddingProviderConfigError(
+                "OPENAI_API_KEYS should be set".to_owned(),
+            ));
+        }
+
+        let best = keys
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| key.cooling_down_for(now).is_none())
+            // most remaining quota first, then the least used key
+            .min_by_key(|(_, key)| {
+                let (remaining_requests, remaining_tokens) = key.remaining_quota(now);
+                (
+                    Reverse(remaining_requests),
+                    Reverse(remaining_tokens),
+                    key.requests,
+                )
+            })
+            .map(|(index, _)| index);
+
+        match best {
+            Some(index) => {
+                keys[index].requests += 1;
+                Ok((index, keys[index].key.clone()))
+            }
+            None => {
+                let wait = keys
+                    .iter()
+                    .filter_map(|key| key.cooling_down_for(now))
+                    .min()
+                    .unwrap_or(self.cooldown);
+                Err(ServiceError::EmbeddingProviderUnavailable(
+                    wait.as_secs_f64().ceil().max(1.0) as u64,
+                ))
+            }
+        }
+    }
+
+    /// How long until the first key that is cooling down is back, if none is usable now
+    fn every_key_cooling_down_for(&self, now: Instant) -> Option<Duration> {
+        let keys = self.keys.lock().expect("Key pool lock poisoned");
+        if keys.iter().any(|key| key.cooling_down_for(now).is_none()) {
+            return None;
+        }
+        keys.iter()
+            .filter_map(|key| key.cooling_down_for(now))
+            .min()
+    }
+
+    /// Sends a request built by `send` with the best key. A key that is rejected or rate
+    /// limited is taken out of rotation and the request goes out again with the next key right
+    /// away, so the retries and circuit breaker of the provider only see a failure when no key
+    /// is usable, or one that is not about the key.
+    pub async fn send_with_key<F, Fut>(&self, mut send: F) -> Result<Response, ProviderCallError>
+    where
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = Result<Response, reqwest::Error>>,
+    {
+        let key_count = self.keys.lock().expect("Key pool lock poisoned").len();
+        let mut tried = 0;
+        loop {
+            let (key_index, api_key) = match self.acquire(Instant::now()) {
+                Ok(key) => key,
+                Err(ServiceError::EmbeddingProviderUnavailable(wait)) => {
+                    return Err(ProviderCallError::Transient {
+                        message: "Every OpenAI key is cooling down".to_owned(),
+                        retry_after: Some(Duration::from_secs(wait)),
+                    })
+                }
+                Err(err) => return Err(ProviderCallError::Permanent(err)),
+            };
+
+            let response = send(api_key.clone()).await.map_err(|err| {
+                self.record_error(key_index, format!("{:?}", err), Instant::now());
+                ProviderCallError::from_request_error(err)
+            })?;
+            let status = response.status();
+            self.record_response(key_index, status, response.headers(), Instant::now());
+            if !is_key_failure(status) {
+                return Ok(response);
+            }
+
+            let message = format!("OpenAI answered key {} with {}", mask_key(&api_key), status);
+            log::info!("{}", message);
+            tried += 1;
+            // a key whose cooldown already passed could come up again, so every key gets one go
+            if tried >= key_count {
+                return Err(ProviderCallError::Transient {
+                    message,
+                    retry_after: self.every_key_cooling_down_for(Instant::now()),
+                });
+            }
+        }
+    }
+
+    /// Updates the key's health and quota from the response to a call made with it
+    pub fn record_response(
+        &self,
+        index: usize,
+        status: StatusCode,
+        headers: &HeaderMap,
+        now: Instant,
+    ) {
+        let mut keys = self.keys.lock().expect("Key pool lock poisoned");
+        let Some(key) = keys.get_mut(index) else {
+            return;
+        };
+
+        let reset_requests = header_duration(headers, "x-ratelimit-reset-requests");
+        if let Some(remaining_requests) = header_u64(headers, "x-ratelimit-remaining-requests") {
+            key.remaining_requests = Some(remaining_requests);
+            key.quota_resets_at = reset_requests.map(|reset| now + reset);
+        }
+        if let Some(remaining_tokens) = header_u64(headers, "x-ratelimit-remaining-tokens") {
+            key.remaining_tokens = Some(remaining_tokens);
+        }
+
+        if status.is_success() {
+            key.successes += 1;
+            key.consecutive_failures = 0;
+            if key.remaining_requests == Some(0) {
+                key.cool_down(reset_requests.unwrap_or(self.cooldown), now);
+            }
+        } else if status == StatusCode::TOO_MANY_REQUESTS {
+            key.rate_limited += 1;
+            key.failures += 1;
+            key.last_error = Some(status.to_string());
+            // without headers on the 429, the key is out until its last reported quota resets
+            let known_reset = key
+                .quota_resets_at
+                .filter(|quota_resets_at| *quota_resets_at > now)
+                .map(|quota_resets_at| quota_resets_at - now);
+            key.cool_down(
+                parse_retry_after(headers)
+                    .or(reset_requests)
+                    .or(known_reset)
+                    .unwrap_or(self.cooldown),
+                now,
+            );
+        } else if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            key.failures += 1;
+            key.last_error = Some(status.to_string());
+            key.cool_down(
+                self.cooldown.saturating_mul(REJECTED_KEY_COOLDOWN_FACTOR),
+                now,
+            );
+        } else {
+            key.record_failure(status.to_string(), self.cooldown, now);
+        }
+    }
+
+    /// Records a call that got no response, such as a timeout
+    pub fn record_error(&self, index: usize, message: String, now: Instant) {
+        let mut keys = self.keys.lock().expect("Key pool lock poisoned");
+        if let Some(key) = keys.get_mut(index) {
+            key.record_failure(message, self.cooldown, now);
+        }
+    }
+
+    pub fn usage(&self, now: Instant) -> Vec<OpenAIKeyUsage> {
+        self.keys
+            .lock()
+            .expect("Key pool lock poisoned")
+            .iter()
+            .map(|key| OpenAIKeyUsage {
+                key: mask_key(&key.key),
+                requests: key.requests,
+                successes: key.successes,
+                failures: key.failures,
+                rate_limited: key.rate_limited,
+                remaining_requests: key.remaining_requests,
+                remaining_tokens: key.remaining_tokens,
+                cooling_down_seconds: key
+                    .cooling_down_for(now)
+                    .map(|cooldown| cooldown.as_secs_f64().ceil() as u64),
+                last_error: key.last_error.clone(),
+            })
+            .collect()
+    }
+}
+
+static OPENAI_KEY_POOL: OnceLock<OpenAIKeyPool> = OnceLock::new();
+
+pub fn openai_key_pool() -> &'static OpenAIKeyPool {
+    OPENAI_KEY_POOL.get_or_init(|| match OpenAIKeyPool::from_env() {
+        Ok(key_pool) => key_pool,
+        Err(err) => panic!("Failed to configure the OpenAI keys: {:?}", err),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn quota_headers(remaining_requests: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-ratelimit-remaining-requests",
+            HeaderValue::from_static(remaining_requests),
+        );
+        headers.insert(
+            "x-ratelimit-reset-requests",
+            HeaderValue::from_static("6m0s"),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_parse_reset_duration() {
+        assert_eq!(parse_reset_duration("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(
+            parse_reset_duration("20ms"),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(parse_reset_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(
+            parse_reset_duration("1h2m3.5s"),
+            Some(Duration::from_millis(3_723_500))
+        );
+        assert_eq!(parse_reset_duration(""), None);
+        assert_eq!(parse_reset_duration("soon"), None);
+    }
+
+    #[test]
+    fn test_key_pool_balances_by_quota_and_skips_cooling_keys() {
+        let pool = OpenAIKeyPool::new(
+            vec!["sk-first1111".to_owned(), "sk-second2222".to_owned()],
+            Duration::from_secs(60),
+        );
+        let now = Instant::now();
+
+        // unused keys are taken in turn until they report their quota
+        assert_eq!(pool.acquire(now).unwrap().0, 0);
+        assert_eq!(pool.acquire(now).unwrap().0, 1);
+        pool.record_response(0, StatusCode::OK, &quota_headers("10"), now);
+        pool.record_response(1, StatusCode::OK, &quota_headers("500"), now);
+        assert_eq!(pool.acquire(now).unwrap().0, 1);
+
+        pool.record_response(1, StatusCode::TOO_MANY_REQUESTS, &HeaderMap::new(), now);
+        assert_eq!(pool.acquire(now).unwrap().0, 0);
+
+        pool.record_response(0, StatusCode::UNAUTHORIZED, &HeaderMap::new(), now);
+        match pool.acquire(now) {
+            Err(ServiceError::EmbeddingProviderUnavailable(wait)) => assert_eq!(wait, 360),
+            _ => panic!("Expected every key to be cooling down"),
+        }
+
+        // the rate limited key is back once its quota resets
+        let later = now + Duration::from_secs(361);
+        assert_eq!(pool.acquire(later).unwrap().0, 1);
+
+        let usage = pool.usage(later);
+        assert_eq!(usage[0].key, "...1111");
+        assert_eq!(usage[0].failures, 1);
+        assert_eq!(usage[0].cooling_down_seconds, Some(239));
+        assert_eq!(usage[1].rate_limited, 1);
+        assert_eq!(usage[1].requests, 3);
+    }
+
+    fn response(status: StatusCode, retry_after: Option<&'static str>) -> Response {
+        let mut response = http::Response::builder().status(status.as_u16());
+        if let Some(retry_after) = retry_after {
+            response = response.header("retry-after", retry_after);
+        }
+        Response::from(response.body("{}").unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn test_key_failures_move_on_to_the_next_key() {
+        let pool = OpenAIKeyPool::new(
+            vec!["sk-first1111".to_owned(), "sk-second2222".to_owned()],
+            Duration::from_secs(60),
+        );
+
+        // a long Retry-After on the first key does not hold up the second
+        let mut sent_with = vec![];
+        let result = pool
+            .send_with_key(|api_key| {
+                sent_with.push(api_key.clone());
+                let status = if api_key == "sk-first1111" {
+                    StatusCode::TOO_MANY_REQUESTS
+                } else {
+                    StatusCode::OK
+                };
+                async move { Ok(response(status, Some("3600"))) }
+            })
+            .await;
+        assert!(matches!(result, Ok(sent) if sent.status() == StatusCode::OK));
+        assert_eq!(sent_with, vec!["sk-first1111", "sk-second2222"]);
+
+        // once the second key is rejected too, no key is usable until its cooldown ends
+        let result = pool
+            .send_with_key(|_| async { Ok(response(StatusCode::UNAUTHORIZED, None)) })
+            .await;
+        match result {
+            Err(ProviderCallError::Transient { retry_after, .. }) => {
+                assert_eq!(retry_after, Some(Duration::from_secs(600)))
+            }
+            _ => panic!("Expected a transient failure once every key failed"),
+        }
+        let mut sent = 0;
+        let result = pool
+            .send_with_key(|_| {
+                sent += 1;
+                async { Ok(response(StatusCode::OK, None)) }
+            })
+            .await;
+        assert!(matches!(result, Err(ProviderCallError::Transient { .. })));
+        assert_eq!(sent, 0);
+    }
+
+    #[test]
+    fn test_key_pool_cools_down_failing_keys() {
+        let pool = OpenAIKeyPool::new(vec!["sk-only".to_owned()], Duration::from_secs(60));
+        let now = Instant::now();
+
+        for _ in 0..KEY_FAILURE_THRESHOLD {
+            assert!(pool.acquire(now).is_ok());
+            pool.record_error(0, "timed out".to_owned(), now);
+        }
+        assert!(pool.acquire(now).is_err());
+        assert!(pool.acquire(now + Duration::from_secs(61)).is_ok());
+    }
+}
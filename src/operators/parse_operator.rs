@@ -1,6 +1,7 @@
 use regex::Regex;
 use regex_split::RegexSplit;
 use scraper::Html;
+use sha2::{Digest, Sha256};
 use std::cmp;
 
 pub fn remove_large_chunks(cur_chunks: Vec<String>) -> Vec<String> {
@@ -89,3 +90,37 @@ pub fn chunk_document(document: String) -> Vec<String> {
 
     remove_large_chunks(groups)
 }
+
+/// Hex sha256 of the text of `doc_chunks` with whitespace collapsed, so edits to markup or
+/// spacing alone leave it unchanged
+pub fn content_hash(doc_chunks: &[String]) -> String {
+    let normalized_text = doc_chunks
+        .iter()
+        .flat_map(|chunk| chunk.split_whitespace())
+        .collect::<Vec<&str>>()
+        .join(" ");
+
+    format!("{:x}", Sha256::digest(normalized_text.as_bytes()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_content_hash_ignores_markup_and_spacing() {
+        let original = content_hash(&chunk_document(
+            "<p>The tide came in.</p>\n<p>The boats  rose with it.</p>".to_owned(),
+        ));
+        let reformatted = content_hash(&chunk_document(
+            "<div>The tide came in.</div> <div>The boats rose   with it.</div>".to_owned(),
+        ));
+        let edited = content_hash(&chunk_document(
+            "<p>The tide went out.</p>\n<p>The boats rose with it.</p>".to_owned(),
+        ));
+
+        assert_eq!(original, reformatted);
+        assert_ne!(original, edited);
+        assert_eq!(original.len(), 64);
+    }
+}
@@ -0,0 +1,552 @@
+use super::{
+    embedding_operator::average_embeddings,
+    vector_store_operator::{
+        normalize, ScoredVectorRecord, VectorFilter, VectorPayload, VectorRecord, VectorStore,
+    },
+};
+use async_trait::async_trait;
+use qdrant_client::{
+    prelude::Value,
+    qdrant::{value::Kind, ListValue, Struct},
+};
+use sqlx::{postgres::PgRow, Pool, Postgres, QueryBuilder, Row};
+
+/// Stores vectors in Postgres with the pgvector extension. Every collection shares the
+/// `vector_points` table, and gets its own partial HNSW index over the vectors cast to the
+/// collection's dimension, which is why every distance below is computed on that same cast.
+pub struct PgVectorStore {
+    pool: Pool<Postgres>,
+}
+
+impl PgVectorStore {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        Self { pool }
+    }
+
+    /// Checks that the migrations created the tables, which they only do when pgvector is
+    /// available to the database
+    pub async fn check_installed(&self) -> anyhow::Result<()> {
+        let installed: bool = sqlx::query_scalar("SELECT to_regclass('vector_points') IS NOT NULL")
+            .fetch_one(&self.pool)
+            .await?;
+
+        if !installed {
+            anyhow::bail!(
+                "The vector_points table is missing, pgvector has to be installed before the \
+                 migrations run"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Resolves an alias to its collection, returning the collection's name and dimension
+    async fn resolve_collection(&self, collection_name: &str) -> anyhow::Result<(String, u64)> {
+        let collection: Option<(String, i32)> = sqlx::query_as(
+            r#"
+            SELECT vector_collections.name, vector_collections.size
+            FROM vector_collections
+            LEFT JOIN vector_aliases ON vector_aliases.collection_name = vector_collections.name
+            WHERE vector_collections.name = $1 OR vector_aliases.alias = $1
+            LIMIT 1
+            "#,
+        )
+        .bind(collection_name)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        collection
+            .map(|(name, size)| (name, size as u64))
+            .ok_or_else(|| anyhow::anyhow!("Collection {} does not exist", collection_name))
+    }
+
+    async fn search_by_vector(
+        &self,
+        collection_name: &str,
+        vector: &[f32],
+        filter: &VectorFilter,
+        exclude_ids: &[uuid::Uuid],
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<ScoredVectorRecord>> {
+        let (collection_name, size) = self.resolve_collection(collection_name).await?;
+        check_collection_name(&collection_name)?;
+        check_dimension(vector, size)?;
+        let vector = vector_to_text(vector);
+
+        // The HNSW index hands back only its `ef_search` nearest candidates before the filter
+        // applies, so a selective filter could leave fewer results than match, or none. Those
+        // searches rank every matching point exactly instead, which the materialized candidates
+        // force, and stay cheap since few points match.
+        let exact = filter.has_id.is_some() || !filter.must.is_empty();
+        let mut query = if exact {
+            QueryBuilder::<Postgres>::new(format!(
+                "WITH candidates AS MATERIALIZED (SELECT id, payload, embedding FROM vector_points \
+                 WHERE collection_name = '{collection_name}'"
+            ))
+        } else {
+            // the collection name and ordering are written out in full so the planner can
+            // match them against the collection's partial index
+            let mut query = QueryBuilder::<Postgres>::new(format!(
+                "SELECT id, payload, 1 - (embedding::vector({size}) <=> "
+            ));
+            query.push_bind(vector.clone()).push(format!(
+                "::vector({size})) AS score FROM vector_points \
+                 WHERE collection_name = '{collection_name}'"
+            ));
+            query
+        };
+        push_filter(&mut query, filter);
+        if !exclude_ids.is_empty() {
+            query
+                .push(" AND NOT (id = ANY(")
+                .push_bind(exclude_ids.to_vec())
+                .push("))");
+        }
+        if exact {
+            query
+                .push(format!(
+                    ") SELECT id, payload, 1 - (embedding::vector({size}) <=> "
+                ))
+                .push_bind(vector.clone())
+                .push(format!("::vector({size})) AS score FROM candidates"));
+        }
+        query
+            .push(format!(" ORDER BY embedding::vector({size}) <=> "))
+            .push_bind(vector)
+            .push(format!("::vector({size}) LIMIT "))
+            .push_bind(limit as i64)
+            .push(" OFFSET ")
+            .push_bind(offset as i64);
+
+        let rows = query.build().fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(ScoredVectorRecord {
+                    id: row.try_get("id")?,
+                    score: row.try_get::<f64, _>("score")? as f32,
+                    payload: json_to_payload(row.try_get("payload")?),
+                })
+            })
+            .collect()
+    }
+}
+
+fn check_collection_name(collection_name: &str) -> anyhow::Result<()> {
+    if collection_name.is_empty()
+        || !collection_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(anyhow::anyhow!(
+            "Collection name {} may only contain letters, digits and underscores",
+            collection_name
+        ));
+    }
+    Ok(())
+}
+
+fn check_dimension(vector: &[f32], size: u64) -> anyhow::Result<()> {
+    if vector.len() as u64 != size {
+        return Err(anyhow::anyhow!(
+            "Vector has {} dimensions, the collection expects {}",
+            vector.len(),
+            size
+        ));
+    }
+    Ok(())
+}
+
+fn push_filter(query: &mut QueryBuilder<'_, Postgres>, filter: &VectorFilter) {
+    if let Some(ids) = filter.has_id.as_ref() {
+        query
+            .push(" AND id = ANY(")
+            .push_bind(ids.clone())
+            .push(")");
+    }
+    // ->> reads integers and strings alike as text, the same way payload_integer accepts both
+    for payload_match in filter.must.iter() {
+        query
+            .push(" AND payload ->> ")
+            .push_bind(payload_match.key.clone())
+            .push(" = ")
+            .push_bind(payload_match.value.to_string());
+    }
+    for payload_match in filter.must_not.iter() {
+        query
+            .push(" AND payload ->> ")
+            .push_bind(payload_match.key.clone())
+            .push(" IS DISTINCT FROM ")
+            .push_bind(payload_match.value.to_string());
+    }
+}
+
+pub fn vector_to_text(vector: &[f32]) -> String {
+    format!(
+        "[{}]",
+        vector
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    )
+}
+
+pub fn text_to_vector(text: &str) -> anyhow::Result<Vec<f32>> {
+    let values = text.trim().trim_start_matches('[').trim_end_matches(']');
+    if values.is_empty() {
+        return Ok(vec![]);
+    }
+
+    values
+        .split(',')
+        .map(|value| {
+            value
+                .trim()
+                .parse::<f32>()
+                .map_err(|err| anyhow::anyhow!("Invalid vector value {}: {}", value, err))
+        })
+        .collect()
+}
+
+fn value_to_json(value: Value) -> serde_json::Value {
+    match value.kind {
+        Some(Kind::DoubleValue(num)) => serde_json::Number::from_f64(num)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        Some(Kind::IntegerValue(num)) => num.into(),
+        Some(Kind::StringValue(s)) => s.into(),
+        Some(Kind::BoolValue(b)) => b.into(),
+        Some(Kind::StructValue(s)) => serde_json::Value::Object(
+            s.fields
+                .into_iter()
+                .map(|(key, value)| (key, value_to_json(value)))
+                .collect(),
+        ),
+        Some(Kind::ListValue(list)) => {
+            serde_json::Value::Array(list.values.into_iter().map(value_to_json).collect())
+        }
+        Some(Kind::NullValue(_)) | None => serde_json::Value::Null,
+    }
+}
+
+fn json_to_value(value: serde_json::Value) -> Value {
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(b) => Kind::BoolValue(b),
+        serde_json::Value::Number(num) => match num.as_i64() {
+            Some(num) => Kind::IntegerValue(num),
+            None => Kind::DoubleValue(num.as_f64().unwrap_or(0.0)),
+        },
+        serde_json::Value::String(s) => Kind::StringValue(s),
+        serde_json::Value::Array(values) => Kind::ListValue(ListValue {
+            values: values.into_iter().map(json_to_value).collect(),
+        }),
+        serde_json::Value::Object(fields) => Kind::StructValue(Struct {
+            fields: fields
+                .into_iter()
+                .map(|(key, value)| (key, json_to_value(value)))
+                .collect(),
+        }),
+    };
+    Value { kind: Some(kind) }
+}
+
+pub fn payload_to_json(payload: VectorPayload) -> serde_json::Value {
+    serde_json::Value::Object(
+        payload
+            .into_iter()
+            .map(|(key, value)| (key, value_to_json(value)))
+            .collect(),
+    )
+}
+
+pub fn json_to_payload(json: serde_json::Value) -> VectorPayload {
+    match json {
+        serde_json::Value::Object(fields) => fields
+            .into_iter()
+            .map(|(key, value)| (key, json_to_value(value)))
+            .collect(),
+        _ => VectorPayload::new(),
+    }
+}
+
+fn row_to_record(row: &PgRow) -> anyhow::Result<VectorRecord> {
+    Ok(VectorRecord {
+        id: row.try_get("id")?,
+        vector: text_to_vector(row.try_get("embedding")?)?,
+        payload: json_to_payload(row.try_get("payload")?),
+    })
+}
+
+#[async_trait]
+impl VectorStore for PgVectorStore {
+    async fn create_collection(&self, collection_name: &str, size: u64) -> anyhow::Result<()> {
+        check_collection_name(collection_name)?;
+        if self.alias_target(collection_name).await?.is_some() {
+            return Ok(());
+        }
+
+        sqlx::query(
+            "INSERT INTO vector_collections (name, size) VALUES ($1, $2) ON CONFLICT (name) DO NOTHING",
+        )
+        .bind(collection_name)
+        .bind(size as i32)
+        .execute(&self.pool)
+        .await?;
+
+        let (_, size) = self.resolve_collection(collection_name).await?;
+        // names were checked above, so they are safe to use in the index definition
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS {collection_name}_hnsw ON vector_points \
+             USING hnsw ((embedding::vector({size})) vector_cosine_ops) \
+             WHERE collection_name = '{collection_name}'"
+        ))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_collection(&self, collection_name: &str) -> anyhow::Result<()> {
+        check_collection_name(collection_name)?;
+
+        let mut transaction = self.pool.begin().await?;
+        // vector_points and vector_aliases rows go with the collection
+        sqlx::query("DELETE FROM vector_collections WHERE name = $1")
+            .bind(collection_name)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(&format!("DROP INDEX IF EXISTS {collection_name}_hnsw"))
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn switch_alias(&self, alias_name: &str, collection_name: &str) -> anyhow::Result<()> {
+        let shadowed: Option<String> =
+            sqlx::query_scalar("SELECT name FROM vector_collections WHERE name = $1")
+                .bind(alias_name)
+                .fetch_optional(&self.pool)
+                .await?;
+        if shadowed.is_some() {
+            return Err(anyhow::anyhow!(
+                "Alias {} would shadow a collection with the same name",
+                alias_name
+            ));
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO vector_aliases (alias, collection_name) VALUES ($1, $2)
+            ON CONFLICT (alias) DO UPDATE SET collection_name = EXCLUDED.collection_name
+            "#,
+        )
+        .bind(alias_name)
+        .bind(collection_name)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn alias_target(&self, alias_name: &str) -> anyhow::Result<Option<String>> {
+        let collection_name =
+            sqlx::query_scalar("SELECT collection_name FROM vector_aliases WHERE alias = $1")
+                .bind(alias_name)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(collection_name)
+    }
+
+    async fn count(&self, collection_name: &str) -> anyhow::Result<u64> {
+        let (collection_name, _) = self.resolve_collection(collection_name).await?;
+
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM vector_points WHERE collection_name = $1")
+                .bind(collection_name)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(count as u64)
+    }
+
+    async fn upsert(
+        &self,
+        collection_name: &str,
+        records: Vec<VectorRecord>,
+    ) -> anyhow::Result<()> {
+        let (collection_name, size) = self.resolve_collection(collection_name).await?;
+        for record in records.iter() {
+            check_dimension(&record.vector, size)?;
+        }
+
+        let mut transaction = self.pool.begin().await?;
+        for record in records {
+            sqlx::query(
+                r#"
+                INSERT INTO vector_points (collection_name, id, embedding, payload)
+                VALUES ($1, $2, $3::vector, $4)
+                ON CONFLICT (collection_name, id) DO UPDATE
+                SET embedding = EXCLUDED.embedding, payload = EXCLUDED.payload
+                "#,
+            )
+            .bind(&collection_name)
+            .bind(record.id)
+            .bind(vector_to_text(&normalize(record.vector)))
+            .bind(payload_to_json(record.payload))
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, collection_name: &str, ids: Vec<uuid::Uuid>) -> anyhow::Result<()> {
+        let (collection_name, _) = self.resolve_collection(collection_name).await?;
+
+        sqlx::query("DELETE FROM vector_points WHERE collection_name = $1 AND id = ANY($2)")
+            .bind(collection_name)
+            .bind(ids)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn retrieve(
+        &self,
+        collection_name: &str,
+        ids: Vec<uuid::Uuid>,
+    ) -> anyhow::Result<Vec<VectorRecord>> {
+        let (collection_name, _) = self.resolve_collection(collection_name).await?;
+
+        let rows = sqlx::query(
+            r#"
+            SELECT id, embedding::text AS embedding, payload
+            FROM vector_points
+            WHERE collection_name = $1 AND id = ANY($2)
+            "#,
+        )
+        .bind(collection_name)
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(row_to_record).collect()
+    }
+
+    async fn search(
+        &self,
+        collection_name: &str,
+        vector: Vec<f32>,
+        filter: VectorFilter,
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<ScoredVectorRecord>> {
+        self.search_by_vector(collection_name, &vector, &filter, &[], limit, offset)
+            .await
+    }
+
+    async fn recommend(
+        &self,
+        collection_name: &str,
+        positive: Vec<uuid::Uuid>,
+        filter: VectorFilter,
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<ScoredVectorRecord>> {
+        let positive_records = self.retrieve(collection_name, positive.clone()).await?;
+        if let Some(missing) = positive
+            .iter()
+            .find(|id| !positive_records.iter().any(|record| record.id == **id))
+        {
+            return Err(anyhow::anyhow!("No point with id {} found", missing));
+        }
+
+        let average = average_embeddings(
+            positive_records
+                .into_iter()
+                .map(|record| record.vector)
+                .collect(),
+        )
+        .map_err(|err| anyhow::anyhow!("No positive points to recommend from: {}", err))?;
+
+        self.search_by_vector(collection_name, &average, &filter, &positive, limit, offset)
+            .await
+    }
+
+    async fn scroll(
+        &self,
+        collection_name: &str,
+        filter: VectorFilter,
+        offset: Option<uuid::Uuid>,
+        limit: u64,
+    ) -> anyhow::Result<(Vec<VectorRecord>, Option<uuid::Uuid>)> {
+        let (collection_name, _) = self.resolve_collection(collection_name).await?;
+
+        let mut query = QueryBuilder::<Postgres>::new(
+            "SELECT id, embedding::text AS embedding, payload FROM vector_points WHERE collection_name = ",
+        );
+        query.push_bind(collection_name);
+        if let Some(offset) = offset {
+            query.push(" AND id >= ").push_bind(offset);
+        }
+        push_filter(&mut query, &filter);
+        // one extra row tells where the next page starts
+        query
+            .push(" ORDER BY id LIMIT ")
+            .push_bind(limit as i64 + 1);
+
+        let rows = query.build().fetch_all(&self.pool).await?;
+        let mut records = rows
+            .iter()
+            .map(row_to_record)
+            .collect::<anyhow::Result<Vec<VectorRecord>>>()?;
+        let next_offset = if records.len() as u64 > limit {
+            records.pop().map(|record| record.id)
+        } else {
+            None
+        };
+
+        Ok((records, next_offset))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_vector_text_round_trip() {
+        let vector = vec![0.5, -1.0, 0.125, 3.0];
+        assert_eq!(vector_to_text(&vector), "[0.5,-1,0.125,3]");
+        assert_eq!(text_to_vector(&vector_to_text(&vector)).unwrap(), vector);
+        assert_eq!(text_to_vector("[]").unwrap(), Vec::<f32>::new());
+        assert!(text_to_vector("[1,a]").is_err());
+    }
+
+    #[test]
+    pub fn test_payload_json_round_trip() {
+        let mut payload = VectorPayload::new();
+        payload.insert("story_id".to_owned(), "12".to_string().into());
+        payload.insert("index".to_owned(), 3_i64.into());
+
+        let json = payload_to_json(payload);
+        assert_eq!(json, serde_json::json!({"story_id": "12", "index": 3}));
+
+        let payload = json_to_payload(json);
+        assert_eq!(
+            payload.get("story_id").and_then(|value| value.kind.clone()),
+            Some(Kind::StringValue("12".to_owned()))
+        );
+        assert_eq!(
+            payload.get("index").and_then(|value| value.kind.clone()),
+            Some(Kind::IntegerValue(3))
+        );
+    }
+}
@@ -1,104 +1,178 @@
-use super::doc_group_embedding_operator::DocGroupQdrantPointIdContainer;
+use super::{
+    doc_group_embedding_operator::DocGroupQdrantPointIdContainer,
+    embedding_operator::ChapterGroupEmbedding,
+    vector_outbox_operator::VectorMutation,
+    vector_store_operator::{
+        PayloadMatch, QdrantCollectionConfig, ScoredVectorRecord, VectorFilter, VectorQuantization,
+        VectorRecord, VectorStore,
+    },
+};
 use crate::{
-    data::models::{DocEmbedding, DocEmbeddingQdrantPayload, DocGroupEmbeddingQdrantPayload},
+    data::models::{
+        DocEmbedding, DocEmbeddingQdrantPayload, DocGroupEmbedding, DocGroupEmbeddingQdrantPayload,
+        DocGroupSpec, NamedChapterRange, NamedChapterRangeQdrantPayload, StoryEmbedding,
+        StoryEmbeddingQdrantPayload,
+    },
     errors::ServiceError,
 };
 use qdrant_client::{
     prelude::{QdrantClient, QdrantClientConfig},
-    qdrant::{
-        self, point_id::PointIdOptions, r#match::MatchValue,
-        with_payload_selector::SelectorOptions, CreateCollection, Distance, FieldCondition,
-        HasIdCondition, Match, PointId, PointStruct, RecommendPoints, SearchPoints, VectorParams,
-        VectorsConfig, WithPayloadSelector,
-    },
+    qdrant::{CompressionRatio, Distance},
 };
+use std::time::Duration;
+
+pub fn doc_collection_name(doc_group: Option<DocGroupSpec>) -> String {
+    match doc_group {
+        Some(doc_group) => doc_group.collection_name(),
+        None => "doc_embeddings".to_owned(),
+    }
+}
 
-pub async fn get_qdrant_connection() -> Result<QdrantClient, ServiceError> {
-    let qdrant_url = std::env::var("QDRANT_URL").expect("QDRANT_URL must be set");
-    let qdrant_api_key = std::env::var("QDRANT_API_KEY").expect("QDRANT_API_KEY must be set");
+pub const STORY_EMBEDDINGS_COLLECTION: &str = "story_embeddings";
+pub const NAMED_CHAPTER_RANGES_COLLECTION: &str = "named_chapter_ranges";
+
+pub fn embedding_size() -> u64 {
+    std::env::var("EMBEDDING_SIZE")
+        .ok()
+        .and_then(|embedding_size| embedding_size.parse::<u64>().ok())
+        .unwrap_or(1536)
+}
+
+fn qdrant_duration_var(name: &str, default_seconds: u64) -> Result<Duration, ServiceError> {
+    match std::env::var(name) {
+        Ok(value) => value.parse::<u64>().map(Duration::from_secs).map_err(|_| {
+            ServiceError::QdrantConnectionError(anyhow::anyhow!(
+                "{} must be a whole number of seconds",
+                name
+            ))
+        }),
+        Err(_) => Ok(Duration::from_secs(default_seconds)),
+    }
+}
+
+/// Builds the client shared by every request; TLS is used whenever QDRANT_URL is https
+pub fn qdrant_client_from_env() -> Result<QdrantClient, ServiceError> {
+    let qdrant_url = std::env::var("QDRANT_URL").map_err(|_| {
+        ServiceError::QdrantConnectionError(anyhow::anyhow!("QDRANT_URL must be set"))
+    })?;
+    let qdrant_api_key = std::env::var("QDRANT_API_KEY").map_err(|_| {
+        ServiceError::QdrantConnectionError(anyhow::anyhow!("QDRANT_API_KEY must be set"))
+    })?;
     let mut config = QdrantClientConfig::from_url(qdrant_url.as_str());
     config.api_key = Some(qdrant_api_key);
+    config.timeout = qdrant_duration_var("QDRANT_TIMEOUT_SECONDS", 5)?;
+    config.connect_timeout = qdrant_duration_var("QDRANT_CONNECT_TIMEOUT_SECONDS", 5)?;
+    config.keep_alive_while_idle = std::env::var("QDRANT_KEEP_ALIVE")
+        .map(|value| value != "false")
+        .unwrap_or(true);
     QdrantClient::new(Some(config)).map_err(ServiceError::QdrantConnectionError)
 }
 
+fn collection_config_var<T: std::str::FromStr>(name: &str) -> Result<Option<T>, ServiceError> {
+    match std::env::var(name) {
+        Ok(value) => value.parse::<T>().map(Some).map_err(|_| {
+            ServiceError::QdrantCollectionConfigError(anyhow::anyhow!(
+                "{} has an invalid value {}",
+                name,
+                value
+            ))
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+pub fn parse_distance(distance: &str) -> Option<Distance> {
+    match distance {
+        "cosine" => Some(Distance::Cosine),
+        "dot" => Some(Distance::Dot),
+        "euclid" => Some(Distance::Euclid),
+        _ => None,
+    }
+}
+
+/// `scalar`, or `product` with an optional compression ratio such as `product:x32`
+pub fn parse_quantization(quantization: &str) -> Option<Option<VectorQuantization>> {
+    let compression = match quantization {
+        "none" => return Some(None),
+        "scalar" => return Some(Some(VectorQuantization::Scalar)),
+        "product" => CompressionRatio::X16,
+        "product:x4" => CompressionRatio::X4,
+        "product:x8" => CompressionRatio::X8,
+        "product:x16" => CompressionRatio::X16,
+        "product:x32" => CompressionRatio::X32,
+        "product:x64" => CompressionRatio::X64,
+        _ => return None,
+    };
+    Some(Some(VectorQuantization::Product(compression)))
+}
+
+/// Settings for collections created from now on, see the README for the variables
+pub fn qdrant_collection_config_from_env() -> Result<QdrantCollectionConfig, ServiceError> {
+    let mut config = QdrantCollectionConfig::default();
+
+    if let Ok(distance) = std::env::var("QDRANT_DISTANCE") {
+        config.distance = parse_distance(&distance).ok_or_else(|| {
+            ServiceError::QdrantCollectionConfigError(anyhow::anyhow!(
+                "QDRANT_DISTANCE must be cosine, dot or euclid"
+            ))
+        })?;
+    }
+    if let Ok(quantization) = std::env::var("QDRANT_QUANTIZATION") {
+        config.quantization = parse_quantization(&quantization).ok_or_else(|| {
+            ServiceError::QdrantCollectionConfigError(anyhow::anyhow!(
+                "QDRANT_QUANTIZATION must be none, scalar or product:x4 to product:x64"
+            ))
+        })?;
+    }
+    config.hnsw_m = collection_config_var("QDRANT_HNSW_M")?;
+    config.hnsw_ef_construct = collection_config_var("QDRANT_HNSW_EF_CONSTRUCT")?;
+    config.on_disk = collection_config_var("QDRANT_ON_DISK")?.unwrap_or(false);
+    config.shard_number = collection_config_var("QDRANT_SHARD_NUMBER")?;
+    config.replication_factor = collection_config_var("QDRANT_REPLICATION_FACTOR")?;
+
+    Ok(config)
+}
+
+/// Creating a collection that already exists is not an error, so the call can be repeated
 pub async fn create_doc_group_collection_qdrant_query(
-    doc_group_size: i32,
+    doc_group: DocGroupSpec,
+    vector_store: &dyn VectorStore,
 ) -> Result<(), ServiceError> {
-    let qdrant_client = get_qdrant_connection().await.unwrap();
-
-    let embedding_size = std::env::var("EMBEDDING_SIZE").unwrap_or("1536".to_owned());
-    let embedding_size = embedding_size.parse::<u64>().unwrap_or(1536);
-
-    let _ = qdrant_client
-        .create_collection(&CreateCollection {
-            collection_name: format!("doc_group_{}", doc_group_size),
-            vectors_config: Some(VectorsConfig {
-                config: Some(qdrant_client::qdrant::vectors_config::Config::Params(
-                    VectorParams {
-                        size: embedding_size,
-                        distance: Distance::Cosine.into(),
-                        hnsw_config: None,
-                        quantization_config: None,
-                        on_disk: None,
-                    },
-                )),
-            }),
-            ..Default::default()
-        })
+    vector_store
+        .create_collection(&doc_group.collection_name(), embedding_size())
         .await
-        .map_err(|err| {
-            log::info!("Failed to create collection: {:?}", err);
-        });
+        .map_err(ServiceError::CreateCollectionQdrantError)
+}
 
-    Ok(())
+pub async fn count_points_qdrant_query(
+    collection_name: String,
+    vector_store: &dyn VectorStore,
+) -> Result<u64, ServiceError> {
+    vector_store
+        .count(&collection_name)
+        .await
+        .map_err(ServiceError::CountPointsQdrantError)
 }
 
 pub async fn get_doc_embeddings_qdrant_query(
     qdrant_points: Vec<uuid::Uuid>,
+    vector_store: &dyn VectorStore,
 ) -> Result<Vec<Vec<f32>>, ServiceError> {
-    let limit = Some(qdrant_points.len() as u32);
-    let qdrant_client = get_qdrant_connection().await?;
+    let limit = qdrant_points.len() as u64;
+    let filter = VectorFilter::has_id(qdrant_points);
 
     let mut resulting_vectors: Vec<Vec<f32>> = vec![];
     let mut offset = None;
 
     loop {
-        let scroll_points = qdrant::ScrollPoints {
-            collection_name: "doc_embeddings".into(),
-            filter: Some(qdrant::Filter {
-                should: vec![HasIdCondition {
-                    has_id: qdrant_points
-                        .clone()
-                        .into_iter()
-                        .map(|id| id.to_string().into())
-                        .collect(),
-                }
-                .into()],
-                ..Default::default()
-            }),
-            offset,
-            limit,
-            with_vectors: Some(true.into()),
-            with_payload: Some(false.into()),
-            ..Default::default()
-        };
-
-        let scroll_response = qdrant_client
-            .scroll(&scroll_points)
+        let (records, next_offset) = vector_store
+            .scroll("doc_embeddings", filter.clone(), offset, limit)
             .await
             .map_err(ServiceError::ScrollDocEmbeddingQdrantError)?;
 
-        let cur_vectors = scroll_response
-            .result
-            .into_iter()
-            .flat_map(|res| match res.vectors?.vectors_options? {
-                qdrant::vectors::VectorsOptions::Vector(vector) => Some(vector.data),
-                _ => None,
-            })
-            .collect::<Vec<Vec<f32>>>();
-        resulting_vectors.extend(cur_vectors);
-
-        offset = scroll_response.next_page_offset;
+        resulting_vectors.extend(records.into_iter().map(|record| record.vector));
+
+        offset = next_offset;
         if offset.is_none() {
             break;
         }
@@ -107,257 +181,237 @@ pub async fn get_doc_embeddings_qdrant_query(
     Ok(resulting_vectors)
 }
 
-/// Returns the PointStructs added
-pub async fn insert_doc_group_embedding_qdrant_query(
+#[derive(Debug, Clone)]
+pub struct QdrantVectorPoint {
+    pub point_id: uuid::Uuid,
+    pub vector: Vec<f32>,
+    pub payload: DocEmbeddingQdrantPayload,
+}
+
+pub async fn get_points_with_vectors_qdrant_query(
+    collection_name: String,
+    qdrant_point_ids: Vec<uuid::Uuid>,
+    vector_store: &dyn VectorStore,
+) -> Result<Vec<QdrantVectorPoint>, ServiceError> {
+    let records = vector_store
+        .retrieve(&collection_name, qdrant_point_ids)
+        .await
+        .map_err(ServiceError::GetPointsQdrantError)?;
+
+    Ok(records
+        .into_iter()
+        .map(|record| QdrantVectorPoint {
+            point_id: record.id,
+            vector: record.vector,
+            payload: record.payload.into(),
+        })
+        .collect())
+}
+
+/// Returns the DocGroupEmbeddings to store and the upserts the relay applies once they commit.
+/// A group that was indexed before keeps its point id.
+pub fn doc_group_embedding_vector_mutations(
     existing_doc_groups: Vec<DocGroupQdrantPointIdContainer>,
-    vectors: Vec<Vec<f32>>,
+    chapter_groups: Vec<ChapterGroupEmbedding>,
     story_id: i64,
-    doc_group_size: i32,
-) -> Result<Vec<PointStruct>, ServiceError> {
-    let points: Vec<PointStruct> = vectors
+    doc_group: DocGroupSpec,
+) -> (Vec<DocGroupEmbedding>, Vec<VectorMutation>) {
+    chapter_groups
         .into_iter()
-        .enumerate()
-        .map(|(idx, vector)| {
+        .map(|chapter_group| {
             let similar_existing_doc_group_point_id = existing_doc_groups
                 .iter()
-                .find(|doc_group| doc_group.index == idx as i32)
+                .find(|doc_group| doc_group.index == chapter_group.index)
                 .map(|doc_group| doc_group.qdrant_point_id);
 
-            PointStruct {
-                id: Some(
-                    (similar_existing_doc_group_point_id.unwrap_or(uuid::Uuid::new_v4()))
-                        .to_string()
+            let now = chrono::Utc::now().naive_utc();
+            let doc_group_embedding = DocGroupEmbedding {
+                id: uuid::Uuid::new_v4(),
+                story_id,
+                doc_group_size: doc_group.doc_group_size,
+                doc_group_stride: doc_group.doc_group_stride,
+                index: chapter_group.index,
+                first_index: chapter_group.first_index,
+                last_index: chapter_group.last_index,
+                qdrant_point_id: similar_existing_doc_group_point_id
+                    .unwrap_or(uuid::Uuid::new_v4()),
+                created_at: now,
+                updated_at: now,
+            };
+
+            let mutation = VectorMutation::Upsert {
+                collection_name: doc_group.collection_name(),
+                record: VectorRecord {
+                    id: doc_group_embedding.qdrant_point_id,
+                    vector: chapter_group.embedding,
+                    payload: DocGroupEmbeddingQdrantPayload::from(doc_group_embedding.clone())
                         .into(),
-                ),
-                vectors: Some(vector.into()),
-                payload: DocGroupEmbeddingQdrantPayload {
-                    story_id,
-                    doc_group_size,
-                    index: idx as i32,
-                }
-                .into(),
-            }
-        })
-        .collect();
-
-    let qdrant_client = get_qdrant_connection().await?;
+                },
+            };
 
-    qdrant_client
-        .upsert_points(
-            format!("doc_group_{}", doc_group_size),
-            None,
-            points.clone(),
-            None,
-        )
-        .await
-        .map_err(ServiceError::UpsertDocGroupEmbeddingQdrantError)?;
-
-    Ok(points)
+            (doc_group_embedding, mutation)
+        })
+        .unzip()
 }
 
-pub async fn delete_reinsert_doc_embedding_qdrant_query(
-    point_id_to_delete: Option<uuid::Uuid>,
+/// What the relay applies once a chapter write commits: the new point in every collection, and
+/// the removal of the point it replaced
+pub fn doc_embedding_vector_mutations(
     doc_embedding: DocEmbedding,
     vector: Vec<f32>,
-) -> Result<(), ServiceError> {
-    let client = get_qdrant_connection().await?;
-
-    if let Some(point_id_to_delete) = point_id_to_delete {
-        let point_ids_to_delete: Vec<PointId> = vec![point_id_to_delete.to_string().into()];
+    replaced_point_id: Option<uuid::Uuid>,
+    collection_names: &[String],
+) -> Vec<VectorMutation> {
+    let record = VectorRecord {
+        id: doc_embedding.qdrant_point_id,
+        vector,
+        payload: DocEmbeddingQdrantPayload::from(doc_embedding).into(),
+    };
 
-        let filter = qdrant::Filter {
-            should: vec![HasIdCondition {
-                has_id: point_ids_to_delete,
-            }
-            .into()],
-            ..Default::default()
-        };
+    let mut mutations = vec![];
+    for collection_name in collection_names {
+        mutations.push(VectorMutation::Upsert {
+            collection_name: collection_name.clone(),
+            record: record.clone(),
+        });
+        if let Some(replaced_point_id) = replaced_point_id {
+            mutations.push(VectorMutation::Delete {
+                collection_name: collection_name.clone(),
+                point_id: replaced_point_id,
+            });
+        }
+    }
+    mutations
+}
 
-        client
-            .delete_points("doc_embeddings", None, &filter.into(), None)
-            .await
-            .map_err(ServiceError::DeleteDocEmbeddingQdrantError)?;
+pub fn story_embedding_vector_mutation(
+    story_embedding: StoryEmbedding,
+    vector: Vec<f32>,
+) -> VectorMutation {
+    VectorMutation::Upsert {
+        collection_name: STORY_EMBEDDINGS_COLLECTION.to_owned(),
+        record: VectorRecord {
+            id: story_embedding.qdrant_point_id,
+            vector,
+            payload: StoryEmbeddingQdrantPayload::from(story_embedding).into(),
+        },
     }
+}
 
-    let point = PointStruct {
-        id: Some(doc_embedding.qdrant_point_id.to_string().into()),
-        vectors: Some(vector.into()),
-        payload: DocEmbeddingQdrantPayload::from(doc_embedding).into(),
-    };
+pub fn named_chapter_range_vector_mutation(
+    named_chapter_range: NamedChapterRange,
+    vector: Vec<f32>,
+) -> VectorMutation {
+    VectorMutation::Upsert {
+        collection_name: NAMED_CHAPTER_RANGES_COLLECTION.to_owned(),
+        record: VectorRecord {
+            id: named_chapter_range.qdrant_point_id,
+            vector,
+            payload: NamedChapterRangeQdrantPayload::from(named_chapter_range).into(),
+        },
+    }
+}
 
-    client
-        .upsert_points_blocking("doc_embeddings", None, vec![point], None)
+pub async fn recommend_story_embeddings_qdrant_query(
+    positive_qdrant_ids: Vec<uuid::Uuid>,
+    limit: Option<u64>,
+    page: Option<u64>,
+    vector_store: &dyn VectorStore,
+) -> Result<Vec<QdrantPoints>, ServiceError> {
+    let limit = limit.unwrap_or(10);
+    let records = vector_store
+        .recommend(
+            STORY_EMBEDDINGS_COLLECTION,
+            positive_qdrant_ids,
+            VectorFilter::default(),
+            limit,
+            page.unwrap_or(0) * limit,
+        )
         .await
-        .map_err(ServiceError::UpsertDocEmbeddingQdrantError)?;
+        .map_err(ServiceError::RecommendQdrantDocEmbeddingGroupError)?;
 
-    Ok(())
+    Ok(records.into_iter().map(QdrantPoints::from).collect())
 }
 
 pub async fn recommend_group_doc_embeddings_qdrant_query(
     positive_qdrant_ids: Vec<uuid::Uuid>,
-    doc_group_size: i32,
+    doc_group: DocGroupSpec,
     limit: Option<u64>,
     page: Option<u64>,
-) -> Result<Vec<i64>, ServiceError> {
-    let client = get_qdrant_connection().await?;
-
-    let recommend_result = client
-        .recommend(&RecommendPoints {
-            collection_name: format!("doc_group_{}", doc_group_size),
-            positive: positive_qdrant_ids
-                .into_iter()
-                .map(|id| id.to_string().into())
-                .collect(),
-            negative: vec![],
-            filter: None,
-            limit: limit.unwrap_or(10) * 2,
-            with_payload: Some(WithPayloadSelector {
-                selector_options: Some(SelectorOptions::Enable(true)),
-            }),
-            params: None,
-            score_threshold: None,
-            offset: Some(page.unwrap_or(0) * (limit.unwrap_or(10) * 2)),
-            using: None,
-            with_vectors: None,
-            lookup_from: None,
-            read_consistency: None,
-            negative_vectors: vec![],
-            positive_vectors: vec![],
-            strategy: None,
-            timeout: None,
-            shard_key_selector: None,
-        })
+    vector_store: &dyn VectorStore,
+) -> Result<Vec<QdrantPoints>, ServiceError> {
+    let limit = limit.unwrap_or(10) * 2;
+    let records = vector_store
+        .recommend(
+            &doc_group.collection_name(),
+            positive_qdrant_ids,
+            VectorFilter::default(),
+            limit,
+            page.unwrap_or(0) * limit,
+        )
         .await
         .map_err(ServiceError::RecommendQdrantDocEmbeddingGroupError)?;
 
-    let mut story_ids = vec![];
-
-    for point in recommend_result.result {
-        let story_id_as_str = point
-            .payload
-            .get("story_id")
-            .expect("story_id not found")
-            .as_str()
-            .expect("story_id is not a string");
-
-        let story_id: i64 = story_id_as_str.parse().expect("story_id is not a number");
+    Ok(records.into_iter().map(QdrantPoints::from).collect())
+}
 
-        story_ids.push(story_id);
-    }
+pub async fn recommend_similar_points_qdrant_query(
+    positive_qdrant_id: uuid::Uuid,
+    exclude_story_id: i64,
+    collection_name: String,
+    limit: Option<u64>,
+    page: Option<u64>,
+    vector_store: &dyn VectorStore,
+) -> Result<Vec<QdrantPoints>, ServiceError> {
+    let limit = limit.unwrap_or(10);
+    let records = vector_store
+        .recommend(
+            &collection_name,
+            vec![positive_qdrant_id],
+            VectorFilter::must_not(PayloadMatch::new("story_id", exclude_story_id)),
+            limit,
+            page.unwrap_or(0) * limit,
+        )
+        .await
+        .map_err(ServiceError::RecommendQdrantDocEmbeddingGroupError)?;
 
-    Ok(story_ids)
+    Ok(records.into_iter().map(QdrantPoints::from).collect())
 }
 
+#[derive(Debug, Clone)]
 pub struct QdrantPoints {
     pub score: f32,
     pub point_id: uuid::Uuid,
     pub payload: DocEmbeddingQdrantPayload,
 }
 
+impl From<ScoredVectorRecord> for QdrantPoints {
+    fn from(record: ScoredVectorRecord) -> Self {
+        Self {
+            score: record.score,
+            point_id: record.id,
+            payload: record.payload.into(),
+        }
+    }
+}
+
 pub async fn search_qdrant_query(
     embedding: Vec<f32>,
     page: u64,
-    doc_group_size: Option<i32>,
+    limit: u64,
+    collection_name: String,
+    vector_store: &dyn VectorStore,
 ) -> Result<Vec<QdrantPoints>, ServiceError> {
-    let qdrant_client = get_qdrant_connection().await?;
-    let data = qdrant_client
-        .search_points(&SearchPoints {
-            collection_name: if doc_group_size.is_some() {
-                format!("doc_group_{}", doc_group_size.unwrap())
-            } else {
-                "doc_embeddings".to_owned()
-            },
-            vector: embedding,
-            limit: 10,
-            offset: Some((page - 1) * 10),
-            with_payload: Some(true.into()),
-            ..Default::default()
-        })
+    let records = vector_store
+        .search(
+            &collection_name,
+            embedding,
+            VectorFilter::default(),
+            limit,
+            (page - 1) * limit,
+        )
         .await
         .map_err(ServiceError::QdrantSearchError)?;
 
-    let point_ids: Vec<QdrantPoints> = data
-        .result
-        .iter()
-        .filter_map(|point| match point.clone().id?.point_id_options? {
-            PointIdOptions::Uuid(id) => Some(QdrantPoints {
-                score: point.score,
-                point_id: uuid::Uuid::parse_str(&id).ok()?,
-                payload: point.payload.clone().into(),
-            }),
-            PointIdOptions::Num(_) => None,
-        })
-        .collect();
-
-    Ok(point_ids)
-}
-
-pub async fn similarity_top_filtered_point(
-    query_embedding: Vec<f32>,
-    story_id: i64,
-    index: i64,
-    doc_group_size: Option<i32>,
-) -> Result<Option<f32>, ServiceError> {
-    let mut collection_name = "doc_embeddings".to_owned();
-
-    if let Some(doc_group_size) = doc_group_size {
-        if doc_group_size > 1 {
-            collection_name = format!("doc_group_{}", doc_group_size);
-        }
-    }
-
-    let qdrant_filter = qdrant::Filter {
-        should: vec![
-            FieldCondition {
-                key: "story_id".to_owned(),
-                r#match: Some(Match {
-                    match_value: Some(MatchValue::Text(story_id.to_string())),
-                }),
-                range: None,
-                geo_bounding_box: None,
-                geo_radius: None,
-                values_count: None,
-                geo_polygon: None,
-            }
-            .into(),
-            FieldCondition {
-                key: "index".to_owned(),
-                r#match: Some(Match {
-                    match_value: Some(MatchValue::Integer(index)),
-                }),
-                range: None,
-                geo_bounding_box: None,
-                geo_radius: None,
-                values_count: None,
-                geo_polygon: None,
-            }
-            .into(),
-        ],
-        ..Default::default()
-    };
-
-    let qdrant_client = get_qdrant_connection().await?;
-
-    let search_result: Option<f32> = qdrant_client
-        .search_points(&SearchPoints {
-            collection_name,
-            vector: query_embedding,
-            limit: 1,
-            offset: None,
-            with_payload: None,
-            filter: Some(qdrant_filter),
-            ..Default::default()
-        })
-        .await
-        .map_err(ServiceError::QdrantSearchError)
-        .map(|res| {
-            res.result
-                .into_iter()
-                .map(|point| point.score)
-                .collect::<Vec<f32>>()
-                .first()
-                .cloned()
-        })?;
-
-    Ok(search_result)
+    Ok(records.into_iter().map(QdrantPoints::from).collect())
 }
@@ -0,0 +1,248 @@
+use super::{
+    embedding_operator::{create_embedding, embedding_model_id},
+    parse_operator::content_hash,
+};
+use crate::errors::ServiceError;
+use lru::LruCache;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const REDIS_KEY_PREFIX: &str = "query_embedding";
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct QueryEmbeddingCacheStats {
+    pub entries: usize,
+    pub local_hits: u64,
+    pub redis_hits: u64,
+    pub misses: u64,
+}
+
+/// A query embedding and when it was cached
+type CachedQueryEmbedding = (Instant, Vec<f32>);
+
+/// Embeddings of search queries, kept in process for `ttl` and evicted least recently used
+/// first. With Redis configured, entries are shared with every other instance through it.
+pub struct QueryEmbeddingCache {
+    local: Option<Mutex<LruCache<String, CachedQueryEmbedding>>>,
+    ttl: Duration,
+    redis: Option<ConnectionManager>,
+    local_hits: AtomicU64,
+    redis_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+fn query_cache_var(name: &str, default: u64) -> Result<u64, ServiceError> {
+    match std::env::var(name) {
+        Ok(value) => value.parse::<u64>().map_err(|_| {
+            ServiceError::QueryEmbeddingCacheError(anyhow::anyhow!(
+                "{} must be a whole number",
+                name
+            ))
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
+impl QueryEmbeddingCache {
+    /// A `capacity` of 0 turns the in-process tier off
+    pub fn new(capacity: usize, ttl: Duration, redis: Option<ConnectionManager>) -> Self {
+        Self {
+            local: NonZeroUsize::new(capacity).map(|capacity| Mutex::new(LruCache::new(capacity))),
+            ttl,
+            redis,
+            local_hits: AtomicU64::new(0),
+            redis_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn from_env() -> Result<Self, ServiceError> {
+        let capacity = query_cache_var("QUERY_EMBEDDING_CACHE_SIZE", 1000)?;
+        let ttl = Duration::from_secs(query_cache_var("QUERY_EMBEDDING_CACHE_TTL_SECONDS", 3600)?);
+
+        let redis = match std::env::var("REDIS_URL") {
+            Ok(redis_url) => {
+                let client = redis::Client::open(redis_url)
+                    .map_err(|err| ServiceError::QueryEmbeddingCacheError(err.into()))?;
+                Some(
+                    ConnectionManager::new(client)
+                        .await
+                        .map_err(|err| ServiceError::QueryEmbeddingCacheError(err.into()))?,
+                )
+            }
+            Err(_) => None,
+        };
+
+        Ok(Self::new(capacity as usize, ttl, redis))
+    }
+
+    fn key(query: &str) -> String {
+        format!(
+            "{}:{}:{}",
+            REDIS_KEY_PREFIX,
+            embedding_model_id(),
+            content_hash(&[query.to_owned()])
+        )
+    }
+
+    fn get_local(&self, key: &str) -> Option<Vec<f32>> {
+        let mut local = self
+            .local
+            .as_ref()?
+            .lock()
+            .expect("Query cache lock poisoned");
+        match local.get(key) {
+            Some((cached_at, embedding)) if cached_at.elapsed() < self.ttl => {
+                Some(embedding.clone())
+            }
+            Some(_) => {
+                local.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put_local(&self, key: String, embedding: Vec<f32>) {
+        if let Some(local) = self.local.as_ref() {
+            local
+                .lock()
+                .expect("Query cache lock poisoned")
+                .put(key, (Instant::now(), embedding));
+        }
+    }
+
+    async fn get_redis(&self, key: &str) -> redis::RedisResult<Option<Vec<f32>>> {
+        let Some(mut redis) = self.redis.clone() else {
+            return Ok(None);
+        };
+        let cached: Option<String> = redis::cmd("GET").arg(key).query_async(&mut redis).await?;
+
+        Ok(cached.and_then(|cached| serde_json::from_str(&cached).ok()))
+    }
+
+    async fn put_redis(&self, key: &str, embedding: &[f32]) -> redis::RedisResult<()> {
+        let Some(mut redis) = self.redis.clone() else {
+            return Ok(());
+        };
+        redis::cmd("SET")
+            .arg(key)
+            .arg(serde_json::to_string(embedding).unwrap_or_default())
+            .arg("EX")
+            .arg(self.ttl.as_secs().max(1))
+            .query_async(&mut redis)
+            .await
+    }
+
+    /// Embeds the query, or returns its cached embedding. Redis errors are logged and treated
+    /// as misses, so search keeps working while Redis is down.
+    pub async fn create_embedding(&self, query: String) -> Result<Vec<f32>, ServiceError> {
+        let key = Self::key(&query);
+
+        if let Some(embedding) = self.get_local(&key) {
+            self.local_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(embedding);
+        }
+
+        match self.get_redis(&key).await {
+            Ok(Some(embedding)) => {
+                self.redis_hits.fetch_add(1, Ordering::Relaxed);
+                self.put_local(key, embedding.clone());
+                return Ok(embedding);
+            }
+            Ok(None) => {}
+            Err(err) => log::info!("Failed to read cached query embedding: {:?}", err),
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let embedding = create_embedding(query).await?;
+        if let Err(err) = self.put_redis(&key, &embedding).await {
+            log::info!("Failed to cache query embedding: {:?}", err);
+        }
+        self.put_local(key, embedding.clone());
+
+        Ok(embedding)
+    }
+
+    /// Counts since the process started, and the entries held in process
+    pub fn stats(&self) -> QueryEmbeddingCacheStats {
+        QueryEmbeddingCacheStats {
+            entries: self.local.as_ref().map_or(0, |local| {
+                local.lock().expect("Query cache lock poisoned").len()
+            }),
+            local_hits: self.local_hits.load(Ordering::Relaxed),
+            redis_hits: self.redis_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Drops every cached query embedding, in Redis too. Other instances keep their in-process
+    /// entries until they expire.
+    pub async fn flush(&self) -> Result<(), ServiceError> {
+        if let Some(local) = self.local.as_ref() {
+            local.lock().expect("Query cache lock poisoned").clear();
+        }
+
+        let Some(mut redis) = self.redis.clone() else {
+            return Ok(());
+        };
+        let mut cursor: u64 = 0;
+        loop {
+            let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(format!("{}:*", REDIS_KEY_PREFIX))
+                .arg("COUNT")
+                .arg(100)
+                .query_async(&mut redis)
+                .await
+                .map_err(|err| ServiceError::QueryEmbeddingCacheError(err.into()))?;
+            if !keys.is_empty() {
+                redis::cmd("DEL")
+                    .arg(keys)
+                    .query_async::<_, ()>(&mut redis)
+                    .await
+                    .map_err(|err| ServiceError::QueryEmbeddingCacheError(err.into()))?;
+            }
+
+            cursor = next_cursor;
+            if cursor == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_local_query_cache_evicts_and_expires() {
+        let cache = QueryEmbeddingCache::new(2, Duration::from_millis(50), None);
+        cache.put_local("first".to_owned(), vec![1.0]);
+        cache.put_local("second".to_owned(), vec![2.0]);
+        assert_eq!(cache.get_local("first"), Some(vec![1.0]));
+
+        // the second entry is now the least recently used
+        cache.put_local("third".to_owned(), vec![3.0]);
+        assert_eq!(cache.get_local("second"), None);
+        assert_eq!(cache.get_local("third"), Some(vec![3.0]));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get_local("first"), None);
+        assert_eq!(cache.stats().entries, 1);
+
+        let disabled = QueryEmbeddingCache::new(0, Duration::from_secs(60), None);
+        disabled.put_local("first".to_owned(), vec![1.0]);
+        assert_eq!(disabled.get_local("first"), None);
+    }
+}
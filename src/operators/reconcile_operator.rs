@@ -0,0 +1,577 @@
+use super::{
+    doc_embedding_operator::create_doc_group_embedding,
+    doc_group_embedding_operator::get_registered_doc_groups_pg_query,
+    embedding_operator::get_average_embedding,
+    parse_operator::chunk_document,
+    qdrant_operator::{doc_collection_name, doc_embedding_vector_mutations},
+    reindex_operator::get_doc_embedding_write_collections_pg_query,
+    vector_outbox_operator::{
+        drain_vector_mutations, enqueue_vector_mutations_pg_query, VectorMutation,
+    },
+    vector_store_operator::{VectorFilter, VectorStore},
+};
+use crate::{
+    data::models::{DocEmbedding, DocGroupSpec, IndexDocumentGroupRequest, ReconcileJob},
+    errors::ServiceError,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+const RECONCILE_BATCH_SIZE: i64 = 100;
+
+/// A chapter point is written before the row that points at it is committed, so a point only
+/// counts as an orphan if its row is still missing after this long
+const ORPHAN_POINT_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MissingDocEmbeddingPoint {
+    pub story_id: i64,
+    pub index: i32,
+    pub qdrant_point_id: uuid::Uuid,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MissingDocGroupPoint {
+    pub story_id: i64,
+    pub doc_group_size: i32,
+    pub doc_group_stride: i32,
+    pub index: i32,
+    pub qdrant_point_id: uuid::Uuid,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct OrphanDocGroupPoint {
+    pub collection_name: String,
+    pub qdrant_point_id: uuid::Uuid,
+}
+
+/// What a reconcile found. When `fixed` is set, chapters and doc groups were embedded again and
+/// orphan points deleted.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ReconcileReport {
+    pub rows_without_points: Vec<MissingDocEmbeddingPoint>,
+    pub points_without_rows: Vec<uuid::Uuid>,
+    pub doc_groups_without_points: Vec<MissingDocGroupPoint>,
+    pub doc_group_points_without_rows: Vec<OrphanDocGroupPoint>,
+    pub fixed: bool,
+}
+
+impl ReconcileReport {
+    pub fn is_consistent(&self) -> bool {
+        self.rows_without_points.is_empty()
+            && self.points_without_rows.is_empty()
+            && self.doc_groups_without_points.is_empty()
+            && self.doc_group_points_without_rows.is_empty()
+    }
+}
+
+async fn existing_point_ids(
+    collection_name: &str,
+    qdrant_point_ids: Vec<uuid::Uuid>,
+    vector_store: &dyn VectorStore,
+) -> Result<HashSet<uuid::Uuid>, ServiceError> {
+    Ok(vector_store
+        .retrieve(collection_name, qdrant_point_ids)
+        .await
+        .map_err(ServiceError::GetPointsQdrantError)?
+        .into_iter()
+        .map(|record| record.id)
+        .collect())
+}
+
+async fn find_rows_without_points(
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<Vec<MissingDocEmbeddingPoint>, ServiceError> {
+    let mut missing = vec![];
+    let mut last_id: Option<uuid::Uuid> = None;
+
+    loop {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, story_id, index, qdrant_point_id
+            FROM doc_embeddings
+            WHERE $1::UUID IS NULL OR id > $1
+            ORDER BY id
+            LIMIT $2
+            "#,
+            last_id,
+            RECONCILE_BATCH_SIZE,
+        )
+        .fetch_all(&pool)
+        .await
+        .map_err(ServiceError::ReconcilePgError)?;
+
+        let Some(last_row) = rows.last() else {
+            return Ok(missing);
+        };
+        last_id = Some(last_row.id);
+
+        let existing = existing_point_ids(
+            &doc_collection_name(None),
+            rows.iter().map(|row| row.qdrant_point_id).collect(),
+            vector_store,
+        )
+        .await?;
+        missing.extend(
+            rows.into_iter()
+                .filter(|row| !existing.contains(&row.qdrant_point_id))
+                .map(|row| MissingDocEmbeddingPoint {
+                    story_id: row.story_id,
+                    index: row.index,
+                    qdrant_point_id: row.qdrant_point_id,
+                }),
+        );
+    }
+}
+
+async fn find_doc_groups_without_points(
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<Vec<MissingDocGroupPoint>, ServiceError> {
+    let mut missing = vec![];
+    let mut last_id: Option<uuid::Uuid> = None;
+
+    loop {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, story_id, doc_group_size, doc_group_stride, index, qdrant_point_id
+            FROM doc_group_embeddings
+            WHERE $1::UUID IS NULL OR id > $1
+            ORDER BY id
+            LIMIT $2
+            "#,
+            last_id,
+            RECONCILE_BATCH_SIZE,
+        )
+        .fetch_all(&pool)
+        .await
+        .map_err(ServiceError::ReconcilePgError)?;
+
+        let Some(last_row) = rows.last() else {
+            return Ok(missing);
+        };
+        last_id = Some(last_row.id);
+
+        // each group size has its own collection
+        let mut existing = HashSet::new();
+        let doc_groups: HashSet<DocGroupSpec> = rows
+            .iter()
+            .map(|row| DocGroupSpec {
+                doc_group_size: row.doc_group_size,
+                doc_group_stride: row.doc_group_stride,
+            })
+            .collect();
+        for doc_group in doc_groups {
+            existing.extend(
+                existing_point_ids(
+                    &doc_group.collection_name(),
+                    rows.iter()
+                        .filter(|row| {
+                            row.doc_group_size == doc_group.doc_group_size
+                                && row.doc_group_stride == doc_group.doc_group_stride
+                        })
+                        .map(|row| row.qdrant_point_id)
+                        .collect(),
+                    vector_store,
+                )
+                .await?,
+            );
+        }
+
+        missing.extend(
+            rows.into_iter()
+                .filter(|row| !existing.contains(&row.qdrant_point_id))
+                .map(|row| MissingDocGroupPoint {
+                    story_id: row.story_id,
+                    doc_group_size: row.doc_group_size,
+                    doc_group_stride: row.doc_group_stride,
+                    index: row.index,
+                    qdrant_point_id: row.qdrant_point_id,
+                }),
+        );
+    }
+}
+
+/// Of `qdrant_point_ids`, the ones no chapter row points at
+async fn point_ids_without_rows(
+    qdrant_point_ids: Vec<uuid::Uuid>,
+    pool: Pool<Postgres>,
+) -> Result<Vec<uuid::Uuid>, ServiceError> {
+    let with_rows: HashSet<uuid::Uuid> = sqlx::query!(
+        r#"
+        SELECT qdrant_point_id
+        FROM doc_embeddings
+        WHERE qdrant_point_id = ANY($1)
+        "#,
+        qdrant_point_ids.as_slice(),
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::ReconcilePgError)?
+    .into_iter()
+    .map(|row| row.qdrant_point_id)
+    .collect();
+
+    Ok(qdrant_point_ids
+        .into_iter()
+        .filter(|qdrant_point_id| !with_rows.contains(qdrant_point_id))
+        .collect())
+}
+
+async fn find_points_without_rows(
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<Vec<uuid::Uuid>, ServiceError> {
+    let mut missing = vec![];
+    let mut offset = None;
+
+    loop {
+        let (records, next_offset) = vector_store
+            .scroll(
+                &doc_collection_name(None),
+                VectorFilter::default(),
+                offset,
+                RECONCILE_BATCH_SIZE as u64,
+            )
+            .await
+            .map_err(ServiceError::ScrollDocEmbeddingQdrantError)?;
+
+        missing.extend(
+            point_ids_without_rows(
+                records.into_iter().map(|record| record.id).collect(),
+                pool.clone(),
+            )
+            .await?,
+        );
+
+        offset = next_offset;
+        if offset.is_none() {
+            return Ok(missing);
+        }
+    }
+}
+
+/// Of `qdrant_point_ids`, the ones no doc group row points at
+async fn doc_group_point_ids_without_rows(
+    qdrant_point_ids: Vec<uuid::Uuid>,
+    pool: Pool<Postgres>,
+) -> Result<Vec<uuid::Uuid>, ServiceError> {
+    let with_rows: HashSet<uuid::Uuid> = sqlx::query!(
+        r#"
+        SELECT qdrant_point_id
+        FROM doc_group_embeddings
+        WHERE qdrant_point_id = ANY($1)
+        "#,
+        qdrant_point_ids.as_slice(),
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::ReconcilePgError)?
+    .into_iter()
+    .map(|row| row.qdrant_point_id)
+    .collect();
+
+    Ok(qdrant_point_ids
+        .into_iter()
+        .filter(|qdrant_point_id| !with_rows.contains(qdrant_point_id))
+        .collect())
+}
+
+async fn find_doc_group_points_without_rows(
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<Vec<OrphanDocGroupPoint>, ServiceError> {
+    let mut missing = vec![];
+
+    for doc_group in get_registered_doc_groups_pg_query(pool.clone()).await? {
+        let collection_name = doc_group.collection_name();
+        let mut offset = None;
+        loop {
+            let (records, next_offset) = vector_store
+                .scroll(
+                    &collection_name,
+                    VectorFilter::default(),
+                    offset,
+                    RECONCILE_BATCH_SIZE as u64,
+                )
+                .await
+                .map_err(ServiceError::ScrollDocEmbeddingQdrantError)?;
+
+            missing.extend(
+                doc_group_point_ids_without_rows(
+                    records.into_iter().map(|record| record.id).collect(),
+                    pool.clone(),
+                )
+                .await?
+                .into_iter()
+                .map(|qdrant_point_id| OrphanDocGroupPoint {
+                    collection_name: collection_name.clone(),
+                    qdrant_point_id,
+                }),
+            );
+
+            offset = next_offset;
+            if offset.is_none() {
+                break;
+            }
+        }
+    }
+
+    Ok(missing)
+}
+
+async fn enqueue_mutations(
+    mutations: Vec<VectorMutation>,
+    pool: Pool<Postgres>,
+) -> Result<(), ServiceError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+    enqueue_vector_mutations_pg_query(mutations, Duration::ZERO, &mut transaction).await?;
+    transaction
+        .commit()
+        .await
+        .map_err(ServiceError::PgTransactionError)
+}
+
+/// Compares the chapter and doc group rows in Postgres with the points in the vector store
+pub async fn reconcile(
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<ReconcileReport, ServiceError> {
+    Ok(ReconcileReport {
+        rows_without_points: find_rows_without_points(pool.clone(), vector_store).await?,
+        points_without_rows: find_points_without_rows(pool.clone(), vector_store).await?,
+        doc_groups_without_points: find_doc_groups_without_points(pool.clone(), vector_store)
+            .await?,
+        doc_group_points_without_rows: find_doc_group_points_without_rows(pool, vector_store)
+            .await?,
+        fixed: false,
+    })
+}
+
+/// Embeds chapters missing a point again from their stored html, rebuilds the doc groups
+/// missing a point and deletes chapter and doc group points no row points at. Every write goes
+/// through the outbox, which is drained before anything reads it back.
+pub async fn reconcile_and_fix(
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<ReconcileReport, ServiceError> {
+    let mut report = reconcile(pool.clone(), vector_store).await?;
+    if report.is_consistent() {
+        return Ok(report);
+    }
+
+    let write_collections = get_doc_embedding_write_collections_pg_query(pool.clone()).await?;
+    let missing_point_ids: Vec<uuid::Uuid> = report
+        .rows_without_points
+        .iter()
+        .map(|missing| missing.qdrant_point_id)
+        .collect();
+    let doc_embeddings = sqlx::query_as!(
+        DocEmbedding,
+        r#"
+        SELECT *
+        FROM doc_embeddings
+        WHERE qdrant_point_id = ANY($1)
+        "#,
+        missing_point_ids.as_slice(),
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::ReconcilePgError)?;
+    let mut mutations = vec![];
+    for doc_embedding in doc_embeddings {
+        let doc_chunks = chunk_document(doc_embedding.doc_html.clone());
+        if doc_chunks.is_empty() {
+            log::info!(
+                "Chapter {} of story {} has no text to embed",
+                doc_embedding.index,
+                doc_embedding.story_id
+            );
+            continue;
+        }
+        let embedding = get_average_embedding(doc_chunks, pool.clone()).await?;
+        mutations.extend(doc_embedding_vector_mutations(
+            doc_embedding,
+            embedding,
+            None,
+            &write_collections,
+        ));
+    }
+    enqueue_mutations(mutations, pool.clone()).await?;
+    drain_vector_mutations(pool.clone(), vector_store).await?;
+
+    // groups are rebuilt per story from the chapter points, which now all exist
+    let stories: HashSet<(i64, DocGroupSpec)> = report
+        .doc_groups_without_points
+        .iter()
+        .map(|missing| {
+            (
+                missing.story_id,
+                DocGroupSpec {
+                    doc_group_size: missing.doc_group_size,
+                    doc_group_stride: missing.doc_group_stride,
+                },
+            )
+        })
+        .collect();
+    for (story_id, doc_group) in stories {
+        create_doc_group_embedding(
+            IndexDocumentGroupRequest::Story {
+                story_id,
+                doc_group_size: doc_group.doc_group_size,
+                doc_group_stride: Some(doc_group.doc_group_stride),
+            },
+            pool.clone(),
+            vector_store,
+        )
+        .await?;
+    }
+
+    if !report.points_without_rows.is_empty() || !report.doc_group_points_without_rows.is_empty() {
+        actix_rt::time::sleep(ORPHAN_POINT_GRACE).await;
+    }
+
+    let mut mutations = vec![];
+    if !report.points_without_rows.is_empty() {
+        report.points_without_rows =
+            point_ids_without_rows(report.points_without_rows, pool.clone()).await?;
+        mutations.extend(report.points_without_rows.iter().map(|point_id| {
+            VectorMutation::Delete {
+                collection_name: doc_collection_name(None),
+                point_id: *point_id,
+            }
+        }));
+    }
+
+    if !report.doc_group_points_without_rows.is_empty() {
+        let still_orphaned: HashSet<uuid::Uuid> = doc_group_point_ids_without_rows(
+            report
+                .doc_group_points_without_rows
+                .iter()
+                .map(|orphan| orphan.qdrant_point_id)
+                .collect(),
+            pool.clone(),
+        )
+        .await?
+        .into_iter()
+        .collect();
+        report
+            .doc_group_points_without_rows
+            .retain(|orphan| still_orphaned.contains(&orphan.qdrant_point_id));
+        mutations.extend(report.doc_group_points_without_rows.iter().map(|orphan| {
+            VectorMutation::Delete {
+                collection_name: orphan.collection_name.clone(),
+                point_id: orphan.qdrant_point_id,
+            }
+        }));
+    }
+    enqueue_mutations(mutations, pool.clone()).await?;
+    drain_vector_mutations(pool, vector_store).await?;
+
+    report.fixed = true;
+    Ok(report)
+}
+
+pub async fn get_reconcile_job_pg_query(
+    id: uuid::Uuid,
+    pool: Pool<Postgres>,
+) -> Result<ReconcileJob, ServiceError> {
+    sqlx::query_as!(
+        ReconcileJob,
+        r#"
+        SELECT *
+        FROM reconcile_jobs
+        WHERE id = $1
+        "#,
+        id,
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ServiceError::ReconcilePgError)?
+    .ok_or(ServiceError::ReconcileJobNotFound)
+}
+
+/// A repair cut short by a restart is marked failed so a new one can be started
+pub async fn fail_interrupted_reconcile_jobs_pg_query(
+    pool: Pool<Postgres>,
+) -> Result<(), ServiceError> {
+    sqlx::query!(
+        r#"
+        UPDATE reconcile_jobs
+        SET status = 'failed', error = 'Interrupted by a restart'
+        WHERE status = 'running'
+        "#,
+    )
+    .execute(&pool)
+    .await
+    .map_err(ServiceError::ReconcilePgError)?;
+
+    Ok(())
+}
+
+/// Records a repair and runs `reconcile_and_fix` in the background, since embedding chapters
+/// again and waiting out the orphan grace period takes far longer than a request should
+pub async fn start_reconcile_job(
+    pool: Pool<Postgres>,
+    vector_store: Arc<dyn VectorStore>,
+) -> Result<ReconcileJob, ServiceError> {
+    let job = sqlx::query_as!(
+        ReconcileJob,
+        r#"
+        INSERT INTO reconcile_jobs (id)
+        VALUES ($1)
+        ON CONFLICT DO NOTHING
+        RETURNING *
+        "#,
+        uuid::Uuid::new_v4(),
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ServiceError::ReconcilePgError)?
+    .ok_or(ServiceError::ReconcileAlreadyRunning)?;
+
+    actix_rt::spawn(run_reconcile_job(job.id, pool, vector_store));
+
+    Ok(job)
+}
+
+async fn run_reconcile_job(
+    id: uuid::Uuid,
+    pool: Pool<Postgres>,
+    vector_store: Arc<dyn VectorStore>,
+) {
+    let (status, report, error) = match reconcile_and_fix(pool.clone(), vector_store.as_ref()).await
+    {
+        Ok(report) => ("completed", serde_json::to_value(report).ok(), None),
+        Err(err) => {
+            log::info!("Reconcile job {} failed: {:?}", id, err);
+            ("failed", None, Some(format!("{:?}", err)))
+        }
+    };
+
+    let _ = sqlx::query!(
+        r#"
+        UPDATE reconcile_jobs
+        SET status = $2, report = $3, error = $4
+        WHERE id = $1
+        "#,
+        id,
+        status,
+        report,
+        error,
+    )
+    .execute(&pool)
+    .await
+    .map_err(|err| {
+        log::info!(
+            "Failed to record the outcome of reconcile job {}: {:?}",
+            id,
+            err
+        );
+    });
+}
@@ -0,0 +1,571 @@
+use super::{
+    embedding_operator::get_average_embedding,
+    parse_operator::chunk_document,
+    qdrant_operator::embedding_size,
+    vector_store_operator::{VectorFilter, VectorRecord, VectorStore},
+};
+use crate::{
+    data::models::{DocEmbedding, DocEmbeddingQdrantPayload, DocEmbeddingReindex},
+    errors::ServiceError,
+};
+use sqlx::{Pool, Postgres};
+use std::{collections::HashSet, sync::Arc};
+
+/// Readers and writers always go through this alias, so a reindex goes live by moving it
+pub const DOC_EMBEDDINGS_ALIAS: &str = "doc_embeddings";
+
+/// The collection behind the alias until the first reindex goes live
+const ORIGINAL_DOC_EMBEDDINGS_COLLECTION: &str = "doc_embeddings_v0";
+
+const REINDEX_BATCH_SIZE: i64 = 50;
+
+pub async fn get_reindexes_pg_query(
+    pool: Pool<Postgres>,
+) -> Result<Vec<DocEmbeddingReindex>, ServiceError> {
+    sqlx::query_as!(
+        DocEmbeddingReindex,
+        r#"
+        SELECT *
+        FROM doc_embedding_reindexes
+        ORDER BY version DESC
+        "#,
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectReindexPgError)
+}
+
+pub async fn get_reindex_pg_query(
+    version: i32,
+    pool: Pool<Postgres>,
+) -> Result<DocEmbeddingReindex, ServiceError> {
+    sqlx::query_as!(
+        DocEmbeddingReindex,
+        r#"
+        SELECT *
+        FROM doc_embedding_reindexes
+        WHERE version = $1
+        "#,
+        version,
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ServiceError::SelectReindexPgError)?
+    .ok_or(ServiceError::ReindexNotFound)
+}
+
+/// Every collection a chapter write has to reach: the alias, a rebuild that has not gone live
+/// yet, and the collection the live version would roll back to
+pub async fn get_doc_embedding_write_collections_pg_query(
+    pool: Pool<Postgres>,
+) -> Result<Vec<String>, ServiceError> {
+    let collections = sqlx::query!(
+        r#"
+        SELECT collection_name AS "collection_name!"
+        FROM doc_embedding_reindexes
+        WHERE status IN ('running', 'completed')
+        UNION
+        SELECT previous_collection AS "collection_name!"
+        FROM doc_embedding_reindexes
+        WHERE status = 'live' AND previous_collection IS NOT NULL
+        "#,
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectReindexPgError)?;
+
+    let mut write_collections = vec![DOC_EMBEDDINGS_ALIAS.to_owned()];
+    write_collections.extend(
+        collections
+            .into_iter()
+            .map(|collection| collection.collection_name),
+    );
+    Ok(write_collections)
+}
+
+/// A rebuild cut short by a restart cannot be resumed, so it is marked failed and a new one
+/// can be started
+pub async fn fail_interrupted_reindexes_pg_query(pool: Pool<Postgres>) -> Result<(), ServiceError> {
+    sqlx::query!(
+        r#"
+        UPDATE doc_embedding_reindexes
+        SET status = 'failed', error = 'Interrupted by a restart'
+        WHERE status = 'running'
+        "#,
+    )
+    .execute(&pool)
+    .await
+    .map_err(ServiceError::UpdateReindexPgError)?;
+
+    Ok(())
+}
+
+/// Creates the next version's collection and rebuilds it in the background. Completed versions
+/// that never went live are abandoned, since they stop receiving writes.
+pub async fn start_doc_embedding_reindex(
+    pool: Pool<Postgres>,
+    vector_store: Arc<dyn VectorStore>,
+) -> Result<DocEmbeddingReindex, ServiceError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+
+    let running = sqlx::query!(
+        r#"
+        SELECT version
+        FROM doc_embedding_reindexes
+        WHERE status = 'running'
+        "#,
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(ServiceError::SelectReindexPgError)?;
+    if running.is_some() {
+        return Err(ServiceError::ReindexAlreadyRunning);
+    }
+
+    let abandoned = sqlx::query!(
+        r#"
+        UPDATE doc_embedding_reindexes
+        SET status = 'abandoned'
+        WHERE status = 'completed'
+        RETURNING collection_name
+        "#,
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(ServiceError::UpdateReindexPgError)?;
+
+    let reindex = sqlx::query_as!(
+        DocEmbeddingReindex,
+        r#"
+        INSERT INTO doc_embedding_reindexes (version, collection_name, total_count)
+        SELECT
+            COALESCE(MAX(version), 0) + 1,
+            'doc_embeddings_v' || (COALESCE(MAX(version), 0) + 1),
+            (SELECT COUNT(*) FROM doc_embeddings)
+        FROM doc_embedding_reindexes
+        RETURNING *
+        "#,
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(ServiceError::StartReindexPgError)?;
+
+    vector_store
+        .create_collection(&reindex.collection_name, embedding_size())
+        .await
+        .map_err(ServiceError::CreateCollectionQdrantError)?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+
+    for collection in abandoned {
+        let _ = vector_store
+            .delete_collection(&collection.collection_name)
+            .await
+            .map_err(|err| {
+                log::info!(
+                    "Failed to delete abandoned collection {}: {:?}",
+                    collection.collection_name,
+                    err
+                );
+            });
+    }
+
+    actix_rt::spawn(run_doc_embedding_reindex(
+        reindex.clone(),
+        pool,
+        vector_store,
+    ));
+
+    Ok(reindex)
+}
+
+async fn run_doc_embedding_reindex(
+    reindex: DocEmbeddingReindex,
+    pool: Pool<Postgres>,
+    vector_store: Arc<dyn VectorStore>,
+) {
+    let (status, error) =
+        match rebuild_doc_embeddings(&reindex, pool.clone(), vector_store.as_ref()).await {
+            Ok(()) => ("completed", None),
+            Err(err) => {
+                log::info!("Reindex {} failed: {:?}", reindex.version, err);
+                ("failed", Some(format!("{:?}", err)))
+            }
+        };
+
+    let _ = sqlx::query!(
+        r#"
+        UPDATE doc_embedding_reindexes
+        SET status = $2, error = $3
+        WHERE version = $1
+        "#,
+        reindex.version,
+        status,
+        error,
+    )
+    .execute(&pool)
+    .await
+    .map_err(|err| {
+        log::info!(
+            "Failed to record the outcome of reindex {}: {:?}",
+            reindex.version,
+            err
+        );
+    });
+}
+
+/// Embeds every stored chapter again and writes it under its existing point id, so Postgres
+/// keeps pointing at the right vectors once the new collection goes live
+async fn rebuild_doc_embeddings(
+    reindex: &DocEmbeddingReindex,
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<(), ServiceError> {
+    let mut last_id: Option<uuid::Uuid> = None;
+
+    loop {
+        let doc_embeddings = sqlx::query_as!(
+            DocEmbedding,
+            r#"
+            SELECT *
+            FROM doc_embeddings
+            WHERE $1::UUID IS NULL OR id > $1
+            ORDER BY id
+            LIMIT $2
+            "#,
+            last_id,
+            REINDEX_BATCH_SIZE,
+        )
+        .fetch_all(&pool)
+        .await
+        .map_err(ServiceError::SelectReindexPgError)?;
+
+        let Some(last_doc_embedding) = doc_embeddings.last() else {
+            return Ok(());
+        };
+        last_id = Some(last_doc_embedding.id);
+        let batch_size = doc_embeddings.len() as i64;
+
+        let mut records = vec![];
+        for doc_embedding in doc_embeddings {
+            let doc_chunks = chunk_document(doc_embedding.doc_html.clone());
+            if doc_chunks.is_empty() {
+                continue;
+            }
+
+            records.push(VectorRecord {
+                id: doc_embedding.qdrant_point_id,
+                vector: get_average_embedding(doc_chunks, pool.clone()).await?,
+                payload: DocEmbeddingQdrantPayload::from(doc_embedding).into(),
+            });
+        }
+
+        // A chapter rewritten while the batch was embedded has a new point, which the chapter
+        // write recorded for this collection too, and the relay deletes its old point once that
+        // write commits. Writing the old point now would bring it back as an orphan, so only points
+        // rows still refer to are written, with the rows locked until the write is done.
+        let mut transaction = pool
+            .begin()
+            .await
+            .map_err(ServiceError::PgTransactionError)?;
+        let record_ids: Vec<uuid::Uuid> = records.iter().map(|record| record.id).collect();
+        let current_qdrant_point_ids: HashSet<uuid::Uuid> = sqlx::query_scalar!(
+            r#"
+            SELECT qdrant_point_id
+            FROM doc_embeddings
+            WHERE qdrant_point_id = ANY($1)
+            FOR SHARE
+            "#,
+            &record_ids,
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(ServiceError::SelectReindexPgError)?
+        .into_iter()
+        .collect();
+        records.retain(|record| current_qdrant_point_ids.contains(&record.id));
+
+        vector_store
+            .upsert(&reindex.collection_name, records)
+            .await
+            .map_err(ServiceError::UpsertDocEmbeddingQdrantError)?;
+        transaction
+            .commit()
+            .await
+            .map_err(ServiceError::PgTransactionError)?;
+
+        sqlx::query!(
+            r#"
+            UPDATE doc_embedding_reindexes
+            SET indexed_count = indexed_count + $2
+            WHERE version = $1
+            "#,
+            reindex.version,
+            batch_size,
+        )
+        .execute(&pool)
+        .await
+        .map_err(ServiceError::UpdateReindexPgError)?;
+    }
+}
+
+/// Makes `doc_embeddings` an alias before the server starts serving. A `doc_embeddings`
+/// collection left by an older version is copied to the first versioned collection and replaced
+/// by the alias, so no switch ever has to delete the collection searches are reading.
+pub async fn ensure_doc_embeddings_alias(
+    vector_store: &dyn VectorStore,
+) -> Result<(), ServiceError> {
+    if vector_store
+        .alias_target(DOC_EMBEDDINGS_ALIAS)
+        .await
+        .map_err(ServiceError::SwitchAliasQdrantError)?
+        .is_some()
+    {
+        return Ok(());
+    }
+
+    // counting only succeeds when a collection has the name, since it is not an alias
+    if vector_store.count(DOC_EMBEDDINGS_ALIAS).await.is_ok() {
+        copy_collection(
+            DOC_EMBEDDINGS_ALIAS,
+            ORIGINAL_DOC_EMBEDDINGS_COLLECTION,
+            vector_store,
+        )
+        .await?;
+        vector_store
+            .delete_collection(DOC_EMBEDDINGS_ALIAS)
+            .await
+            .map_err(ServiceError::SwitchAliasQdrantError)?;
+    } else {
+        vector_store
+            .create_collection(ORIGINAL_DOC_EMBEDDINGS_COLLECTION, embedding_size())
+            .await
+            .map_err(ServiceError::CreateCollectionQdrantError)?;
+    }
+
+    vector_store
+        .switch_alias(DOC_EMBEDDINGS_ALIAS, ORIGINAL_DOC_EMBEDDINGS_COLLECTION)
+        .await
+        .map_err(ServiceError::SwitchAliasQdrantError)
+}
+
+/// Copies every point of a collection into a new one
+async fn copy_collection(
+    from_collection: &str,
+    to_collection: &str,
+    vector_store: &dyn VectorStore,
+) -> Result<(), ServiceError> {
+    vector_store
+        .create_collection(to_collection, embedding_size())
+        .await
+        .map_err(ServiceError::CreateCollectionQdrantError)?;
+
+    let mut offset = None;
+    loop {
+        let (records, next_offset) = vector_store
+            .scroll(
+                from_collection,
+                VectorFilter::default(),
+                offset,
+                REINDEX_BATCH_SIZE as u64,
+            )
+            .await
+            .map_err(ServiceError::ScrollDocEmbeddingQdrantError)?;
+
+        vector_store
+            .upsert(to_collection, records)
+            .await
+            .map_err(ServiceError::UpsertDocEmbeddingQdrantError)?;
+
+        offset = next_offset;
+        if offset.is_none() {
+            return Ok(());
+        }
+    }
+}
+
+/// Points `doc_embeddings` at a completed rebuild in one step. The version that was live stays
+/// up to date so the switch can be rolled back.
+pub async fn switch_doc_embeddings_alias(
+    version: i32,
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<DocEmbeddingReindex, ServiceError> {
+    let reindex = get_reindex_pg_query(version, pool.clone()).await?;
+    if reindex.status != "completed" {
+        return Err(ServiceError::ReindexNotSwitchable);
+    }
+
+    let previous_collection = vector_store
+        .alias_target(DOC_EMBEDDINGS_ALIAS)
+        .await
+        .map_err(ServiceError::SwitchAliasQdrantError)?
+        .ok_or_else(|| {
+            ServiceError::SwitchAliasQdrantError(anyhow::anyhow!(
+                "{} is not an alias",
+                DOC_EMBEDDINGS_ALIAS
+            ))
+        })?;
+
+    vector_store
+        .switch_alias(DOC_EMBEDDINGS_ALIAS, &reindex.collection_name)
+        .await
+        .map_err(ServiceError::SwitchAliasQdrantError)?;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+
+    sqlx::query!(
+        r#"
+        UPDATE doc_embedding_reindexes
+        SET status = 'retired'
+        WHERE status = 'live'
+        "#,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServiceError::UpdateReindexPgError)?;
+
+    let reindex = sqlx::query_as!(
+        DocEmbeddingReindex,
+        r#"
+        UPDATE doc_embedding_reindexes
+        SET status = 'live', previous_collection = $2, switched_at = CURRENT_TIMESTAMP
+        WHERE version = $1
+        RETURNING *
+        "#,
+        version,
+        previous_collection,
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(ServiceError::UpdateReindexPgError)?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+
+    Ok(reindex)
+}
+
+/// Points `doc_embeddings` back at the collection that was live before the last switch and
+/// returns the version that was rolled back
+pub async fn rollback_doc_embeddings_alias(
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<DocEmbeddingReindex, ServiceError> {
+    let live = sqlx::query_as!(
+        DocEmbeddingReindex,
+        r#"
+        SELECT *
+        FROM doc_embedding_reindexes
+        WHERE status = 'live'
+        "#,
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ServiceError::SelectReindexPgError)?
+    .ok_or(ServiceError::NothingToRollBack)?;
+    let previous_collection = live
+        .previous_collection
+        .clone()
+        .ok_or(ServiceError::NothingToRollBack)?;
+
+    vector_store
+        .switch_alias(DOC_EMBEDDINGS_ALIAS, &previous_collection)
+        .await
+        .map_err(ServiceError::SwitchAliasQdrantError)?;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+
+    let rolled_back = sqlx::query_as!(
+        DocEmbeddingReindex,
+        r#"
+        UPDATE doc_embedding_reindexes
+        SET status = 'rolled_back'
+        WHERE version = $1
+        RETURNING *
+        "#,
+        live.version,
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(ServiceError::UpdateReindexPgError)?;
+
+    // the restored version's own previous collection stopped receiving writes at the last
+    // switch, so rolling back further is not offered
+    sqlx::query!(
+        r#"
+        UPDATE doc_embedding_reindexes
+        SET status = 'live', previous_collection = NULL
+        WHERE collection_name = $1 AND status = 'retired'
+        "#,
+        previous_collection,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServiceError::UpdateReindexPgError)?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(ServiceError::PgTransactionError)?;
+
+    Ok(rolled_back)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::operators::vector_store_operator::{InMemoryVectorStore, VectorPayload};
+
+    #[actix_rt::test]
+    async fn test_ensure_doc_embeddings_alias_replaces_legacy_collection() {
+        let store = InMemoryVectorStore::default();
+        store
+            .create_collection(DOC_EMBEDDINGS_ALIAS, embedding_size())
+            .await
+            .unwrap();
+        let mut vector = vec![0.0; embedding_size() as usize];
+        vector[0] = 1.0;
+        store
+            .upsert(
+                DOC_EMBEDDINGS_ALIAS,
+                vec![VectorRecord {
+                    id: uuid::Uuid::from_u128(1),
+                    vector,
+                    payload: VectorPayload::new(),
+                }],
+            )
+            .await
+            .unwrap();
+
+        ensure_doc_embeddings_alias(&store).await.unwrap();
+        // running again at the next startup leaves the alias alone
+        ensure_doc_embeddings_alias(&store).await.unwrap();
+
+        assert_eq!(
+            store.alias_target(DOC_EMBEDDINGS_ALIAS).await.unwrap(),
+            Some(ORIGINAL_DOC_EMBEDDINGS_COLLECTION.to_owned())
+        );
+        assert_eq!(
+            store
+                .count(ORIGINAL_DOC_EMBEDDINGS_COLLECTION)
+                .await
+                .unwrap(),
+            1
+        );
+    }
+}
@@ -1,42 +1,77 @@
-use super::qdrant_operator::QdrantPoints;
-use crate::{data::models::DocEmbedding, data::models::DocGroupEmbedding, errors::ServiceError};
+use super::doc_embedding_operator::get_doc_embedding_qdrant_ids_by_story_index_pg_query;
+use super::doc_group_embedding_operator::get_doc_group_qdrant_ids_by_story_index_pg_query;
+use super::named_chapter_range_operator::get_named_chapter_ranges_by_qdrant_ids_pg_query;
+use super::qdrant_operator::{
+    doc_collection_name, get_points_with_vectors_qdrant_query, QdrantPoints,
+};
+use super::story_embedding_operator::get_story_embeddings_by_qdrant_ids_pg_query;
+use super::vector_store_operator::VectorStore;
+use crate::{
+    data::models::{
+        DocEmbedding, DocGroupEmbedding, DocGroupSpec, NamedChapterRange, SimilarityTarget,
+        StoryEmbedding,
+    },
+    errors::ServiceError,
+};
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum DocEmbeddingType {
     DocEmbedding(DocEmbedding),
     DocGroupEmbedding(DocGroupEmbedding),
+    StoryEmbedding(StoryEmbedding),
+    NamedChapterRange(NamedChapterRange),
 }
+/// Sorts rows fetched by point id into the order of the points, which is the vector store's
+/// ranking, since Postgres returns them in no particular order
+fn in_point_order<T>(
+    mut rows: Vec<T>,
+    points: &[QdrantPoints],
+    qdrant_point_id: impl Fn(&T) -> uuid::Uuid,
+) -> Vec<T> {
+    let positions: HashMap<uuid::Uuid, usize> = points
+        .iter()
+        .enumerate()
+        .map(|(position, point)| (point.point_id, position))
+        .collect();
+    rows.sort_by_key(|row| positions.get(&qdrant_point_id(row)).copied());
+    rows
+}
+
 pub async fn get_docs_by_point_id(
     points: Vec<QdrantPoints>,
-    doc_group_size: Option<i32>,
+    doc_group: Option<DocGroupSpec>,
     pool: Pool<Postgres>,
 ) -> Result<Vec<DocEmbeddingType>, ServiceError> {
     let qdrant_point_ids = points
         .iter()
         .map(|point| point.point_id)
         .collect::<Vec<uuid::Uuid>>();
-    match doc_group_size {
-        Some(doc_group_size) => {
+    match doc_group {
+        Some(doc_group) => {
             let embeds = sqlx::query_as!(
                 DocGroupEmbedding,
                 r#"
                 SELECT *
                 FROM doc_group_embeddings
-                WHERE qdrant_point_id = ANY($1) AND doc_group_size = $2
+                WHERE qdrant_point_id = ANY($1) AND doc_group_size = $2 AND doc_group_stride = $3
                 "#,
                 qdrant_point_ids.as_slice(),
-                doc_group_size,
+                doc_group.doc_group_size,
+                doc_group.doc_group_stride,
             )
             .fetch_all(&pool)
             .await
             .map_err(ServiceError::PgSearchError)?;
 
-            Ok(embeds
-                .into_iter()
-                .map(DocEmbeddingType::DocGroupEmbedding)
-                .collect::<Vec<DocEmbeddingType>>())
+            Ok(
+                in_point_order(embeds, &points, |embed| embed.qdrant_point_id)
+                    .into_iter()
+                    .map(DocEmbeddingType::DocGroupEmbedding)
+                    .collect::<Vec<DocEmbeddingType>>(),
+            )
         }
         None => {
             let embeds = sqlx::query_as!(
@@ -51,10 +86,231 @@ pub async fn get_docs_by_point_id(
             .fetch_all(&pool)
             .await
             .map_err(ServiceError::PgSearchError)?;
-            Ok(embeds
-                .into_iter()
-                .map(DocEmbeddingType::DocEmbedding)
-                .collect::<Vec<DocEmbeddingType>>())
+            Ok(
+                in_point_order(embeds, &points, |embed| embed.qdrant_point_id)
+                    .into_iter()
+                    .map(DocEmbeddingType::DocEmbedding)
+                    .collect::<Vec<DocEmbeddingType>>(),
+            )
         }
     }
 }
+
+pub async fn get_stories_by_point_id(
+    points: Vec<QdrantPoints>,
+    pool: Pool<Postgres>,
+) -> Result<Vec<DocEmbeddingType>, ServiceError> {
+    let qdrant_point_ids = points
+        .iter()
+        .map(|point| point.point_id)
+        .collect::<Vec<uuid::Uuid>>();
+
+    let embeds = get_story_embeddings_by_qdrant_ids_pg_query(qdrant_point_ids, pool).await?;
+
+    Ok(
+        in_point_order(embeds, &points, |embed| embed.qdrant_point_id)
+            .into_iter()
+            .map(DocEmbeddingType::StoryEmbedding)
+            .collect::<Vec<DocEmbeddingType>>(),
+    )
+}
+
+pub async fn get_named_chapter_ranges_by_point_id(
+    points: Vec<QdrantPoints>,
+    pool: Pool<Postgres>,
+) -> Result<Vec<DocEmbeddingType>, ServiceError> {
+    let qdrant_point_ids = points
+        .iter()
+        .map(|point| point.point_id)
+        .collect::<Vec<uuid::Uuid>>();
+
+    let embeds = get_named_chapter_ranges_by_qdrant_ids_pg_query(qdrant_point_ids, pool).await?;
+
+    Ok(
+        in_point_order(embeds, &points, |embed| embed.qdrant_point_id)
+            .into_iter()
+            .map(DocEmbeddingType::NamedChapterRange)
+            .collect::<Vec<DocEmbeddingType>>(),
+    )
+}
+
+/// Returns the stored vector of every target, in the same order as the targets. Targets that
+/// are not indexed are returned as `None`.
+pub async fn get_target_vectors_query(
+    targets: &[SimilarityTarget],
+    doc_group: Option<DocGroupSpec>,
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<Vec<Option<Vec<f32>>>, ServiceError> {
+    let story_ids = targets
+        .iter()
+        .map(|target| target.story_id)
+        .collect::<Vec<i64>>();
+    let indices = targets
+        .iter()
+        .map(|target| target.index)
+        .collect::<Vec<i32>>();
+
+    let qdrant_point_ids = match doc_group {
+        Some(doc_group) => {
+            get_doc_group_qdrant_ids_by_story_index_pg_query(story_ids, indices, doc_group, pool)
+                .await?
+        }
+        None => {
+            get_doc_embedding_qdrant_ids_by_story_index_pg_query(story_ids, indices, pool).await?
+        }
+    };
+
+    let points = get_points_with_vectors_qdrant_query(
+        doc_collection_name(doc_group),
+        qdrant_point_ids
+            .iter()
+            .map(|container| container.qdrant_point_id)
+            .collect(),
+        vector_store,
+    )
+    .await?;
+
+    Ok(targets
+        .iter()
+        .map(|target| {
+            let qdrant_point_id = qdrant_point_ids
+                .iter()
+                .find(|container| {
+                    container.story_id == target.story_id && container.index == target.index
+                })?
+                .qdrant_point_id;
+
+            points
+                .iter()
+                .find(|point| point.point_id == qdrant_point_id)
+                .map(|point| point.vector.clone())
+        })
+        .collect())
+}
+
+/// Dampens the weight of the top ranks in reciprocal rank fusion
+pub const RRF_K: f32 = 60.0;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct MultiResolutionMatch {
+    pub story_id: i64,
+    pub score: f32,
+    /// `None` when single chapters matched best
+    pub best_doc_group: Option<DocGroupSpec>,
+    pub best_index: i32,
+    pub best_rank: usize,
+    pub best_similarity: f32,
+}
+
+/// Fuses the ranked points of every resolution by story with reciprocal rank fusion. A
+/// resolution contributes `1 / (RRF_K + rank)` for the best ranked point of each story, and the
+/// resolution where the story ranked highest is reported as its best match. Ties go to the
+/// resolution that comes first.
+pub fn fuse_resolutions_by_story(
+    rankings: Vec<(Option<DocGroupSpec>, Vec<QdrantPoints>)>,
+) -> Vec<MultiResolutionMatch> {
+    let mut matches: HashMap<i64, MultiResolutionMatch> = HashMap::new();
+
+    for (doc_group, points) in rankings {
+        let mut seen_story_ids = HashSet::new();
+        for (position, point) in points.iter().enumerate() {
+            let story_id = point.payload.story_id;
+            if !seen_story_ids.insert(story_id) {
+                continue;
+            }
+
+            let rank = position + 1;
+            let contribution = 1.0 / (RRF_K + rank as f32);
+            let best = MultiResolutionMatch {
+                story_id,
+                score: contribution,
+                best_doc_group: doc_group,
+                best_index: point.payload.index,
+                best_rank: rank,
+                best_similarity: point.score,
+            };
+
+            match matches.entry(story_id) {
+                Entry::Occupied(mut existing) => {
+                    let existing = existing.get_mut();
+                    let score = existing.score + contribution;
+                    if rank < existing.best_rank {
+                        *existing = best;
+                    }
+                    existing.score = score;
+                }
+                Entry::Vacant(vacant) => {
+                    vacant.insert(best);
+                }
+            }
+        }
+    }
+
+    let mut matches = matches.into_values().collect::<Vec<MultiResolutionMatch>>();
+    matches.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then(a.story_id.cmp(&b.story_id))
+    });
+    matches
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data::models::DocEmbeddingQdrantPayload;
+
+    fn point(story_id: i64, index: i32, score: f32) -> QdrantPoints {
+        QdrantPoints {
+            score,
+            point_id: uuid::Uuid::new_v4(),
+            payload: DocEmbeddingQdrantPayload { story_id, index },
+        }
+    }
+
+    #[test]
+    pub fn test_fuse_resolutions_by_story() {
+        let size_3 = DocGroupSpec::new(3, None).unwrap();
+        let rankings = vec![
+            (
+                None,
+                vec![point(1, 4, 0.9), point(2, 0, 0.8), point(1, 5, 0.7)],
+            ),
+            (Some(size_3), vec![point(2, 1, 0.95), point(3, 2, 0.6)]),
+        ];
+
+        let fused = fuse_resolutions_by_story(rankings);
+
+        // Story 2 is ranked in both resolutions, story 1 only counts its best chapter once
+        assert_eq!(
+            fused.iter().map(|m| m.story_id).collect::<Vec<i64>>(),
+            vec![2, 1, 3]
+        );
+        assert!((fused[0].score - (1.0 / 62.0 + 1.0 / 61.0)).abs() < 1e-6);
+        assert!((fused[1].score - 1.0 / 61.0).abs() < 1e-6);
+
+        assert_eq!(fused[0].best_doc_group, Some(size_3));
+        assert_eq!(fused[0].best_index, 1);
+        assert_eq!(fused[0].best_rank, 1);
+        assert_eq!(fused[1].best_doc_group, None);
+        assert_eq!(fused[1].best_index, 4);
+    }
+
+    #[test]
+    pub fn test_rows_follow_the_ranking_of_their_points() {
+        let points = vec![point(1, 2, 0.9), point(1, 0, 0.8), point(1, 1, 0.7)];
+        let rows = vec![
+            (points[1].point_id, 0),
+            (points[2].point_id, 1),
+            (points[0].point_id, 2),
+        ];
+
+        let ordered = in_point_order(rows, &points, |row| row.0);
+
+        assert_eq!(
+            ordered.iter().map(|row| row.1).collect::<Vec<i32>>(),
+            vec![2, 0, 1]
+        );
+    }
+}
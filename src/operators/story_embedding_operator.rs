@@ -0,0 +1,257 @@
+use super::doc_embedding_operator::{
+    get_chapter_points_with_vectors, get_chapter_vectors_in_range,
+};
+use super::embedding_operator::{story_embedding_half_life, StoryCentroidSum};
+use super::qdrant_operator::story_embedding_vector_mutation;
+use super::vector_outbox_operator::enqueue_vector_mutations_pg_query;
+use super::vector_store_operator::{normalize, VectorStore};
+use crate::{data::models::StoryEmbedding, errors::ServiceError};
+use sqlx::{Pool, Postgres, Transaction};
+use std::time::Duration;
+
+pub struct StoryQdrantPointIdContainer {
+    pub story_id: i64,
+    pub qdrant_point_id: uuid::Uuid,
+}
+
+pub async fn get_story_embedding_qdrant_ids_pg_query(
+    story_ids: Vec<i64>,
+    pool: Pool<Postgres>,
+) -> Result<Vec<StoryQdrantPointIdContainer>, ServiceError> {
+    let qdrant_point_ids = sqlx::query_as!(
+        StoryQdrantPointIdContainer,
+        r#"
+        SELECT story_id, qdrant_point_id
+        FROM story_embeddings
+        WHERE story_id = ANY($1)
+        "#,
+        story_ids.as_slice(),
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectStoryEmbeddingsPgError)?;
+
+    Ok(qdrant_point_ids)
+}
+
+pub async fn get_story_embeddings_by_qdrant_ids_pg_query(
+    qdrant_point_ids: Vec<uuid::Uuid>,
+    pool: Pool<Postgres>,
+) -> Result<Vec<StoryEmbedding>, ServiceError> {
+    let story_embeddings = sqlx::query_as!(
+        StoryEmbedding,
+        r#"
+        SELECT *
+        FROM story_embeddings
+        WHERE qdrant_point_id = ANY($1)
+        "#,
+        qdrant_point_ids.as_slice(),
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::SelectStoryEmbeddingsPgError)?;
+
+    Ok(story_embeddings)
+}
+
+pub async fn upsert_story_embedding_pg_query(
+    story_embedding: StoryEmbedding,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), ServiceError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO story_embeddings (id, story_id, chapter_count, recency_half_life, qdrant_point_id, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (story_id) DO UPDATE
+        SET
+            chapter_count = EXCLUDED.chapter_count,
+            recency_half_life = EXCLUDED.recency_half_life,
+            updated_at = EXCLUDED.updated_at
+        "#,
+        story_embedding.id,
+        story_embedding.story_id,
+        story_embedding.chapter_count,
+        story_embedding.recency_half_life,
+        story_embedding.qdrant_point_id,
+        story_embedding.created_at,
+        story_embedding.updated_at,
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(ServiceError::UpsertStoryEmbeddingPgError)?;
+
+    Ok(())
+}
+
+pub async fn upsert_story_embedding_sum_pg_query(
+    story_id: i64,
+    story_centroid_sum: &StoryCentroidSum,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), ServiceError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO story_embedding_sums (story_id, weighted_sum, total_weight, latest_index)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (story_id) DO UPDATE
+        SET
+            weighted_sum = EXCLUDED.weighted_sum,
+            total_weight = EXCLUDED.total_weight,
+            latest_index = EXCLUDED.latest_index
+        "#,
+        story_id,
+        story_centroid_sum.weighted_sum.as_slice(),
+        story_centroid_sum.total_weight,
+        story_centroid_sum.latest_index,
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(ServiceError::UpsertStoryEmbeddingPgError)?;
+
+    Ok(())
+}
+
+/// The point is written by the relay once the transaction commits
+async fn write_story_embedding(
+    story_embedding: StoryEmbedding,
+    story_centroid_sum: StoryCentroidSum,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), ServiceError> {
+    let centroid = story_centroid_sum.centroid()?;
+    let story_id = story_embedding.story_id;
+
+    enqueue_vector_mutations_pg_query(
+        vec![story_embedding_vector_mutation(
+            story_embedding.clone(),
+            centroid,
+        )],
+        Duration::ZERO,
+        transaction,
+    )
+    .await?;
+    upsert_story_embedding_pg_query(story_embedding, transaction).await?;
+    upsert_story_embedding_sum_pg_query(story_id, &story_centroid_sum, transaction).await?;
+
+    Ok(())
+}
+
+/// Recomputes the centroid of every chapter of the story. Chapters are read through
+/// `transaction` so an uncommitted chapter upsert is included. The story keeps its Qdrant point
+/// id, so the vector is replaced in place.
+pub async fn recompute_story_embedding(
+    story_id: i64,
+    transaction: &mut Transaction<'_, Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<(), ServiceError> {
+    let chapter_embeddings =
+        get_chapter_vectors_in_range(story_id, 0..=i32::MAX, transaction, vector_store).await?;
+
+    if chapter_embeddings.is_empty() {
+        return Ok(());
+    }
+
+    let existing_qdrant_point_id = sqlx::query!(
+        r#"
+        SELECT qdrant_point_id
+        FROM story_embeddings
+        WHERE story_id = $1
+        "#,
+        story_id,
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map_err(ServiceError::SelectStoryEmbeddingsPgError)?
+    .map(|story_embedding| story_embedding.qdrant_point_id);
+
+    let half_life = story_embedding_half_life();
+    let story_embedding = StoryEmbedding::from_details(
+        None,
+        story_id,
+        chapter_embeddings.len() as i32,
+        half_life,
+        existing_qdrant_point_id,
+        None,
+        None,
+    );
+
+    write_story_embedding(
+        story_embedding,
+        StoryCentroidSum::from_chapters(&chapter_embeddings, half_life),
+        transaction,
+    )
+    .await
+}
+
+/// Updates the story embedding for one chapter write from the story's running sum, replacing
+/// the vector of `previous_qdrant_point_id` if the chapter was indexed before. Falls back to
+/// [`recompute_story_embedding`] when there is no usable sum, such as for a new story or after
+/// the half-life changed.
+pub async fn update_story_embedding_for_chapter(
+    story_id: i64,
+    index: i32,
+    previous_qdrant_point_id: Option<uuid::Uuid>,
+    embedding: Vec<f32>,
+    transaction: &mut Transaction<'_, Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<(), ServiceError> {
+    // the row lock orders concurrent chapter writes of the same story
+    let stored = sqlx::query!(
+        r#"
+        SELECT story_embeddings.id, story_embeddings.chapter_count,
+            story_embeddings.recency_half_life, story_embeddings.qdrant_point_id,
+            story_embeddings.created_at, story_embedding_sums.weighted_sum,
+            story_embedding_sums.total_weight, story_embedding_sums.latest_index
+        FROM story_embeddings
+        JOIN story_embedding_sums ON story_embedding_sums.story_id = story_embeddings.story_id
+        WHERE story_embeddings.story_id = $1
+        FOR UPDATE
+        "#,
+        story_id,
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map_err(ServiceError::SelectStoryEmbeddingsPgError)?;
+
+    let half_life = story_embedding_half_life();
+    let Some(stored) = stored.filter(|stored| {
+        stored.recency_half_life == half_life && stored.weighted_sum.len() == embedding.len()
+    }) else {
+        return recompute_story_embedding(story_id, transaction, vector_store).await;
+    };
+
+    let mut story_centroid_sum = StoryCentroidSum {
+        weighted_sum: stored.weighted_sum,
+        total_weight: stored.total_weight,
+        latest_index: stored.latest_index,
+    };
+    let mut chapter_count = stored.chapter_count;
+
+    match previous_qdrant_point_id {
+        Some(previous_qdrant_point_id) => {
+            let Some((_, previous_vector)) = get_chapter_points_with_vectors(
+                vec![previous_qdrant_point_id],
+                transaction,
+                vector_store,
+            )
+            .await?
+            .pop() else {
+                return recompute_story_embedding(story_id, transaction, vector_store).await;
+            };
+            story_centroid_sum.remove(index, &previous_vector, half_life);
+        }
+        None => chapter_count += 1,
+    }
+    // stored chapter vectors are normalized, so the sum is built from normalized vectors too
+    story_centroid_sum.add(index, &normalize(embedding), half_life);
+
+    let story_embedding = StoryEmbedding::from_details(
+        Some(stored.id),
+        story_id,
+        chapter_count,
+        half_life,
+        Some(stored.qdrant_point_id),
+        Some(stored.created_at),
+        None,
+    );
+
+    write_story_embedding(story_embedding, story_centroid_sum, transaction).await
+}
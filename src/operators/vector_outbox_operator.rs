@@ -0,0 +1,341 @@
+use super::pgvector_operator::{json_to_payload, payload_to_json};
+use super::vector_store_operator::{VectorRecord, VectorStore};
+use crate::errors::ServiceError;
+use actix_rt::time::timeout;
+use futures::{
+    channel::{
+        mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    StreamExt,
+};
+use sqlx::{Pool, Postgres, Transaction};
+use std::{sync::Arc, time::Duration};
+
+const OUTBOX_BATCH_SIZE: i64 = 50;
+
+/// A mutation is dropped from the relay after this many failed attempts and left in the table
+/// with `gave_up_at` set, so one bad mutation cannot hold up the points behind it forever
+const OUTBOX_MAX_ATTEMPTS: i32 = 10;
+
+/// How long a relay has to apply the mutations it claimed before another relay may take them
+const OUTBOX_CLAIM_LEASE: Duration = Duration::from_secs(300);
+
+/// How long a handler waits for the relay to apply the mutations it just committed
+const OUTBOX_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub enum VectorMutation {
+    Upsert {
+        collection_name: String,
+        record: VectorRecord,
+    },
+    Delete {
+        collection_name: String,
+        point_id: uuid::Uuid,
+    },
+}
+
+/// Delay before retrying a mutation that failed `attempts` times
+pub fn outbox_retry_delay(attempts: i32) -> Duration {
+    Duration::from_secs(2u64.saturating_pow(attempts.max(0) as u32).min(300))
+}
+
+/// Records the mutations in the transaction of the metadata write they belong to and returns
+/// their ids. They are applied once `delay` has passed after the transaction commits.
+pub async fn enqueue_vector_mutations_pg_query(
+    mutations: Vec<VectorMutation>,
+    delay: Duration,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<i64>, ServiceError> {
+    let mut ids = vec![];
+
+    for mutation in mutations {
+        let (collection_name, operation, point_id, vector, payload) = match mutation {
+            VectorMutation::Upsert {
+                collection_name,
+                record,
+            } => (
+                collection_name,
+                "upsert",
+                record.id,
+                Some(record.vector),
+                Some(payload_to_json(record.payload)),
+            ),
+            VectorMutation::Delete {
+                collection_name,
+                point_id,
+            } => (collection_name, "delete", point_id, None, None),
+        };
+
+        let id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO vector_outbox (collection_name, operation, point_id, vector, payload, available_at)
+            VALUES ($1, $2, $3, $4, $5, CURRENT_TIMESTAMP + make_interval(secs => $6))
+            RETURNING id
+            "#,
+            collection_name,
+            operation,
+            point_id,
+            vector.as_deref(),
+            payload,
+            delay.as_secs_f64(),
+        )
+        .fetch_one(&mut **transaction)
+        .await
+        .map_err(ServiceError::EnqueueVectorMutationPgError)?;
+        ids.push(id);
+    }
+
+    Ok(ids)
+}
+
+/// Returns the vector of every point among `point_ids` that has an upsert still waiting for the
+/// relay, including upserts recorded earlier in `transaction`. The latest upsert of a point wins.
+pub async fn get_pending_vectors_pg_query(
+    point_ids: &[uuid::Uuid],
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<(uuid::Uuid, Vec<f32>)>, ServiceError> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT DISTINCT ON (point_id) point_id, vector AS "vector!: Vec<f32>"
+        FROM vector_outbox
+        WHERE point_id = ANY($1) AND operation = 'upsert' AND gave_up_at IS NULL
+        ORDER BY point_id, id DESC
+        "#,
+        point_ids,
+    )
+    .fetch_all(&mut **transaction)
+    .await
+    .map_err(ServiceError::RelayVectorMutationPgError)?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.point_id, row.vector))
+        .collect())
+}
+
+struct OutboxRow {
+    id: i64,
+    collection_name: String,
+    operation: String,
+    point_id: uuid::Uuid,
+    vector: Option<Vec<f32>>,
+    payload: Option<serde_json::Value>,
+    attempts: i32,
+}
+
+async fn apply_outbox_row(row: &OutboxRow, vector_store: &dyn VectorStore) -> anyhow::Result<()> {
+    match row.operation.as_str() {
+        "upsert" => {
+            vector_store
+                .upsert(
+                    &row.collection_name,
+                    vec![VectorRecord {
+                        id: row.point_id,
+                        vector: row.vector.clone().unwrap_or_default(),
+                        payload: json_to_payload(row.payload.clone().unwrap_or_default()),
+                    }],
+                )
+                .await
+        }
+        "delete" => {
+            vector_store
+                .delete(&row.collection_name, vec![row.point_id])
+                .await
+        }
+        operation => Err(anyhow::anyhow!("Unknown outbox operation {}", operation)),
+    }
+}
+
+/// Claims one batch of due mutations, so other relays skip them, and applies them after the
+/// claim committed, so no transaction stays open across vector store calls. Returns how many
+/// were taken. A relay that dies mid-batch leaves its claims to expire, and upserts and deletes
+/// of a given id are idempotent, so the batch is simply applied again.
+pub async fn relay_vector_mutations(
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<usize, ServiceError> {
+    // A mutation waits for every earlier pending mutation of the same point, claimed or not, so
+    // mutations of a point are never reordered even with several relays running
+    let rows = sqlx::query_as!(
+        OutboxRow,
+        r#"
+        UPDATE vector_outbox
+        SET available_at = CURRENT_TIMESTAMP + make_interval(secs => $2)
+        WHERE id IN (
+            SELECT id
+            FROM vector_outbox outbox
+            WHERE gave_up_at IS NULL
+                AND available_at <= CURRENT_TIMESTAMP
+                AND NOT EXISTS (
+                    SELECT 1
+                    FROM vector_outbox earlier
+                    WHERE earlier.gave_up_at IS NULL
+                        AND earlier.collection_name = outbox.collection_name
+                        AND earlier.point_id = outbox.point_id
+                        AND earlier.id < outbox.id
+                )
+            ORDER BY id
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, collection_name, operation, point_id, vector AS "vector: Vec<f32>", payload, attempts
+        "#,
+        OUTBOX_BATCH_SIZE,
+        OUTBOX_CLAIM_LEASE.as_secs_f64(),
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServiceError::RelayVectorMutationPgError)?;
+
+    for row in rows.iter() {
+        match apply_outbox_row(row, vector_store).await {
+            Ok(()) => {
+                sqlx::query!(
+                    r#"
+                    DELETE FROM vector_outbox
+                    WHERE id = $1
+                    "#,
+                    row.id,
+                )
+                .execute(&pool)
+                .await
+                .map_err(ServiceError::RelayVectorMutationPgError)?;
+            }
+            Err(err) => {
+                let attempts = row.attempts + 1;
+                log::info!(
+                    "Failed to apply outbox mutation {} after {} attempts: {:?}",
+                    row.id,
+                    attempts,
+                    err
+                );
+                sqlx::query!(
+                    r#"
+                    UPDATE vector_outbox
+                    SET attempts = $2,
+                        last_error = $3,
+                        available_at = CURRENT_TIMESTAMP + make_interval(secs => $4),
+                        gave_up_at = CASE WHEN $2::INTEGER >= $5::INTEGER THEN CURRENT_TIMESTAMP END
+                    WHERE id = $1
+                    "#,
+                    row.id,
+                    attempts,
+                    format!("{:?}", err),
+                    outbox_retry_delay(attempts).as_secs_f64(),
+                    OUTBOX_MAX_ATTEMPTS,
+                )
+                .execute(&pool)
+                .await
+                .map_err(ServiceError::RelayVectorMutationPgError)?;
+            }
+        }
+    }
+
+    Ok(rows.len())
+}
+
+/// Applies every mutation that is due, for work that reads back what it just recorded
+pub async fn drain_vector_mutations(
+    pool: Pool<Postgres>,
+    vector_store: &dyn VectorStore,
+) -> Result<(), ServiceError> {
+    while relay_vector_mutations(pool.clone(), vector_store).await? as i64 == OUTBOX_BATCH_SIZE {}
+
+    Ok(())
+}
+
+/// Handle shared with the handlers to wake the relay once they committed new mutations
+#[derive(Clone)]
+pub struct VectorOutboxRelay {
+    sender: UnboundedSender<Option<oneshot::Sender<()>>>,
+}
+
+impl VectorOutboxRelay {
+    /// Spawns the worker that applies recorded mutations to the vector store. It also polls
+    /// every `poll_interval` for retries, delayed mutations and mutations of other instances.
+    pub fn start(
+        pool: Pool<Postgres>,
+        vector_store: Arc<dyn VectorStore>,
+        poll_interval: Duration,
+    ) -> Self {
+        let (sender, receiver) = unbounded();
+        actix_rt::spawn(run_vector_outbox_relay(
+            receiver,
+            pool,
+            vector_store,
+            poll_interval,
+        ));
+        Self { sender }
+    }
+
+    pub fn notify(&self) {
+        if let Err(err) = self.sender.unbounded_send(None) {
+            log::info!("Failed to wake the vector outbox relay: {:?}", err);
+        }
+    }
+
+    /// Wakes the relay and waits until it went through every due mutation, so a handler can
+    /// answer once its writes are searchable. Mutations that fail are left to the retries.
+    /// Returns `false` when the relay did not get through them in time, so they are still
+    /// pending.
+    pub async fn flush(&self) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        if let Err(err) = self.sender.unbounded_send(Some(sender)) {
+            log::info!("Failed to wake the vector outbox relay: {:?}", err);
+            return false;
+        }
+        match timeout(OUTBOX_FLUSH_TIMEOUT, receiver).await {
+            Ok(Ok(())) => true,
+            // the relay stopped before answering
+            Ok(Err(_)) => false,
+            Err(_) => {
+                log::info!("Timed out waiting for the vector outbox relay");
+                false
+            }
+        }
+    }
+}
+
+async fn run_vector_outbox_relay(
+    mut receiver: UnboundedReceiver<Option<oneshot::Sender<()>>>,
+    pool: Pool<Postgres>,
+    vector_store: Arc<dyn VectorStore>,
+    poll_interval: Duration,
+) {
+    let mut flushes: Vec<oneshot::Sender<()>> = vec![];
+
+    loop {
+        match relay_vector_mutations(pool.clone(), vector_store.as_ref()).await {
+            // more may be due right away
+            Ok(taken) if taken as i64 == OUTBOX_BATCH_SIZE => continue,
+            Ok(_) => {}
+            Err(err) => log::info!("Failed to relay vector mutations: {:?}", err),
+        }
+
+        for flush in flushes.drain(..) {
+            let _ = flush.send(());
+        }
+
+        match timeout(poll_interval, receiver.next()).await {
+            // every relay handle was dropped, the server is shutting down
+            Ok(None) => break,
+            Ok(Some(Some(flush))) => flushes.push(flush),
+            Ok(Some(None)) | Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_outbox_retry_delay() {
+        assert_eq!(outbox_retry_delay(1), Duration::from_secs(2));
+        assert_eq!(outbox_retry_delay(4), Duration::from_secs(16));
+        assert_eq!(outbox_retry_delay(9), Duration::from_secs(300));
+        assert_eq!(outbox_retry_delay(100), Duration::from_secs(300));
+    }
+}
@@ -0,0 +1,1207 @@
+use super::embedding_operator::cosine_similarity;
+use crate::data::models::payload_integer;
+use async_trait::async_trait;
+use qdrant_client::{
+    prelude::{QdrantClient, Value},
+    qdrant::{
+        self, alias_operations, point_id::PointIdOptions, quantization_config, vectors_config,
+        AliasOperations, ChangeAliases, CompressionRatio, Condition, CountPoints, CreateAlias,
+        CreateCollection, DeleteAlias, Distance, HasIdCondition, HnswConfigDiff, PointId,
+        PointStruct, ProductQuantization, QuantizationConfig, QuantizationType, RecommendPoints,
+        ScalarQuantization, ScrollPoints, SearchPoints, VectorParams, VectorsConfig,
+    },
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+pub type VectorPayload = HashMap<String, Value>;
+
+#[derive(Debug, Clone)]
+pub struct VectorRecord {
+    pub id: uuid::Uuid,
+    pub vector: Vec<f32>,
+    pub payload: VectorPayload,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScoredVectorRecord {
+    pub id: uuid::Uuid,
+    pub score: f32,
+    pub payload: VectorPayload,
+}
+
+/// Matches an integer payload field, whether the collection stores it as an integer or a string
+#[derive(Debug, Clone)]
+pub struct PayloadMatch {
+    pub key: String,
+    pub value: i64,
+}
+
+impl PayloadMatch {
+    pub fn new(key: &str, value: i64) -> Self {
+        Self {
+            key: key.to_owned(),
+            value,
+        }
+    }
+
+    fn matches(&self, payload: &VectorPayload) -> bool {
+        payload_integer(payload.get(&self.key)) == Some(self.value)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VectorFilter {
+    pub has_id: Option<Vec<uuid::Uuid>>,
+    pub must: Vec<PayloadMatch>,
+    pub must_not: Vec<PayloadMatch>,
+}
+
+impl VectorFilter {
+    pub fn has_id(ids: Vec<uuid::Uuid>) -> Self {
+        Self {
+            has_id: Some(ids),
+            ..Default::default()
+        }
+    }
+
+    pub fn must_not(payload_match: PayloadMatch) -> Self {
+        Self {
+            must_not: vec![payload_match],
+            ..Default::default()
+        }
+    }
+
+    pub fn matches(&self, id: &uuid::Uuid, payload: &VectorPayload) -> bool {
+        self.has_id.as_ref().is_none_or(|ids| ids.contains(id))
+            && self.must.iter().all(|condition| condition.matches(payload))
+            && !self
+                .must_not
+                .iter()
+                .any(|condition| condition.matches(payload))
+    }
+}
+
+/// Everything the service needs from a vector database. Collections store vectors normalized to
+/// unit length and every score is their cosine similarity, whatever distance Qdrant is configured
+/// with. Recommendations score against the average of the positive vectors, leaving the positive
+/// points themselves out.
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// Creating a collection that already exists is not an error
+    async fn create_collection(&self, collection_name: &str, size: u64) -> anyhow::Result<()>;
+
+    /// Deleting a collection that does not exist is not an error. Aliases pointing at the
+    /// collection are removed with it.
+    async fn delete_collection(&self, collection_name: &str) -> anyhow::Result<()>;
+
+    /// Points the alias at the collection in one step, so readers of the alias never see it
+    /// missing. Every other method accepts an alias wherever it takes a collection name.
+    async fn switch_alias(&self, alias_name: &str, collection_name: &str) -> anyhow::Result<()>;
+
+    /// The collection the alias points at, None when no such alias exists
+    async fn alias_target(&self, alias_name: &str) -> anyhow::Result<Option<String>>;
+
+    async fn count(&self, collection_name: &str) -> anyhow::Result<u64>;
+
+    async fn upsert(&self, collection_name: &str, records: Vec<VectorRecord>)
+        -> anyhow::Result<()>;
+
+    async fn delete(&self, collection_name: &str, ids: Vec<uuid::Uuid>) -> anyhow::Result<()>;
+
+    /// Unknown ids are left out of the result
+    async fn retrieve(
+        &self,
+        collection_name: &str,
+        ids: Vec<uuid::Uuid>,
+    ) -> anyhow::Result<Vec<VectorRecord>>;
+
+    async fn search(
+        &self,
+        collection_name: &str,
+        vector: Vec<f32>,
+        filter: VectorFilter,
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<ScoredVectorRecord>>;
+
+    async fn recommend(
+        &self,
+        collection_name: &str,
+        positive: Vec<uuid::Uuid>,
+        filter: VectorFilter,
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<ScoredVectorRecord>>;
+
+    /// Pages through the matching points in id order, returning the id to continue from
+    async fn scroll(
+        &self,
+        collection_name: &str,
+        filter: VectorFilter,
+        offset: Option<uuid::Uuid>,
+        limit: u64,
+    ) -> anyhow::Result<(Vec<VectorRecord>, Option<uuid::Uuid>)>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorQuantization {
+    Scalar,
+    Product(CompressionRatio),
+}
+
+/// Settings for every collection the service creates in Qdrant. Unset values are left to
+/// Qdrant's defaults.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QdrantCollectionConfig {
+    pub distance: Distance,
+    pub hnsw_m: Option<u64>,
+    pub hnsw_ef_construct: Option<u64>,
+    pub quantization: Option<VectorQuantization>,
+    pub on_disk: bool,
+    pub shard_number: Option<u32>,
+    pub replication_factor: Option<u32>,
+}
+
+impl Default for QdrantCollectionConfig {
+    fn default() -> Self {
+        Self {
+            distance: Distance::Cosine,
+            hnsw_m: None,
+            hnsw_ef_construct: None,
+            quantization: None,
+            on_disk: false,
+            shard_number: None,
+            replication_factor: None,
+        }
+    }
+}
+
+impl QdrantCollectionConfig {
+    /// A score Qdrant computed with this distance as cosine similarity. On unit vectors a dot
+    /// product is the cosine, and a euclidean distance `d` is `1 - d² / 2` from it.
+    pub fn cosine_score(&self, score: f32) -> f32 {
+        match self.distance {
+            Distance::Euclid => 1.0 - score * score / 2.0,
+            _ => score,
+        }
+    }
+
+    fn hnsw_config(&self) -> Option<HnswConfigDiff> {
+        if self.hnsw_m.is_none() && self.hnsw_ef_construct.is_none() {
+            return None;
+        }
+
+        Some(HnswConfigDiff {
+            m: self.hnsw_m,
+            ef_construct: self.hnsw_ef_construct,
+            ..Default::default()
+        })
+    }
+
+    fn quantization_config(&self) -> Option<QuantizationConfig> {
+        let quantization = match self.quantization? {
+            VectorQuantization::Scalar => {
+                quantization_config::Quantization::Scalar(ScalarQuantization {
+                    r#type: QuantizationType::Int8.into(),
+                    quantile: None,
+                    always_ram: Some(true),
+                })
+            }
+            VectorQuantization::Product(compression) => {
+                quantization_config::Quantization::Product(ProductQuantization {
+                    compression: compression.into(),
+                    always_ram: Some(true),
+                })
+            }
+        };
+
+        Some(QuantizationConfig {
+            quantization: Some(quantization),
+        })
+    }
+
+    fn create_collection(&self, collection_name: &str, size: u64) -> CreateCollection {
+        CreateCollection {
+            collection_name: collection_name.to_owned(),
+            vectors_config: Some(VectorsConfig {
+                config: Some(vectors_config::Config::Params(VectorParams {
+                    size,
+                    distance: self.distance.into(),
+                    hnsw_config: None,
+                    quantization_config: None,
+                    on_disk: Some(self.on_disk),
+                })),
+            }),
+            hnsw_config: self.hnsw_config(),
+            quantization_config: self.quantization_config(),
+            shard_number: self.shard_number,
+            replication_factor: self.replication_factor,
+            ..Default::default()
+        }
+    }
+
+    /// Describes every way an existing collection differs from this config. Settings left to
+    /// Qdrant's defaults are not compared.
+    pub fn drift(&self, size: u64, existing: &qdrant::CollectionConfig) -> Vec<String> {
+        let mut drift = vec![];
+        let params = existing.params.clone().unwrap_or_default();
+
+        match params
+            .vectors_config
+            .and_then(|vectors_config| vectors_config.config)
+        {
+            Some(vectors_config::Config::Params(vector_params)) => {
+                if vector_params.size != size {
+                    drift.push(format!(
+                        "vector size is {}, configured {}",
+                        vector_params.size, size
+                    ));
+                }
+                if vector_params.distance != i32::from(self.distance) {
+                    drift.push(format!(
+                        "distance is {:?}, configured {:?}",
+                        Distance::from_i32(vector_params.distance).unwrap_or_default(),
+                        self.distance
+                    ));
+                }
+                if vector_params.on_disk.unwrap_or(false) != self.on_disk {
+                    drift.push(format!(
+                        "on_disk is {}, configured {}",
+                        vector_params.on_disk.unwrap_or(false),
+                        self.on_disk
+                    ));
+                }
+            }
+            _ => drift.push("collection has no single unnamed vector".to_owned()),
+        }
+
+        let hnsw_config = existing.hnsw_config.clone().unwrap_or_default();
+        for (name, existing_value, configured_value) in [
+            ("hnsw m", hnsw_config.m, self.hnsw_m),
+            (
+                "hnsw ef_construct",
+                hnsw_config.ef_construct,
+                self.hnsw_ef_construct,
+            ),
+        ] {
+            if let Some(configured_value) = configured_value {
+                if existing_value != Some(configured_value) {
+                    drift.push(format!(
+                        "{} is {:?}, configured {}",
+                        name, existing_value, configured_value
+                    ));
+                }
+            }
+        }
+
+        // None when Qdrant uses a kind of quantization this config cannot express
+        let existing_quantization = match existing
+            .quantization_config
+            .clone()
+            .and_then(|quantization_config| quantization_config.quantization)
+        {
+            None => Some(None),
+            Some(quantization_config::Quantization::Scalar(_)) => {
+                Some(Some(VectorQuantization::Scalar))
+            }
+            Some(quantization_config::Quantization::Product(product)) => {
+                CompressionRatio::from_i32(product.compression)
+                    .map(|compression| Some(VectorQuantization::Product(compression)))
+            }
+            Some(_) => None,
+        };
+        if existing_quantization != Some(self.quantization) {
+            drift.push(format!(
+                "quantization is {}, configured {}",
+                existing_quantization.map_or("unsupported".to_owned(), describe_quantization),
+                describe_quantization(self.quantization)
+            ));
+        }
+
+        if let Some(shard_number) = self.shard_number {
+            if params.shard_number != shard_number {
+                drift.push(format!(
+                    "shard number is {}, configured {}",
+                    params.shard_number, shard_number
+                ));
+            }
+        }
+        if let Some(replication_factor) = self.replication_factor {
+            if params.replication_factor.unwrap_or(1) != replication_factor {
+                drift.push(format!(
+                    "replication factor is {}, configured {}",
+                    params.replication_factor.unwrap_or(1),
+                    replication_factor
+                ));
+            }
+        }
+
+        drift
+    }
+}
+
+fn describe_quantization(quantization: Option<VectorQuantization>) -> String {
+    match quantization {
+        None => "none".to_owned(),
+        Some(VectorQuantization::Scalar) => "scalar".to_owned(),
+        Some(VectorQuantization::Product(compression)) => format!("product {:?}", compression),
+    }
+}
+
+pub struct QdrantVectorStore {
+    client: QdrantClient,
+    collection_config: QdrantCollectionConfig,
+}
+
+impl QdrantVectorStore {
+    pub fn new(client: QdrantClient, collection_config: QdrantCollectionConfig) -> Self {
+        Self {
+            client,
+            collection_config,
+        }
+    }
+
+    pub async fn health_check(&self) -> anyhow::Result<()> {
+        self.client.health_check().await.map(|_| ())
+    }
+
+    /// Every existing collection that differs from the configured settings, with the
+    /// differences. Changing the config only affects collections created afterwards, so these
+    /// keep their settings until they are rebuilt.
+    pub async fn collection_drift(&self, size: u64) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+        let collections = self.client.list_collections().await?;
+
+        let mut drifted = vec![];
+        for collection in collections.collections {
+            let existing = self
+                .client
+                .collection_info(&collection.name)
+                .await?
+                .result
+                .and_then(|info| info.config)
+                .unwrap_or_default();
+            let drift = self.collection_config.drift(size, &existing);
+            if !drift.is_empty() {
+                drifted.push((collection.name, drift));
+            }
+        }
+
+        Ok(drifted)
+    }
+}
+
+fn point_id_to_uuid(point_id: Option<PointId>) -> Option<uuid::Uuid> {
+    match point_id?.point_id_options? {
+        PointIdOptions::Uuid(id) => uuid::Uuid::parse_str(&id).ok(),
+        PointIdOptions::Num(_) => None,
+    }
+}
+
+fn vectors_to_vec(vectors: Option<qdrant::Vectors>) -> Option<Vec<f32>> {
+    match vectors?.vectors_options? {
+        qdrant::vectors::VectorsOptions::Vector(vector) => Some(vector.data),
+        _ => None,
+    }
+}
+
+fn payload_match_conditions(payload_match: &PayloadMatch) -> Vec<Condition> {
+    vec![
+        Condition::matches(payload_match.key.clone(), payload_match.value.to_string()),
+        Condition::matches(payload_match.key.clone(), payload_match.value),
+    ]
+}
+
+fn qdrant_filter(filter: &VectorFilter) -> Option<qdrant::Filter> {
+    let mut must: Vec<Condition> = filter
+        .must
+        .iter()
+        .map(|payload_match| {
+            qdrant::Filter {
+                should: payload_match_conditions(payload_match),
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect();
+    if let Some(ids) = filter.has_id.as_ref() {
+        must.push(
+            HasIdCondition {
+                has_id: ids.iter().map(|id| id.to_string().into()).collect(),
+            }
+            .into(),
+        );
+    }
+    let must_not: Vec<Condition> = filter
+        .must_not
+        .iter()
+        .flat_map(payload_match_conditions)
+        .collect();
+
+    if must.is_empty() && must_not.is_empty() {
+        return None;
+    }
+
+    Some(qdrant::Filter {
+        must,
+        must_not,
+        ..Default::default()
+    })
+}
+
+#[async_trait]
+impl VectorStore for QdrantVectorStore {
+    async fn create_collection(&self, collection_name: &str, size: u64) -> anyhow::Result<()> {
+        if self.client.has_collection(collection_name).await?
+            || self.alias_target(collection_name).await?.is_some()
+        {
+            return Ok(());
+        }
+
+        self.client
+            .create_collection(
+                &self
+                    .collection_config
+                    .create_collection(collection_name, size),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_collection(&self, collection_name: &str) -> anyhow::Result<()> {
+        if self.client.has_collection(collection_name).await? {
+            self.client.delete_collection(collection_name).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn switch_alias(&self, alias_name: &str, collection_name: &str) -> anyhow::Result<()> {
+        // both actions go in one request, which Qdrant applies atomically
+        let mut actions = vec![];
+        if self.alias_target(alias_name).await?.is_some() {
+            actions.push(AliasOperations {
+                action: Some(alias_operations::Action::DeleteAlias(DeleteAlias {
+                    alias_name: alias_name.to_owned(),
+                })),
+            });
+        }
+        actions.push(AliasOperations {
+            action: Some(alias_operations::Action::CreateAlias(CreateAlias {
+                collection_name: collection_name.to_owned(),
+                alias_name: alias_name.to_owned(),
+            })),
+        });
+
+        self.client
+            .update_aliases(ChangeAliases {
+                actions,
+                timeout: None,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn alias_target(&self, alias_name: &str) -> anyhow::Result<Option<String>> {
+        let aliases = self.client.list_aliases().await?;
+
+        Ok(aliases
+            .aliases
+            .into_iter()
+            .find(|alias| alias.alias_name == alias_name)
+            .map(|alias| alias.collection_name))
+    }
+
+    async fn count(&self, collection_name: &str) -> anyhow::Result<u64> {
+        let count = self
+            .client
+            .count(&CountPoints {
+                collection_name: collection_name.to_owned(),
+                filter: None,
+                exact: Some(true),
+                ..Default::default()
+            })
+            .await?;
+
+        Ok(count.result.map(|result| result.count).unwrap_or(0))
+    }
+
+    async fn upsert(
+        &self,
+        collection_name: &str,
+        records: Vec<VectorRecord>,
+    ) -> anyhow::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        // Qdrant only normalizes for cosine distance, other metrics rely on this
+        let points = records
+            .into_iter()
+            .map(|record| PointStruct {
+                id: Some(record.id.to_string().into()),
+                vectors: Some(normalize(record.vector).into()),
+                payload: record.payload,
+            })
+            .collect();
+
+        self.client
+            .upsert_points_blocking(collection_name, None, points, None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, collection_name: &str, ids: Vec<uuid::Uuid>) -> anyhow::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let filter = qdrant::Filter {
+            should: vec![HasIdCondition {
+                has_id: ids.into_iter().map(|id| id.to_string().into()).collect(),
+            }
+            .into()],
+            ..Default::default()
+        };
+
+        self.client
+            .delete_points(collection_name, None, &filter.into(), None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn retrieve(
+        &self,
+        collection_name: &str,
+        ids: Vec<uuid::Uuid>,
+    ) -> anyhow::Result<Vec<VectorRecord>> {
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let point_ids: Vec<PointId> = ids.into_iter().map(|id| id.to_string().into()).collect();
+
+        let get_response = self
+            .client
+            .get_points(
+                collection_name,
+                None,
+                &point_ids,
+                Some(true),
+                Some(true),
+                None,
+            )
+            .await?;
+
+        Ok(get_response
+            .result
+            .into_iter()
+            .filter_map(|point| {
+                Some(VectorRecord {
+                    id: point_id_to_uuid(point.id)?,
+                    vector: vectors_to_vec(point.vectors)?,
+                    payload: point.payload,
+                })
+            })
+            .collect())
+    }
+
+    async fn search(
+        &self,
+        collection_name: &str,
+        vector: Vec<f32>,
+        filter: VectorFilter,
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<ScoredVectorRecord>> {
+        let search_response = self
+            .client
+            .search_points(&SearchPoints {
+                collection_name: collection_name.to_owned(),
+                vector,
+                filter: qdrant_filter(&filter),
+                limit,
+                offset: Some(offset),
+                with_payload: Some(true.into()),
+                ..Default::default()
+            })
+            .await?;
+
+        Ok(search_response
+            .result
+            .into_iter()
+            .filter_map(|point| {
+                Some(ScoredVectorRecord {
+                    id: point_id_to_uuid(point.id)?,
+                    score: self.collection_config.cosine_score(point.score),
+                    payload: point.payload,
+                })
+            })
+            .collect())
+    }
+
+    async fn recommend(
+        &self,
+        collection_name: &str,
+        positive: Vec<uuid::Uuid>,
+        filter: VectorFilter,
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<ScoredVectorRecord>> {
+        let recommend_response = self
+            .client
+            .recommend(&RecommendPoints {
+                collection_name: collection_name.to_owned(),
+                positive: positive
+                    .into_iter()
+                    .map(|id| id.to_string().into())
+                    .collect(),
+                filter: qdrant_filter(&filter),
+                limit,
+                offset: Some(offset),
+                with_payload: Some(true.into()),
+                ..Default::default()
+            })
+            .await?;
+
+        Ok(recommend_response
+            .result
+            .into_iter()
+            .filter_map(|point| {
+                Some(ScoredVectorRecord {
+                    id: point_id_to_uuid(point.id)?,
+                    score: self.collection_config.cosine_score(point.score),
+                    payload: point.payload,
+                })
+            })
+            .collect())
+    }
+
+    async fn scroll(
+        &self,
+        collection_name: &str,
+        filter: VectorFilter,
+        offset: Option<uuid::Uuid>,
+        limit: u64,
+    ) -> anyhow::Result<(Vec<VectorRecord>, Option<uuid::Uuid>)> {
+        let scroll_response = self
+            .client
+            .scroll(&ScrollPoints {
+                collection_name: collection_name.to_owned(),
+                filter: qdrant_filter(&filter),
+                offset: offset.map(|id| id.to_string().into()),
+                limit: Some(limit as u32),
+                with_vectors: Some(true.into()),
+                with_payload: Some(true.into()),
+                ..Default::default()
+            })
+            .await?;
+
+        let records = scroll_response
+            .result
+            .into_iter()
+            .filter_map(|point| {
+                Some(VectorRecord {
+                    id: point_id_to_uuid(point.id)?,
+                    vector: vectors_to_vec(point.vectors)?,
+                    payload: point.payload,
+                })
+            })
+            .collect();
+
+        Ok((records, point_id_to_uuid(scroll_response.next_page_offset)))
+    }
+}
+
+struct InMemoryCollection {
+    size: u64,
+    records: BTreeMap<uuid::Uuid, VectorRecord>,
+}
+
+#[derive(Default)]
+struct InMemoryState {
+    collections: HashMap<String, InMemoryCollection>,
+    aliases: HashMap<String, String>,
+}
+
+impl InMemoryState {
+    fn resolve<'a>(&'a self, collection_name: &'a str) -> &'a str {
+        self.aliases
+            .get(collection_name)
+            .map(String::as_str)
+            .unwrap_or(collection_name)
+    }
+}
+
+/// Brute-force store kept in process memory, used to run the service and its tests without a
+/// vector database
+#[derive(Default)]
+pub struct InMemoryVectorStore {
+    state: RwLock<InMemoryState>,
+}
+
+impl InMemoryVectorStore {
+    fn read(&self) -> anyhow::Result<RwLockReadGuard<'_, InMemoryState>> {
+        self.state
+            .read()
+            .map_err(|_| anyhow::anyhow!("In-memory vector store lock was poisoned"))
+    }
+
+    fn write(&self) -> anyhow::Result<RwLockWriteGuard<'_, InMemoryState>> {
+        self.state
+            .write()
+            .map_err(|_| anyhow::anyhow!("In-memory vector store lock was poisoned"))
+    }
+
+    fn with_collection<T>(
+        &self,
+        collection_name: &str,
+        f: impl FnOnce(&InMemoryCollection) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let state = self.read()?;
+        let collection = state
+            .collections
+            .get(state.resolve(collection_name))
+            .ok_or_else(|| anyhow::anyhow!("Collection {} does not exist", collection_name))?;
+        f(collection)
+    }
+
+    fn with_collection_mut<T>(
+        &self,
+        collection_name: &str,
+        f: impl FnOnce(&mut InMemoryCollection) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut state = self.write()?;
+        let resolved_name = state.resolve(collection_name).to_owned();
+        let collection = state
+            .collections
+            .get_mut(&resolved_name)
+            .ok_or_else(|| anyhow::anyhow!("Collection {} does not exist", collection_name))?;
+        f(collection)
+    }
+}
+
+/// Scales the vector to unit length, leaving zero vectors as they are
+pub fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|value| *value /= norm);
+    }
+    vector
+}
+
+/// Scores every record passing the filter against the vector, best first
+fn brute_force_search(
+    records: impl Iterator<Item = VectorRecord>,
+    vector: &[f32],
+    filter: &VectorFilter,
+    limit: u64,
+    offset: u64,
+) -> Vec<ScoredVectorRecord> {
+    let mut scored = records
+        .filter(|record| filter.matches(&record.id, &record.payload))
+        .map(|record| ScoredVectorRecord {
+            id: record.id,
+            score: cosine_similarity(vector, &record.vector),
+            payload: record.payload,
+        })
+        .collect::<Vec<ScoredVectorRecord>>();
+    scored.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.id.cmp(&b.id)));
+
+    scored
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit as usize)
+        .collect()
+}
+
+#[async_trait]
+impl VectorStore for InMemoryVectorStore {
+    async fn create_collection(&self, collection_name: &str, size: u64) -> anyhow::Result<()> {
+        let mut state = self.write()?;
+        if state.aliases.contains_key(collection_name) {
+            return Ok(());
+        }
+        state
+            .collections
+            .entry(collection_name.to_owned())
+            .or_insert_with(|| InMemoryCollection {
+                size,
+                records: BTreeMap::new(),
+            });
+        Ok(())
+    }
+
+    async fn delete_collection(&self, collection_name: &str) -> anyhow::Result<()> {
+        let mut state = self.write()?;
+        state.collections.remove(collection_name);
+        state
+            .aliases
+            .retain(|_, target| target.as_str() != collection_name);
+        Ok(())
+    }
+
+    async fn switch_alias(&self, alias_name: &str, collection_name: &str) -> anyhow::Result<()> {
+        let mut state = self.write()?;
+        if state.collections.contains_key(alias_name) {
+            return Err(anyhow::anyhow!(
+                "Alias {} would shadow a collection with the same name",
+                alias_name
+            ));
+        }
+        if !state.collections.contains_key(collection_name) {
+            return Err(anyhow::anyhow!(
+                "Collection {} does not exist",
+                collection_name
+            ));
+        }
+        state
+            .aliases
+            .insert(alias_name.to_owned(), collection_name.to_owned());
+        Ok(())
+    }
+
+    async fn alias_target(&self, alias_name: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.read()?.aliases.get(alias_name).cloned())
+    }
+
+    async fn count(&self, collection_name: &str) -> anyhow::Result<u64> {
+        self.with_collection(collection_name, |collection| {
+            Ok(collection.records.len() as u64)
+        })
+    }
+
+    async fn upsert(
+        &self,
+        collection_name: &str,
+        records: Vec<VectorRecord>,
+    ) -> anyhow::Result<()> {
+        self.with_collection_mut(collection_name, |collection| {
+            if let Some(record) = records
+                .iter()
+                .find(|record| record.vector.len() as u64 != collection.size)
+            {
+                return Err(anyhow::anyhow!(
+                    "Vector of point {} has {} dimensions, collection {} expects {}",
+                    record.id,
+                    record.vector.len(),
+                    collection_name,
+                    collection.size
+                ));
+            }
+
+            for record in records {
+                collection.records.insert(
+                    record.id,
+                    VectorRecord {
+                        vector: normalize(record.vector),
+                        ..record
+                    },
+                );
+            }
+            Ok(())
+        })
+    }
+
+    async fn delete(&self, collection_name: &str, ids: Vec<uuid::Uuid>) -> anyhow::Result<()> {
+        self.with_collection_mut(collection_name, |collection| {
+            for id in ids.iter() {
+                collection.records.remove(id);
+            }
+            Ok(())
+        })
+    }
+
+    async fn retrieve(
+        &self,
+        collection_name: &str,
+        ids: Vec<uuid::Uuid>,
+    ) -> anyhow::Result<Vec<VectorRecord>> {
+        self.with_collection(collection_name, |collection| {
+            Ok(ids
+                .iter()
+                .filter_map(|id| collection.records.get(id).cloned())
+                .collect())
+        })
+    }
+
+    async fn search(
+        &self,
+        collection_name: &str,
+        vector: Vec<f32>,
+        filter: VectorFilter,
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<ScoredVectorRecord>> {
+        self.with_collection(collection_name, |collection| {
+            Ok(brute_force_search(
+                collection.records.values().cloned(),
+                &vector,
+                &filter,
+                limit,
+                offset,
+            ))
+        })
+    }
+
+    async fn recommend(
+        &self,
+        collection_name: &str,
+        positive: Vec<uuid::Uuid>,
+        filter: VectorFilter,
+        limit: u64,
+        offset: u64,
+    ) -> anyhow::Result<Vec<ScoredVectorRecord>> {
+        self.with_collection(collection_name, |collection| {
+            let positive_vectors = positive
+                .iter()
+                .map(|id| {
+                    collection
+                        .records
+                        .get(id)
+                        .map(|record| record.vector.clone())
+                        .ok_or_else(|| anyhow::anyhow!("No point with id {} found", id))
+                })
+                .collect::<anyhow::Result<Vec<Vec<f32>>>>()?;
+            if positive_vectors.is_empty() {
+                return Err(anyhow::anyhow!("No positive points to recommend from"));
+            }
+
+            let mut average = vec![0.0; collection.size as usize];
+            for vector in positive_vectors.iter() {
+                for (sum, value) in average.iter_mut().zip(vector.iter()) {
+                    *sum += value / positive_vectors.len() as f32;
+                }
+            }
+
+            Ok(brute_force_search(
+                collection
+                    .records
+                    .values()
+                    .filter(|record| !positive.contains(&record.id))
+                    .cloned(),
+                &average,
+                &filter,
+                limit,
+                offset,
+            ))
+        })
+    }
+
+    async fn scroll(
+        &self,
+        collection_name: &str,
+        filter: VectorFilter,
+        offset: Option<uuid::Uuid>,
+        limit: u64,
+    ) -> anyhow::Result<(Vec<VectorRecord>, Option<uuid::Uuid>)> {
+        self.with_collection(collection_name, |collection| {
+            let mut matching = collection
+                .records
+                .range(offset.unwrap_or(uuid::Uuid::nil())..)
+                .map(|(_, record)| record)
+                .filter(|record| filter.matches(&record.id, &record.payload));
+
+            let records = matching
+                .by_ref()
+                .take(limit as usize)
+                .cloned()
+                .collect::<Vec<VectorRecord>>();
+            let next_offset = matching.next().map(|record| record.id);
+
+            Ok((records, next_offset))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(id: u128, vector: Vec<f32>, story_id: i64) -> VectorRecord {
+        let mut payload = VectorPayload::new();
+        payload.insert("story_id".to_owned(), story_id.to_string().into());
+        VectorRecord {
+            id: uuid::Uuid::from_u128(id),
+            vector,
+            payload,
+        }
+    }
+
+    async fn store_with_records() -> InMemoryVectorStore {
+        let store = InMemoryVectorStore::default();
+        store.create_collection("test", 2).await.unwrap();
+        store
+            .upsert(
+                "test",
+                vec![
+                    record(1, vec![1.0, 0.0], 1),
+                    record(2, vec![0.9, 0.1], 2),
+                    record(3, vec![0.0, 1.0], 3),
+                    record(4, vec![0.5, 0.5], 2),
+                ],
+            )
+            .await
+            .unwrap();
+        store
+    }
+
+    fn ids(records: &[ScoredVectorRecord]) -> Vec<u128> {
+        records.iter().map(|record| record.id.as_u128()).collect()
+    }
+
+    #[actix_rt::test]
+    async fn test_in_memory_search_with_filter() {
+        let store = store_with_records().await;
+
+        let results = store
+            .search("test", vec![1.0, 0.0], VectorFilter::default(), 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(ids(&results), vec![1, 2, 4, 3]);
+        assert!((results[0].score - 1.0).abs() < 1e-6);
+
+        let results = store
+            .search(
+                "test",
+                vec![1.0, 0.0],
+                VectorFilter::must_not(PayloadMatch::new("story_id", 2)),
+                1,
+                1,
+            )
+            .await
+            .unwrap();
+        assert_eq!(ids(&results), vec![3]);
+    }
+
+    #[actix_rt::test]
+    async fn test_in_memory_recommend_leaves_out_positives() {
+        let store = store_with_records().await;
+
+        let results = store
+            .recommend(
+                "test",
+                vec![uuid::Uuid::from_u128(1), uuid::Uuid::from_u128(3)],
+                VectorFilter::default(),
+                10,
+                0,
+            )
+            .await
+            .unwrap();
+        assert_eq!(ids(&results), vec![4, 2]);
+    }
+
+    #[actix_rt::test]
+    async fn test_in_memory_scroll_retrieve_and_delete() {
+        let store = store_with_records().await;
+
+        let (page, next) = store
+            .scroll("test", VectorFilter::default(), None, 3)
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 3);
+        assert_eq!(next, Some(uuid::Uuid::from_u128(4)));
+        let (page, next) = store
+            .scroll("test", VectorFilter::default(), next, 3)
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(next, None);
+
+        store
+            .delete("test", vec![uuid::Uuid::from_u128(2)])
+            .await
+            .unwrap();
+        let retrieved = store
+            .retrieve(
+                "test",
+                vec![uuid::Uuid::from_u128(2), uuid::Uuid::from_u128(3)],
+            )
+            .await
+            .unwrap();
+        assert_eq!(retrieved.len(), 1);
+        assert_eq!(retrieved[0].vector, vec![0.0, 1.0]);
+        assert_eq!(store.count("test").await.unwrap(), 3);
+
+        assert!(store
+            .upsert("test", vec![record(5, vec![1.0], 1)])
+            .await
+            .is_err());
+        assert!(store.count("missing").await.is_err());
+    }
+
+    #[test]
+    fn test_collection_drift() {
+        let config = QdrantCollectionConfig {
+            hnsw_m: Some(32),
+            quantization: Some(VectorQuantization::Product(CompressionRatio::X32)),
+            ..Default::default()
+        };
+        let create_collection = config.create_collection("test", 4);
+        let existing = qdrant::CollectionConfig {
+            params: Some(qdrant::CollectionParams {
+                vectors_config: create_collection.vectors_config.clone(),
+                ..Default::default()
+            }),
+            hnsw_config: create_collection.hnsw_config.clone(),
+            quantization_config: create_collection.quantization_config.clone(),
+            ..Default::default()
+        };
+        assert!(config.drift(4, &existing).is_empty());
+
+        let changed = QdrantCollectionConfig {
+            distance: Distance::Dot,
+            hnsw_m: Some(16),
+            hnsw_ef_construct: Some(200),
+            quantization: Some(VectorQuantization::Scalar),
+            ..Default::default()
+        };
+        assert_eq!(
+            changed.drift(4, &existing),
+            vec![
+                "distance is Cosine, configured Dot".to_owned(),
+                "hnsw m is Some(32), configured 16".to_owned(),
+                "hnsw ef_construct is None, configured 200".to_owned(),
+                "quantization is product X32, configured scalar".to_owned(),
+            ]
+        );
+        // settings left to Qdrant's defaults are not compared
+        assert_eq!(
+            QdrantCollectionConfig::default().drift(8, &existing),
+            vec![
+                "vector size is 4, configured 8".to_owned(),
+                "quantization is product X32, configured none".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scores_are_cosine_whatever_the_distance() {
+        let a = normalize(vec![1.0, 0.0, 0.0]);
+        let b = normalize(vec![0.6, 0.8, 0.0]);
+        let euclid = a
+            .iter()
+            .zip(b.iter())
+            .map(|(x, y)| (x - y).powi(2))
+            .sum::<f32>()
+            .sqrt();
+
+        for (distance, score) in [
+            (Distance::Cosine, 0.6),
+            (Distance::Dot, 0.6),
+            (Distance::Euclid, euclid),
+        ] {
+            let config = QdrantCollectionConfig {
+                distance,
+                ..Default::default()
+            };
+            assert!((config.cosine_score(score) - cosine_similarity(&a, &b)).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use royal_road_embeddings::{
+    data::models::{EmbeddingLevel, NamedChapterRange},
+    errors::ErrorResponse,
+    handlers::{
+        chapter_range_handler::{
+            GetChapterRangesResponse, RecommendChapterRangeRequest, RecommendChapterRangeResponse,
+            UpsertChapterRangeRequest,
+        },
+        embedding_handler::IndexDocumentRequest,
+        search_handler::SemanticSearchRequest,
+    },
+    operators::search_operator::DocEmbeddingType,
+};
+
+async fn add_document(content: String, story_id: i64, index: i32) {
+    let response = reqwest::Client::new()
+        .post("http://localhost:8090/api/index_document")
+        .header("Authorization", "key")
+        .json(&IndexDocumentRequest {
+            doc_html: content,
+            story_id,
+            index,
+            force: false,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+async fn upsert_range(
+    story_id: i64,
+    name: &str,
+    first_index: i32,
+    last_index: i32,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .put(format!(
+            "http://localhost:8090/api/story/{}/ranges",
+            story_id
+        ))
+        .header("Authorization", "key")
+        .json(&UpsertChapterRangeRequest {
+            name: name.to_string(),
+            first_index,
+            last_index,
+        })
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn get_ranges(story_id: i64) -> Vec<NamedChapterRange> {
+    let response = reqwest::Client::new()
+        .get(format!(
+            "http://localhost:8090/api/story/{}/ranges",
+            story_id
+        ))
+        .header("Authorization", "key")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    response
+        .json::<GetChapterRangesResponse>()
+        .await
+        .unwrap()
+        .ranges
+}
+
+#[actix_rt::test]
+async fn test_named_chapter_ranges() {
+    for story_id in [40, 41] {
+        for i in 0..6 {
+            let content = format!("<p>Chapter {} of story {} at sea.</p>", i, story_id);
+            add_document(content, story_id, i).await;
+        }
+    }
+
+    for (story_id, name, first_index, last_index) in [
+        (40, "Volume 1", 0, 2),
+        (40, "Volume 2", 3, 5),
+        (41, "The Storm Arc", 1, 4),
+    ] {
+        let response = upsert_range(story_id, name, first_index, last_index).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    let ranges = get_ranges(40).await;
+    assert_eq!(
+        ranges
+            .iter()
+            .map(|range| (range.name.as_str(), range.first_index, range.last_index))
+            .collect::<Vec<_>>(),
+        vec![("Volume 1", 0, 2), ("Volume 2", 3, 5)]
+    );
+
+    // Only the range containing the changed chapter is recomputed
+    add_document("<p>A new chapter four.</p>".to_string(), 40, 4).await;
+    let after = get_ranges(40).await;
+    assert_eq!(after[0].updated_at, ranges[0].updated_at);
+    assert!(after[1].updated_at > ranges[1].updated_at);
+    assert_eq!(after[1].qdrant_point_id, ranges[1].qdrant_point_id);
+
+    let response = reqwest::Client::new()
+        .post("http://localhost:8090/api/recommend/range")
+        .header("Authorization", "key")
+        .json(&RecommendChapterRangeRequest {
+            story_id: 40,
+            name: "Volume 2".to_string(),
+            limit: Some(10),
+            page: None,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response
+        .json::<RecommendChapterRangeResponse>()
+        .await
+        .unwrap();
+    assert!(body
+        .recommendations
+        .iter()
+        .all(|recommendation| recommendation.story_id != 40));
+    assert!(body
+        .recommendations
+        .iter()
+        .any(|recommendation| recommendation.name == "The Storm Arc"));
+
+    let response = reqwest::Client::new()
+        .post("http://localhost:8090/api/search")
+        .json(&SemanticSearchRequest {
+            doc_group_size: None,
+            doc_group_stride: None,
+            level: EmbeddingLevel::Range,
+            page: 1,
+            query: "at sea".to_string(),
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let results = response.json::<Vec<DocEmbeddingType>>().await.unwrap();
+    assert!(results
+        .iter()
+        .all(|result| matches!(result, DocEmbeddingType::NamedChapterRange(_))));
+
+    let response = upsert_range(40, "Backwards", 5, 2).await;
+    assert_eq!(response.status(), 400);
+    let error = response.json::<ErrorResponse>().await.unwrap();
+    assert_eq!(error.error_code, "0038");
+
+    let response = reqwest::Client::new()
+        .delete("http://localhost:8090/api/story/41/ranges/The%20Storm%20Arc")
+        .header("Authorization", "key")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+    assert!(get_ranges(41).await.is_empty());
+}
@@ -0,0 +1,65 @@
+use actix_web::test;
+use royal_road_embeddings::handlers::{
+    embedding_cache_handler::EmbeddingCacheStatsResponse,
+    embedding_handler::{IndexDocumentRequest, IndexDocumentResponse},
+};
+
+mod common;
+use common::{embedded_chunk_count, init_test_app, use_fake_embedder, TestDatabase};
+
+const STORY_ID: i64 = 64;
+
+#[actix_rt::test]
+async fn test_repeated_chunks_come_from_the_cache() {
+    use_fake_embedder();
+    let database = TestDatabase::new().await;
+    let pool = database.pool.clone();
+    let api_key = std::env::var("API_KEY").unwrap();
+
+    let (app, _) = init_test_app(pool).await;
+
+    let get_stats = || {
+        test::TestRequest::get()
+            .uri("/api/embedding_cache")
+            .insert_header(("Authorization", api_key.as_str()))
+            .to_request()
+    };
+    // the text is new on every run, so its chunk has never been embedded
+    let doc_html = format!(
+        "<p>The clockmaker wound the tower bell for the {} time.</p>",
+        uuid::Uuid::new_v4()
+    );
+    let index_chapter = |index: i32| {
+        test::TestRequest::post()
+            .uri("/api/index_document")
+            .insert_header(("Authorization", api_key.as_str()))
+            .set_json(IndexDocumentRequest {
+                doc_html: doc_html.clone(),
+                story_id: STORY_ID,
+                index,
+                force: false,
+            })
+            .to_request()
+    };
+
+    let before: EmbeddingCacheStatsResponse =
+        test::call_and_read_body_json(&app, get_stats()).await;
+    let embedded_before = embedded_chunk_count();
+
+    let first: IndexDocumentResponse = test::call_and_read_body_json(&app, index_chapter(0)).await;
+    let after_miss: EmbeddingCacheStatsResponse =
+        test::call_and_read_body_json(&app, get_stats()).await;
+    assert_eq!(after_miss.chunks.misses, before.chunks.misses + 1);
+    assert_eq!(embedded_chunk_count(), embedded_before + 1);
+
+    // another chapter with the same text reuses the cached chunk
+    let second: IndexDocumentResponse = test::call_and_read_body_json(&app, index_chapter(1)).await;
+    let after_hit: EmbeddingCacheStatsResponse =
+        test::call_and_read_body_json(&app, get_stats()).await;
+    assert_eq!(after_hit.chunks.misses, after_miss.chunks.misses);
+    assert!(after_hit.chunks.hits > after_miss.chunks.hits);
+    assert_eq!(embedded_chunk_count(), embedded_before + 1);
+    assert_eq!(first.embedding, second.embedding);
+
+    database.close().await;
+}
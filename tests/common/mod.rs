@@ -0,0 +1,170 @@
+#![allow(dead_code)]
+
+use actix_http::Request;
+use actix_web::{dev::Service, dev::ServiceResponse, test, web, App};
+use async_trait::async_trait;
+use royal_road_embeddings::{
+    configure_routes,
+    errors::ServiceError,
+    operators::{
+        embedding_operator::{set_chunk_embedder, ChunkEmbedder},
+        group_maintenance_operator::GroupMaintenanceQueue,
+        qdrant_operator::{
+            embedding_size, NAMED_CHAPTER_RANGES_COLLECTION, STORY_EMBEDDINGS_COLLECTION,
+        },
+        query_embedding_cache_operator::QueryEmbeddingCache,
+        reindex_operator::ensure_doc_embeddings_alias,
+        vector_outbox_operator::VectorOutboxRelay,
+        vector_store_operator::{InMemoryVectorStore, VectorStore},
+    },
+};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    Connection, Executor, PgConnection, Pool, Postgres,
+};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Embeds text as a bag of hashed words, so chunks sharing words end up close together without
+/// calling an embedding provider
+pub struct FakeEmbedder;
+
+static EMBEDDED_CHUNKS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many chunks `FakeEmbedder` embedded so far in this test binary
+pub fn embedded_chunk_count() -> usize {
+    EMBEDDED_CHUNKS.load(Ordering::SeqCst)
+}
+
+#[async_trait]
+impl ChunkEmbedder for FakeEmbedder {
+    fn model_id(&self) -> String {
+        "fake-bag-of-words".to_owned()
+    }
+
+    async fn embed_chunks(&self, chunks: Vec<String>) -> Result<Vec<Vec<f32>>, ServiceError> {
+        EMBEDDED_CHUNKS.fetch_add(chunks.len(), Ordering::SeqCst);
+        Ok(chunks.iter().map(|chunk| bag_of_words(chunk)).collect())
+    }
+}
+
+fn bag_of_words(text: &str) -> Vec<f32> {
+    let mut embedding = vec![0.0; embedding_size() as usize];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        let mut hasher = DefaultHasher::new();
+        word.to_lowercase().hash(&mut hasher);
+        let position = (hasher.finish() % embedding.len() as u64) as usize;
+        embedding[position] += 1.0;
+    }
+    embedding
+}
+
+/// Sends every embedding of the test binary to `FakeEmbedder`
+pub fn use_fake_embedder() {
+    set_chunk_embedder(Arc::new(FakeEmbedder));
+}
+
+/// A migrated database of its own for one test, created next to the one in DATABASE_URL so
+/// tests never see each other's rows or outbox mutations
+pub struct TestDatabase {
+    pub pool: Pool<Postgres>,
+    name: String,
+    database_url: String,
+}
+
+impl TestDatabase {
+    pub async fn new() -> Self {
+        dotenvy::dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").unwrap();
+        let name = format!("test_{}", uuid::Uuid::new_v4().simple());
+
+        let mut connection = PgConnection::connect(&database_url).await.unwrap();
+        connection
+            .execute(format!("CREATE DATABASE {}", name).as_str())
+            .await
+            .unwrap();
+        connection.close().await.unwrap();
+
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect_with(
+                PgConnectOptions::from_str(&database_url)
+                    .unwrap()
+                    .database(&name),
+            )
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+
+        Self {
+            pool,
+            name,
+            database_url,
+        }
+    }
+
+    /// Drops the database. A test that panics never gets here and leaves its database behind
+    /// to be looked at.
+    pub async fn close(self) {
+        self.pool.close().await;
+
+        let mut connection = PgConnection::connect(&self.database_url).await.unwrap();
+        connection
+            .execute(format!("DROP DATABASE {} WITH (FORCE)", self.name).as_str())
+            .await
+            .unwrap();
+    }
+}
+
+/// Builds the app the way the server does, over an in-memory vector store holding the
+/// collections created at startup. The store is returned too, for tests that look at its points.
+pub async fn init_test_app(
+    pool: Pool<Postgres>,
+) -> (
+    impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+    Arc<dyn VectorStore>,
+) {
+    let vector_store: Arc<dyn VectorStore> = Arc::new(InMemoryVectorStore::default());
+    for collection_name in [STORY_EMBEDDINGS_COLLECTION, NAMED_CHAPTER_RANGES_COLLECTION] {
+        vector_store
+            .create_collection(collection_name, embedding_size())
+            .await
+            .unwrap();
+    }
+    ensure_doc_embeddings_alias(vector_store.as_ref())
+        .await
+        .unwrap();
+
+    let group_maintenance =
+        GroupMaintenanceQueue::start(pool.clone(), vector_store.clone(), Duration::from_secs(60));
+    let relay =
+        VectorOutboxRelay::start(pool.clone(), vector_store.clone(), Duration::from_secs(1));
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool))
+            .app_data(web::Data::from(vector_store.clone()))
+            .app_data(web::Data::new(group_maintenance))
+            .app_data(web::Data::new(relay))
+            .app_data(web::Data::new(QueryEmbeddingCache::new(
+                100,
+                Duration::from_secs(60),
+                None,
+            )))
+            .configure(configure_routes),
+    )
+    .await;
+
+    (app, vector_store)
+}
@@ -0,0 +1,82 @@
+use actix_web::test;
+use royal_road_embeddings::handlers::embedding_handler::{
+    IndexDocumentRequest, IndexDocumentResponse,
+};
+
+mod common;
+use common::{embedded_chunk_count, init_test_app, use_fake_embedder, TestDatabase};
+
+const STORY_ID: i64 = 63;
+
+#[actix_rt::test]
+async fn test_unchanged_chapter_is_not_embedded_again() {
+    use_fake_embedder();
+    let database = TestDatabase::new().await;
+    let pool = database.pool.clone();
+    let api_key = std::env::var("API_KEY").unwrap();
+
+    let (app, _) = init_test_app(pool).await;
+
+    let index_chapter = |doc_html: &str, force: bool| {
+        test::TestRequest::post()
+            .uri("/api/index_document")
+            .insert_header(("Authorization", api_key.as_str()))
+            .set_json(IndexDocumentRequest {
+                doc_html: doc_html.to_owned(),
+                story_id: STORY_ID,
+                index: 0,
+                force,
+            })
+            .to_request()
+    };
+
+    let first: IndexDocumentResponse = test::call_and_read_body_json(
+        &app,
+        index_chapter(
+            "<p>The miller's daughter spun straw by candlelight.</p>",
+            false,
+        ),
+    )
+    .await;
+    assert!(first.changed);
+    let embedded_after_first = embedded_chunk_count();
+    assert!(embedded_after_first > 0);
+    let norm: f32 = first.embedding.iter().map(|value| value * value).sum();
+    assert!((norm.sqrt() - 1.0).abs() < 1e-5);
+
+    let unchanged: IndexDocumentResponse = test::call_and_read_body_json(
+        &app,
+        index_chapter(
+            "<div>The miller's daughter  spun straw by candlelight.</div>",
+            false,
+        ),
+    )
+    .await;
+    assert!(!unchanged.changed);
+    assert_eq!(embedded_chunk_count(), embedded_after_first);
+    for (stored, embedded) in unchanged.embedding.iter().zip(first.embedding) {
+        assert!((stored - embedded).abs() < 1e-5);
+    }
+
+    let forced: IndexDocumentResponse = test::call_and_read_body_json(
+        &app,
+        index_chapter(
+            "<p>The miller's daughter spun straw by candlelight.</p>",
+            true,
+        ),
+    )
+    .await;
+    assert!(forced.changed);
+    // forcing skips the hash comparison, the chunk itself still comes from the cache
+    assert_eq!(embedded_chunk_count(), embedded_after_first);
+
+    let edited: IndexDocumentResponse = test::call_and_read_body_json(
+        &app,
+        index_chapter("<p>The miller's daughter spun straw into gold.</p>", false),
+    )
+    .await;
+    assert!(edited.changed);
+    assert!(embedded_chunk_count() > embedded_after_first);
+
+    database.close().await;
+}
@@ -1,13 +1,17 @@
-use actix_rt;
 use royal_road_embeddings::{
+    data::models::{ChapterRange, EmbeddingLevel, IndexDocumentGroupRequest},
     errors::ErrorResponse,
     handlers::{
-        doc_group_handler::{GroupDocumentRequest, IndexDocumentGroupRequest},
+        doc_group_handler::{
+            GetDocumentGroupResponse, GroupDocumentRequest, ListDocumentGroupsResponse,
+            RecommendDocumentRequest, RecommendDocumentResponse,
+        },
         embedding_handler::{IndexDocumentRequest, IndexDocumentResponse},
     },
 };
 
 use either::Either;
+use sqlx::{PgPool, Row};
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 #[serde(transparent)]
@@ -23,6 +27,7 @@ async fn add_document(content: String, story_id: i64, index: i32) {
         doc_html: content,
         story_id,
         index,
+        force: false,
     };
 
     let response = req
@@ -52,7 +57,10 @@ async fn test_index_document_group() {
         add_document(content, 10, i).await;
     }
 
-    let make_group = GroupDocumentRequest { doc_group_size: 2 };
+    let make_group = GroupDocumentRequest {
+        doc_group_size: 2,
+        doc_group_stride: None,
+    };
 
     // CREATE document
     let response = req
@@ -68,6 +76,7 @@ async fn test_index_document_group() {
     let document_group = IndexDocumentGroupRequest::Story {
         story_id: 10,
         doc_group_size: 2,
+        doc_group_stride: None,
     };
 
     // INDEX documents
@@ -84,3 +93,417 @@ async fn test_index_document_group() {
         panic!("code {:?} {:}", error.error_code, error.message);
     }
 }
+
+async fn index_chapter(content: String, story_id: i64, index: i32) {
+    let response = reqwest::Client::new()
+        .post("http://localhost:8090/api/index_document")
+        .header("Authorization", "key")
+        .json(&IndexDocumentRequest {
+            doc_html: content,
+            story_id,
+            index,
+            force: false,
+        })
+        .send()
+        .await
+        .unwrap();
+    if response.status() != 200 {
+        let error = response.json::<ErrorResponse>().await.unwrap();
+        panic!("{:?} code {:}", error.message, error.error_code);
+    }
+}
+
+async fn get_doc_group_rows(
+    pool: &PgPool,
+    story_id: i64,
+    doc_group_size: i32,
+    doc_group_stride: i32,
+) -> Vec<(i32, uuid::Uuid, chrono::NaiveDateTime)> {
+    sqlx::query(
+        "SELECT index, qdrant_point_id, updated_at FROM doc_group_embeddings WHERE story_id = $1 AND doc_group_size = $2 AND doc_group_stride = $3 ORDER BY index",
+    )
+    .bind(story_id)
+    .bind(doc_group_size)
+    .bind(doc_group_stride)
+    .fetch_all(pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|row| (row.get(0), row.get(1), row.get(2)))
+    .collect()
+}
+
+#[actix_rt::test]
+async fn test_chapter_update_only_recomputes_its_groups() {
+    dotenvy::dotenv().ok();
+    let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap())
+        .await
+        .unwrap();
+    let key = "key";
+    let req = reqwest::Client::new();
+    let story_id = 11;
+
+    for i in 0..6 {
+        index_chapter(format!("This is a test document {}", i), story_id, i).await;
+    }
+
+    for doc_group_size in [2, 3] {
+        let response = req
+            .post("http://localhost:8090/api/document_group")
+            .header("Authorization", key)
+            .json(&GroupDocumentRequest {
+                doc_group_size,
+                doc_group_stride: None,
+            })
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 204);
+
+        let response = req
+            .put("http://localhost:8090/api/document_group")
+            .header("Authorization", key)
+            .json(&IndexDocumentGroupRequest::Story {
+                story_id,
+                doc_group_size,
+                doc_group_stride: None,
+            })
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 204);
+    }
+
+    let before_size_2 = get_doc_group_rows(&pool, story_id, 2, 2).await;
+    let before_size_3 = get_doc_group_rows(&pool, story_id, 3, 3).await;
+    assert_eq!(before_size_2.len(), 3);
+    assert_eq!(before_size_3.len(), 2);
+
+    // Chapter 3 belongs to group 1 for both sizes
+    index_chapter("This chapter was rewritten".to_string(), story_id, 3).await;
+
+    let after_size_2 = get_doc_group_rows(&pool, story_id, 2, 2).await;
+    let after_size_3 = get_doc_group_rows(&pool, story_id, 3, 3).await;
+
+    assert_eq!(after_size_2[0], before_size_2[0]);
+    assert_eq!(after_size_2[2], before_size_2[2]);
+    assert_eq!(after_size_2[1].1, before_size_2[1].1);
+    assert!(after_size_2[1].2 > before_size_2[1].2);
+
+    assert_eq!(after_size_3[0], before_size_3[0]);
+    assert_eq!(after_size_3[1].1, before_size_3[1].1);
+    assert!(after_size_3[1].2 > before_size_3[1].2);
+}
+
+#[actix_rt::test]
+async fn test_sliding_window_doc_groups() {
+    dotenvy::dotenv().ok();
+    let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap())
+        .await
+        .unwrap();
+    let key = "key";
+    let req = reqwest::Client::new();
+    let story_id = 12;
+
+    for i in 0..6 {
+        index_chapter(format!("This is a test document {}", i), story_id, i).await;
+    }
+
+    let response = req
+        .post("http://localhost:8090/api/document_group")
+        .header("Authorization", key)
+        .json(&GroupDocumentRequest {
+            doc_group_size: 3,
+            doc_group_stride: Some(1),
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+
+    let response = req
+        .put("http://localhost:8090/api/document_group")
+        .header("Authorization", key)
+        .json(&IndexDocumentGroupRequest::Story {
+            story_id,
+            doc_group_size: 3,
+            doc_group_stride: Some(1),
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+
+    // One window starts at every chapter
+    let windows = get_doc_group_rows(&pool, story_id, 3, 1).await;
+    assert_eq!(
+        windows.iter().map(|row| row.0).collect::<Vec<i32>>(),
+        vec![0, 1, 2, 3, 4, 5]
+    );
+
+    // Windows of the same size with the default stride are kept apart
+    assert!(get_doc_group_rows(&pool, story_id, 3, 3).await.is_empty());
+
+    // Chapter 2 is part of windows 0, 1 and 2
+    index_chapter("This chapter was rewritten".to_string(), story_id, 2).await;
+    let after = get_doc_group_rows(&pool, story_id, 3, 1).await;
+    for (before, after) in windows.iter().zip(after.iter()) {
+        assert_eq!(before.1, after.1);
+        if before.0 <= 2 {
+            assert!(after.2 > before.2);
+        } else {
+            assert_eq!(before, after);
+        }
+    }
+
+    let response = req
+        .post("http://localhost:8090/api/document_group")
+        .header("Authorization", key)
+        .json(&GroupDocumentRequest {
+            doc_group_size: 3,
+            doc_group_stride: Some(4),
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[actix_rt::test]
+async fn test_list_and_get_document_group() {
+    let key = "key";
+    let req = reqwest::Client::new();
+    let story_id = 13;
+
+    for i in 0..5 {
+        index_chapter(format!("This is a test document {}", i), story_id, i).await;
+    }
+
+    let response = req
+        .post("http://localhost:8090/api/document_group")
+        .header("Authorization", key)
+        .json(&GroupDocumentRequest {
+            doc_group_size: 2,
+            doc_group_stride: None,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+
+    let response = req
+        .put("http://localhost:8090/api/document_group")
+        .header("Authorization", key)
+        .json(&IndexDocumentGroupRequest::Story {
+            story_id,
+            doc_group_size: 2,
+            doc_group_stride: None,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+
+    let response = req
+        .get("http://localhost:8090/api/document_group")
+        .header("Authorization", key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.json::<ListDocumentGroupsResponse>().await.unwrap();
+    let size_2 = body
+        .doc_groups
+        .iter()
+        .find(|doc_group| doc_group.doc_group_size == 2 && doc_group.doc_group_stride == 2)
+        .unwrap();
+    assert!(size_2.point_count >= 3);
+    assert!(size_2.last_rebuilt_at.is_some());
+
+    let response = req
+        .get(format!(
+            "http://localhost:8090/api/document_group/2/{}",
+            story_id
+        ))
+        .header("Authorization", key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.json::<GetDocumentGroupResponse>().await.unwrap();
+    assert_eq!(body.indices, vec![0, 1, 2]);
+    assert_eq!(
+        body.chapter_ranges,
+        vec![
+            ChapterRange {
+                first_index: 0,
+                last_index: 1
+            },
+            ChapterRange {
+                first_index: 2,
+                last_index: 3
+            },
+            ChapterRange {
+                first_index: 4,
+                last_index: 4
+            },
+        ]
+    );
+    assert_eq!(body.embeddings.len(), 3);
+
+    let response = req
+        .get("http://localhost:8090/api/document_group/2/999999")
+        .header("Authorization", key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+#[actix_rt::test]
+async fn test_new_chapters_are_grouped_automatically() {
+    let key = "key";
+    let req = reqwest::Client::new();
+    let story_id = 14;
+
+    let response = req
+        .post("http://localhost:8090/api/document_group")
+        .header("Authorization", key)
+        .json(&GroupDocumentRequest {
+            doc_group_size: 2,
+            doc_group_stride: None,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+
+    for i in 0..4 {
+        index_chapter(format!("This is a test document {}", i), story_id, i).await;
+    }
+
+    // The rebuild runs once the story has been quiet for DOC_GROUP_REBUILD_DEBOUNCE_SECONDS
+    let mut indices = vec![];
+    for _ in 0..60 {
+        actix_rt::time::sleep(std::time::Duration::from_secs(1)).await;
+
+        let response = req
+            .get(format!(
+                "http://localhost:8090/api/document_group/2/{}",
+                story_id
+            ))
+            .header("Authorization", key)
+            .send()
+            .await
+            .unwrap();
+        if response.status() == 200 {
+            indices = response
+                .json::<GetDocumentGroupResponse>()
+                .await
+                .unwrap()
+                .indices;
+            break;
+        }
+    }
+    assert_eq!(indices, vec![0, 1]);
+}
+
+#[actix_rt::test]
+async fn test_recommend_document_group_with_explanations() {
+    let key = "key";
+    let req = reqwest::Client::new();
+
+    for story_id in [26, 27] {
+        for i in 0..4 {
+            let content = format!(
+                "Chapter {} of story {} about a lighthouse keeper.",
+                i, story_id
+            );
+            index_chapter(content, story_id, i).await;
+        }
+    }
+
+    let response = req
+        .post("http://localhost:8090/api/document_group")
+        .header("Authorization", key)
+        .json(&GroupDocumentRequest {
+            doc_group_size: 2,
+            doc_group_stride: None,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+
+    for story_id in [26, 27] {
+        let response = req
+            .put("http://localhost:8090/api/document_group")
+            .header("Authorization", key)
+            .json(&IndexDocumentGroupRequest::Story {
+                story_id,
+                doc_group_size: 2,
+                doc_group_stride: None,
+            })
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 204);
+    }
+
+    let response = req
+        .post("http://localhost:8090/api/recommend")
+        .header("Authorization", key)
+        .json(&RecommendDocumentRequest {
+            doc_group_size: Some(2),
+            doc_group_stride: None,
+            level: EmbeddingLevel::Chapter,
+            story_ids: vec![26],
+            limit: Some(50),
+            page: None,
+            explain: Some(true),
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body = response.json::<RecommendDocumentResponse>().await.unwrap();
+    assert!(!body.recommended_story_ids.contains(&26));
+    assert!(body.recommended_story_ids.contains(&27));
+
+    let explanations = body.explanations.unwrap();
+    let explanation = explanations
+        .iter()
+        .find(|explanation| explanation.story_id == 27)
+        .unwrap();
+    assert_eq!(explanation.seed_story_id, 26);
+    assert_eq!(
+        explanation.chapter_range.last_index - explanation.chapter_range.first_index,
+        1
+    );
+    assert_eq!(
+        explanation.seed_chapter_range.first_index,
+        explanation.seed_group_index * 2
+    );
+    assert!(explanation.similarity > 0.0 && explanation.similarity <= 1.0 + f32::EPSILON);
+
+    // without explain the field is left out of the response
+    let response = req
+        .post("http://localhost:8090/api/recommend")
+        .header("Authorization", key)
+        .json(&RecommendDocumentRequest {
+            doc_group_size: Some(2),
+            doc_group_stride: None,
+            level: EmbeddingLevel::Chapter,
+            story_ids: vec![26],
+            limit: Some(50),
+            page: None,
+            explain: None,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.json::<serde_json::Value>().await.unwrap();
+    assert!(body.get("explanations").is_none());
+}
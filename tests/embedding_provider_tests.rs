@@ -0,0 +1,58 @@
+// talks to the embedding server at an address nothing listens on
+#![cfg(feature = "embedding_server")]
+
+use actix_web::test;
+use royal_road_embeddings::{
+    data::models::EmbeddingLevel, errors::ErrorResponse,
+    handlers::search_handler::SemanticSearchRequest,
+};
+
+mod common;
+use common::{init_test_app, TestDatabase};
+
+#[actix_rt::test]
+async fn test_unreachable_provider_is_reported_as_unavailable() {
+    // set before the provider is first used and before .env, which does not override them
+    std::env::set_var("EMBEDDING_SERVER_CALL", "http://127.0.0.1:9/encode");
+    std::env::set_var("EMBEDDING_MAX_RETRIES", "1");
+    std::env::set_var("EMBEDDING_RETRY_BACKOFF_MILLIS", "10");
+    std::env::set_var("EMBEDDING_CIRCUIT_BREAKER_THRESHOLD", "2");
+    std::env::set_var("EMBEDDING_CIRCUIT_BREAKER_SECONDS", "60");
+    let database = TestDatabase::new().await;
+    let (app, _) = init_test_app(database.pool.clone()).await;
+
+    let search = || {
+        test::TestRequest::post()
+            .uri("/api/search")
+            .set_json(SemanticSearchRequest {
+                doc_group_size: None,
+                doc_group_stride: None,
+                level: EmbeddingLevel::Chapter,
+                page: 1,
+                query: "a lantern in the fog".to_owned(),
+            })
+            .to_request()
+    };
+
+    // both attempts fail to connect, which opens the circuit breaker
+    let response = test::call_service(&app, search()).await;
+    assert_eq!(response.status(), 503);
+    assert!(response.headers().contains_key("Retry-After"));
+    let error: ErrorResponse = test::read_body_json(response).await;
+    assert_eq!(error.error_code, "0062");
+
+    // the breaker is open now, so this fails without waiting on the provider
+    let response = test::call_service(&app, search()).await;
+    assert_eq!(response.status(), 503);
+    let retry_after: u64 = response
+        .headers()
+        .get("Retry-After")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 50 && retry_after <= 60);
+
+    database.close().await;
+}
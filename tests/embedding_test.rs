@@ -19,6 +19,7 @@ async fn test_index_document() {
         doc_html: "html".to_string(),
         story_id: 5,
         index: 5,
+        force: false,
     };
 
     let response = req
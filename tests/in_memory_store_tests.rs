@@ -0,0 +1,66 @@
+use actix_web::test;
+use royal_road_embeddings::{
+    data::models::EmbeddingLevel,
+    handlers::{embedding_handler::IndexDocumentRequest, search_handler::SemanticSearchRequest},
+    operators::search_operator::DocEmbeddingType,
+};
+
+mod common;
+use common::{init_test_app, use_fake_embedder, TestDatabase};
+
+const STORY_ID: i64 = 60;
+
+/// Runs the handlers in process against the in-memory vector store, a database of its own and
+/// the fake embedder, so neither Qdrant nor an embedding provider is needed
+#[actix_rt::test]
+async fn test_index_and_search_with_in_memory_store() {
+    use_fake_embedder();
+    let database = TestDatabase::new().await;
+    let pool = database.pool.clone();
+    let api_key = std::env::var("API_KEY").unwrap();
+
+    let (app, _) = init_test_app(pool).await;
+
+    let chapters = [
+        "<p>The dragon circled the burning keep while the knights readied their lances.</p>",
+        "<p>She planted tomatoes and basil in the garden behind the old farmhouse.</p>",
+    ];
+    for (index, doc_html) in chapters.into_iter().enumerate() {
+        let request = test::TestRequest::post()
+            .uri("/api/index_document")
+            .insert_header(("Authorization", api_key.as_str()))
+            .set_json(IndexDocumentRequest {
+                doc_html: doc_html.to_owned(),
+                story_id: STORY_ID,
+                index: index as i32,
+                force: false,
+            })
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    let request = test::TestRequest::post()
+        .uri("/api/search")
+        .insert_header(("Authorization", api_key.as_str()))
+        .set_json(SemanticSearchRequest {
+            doc_group_size: None,
+            doc_group_stride: None,
+            level: EmbeddingLevel::Chapter,
+            page: 1,
+            query: "growing vegetables in a garden".to_owned(),
+        })
+        .to_request();
+    let results: Vec<DocEmbeddingType> = test::call_and_read_body_json(&app, request).await;
+
+    assert_eq!(results.len(), chapters.len());
+    match results.first() {
+        Some(DocEmbeddingType::DocEmbedding(doc_embedding)) => {
+            assert_eq!(doc_embedding.story_id, STORY_ID);
+            assert_eq!(doc_embedding.index, 1);
+        }
+        other => panic!("Expected a chapter as the best match, got {:?}", other),
+    }
+
+    database.close().await;
+}
@@ -0,0 +1,79 @@
+use actix_web::test;
+use royal_road_embeddings::{
+    data::models::EmbeddingLevel,
+    handlers::{
+        embedding_cache_handler::EmbeddingCacheStatsResponse, search_handler::SemanticSearchRequest,
+    },
+};
+
+mod common;
+use common::{embedded_chunk_count, init_test_app, use_fake_embedder, TestDatabase};
+
+#[actix_rt::test]
+async fn test_repeated_queries_come_from_the_cache() {
+    use_fake_embedder();
+    let database = TestDatabase::new().await;
+    let pool = database.pool.clone();
+    let api_key = std::env::var("API_KEY").unwrap();
+
+    let (app, _) = init_test_app(pool).await;
+
+    let get_stats = || {
+        test::TestRequest::get()
+            .uri("/api/embedding_cache")
+            .insert_header(("Authorization", api_key.as_str()))
+            .to_request()
+    };
+    let search = |query: &str| {
+        test::TestRequest::post()
+            .uri("/api/search")
+            .insert_header(("Authorization", api_key.as_str()))
+            .set_json(SemanticSearchRequest {
+                doc_group_size: None,
+                doc_group_stride: None,
+                level: EmbeddingLevel::Chapter,
+                page: 1,
+                query: query.to_owned(),
+            })
+            .to_request()
+    };
+
+    let embedded_before = embedded_chunk_count();
+    assert_eq!(
+        test::call_service(&app, search("a knight and a dragon"))
+            .await
+            .status(),
+        200
+    );
+    // the same query with other spacing is the same cache entry
+    assert_eq!(
+        test::call_service(&app, search("  a knight and  a dragon "))
+            .await
+            .status(),
+        200
+    );
+    let stats: EmbeddingCacheStatsResponse = test::call_and_read_body_json(&app, get_stats()).await;
+    assert_eq!(stats.queries.misses, 1);
+    assert_eq!(stats.queries.local_hits, 1);
+    assert_eq!(stats.queries.entries, 1);
+    assert_eq!(embedded_chunk_count(), embedded_before + 1);
+
+    let request = test::TestRequest::delete()
+        .uri("/api/embedding_cache/queries")
+        .insert_header(("Authorization", api_key.as_str()))
+        .to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), 204);
+    let stats: EmbeddingCacheStatsResponse = test::call_and_read_body_json(&app, get_stats()).await;
+    assert_eq!(stats.queries.entries, 0);
+
+    // a flushed query is embedded again
+    assert_eq!(
+        test::call_service(&app, search("a knight and a dragon"))
+            .await
+            .status(),
+        200
+    );
+    assert_eq!(embedded_chunk_count(), embedded_before + 2);
+
+    database.close().await;
+}
@@ -0,0 +1,171 @@
+use actix_web::test;
+use royal_road_embeddings::{
+    data::models::{DocGroupSpec, IndexDocumentGroupRequest, ReconcileJob},
+    handlers::{doc_group_handler::GroupDocumentRequest, embedding_handler::IndexDocumentRequest},
+    operators::{
+        doc_embedding_operator::get_doc_embedding_qdrant_id_pg_query,
+        qdrant_operator::embedding_size,
+        reconcile_operator::{OrphanDocGroupPoint, ReconcileReport},
+        vector_store_operator::{VectorPayload, VectorRecord},
+    },
+};
+use std::time::Duration;
+
+mod common;
+use common::{init_test_app, use_fake_embedder, TestDatabase};
+
+const STORY_ID: i64 = 62;
+
+fn orphan_record(qdrant_point_id: uuid::Uuid) -> VectorRecord {
+    VectorRecord {
+        id: qdrant_point_id,
+        vector: vec![1.0; embedding_size() as usize],
+        payload: VectorPayload::new(),
+    }
+}
+
+/// Runs against a database of its own, so the only rows reconciled are the ones created here
+#[actix_rt::test]
+async fn test_reconcile_reports_and_fixes_orphans() {
+    use_fake_embedder();
+    let database = TestDatabase::new().await;
+    let pool = database.pool.clone();
+    let api_key = std::env::var("API_KEY").unwrap();
+
+    let (app, vector_store) = init_test_app(pool.clone()).await;
+
+    let request = test::TestRequest::post()
+        .uri("/api/index_document")
+        .insert_header(("Authorization", api_key.as_str()))
+        .set_json(IndexDocumentRequest {
+            doc_html: "<p>The caravan crossed the salt flats under a sky without stars.</p>"
+                .to_owned(),
+            story_id: STORY_ID,
+            index: 0,
+            force: false,
+        })
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), 200);
+
+    let index_groups = || {
+        test::TestRequest::put()
+            .uri("/api/document_group")
+            .insert_header(("Authorization", api_key.as_str()))
+            .set_json(IndexDocumentGroupRequest::Story {
+                story_id: STORY_ID,
+                doc_group_size: 1,
+                doc_group_stride: None,
+            })
+            .to_request()
+    };
+    // groups of a size that was never created have no collection to go to
+    let response = test::call_service(&app, index_groups()).await;
+    assert_eq!(response.status(), 400);
+
+    let request = test::TestRequest::post()
+        .uri("/api/document_group")
+        .insert_header(("Authorization", api_key.as_str()))
+        .set_json(GroupDocumentRequest {
+            doc_group_size: 1,
+            doc_group_stride: None,
+        })
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), 204);
+    let response = test::call_service(&app, index_groups()).await;
+    assert_eq!(response.status(), 204);
+
+    let qdrant_point_id = get_doc_embedding_qdrant_id_pg_query(STORY_ID, 0, pool.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    vector_store
+        .delete("doc_embeddings", vec![qdrant_point_id])
+        .await
+        .unwrap();
+    let orphan_point_id = uuid::Uuid::new_v4();
+    vector_store
+        .upsert("doc_embeddings", vec![orphan_record(orphan_point_id)])
+        .await
+        .unwrap();
+    let doc_group_collection = DocGroupSpec {
+        doc_group_size: 1,
+        doc_group_stride: 1,
+    }
+    .collection_name();
+    let orphan_doc_group_point = OrphanDocGroupPoint {
+        collection_name: doc_group_collection.clone(),
+        qdrant_point_id: uuid::Uuid::new_v4(),
+    };
+    vector_store
+        .upsert(
+            &doc_group_collection,
+            vec![orphan_record(orphan_doc_group_point.qdrant_point_id)],
+        )
+        .await
+        .unwrap();
+
+    let request = test::TestRequest::get()
+        .uri("/api/reconcile")
+        .insert_header(("Authorization", api_key.as_str()))
+        .to_request();
+    let report: ReconcileReport = test::call_and_read_body_json(&app, request).await;
+    assert!(!report.fixed);
+    assert_eq!(report.rows_without_points.len(), 1);
+    assert_eq!(
+        report.rows_without_points[0].qdrant_point_id,
+        qdrant_point_id
+    );
+    assert_eq!(report.points_without_rows, vec![orphan_point_id]);
+    assert_eq!(
+        report.doc_group_points_without_rows,
+        vec![orphan_doc_group_point.clone()]
+    );
+
+    let request = test::TestRequest::post()
+        .uri("/api/reconcile")
+        .insert_header(("Authorization", api_key.as_str()))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), 202);
+    let mut job: ReconcileJob = test::read_body_json(response).await;
+
+    // the repair waits out the orphan grace period before deleting anything
+    for _ in 0..60 {
+        if job.status != "running" {
+            break;
+        }
+        actix_rt::time::sleep(Duration::from_secs(1)).await;
+        let request = test::TestRequest::get()
+            .uri(&format!("/api/reconcile/{}", job.id))
+            .insert_header(("Authorization", api_key.as_str()))
+            .to_request();
+        job = test::call_and_read_body_json(&app, request).await;
+    }
+    assert_eq!(job.status, "completed", "{:?}", job.error);
+    let report: ReconcileReport = serde_json::from_value(job.report.unwrap()).unwrap();
+    assert!(report.fixed);
+    assert_eq!(report.points_without_rows, vec![orphan_point_id]);
+    assert_eq!(
+        report.doc_group_points_without_rows,
+        vec![orphan_doc_group_point]
+    );
+
+    let request = test::TestRequest::get()
+        .uri("/api/reconcile")
+        .insert_header(("Authorization", api_key.as_str()))
+        .to_request();
+    let report: ReconcileReport = test::call_and_read_body_json(&app, request).await;
+    assert!(report.is_consistent());
+    assert_eq!(
+        vector_store
+            .retrieve("doc_embeddings", vec![qdrant_point_id])
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    database.close().await;
+}
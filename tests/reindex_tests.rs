@@ -0,0 +1,148 @@
+use actix_web::test;
+use royal_road_embeddings::{
+    data::models::{DocEmbeddingReindex, EmbeddingLevel},
+    handlers::{
+        embedding_handler::IndexDocumentRequest,
+        reindex_handler::{ListReindexesResponse, ReindexProgressResponse},
+        search_handler::SemanticSearchRequest,
+    },
+    operators::search_operator::DocEmbeddingType,
+};
+use std::time::Duration;
+
+mod common;
+use common::{embedded_chunk_count, init_test_app, use_fake_embedder, TestDatabase};
+
+const STORY_ID: i64 = 61;
+const CHAPTER: &str =
+    "<p>The lighthouse keeper counted the ships that slipped past the reef at midnight.</p>";
+
+#[actix_rt::test]
+async fn test_reindex_switch_and_rollback() {
+    use_fake_embedder();
+    let database = TestDatabase::new().await;
+    let pool = database.pool.clone();
+    let api_key = std::env::var("API_KEY").unwrap();
+
+    let (app, _) = init_test_app(pool).await;
+
+    let request = test::TestRequest::post()
+        .uri("/api/index_document")
+        .insert_header(("Authorization", api_key.as_str()))
+        .set_json(IndexDocumentRequest {
+            doc_html: CHAPTER.to_owned(),
+            story_id: STORY_ID,
+            index: 0,
+            force: false,
+        })
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), 200);
+    let embedded_before_reindex = embedded_chunk_count();
+    assert!(embedded_before_reindex > 0);
+
+    let request = test::TestRequest::post()
+        .uri("/api/reindex")
+        .insert_header(("Authorization", api_key.as_str()))
+        .to_request();
+    let reindex: DocEmbeddingReindex = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(reindex.status, "running");
+    assert_eq!(
+        reindex.collection_name,
+        format!("doc_embeddings_v{}", reindex.version)
+    );
+
+    let request = test::TestRequest::post()
+        .uri("/api/reindex")
+        .insert_header(("Authorization", api_key.as_str()))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), 409);
+
+    let mut progress = None;
+    for _ in 0..120 {
+        let request = test::TestRequest::get()
+            .uri(&format!("/api/reindex/{}", reindex.version))
+            .insert_header(("Authorization", api_key.as_str()))
+            .to_request();
+        let response: ReindexProgressResponse = test::call_and_read_body_json(&app, request).await;
+        if response.reindex.status != "running" {
+            progress = Some(response);
+            break;
+        }
+        actix_rt::time::sleep(Duration::from_millis(500)).await;
+    }
+    let progress = progress.expect("Reindex did not finish in time");
+    assert_eq!(progress.reindex.status, "completed");
+    assert_eq!(progress.progress, 1.0);
+    // the model did not change, so the rebuild reads every chunk from the cache
+    assert_eq!(embedded_chunk_count(), embedded_before_reindex);
+
+    let request = test::TestRequest::post()
+        .uri(&format!("/api/reindex/{}/switch", reindex.version))
+        .insert_header(("Authorization", api_key.as_str()))
+        .to_request();
+    let switched: DocEmbeddingReindex = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(switched.status, "live");
+    assert_eq!(
+        switched.previous_collection.as_deref(),
+        Some("doc_embeddings_v0")
+    );
+
+    let request = test::TestRequest::get()
+        .uri("/api/reindex")
+        .insert_header(("Authorization", api_key.as_str()))
+        .to_request();
+    let reindexes: ListReindexesResponse = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(reindexes.live_collection, Some(reindex.collection_name));
+
+    // searches read through the alias, now served by the rebuilt collection
+    let request = test::TestRequest::post()
+        .uri("/api/search")
+        .insert_header(("Authorization", api_key.as_str()))
+        .set_json(SemanticSearchRequest {
+            doc_group_size: None,
+            doc_group_stride: None,
+            level: EmbeddingLevel::Chapter,
+            page: 1,
+            query:
+                "The lighthouse keeper counted the ships that slipped past the reef at midnight."
+                    .to_owned(),
+        })
+        .to_request();
+    let results: Vec<DocEmbeddingType> = test::call_and_read_body_json(&app, request).await;
+    match results.first() {
+        Some(DocEmbeddingType::DocEmbedding(doc_embedding)) => {
+            assert_eq!(doc_embedding.story_id, STORY_ID);
+            assert_eq!(doc_embedding.index, 0);
+        }
+        other => panic!("Expected the chapter as the best match, got {:?}", other),
+    }
+
+    let request = test::TestRequest::post()
+        .uri("/api/reindex/rollback")
+        .insert_header(("Authorization", api_key.as_str()))
+        .to_request();
+    let rolled_back: DocEmbeddingReindex = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(rolled_back.version, reindex.version);
+    assert_eq!(rolled_back.status, "rolled_back");
+
+    let request = test::TestRequest::get()
+        .uri("/api/reindex")
+        .insert_header(("Authorization", api_key.as_str()))
+        .to_request();
+    let reindexes: ListReindexesResponse = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(
+        reindexes.live_collection.as_deref(),
+        Some("doc_embeddings_v0")
+    );
+
+    let request = test::TestRequest::post()
+        .uri("/api/reindex/rollback")
+        .insert_header(("Authorization", api_key.as_str()))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), 400);
+
+    database.close().await;
+}
@@ -0,0 +1,188 @@
+use royal_road_embeddings::{
+    data::models::{IndexDocumentGroupRequest, SimilarityTarget},
+    errors::ErrorResponse,
+    handlers::{
+        doc_group_handler::GroupDocumentRequest,
+        embedding_handler::IndexDocumentRequest,
+        search_handler::{
+            BatchSimilarityRequest, BatchSimilarityResponse, MultiResolutionSearchRequest,
+            MultiResolutionSearchResponse, SimilarityToSingleVectorRequest,
+            SimilarityToSingleVectorResponse,
+        },
+    },
+};
+
+const STORY_ID: i64 = 20;
+
+async fn add_document(content: String, story_id: i64, index: i32) {
+    let response = reqwest::Client::new()
+        .post("http://localhost:8090/api/index_document")
+        .header("Authorization", "key")
+        .json(&IndexDocumentRequest {
+            doc_html: content,
+            story_id,
+            index,
+            force: false,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+async fn add_document_group(story_id: i64, doc_group_size: i32) {
+    let req = reqwest::Client::new();
+
+    let response = req
+        .post("http://localhost:8090/api/document_group")
+        .header("Authorization", "key")
+        .json(&GroupDocumentRequest {
+            doc_group_size,
+            doc_group_stride: None,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+
+    let response = req
+        .put("http://localhost:8090/api/document_group")
+        .header("Authorization", "key")
+        .json(&IndexDocumentGroupRequest::Story {
+            story_id,
+            doc_group_size,
+            doc_group_stride: None,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+}
+
+async fn similarity(doc_group_size: Option<i32>, index: i64) -> reqwest::Response {
+    reqwest::Client::new()
+        .post("http://localhost:8090/api/similarity")
+        .header("Authorization", "key")
+        .json(&SimilarityToSingleVectorRequest {
+            query: "A knight rides into the village".to_string(),
+            doc_group_size,
+            doc_group_stride: None,
+            index,
+            story_id: STORY_ID,
+        })
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn setup_story() {
+    // 7 chapters give 4 groups of size 2 and 3 groups of size 3
+    for i in 0..7 {
+        let content = format!("<p>Chapter {} of the knight's journey.</p>", i);
+        add_document(content, STORY_ID, i).await;
+    }
+    add_document_group(STORY_ID, 2).await;
+    add_document_group(STORY_ID, 3).await;
+}
+
+#[actix_rt::test]
+async fn test_similarity_mixed_group_sizes() {
+    setup_story().await;
+
+    for (doc_group_size, index) in [(None, 6), (Some(2), 3), (Some(3), 2)] {
+        let response = similarity(doc_group_size, index).await;
+        assert_eq!(response.status(), 200);
+        let body = response
+            .json::<SimilarityToSingleVectorResponse>()
+            .await
+            .unwrap();
+        assert!(body.similarity <= 1.0 && body.similarity >= -1.0);
+    }
+
+    // Indices that exist for one size must not match through another size
+    for (doc_group_size, index) in [(None, 7), (Some(2), 4), (Some(3), 3), (Some(4), 0)] {
+        let response = similarity(doc_group_size, index).await;
+        let error = response.json::<ErrorResponse>().await.unwrap();
+        assert_eq!(error.error_code, "0019");
+    }
+
+    let response = similarity(Some(0), 0).await;
+    assert_eq!(response.status(), 400);
+}
+
+#[actix_rt::test]
+async fn test_batch_similarity_reports_missing_targets() {
+    setup_story().await;
+
+    let response = reqwest::Client::new()
+        .post("http://localhost:8090/api/similarity/batch")
+        .header("Authorization", "key")
+        .json(&BatchSimilarityRequest {
+            query: "A knight rides into the village".to_string(),
+            doc_group_size: Some(3),
+            doc_group_stride: None,
+            targets: (0..4)
+                .map(|index| SimilarityTarget {
+                    story_id: STORY_ID,
+                    index,
+                })
+                .collect(),
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body = response.json::<BatchSimilarityResponse>().await.unwrap();
+    let found = body
+        .results
+        .iter()
+        .map(|result| result.found)
+        .collect::<Vec<bool>>();
+    assert_eq!(found, vec![true, true, true, false]);
+    assert!(body.results[3].similarity.is_none());
+}
+
+#[actix_rt::test]
+async fn test_multi_resolution_search_fuses_by_story() {
+    setup_story().await;
+
+    let response = reqwest::Client::new()
+        .post("http://localhost:8090/api/search/multi_resolution")
+        .json(&MultiResolutionSearchRequest {
+            query: "The knight's journey".to_string(),
+            limit: Some(5),
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body = response
+        .json::<MultiResolutionSearchResponse>()
+        .await
+        .unwrap();
+    assert!(!body.results.is_empty() && body.results.len() <= 5);
+
+    // Every story appears once, ordered by fused score
+    let mut story_ids = body
+        .results
+        .iter()
+        .map(|result| result.story_id)
+        .collect::<Vec<i64>>();
+    story_ids.sort();
+    story_ids.dedup();
+    assert_eq!(story_ids.len(), body.results.len());
+    assert!(body
+        .results
+        .windows(2)
+        .all(|pair| pair[0].score >= pair[1].score));
+
+    let story = body
+        .results
+        .iter()
+        .find(|result| result.story_id == STORY_ID)
+        .unwrap();
+    assert!(story.best_rank >= 1);
+    assert!(story.score > 0.0);
+}
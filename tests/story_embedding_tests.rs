@@ -0,0 +1,100 @@
+use royal_road_embeddings::{
+    data::models::EmbeddingLevel,
+    errors::ErrorResponse,
+    handlers::{
+        doc_group_handler::{RecommendDocumentRequest, RecommendDocumentResponse},
+        embedding_handler::IndexDocumentRequest,
+        search_handler::SemanticSearchRequest,
+    },
+    operators::search_operator::DocEmbeddingType,
+};
+
+async fn add_document(content: String, story_id: i64, index: i32) {
+    let response = reqwest::Client::new()
+        .post("http://localhost:8090/api/index_document")
+        .header("Authorization", "key")
+        .json(&IndexDocumentRequest {
+            doc_html: content,
+            story_id,
+            index,
+            force: false,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[actix_rt::test]
+async fn test_story_embeddings_follow_index_document() {
+    for story_id in [30, 31] {
+        for i in 0..3 {
+            let content = format!("<p>Chapter {} of story {} about dragons.</p>", i, story_id);
+            add_document(content, story_id, i).await;
+        }
+    }
+
+    let req = reqwest::Client::new();
+
+    let response = req
+        .post("http://localhost:8090/api/search")
+        .json(&SemanticSearchRequest {
+            doc_group_size: None,
+            doc_group_stride: None,
+            level: EmbeddingLevel::Story,
+            page: 1,
+            query: "dragons".to_string(),
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let results = response.json::<Vec<DocEmbeddingType>>().await.unwrap();
+    let story = results
+        .iter()
+        .find_map(|result| match result {
+            DocEmbeddingType::StoryEmbedding(story) if story.story_id == 30 => Some(story),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(story.chapter_count, 3);
+
+    let response = req
+        .post("http://localhost:8090/api/recommend")
+        .header("Authorization", "key")
+        .json(&RecommendDocumentRequest {
+            doc_group_size: None,
+            doc_group_stride: None,
+            level: EmbeddingLevel::Story,
+            story_ids: vec![30],
+            limit: Some(10),
+            page: None,
+            explain: None,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body = response.json::<RecommendDocumentResponse>().await.unwrap();
+    assert!(!body.recommended_story_ids.contains(&30));
+    assert!(body.recommended_story_ids.contains(&31));
+
+    // Story level results cannot be narrowed to a doc group
+    let response = req
+        .post("http://localhost:8090/api/search")
+        .json(&SemanticSearchRequest {
+            doc_group_size: Some(2),
+            doc_group_stride: None,
+            level: EmbeddingLevel::Story,
+            page: 1,
+            query: "dragons".to_string(),
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    let error = response.json::<ErrorResponse>().await.unwrap();
+    assert_eq!(error.error_code, "0037");
+}
@@ -0,0 +1,65 @@
+use royal_road_embeddings::operators::{
+    vector_outbox_operator::{
+        enqueue_vector_mutations_pg_query, relay_vector_mutations, VectorMutation,
+    },
+    vector_store_operator::{InMemoryVectorStore, VectorPayload, VectorRecord, VectorStore},
+};
+use std::time::Duration;
+
+mod common;
+use common::TestDatabase;
+
+#[actix_rt::test]
+async fn test_relay_applies_mutations_of_a_point_in_order() {
+    let database = TestDatabase::new().await;
+    let vector_store = InMemoryVectorStore::default();
+    vector_store.create_collection("test", 2).await.unwrap();
+    let point_id = uuid::Uuid::new_v4();
+
+    let mut transaction = database.pool.begin().await.unwrap();
+    enqueue_vector_mutations_pg_query(
+        vec![
+            VectorMutation::Upsert {
+                collection_name: "test".to_owned(),
+                record: VectorRecord {
+                    id: point_id,
+                    vector: vec![1.0, 0.0],
+                    payload: VectorPayload::new(),
+                },
+            },
+            VectorMutation::Delete {
+                collection_name: "test".to_owned(),
+                point_id,
+            },
+        ],
+        Duration::ZERO,
+        &mut transaction,
+    )
+    .await
+    .unwrap();
+    transaction.commit().await.unwrap();
+
+    // the delete waits for the upsert of the same point, so each pass takes one
+    assert_eq!(
+        relay_vector_mutations(database.pool.clone(), &vector_store)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(vector_store.count("test").await.unwrap(), 1);
+    assert_eq!(
+        relay_vector_mutations(database.pool.clone(), &vector_store)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(vector_store.count("test").await.unwrap(), 0);
+    assert_eq!(
+        relay_vector_mutations(database.pool.clone(), &vector_store)
+            .await
+            .unwrap(),
+        0
+    );
+
+    database.close().await;
+}
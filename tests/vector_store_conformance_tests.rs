@@ -0,0 +1,300 @@
+use royal_road_embeddings::operators::{
+    pgvector_operator::PgVectorStore,
+    qdrant_operator::{qdrant_client_from_env, qdrant_collection_config_from_env},
+    vector_store_operator::{
+        InMemoryVectorStore, PayloadMatch, QdrantVectorStore, ScoredVectorRecord, VectorFilter,
+        VectorPayload, VectorRecord, VectorStore,
+    },
+};
+use sqlx::postgres::PgPoolOptions;
+use std::collections::HashSet;
+
+const COLLECTION: &str = "vector_store_conformance";
+const NEXT_COLLECTION: &str = "vector_store_conformance_v2";
+const ALIAS: &str = "vector_store_conformance_alias";
+const CROWDED_COLLECTION: &str = "vector_store_conformance_crowded";
+
+fn id(num: u128) -> uuid::Uuid {
+    uuid::Uuid::from_u128(num)
+}
+
+fn record(num: u128, vector: Vec<f32>, story_id: i64) -> VectorRecord {
+    let mut payload = VectorPayload::new();
+    // story ids are stored as strings and indices as integers, as in the real collections
+    payload.insert("story_id".to_owned(), story_id.to_string().into());
+    payload.insert("index".to_owned(), (num as i64).into());
+    VectorRecord {
+        id: id(num),
+        vector,
+        payload,
+    }
+}
+
+fn ids(records: &[ScoredVectorRecord]) -> Vec<uuid::Uuid> {
+    records.iter().map(|record| record.id).collect()
+}
+
+/// A filter matching only a point far from the query still finds it, however many nearer points
+/// it excludes
+async fn check_selective_filters(store: &dyn VectorStore) {
+    store.delete_collection(CROWDED_COLLECTION).await.unwrap();
+    store
+        .create_collection(CROWDED_COLLECTION, 3)
+        .await
+        .unwrap();
+    let mut records: Vec<VectorRecord> = (1..=500)
+        .map(|num| record(num, vec![1.0, num as f32 / 1000.0, 0.0], 1))
+        .collect();
+    records.push(record(501, vec![0.0, 0.0, 1.0], 2));
+    store.upsert(CROWDED_COLLECTION, records).await.unwrap();
+
+    let results = store
+        .search(
+            CROWDED_COLLECTION,
+            vec![1.0, 0.0, 0.0],
+            VectorFilter {
+                must: vec![PayloadMatch::new("story_id", 2)],
+                ..Default::default()
+            },
+            10,
+            0,
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(&results), vec![id(501)]);
+
+    let results = store
+        .search(
+            CROWDED_COLLECTION,
+            vec![1.0, 0.0, 0.0],
+            VectorFilter::has_id(vec![id(501), id(500)]),
+            10,
+            0,
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(&results), vec![id(500), id(501)]);
+
+    store.delete_collection(CROWDED_COLLECTION).await.unwrap();
+}
+
+/// Every backend has to pass this against a collection only it uses
+async fn check_vector_store(store: &dyn VectorStore) {
+    check_selective_filters(store).await;
+
+    store.create_collection(COLLECTION, 3).await.unwrap();
+    // creating it again is not an error
+    store.create_collection(COLLECTION, 3).await.unwrap();
+    store
+        .delete(COLLECTION, (1..=4).map(id).collect())
+        .await
+        .unwrap();
+
+    store
+        .upsert(
+            COLLECTION,
+            vec![
+                record(1, vec![2.0, 0.0, 0.0], 1),
+                record(2, vec![0.6, 0.8, 0.0], 2),
+                record(3, vec![0.0, 0.0, 1.0], 3),
+                record(4, vec![0.28, 0.96, 0.0], 2),
+            ],
+        )
+        .await
+        .unwrap();
+    assert_eq!(store.count(COLLECTION).await.unwrap(), 4);
+
+    // vectors come back normalized
+    let retrieved = store
+        .retrieve(COLLECTION, vec![id(1), id(99)])
+        .await
+        .unwrap();
+    assert_eq!(retrieved.len(), 1);
+    assert!((retrieved[0].vector[0] - 1.0).abs() < 1e-5);
+
+    let results = store
+        .search(
+            COLLECTION,
+            vec![1.0, 0.0, 0.0],
+            VectorFilter::default(),
+            10,
+            0,
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(&results), vec![id(1), id(2), id(4), id(3)]);
+    assert!((results[0].score - 1.0).abs() < 1e-4);
+    assert!((results[1].score - 0.6).abs() < 1e-4);
+
+    let results = store
+        .search(
+            COLLECTION,
+            vec![1.0, 0.0, 0.0],
+            VectorFilter::must_not(PayloadMatch::new("story_id", 2)),
+            1,
+            1,
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(&results), vec![id(3)]);
+
+    let results = store
+        .search(
+            COLLECTION,
+            vec![1.0, 0.0, 0.0],
+            VectorFilter {
+                must: vec![PayloadMatch::new("story_id", 2)],
+                ..Default::default()
+            },
+            10,
+            0,
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(&results), vec![id(2), id(4)]);
+
+    let results = store
+        .search(
+            COLLECTION,
+            vec![1.0, 0.0, 0.0],
+            VectorFilter {
+                must: vec![PayloadMatch::new("index", 4)],
+                ..Default::default()
+            },
+            10,
+            0,
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(&results), vec![id(4)]);
+
+    let results = store
+        .recommend(COLLECTION, vec![id(1)], VectorFilter::default(), 10, 0)
+        .await
+        .unwrap();
+    assert_eq!(ids(&results), vec![id(2), id(4), id(3)]);
+
+    let results = store
+        .recommend(
+            COLLECTION,
+            vec![id(1)],
+            VectorFilter::must_not(PayloadMatch::new("story_id", 2)),
+            10,
+            0,
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(&results), vec![id(3)]);
+
+    let mut scrolled = HashSet::new();
+    let mut offset = None;
+    loop {
+        let (records, next_offset) = store
+            .scroll(COLLECTION, VectorFilter::default(), offset, 3)
+            .await
+            .unwrap();
+        assert!(records.len() <= 3);
+        scrolled.extend(records.into_iter().map(|record| record.id));
+        offset = next_offset;
+        if offset.is_none() {
+            break;
+        }
+    }
+    assert_eq!(scrolled, (1..=4).map(id).collect::<HashSet<uuid::Uuid>>());
+
+    let (records, _) = store
+        .scroll(
+            COLLECTION,
+            VectorFilter::has_id(vec![id(2), id(3)]),
+            None,
+            10,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        records
+            .iter()
+            .map(|record| record.id)
+            .collect::<HashSet<uuid::Uuid>>(),
+        HashSet::from([id(2), id(3)])
+    );
+
+    store.delete(COLLECTION, vec![id(2)]).await.unwrap();
+    assert_eq!(store.count(COLLECTION).await.unwrap(), 3);
+    assert!(store
+        .retrieve(COLLECTION, vec![id(2)])
+        .await
+        .unwrap()
+        .is_empty());
+
+    assert!(store
+        .upsert(COLLECTION, vec![record(5, vec![1.0, 0.0], 1)])
+        .await
+        .is_err());
+    assert!(store
+        .count("vector_store_conformance_missing")
+        .await
+        .is_err());
+
+    // deleting the collection left over from an earlier run takes its alias with it
+    store.delete_collection(NEXT_COLLECTION).await.unwrap();
+    store.delete_collection(NEXT_COLLECTION).await.unwrap();
+    store.switch_alias(ALIAS, COLLECTION).await.unwrap();
+    assert_eq!(
+        store.alias_target(ALIAS).await.unwrap(),
+        Some(COLLECTION.to_owned())
+    );
+    assert_eq!(store.count(ALIAS).await.unwrap(), 3);
+
+    store.create_collection(NEXT_COLLECTION, 3).await.unwrap();
+    store
+        .upsert(NEXT_COLLECTION, vec![record(1, vec![0.0, 1.0, 0.0], 1)])
+        .await
+        .unwrap();
+    store.switch_alias(ALIAS, NEXT_COLLECTION).await.unwrap();
+    assert_eq!(store.count(ALIAS).await.unwrap(), 1);
+    let results = store
+        .search(ALIAS, vec![0.0, 1.0, 0.0], VectorFilter::default(), 10, 0)
+        .await
+        .unwrap();
+    assert_eq!(ids(&results), vec![id(1)]);
+    // creating a collection under an alias name leaves the alias alone
+    store.create_collection(ALIAS, 3).await.unwrap();
+    assert_eq!(store.count(ALIAS).await.unwrap(), 1);
+    assert!(store
+        .switch_alias(COLLECTION, NEXT_COLLECTION)
+        .await
+        .is_err());
+
+    store.delete_collection(NEXT_COLLECTION).await.unwrap();
+    assert_eq!(store.alias_target(ALIAS).await.unwrap(), None);
+    assert!(store.count(ALIAS).await.is_err());
+}
+
+#[actix_rt::test]
+async fn test_in_memory_vector_store_conformance() {
+    check_vector_store(&InMemoryVectorStore::default()).await;
+}
+
+#[actix_rt::test]
+async fn test_qdrant_vector_store_conformance() {
+    dotenvy::dotenv().ok();
+    let store = QdrantVectorStore::new(
+        qdrant_client_from_env().unwrap(),
+        qdrant_collection_config_from_env().unwrap(),
+    );
+    check_vector_store(&store).await;
+}
+
+#[actix_rt::test]
+async fn test_pgvector_store_conformance() {
+    dotenvy::dotenv().ok();
+    let pool = PgPoolOptions::new()
+        .max_connections(5)
+        .connect(&std::env::var("DATABASE_URL").unwrap())
+        .await
+        .unwrap();
+    let store = PgVectorStore::new(pool);
+    store.check_installed().await.unwrap();
+    check_vector_store(&store).await;
+}